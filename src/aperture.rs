@@ -0,0 +1,325 @@
+use crate::{
+    error::{Error, Result},
+    math::{concentric_sample_disk, maxf},
+    sampler::Sampler,
+    simd::sinf_cosf,
+};
+use glam::{vec3, Vec3};
+use image;
+use std::{f32, path::Path, sync::Arc};
+
+/// Greyscale image used as the aperture, brighter pixels let through more light. Only the disk
+/// inscribed in the image is used.
+#[derive(Debug)]
+pub struct ApertureMask {
+    width: u32,
+    height: u32,
+    /// Cumulative weight of the rows, normalised to end at 1.
+    row_cdf: Vec<f32>,
+    /// Cumulative weight of the pixels along each row, `width + 1` entries per row.
+    column_cdf: Vec<f32>,
+}
+
+/// Picks the cell of the piecewise constant distribution `cdf` that `u` in [0, 1) falls in,
+/// returning it and how far through the cell `u` is.
+fn sample_cdf(cdf: &[f32], u: f32) -> (usize, f32) {
+    // the first entry above u ends a cell with some weight, cells with none have no width
+    let (mut low, mut high) = (1, cdf.len() - 1);
+    while low < high {
+        let mid = (low + high) / 2;
+        if cdf[mid] > u {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    let cell = low - 1;
+    let offset = (u - cdf[cell]) / (cdf[low] - cdf[cell]);
+    (cell, offset.max(0.0).min(1.0))
+}
+
+/// Turns `weights` into a cumulative distribution starting at 0 and ending at 1, or all zeros
+/// if there's no weight.
+fn cdf_of(weights: impl Iterator<Item = f32>) -> Vec<f32> {
+    let mut cdf = vec![0.0];
+    for weight in weights {
+        let total = cdf[cdf.len() - 1];
+        cdf.push(total + weight);
+    }
+    let total = cdf[cdf.len() - 1];
+    if total > 0.0 {
+        // the last entry divides to exactly 1 and cells without weight keep no width
+        for value in &mut cdf {
+            *value /= total;
+        }
+    }
+    cdf
+}
+
+impl ApertureMask {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ApertureMask> {
+        let path = path.as_ref();
+        let image = image::open(path)
+            .map_err(|err| {
+                Error::Image(format!(
+                    "couldn't load aperture mask {}: {}",
+                    path.display(),
+                    err
+                ))
+            })?
+            .to_luma();
+        let (width, height) = image.dimensions();
+        let weights = image.pixels().map(|p| f32::from(p.data[0])).collect();
+        ApertureMask::new(width, height, weights).map_err(|_| {
+            Error::InvalidParams(format!(
+                "aperture mask {} is black inside its inscribed circle",
+                path.display()
+            ))
+        })
+    }
+
+    /// Builds a mask from `weights`, row by row from the top. Fails if no pixel with its
+    /// centre inside the inscribed disk has any weight.
+    pub fn new(width: u32, height: u32, weights: Vec<f32>) -> Result<ApertureMask> {
+        assert_eq!(weights.len(), width as usize * height as usize);
+        // pixels centred outside the unit disk are left out so samples stay on the lens
+        let inside = |i: usize, j: usize| {
+            let x = (i as f32 + 0.5) / width as f32 * 2.0 - 1.0;
+            let y = 1.0 - (j as f32 + 0.5) / height as f32 * 2.0;
+            x * x + y * y <= 1.0
+        };
+        let weights: Vec<f32> = weights
+            .iter()
+            .enumerate()
+            .map(|(index, &weight)| {
+                let (i, j) = (index % width as usize, index / width as usize);
+                if inside(i, j) {
+                    maxf(weight, 0.0)
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+        let row_cdf = cdf_of(
+            weights
+                .chunks(width as usize)
+                .map(|row| row.iter().sum::<f32>()),
+        );
+        if row_cdf[row_cdf.len() - 1] == 0.0 {
+            return Err(Error::InvalidParams(
+                "aperture mask is black inside its inscribed circle".to_string(),
+            ));
+        }
+        let column_cdf = weights
+            .chunks(width as usize)
+            .flat_map(|row| cdf_of(row.iter().cloned()))
+            .collect();
+        Ok(ApertureMask {
+            width,
+            height,
+            row_cdf,
+            column_cdf,
+        })
+    }
+
+    /// Returns a point in [-1, 1] with y up, distributed like the mask's brightness.
+    fn sample(&self, (u, v): (f32, f32)) -> Vec3 {
+        let (j, y_offset) = sample_cdf(&self.row_cdf, v);
+        let row_len = self.width as usize + 1;
+        let (i, x_offset) = sample_cdf(&self.column_cdf[j * row_len..(j + 1) * row_len], u);
+        let x = (i as f32 + x_offset) / self.width as f32 * 2.0 - 1.0;
+        let y = 1.0 - (j as f32 + y_offset) / self.height as f32 * 2.0;
+        vec3(x, y, 0.0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ApertureShape {
+    Circle,
+    /// Regular polygon formed by `blades` straight aperture blades, rotated by `rotation` degrees.
+    Polygon {
+        blades: u32,
+        rotation: f32,
+    },
+    Mask(Arc<ApertureMask>),
+}
+
+// give up on rejection sampling after this many attempts
+const MAX_ATTEMPTS: u32 = 64;
+
+impl ApertureShape {
+    /// Returns a point on the aperture, uniformly distributed or following the mask's
+    /// brightness, which fits inside the unit disk.
+    fn sample(&self, sampler: &mut dyn Sampler) -> Vec3 {
+        match self {
+            ApertureShape::Circle => concentric_sample_disk(sampler.get_2d()),
+            ApertureShape::Polygon { blades, rotation } => {
+                // all of the triangles fanning out from the centre have equal area
+                let blades = (*blades).max(3);
+                let blade = ((sampler.get_1d() * blades as f32) as u32).min(blades - 1) as f32;
+                let step = 2.0 * f32::consts::PI / blades as f32;
+                let theta0 = rotation.to_radians() + blade * step;
+                let (sin0, cos0) = sinf_cosf(theta0);
+                let (sin1, cos1) = sinf_cosf(theta0 + step);
+                let (mut a, mut b) = sampler.get_2d();
+                if a + b > 1.0 {
+                    a = 1.0 - a;
+                    b = 1.0 - b;
+                }
+                vec3(a * cos0 + b * cos1, a * sin0 + b * sin1, 0.0)
+            }
+            ApertureShape::Mask(mask) => {
+                // only pixels on the edge of the disk can give points outside it
+                for _ in 0..MAX_ATTEMPTS {
+                    let p = mask.sample(sampler.get_2d());
+                    if p.length_squared() <= 1.0 {
+                        return p;
+                    }
+                }
+                let p = mask.sample(sampler.get_2d());
+                p / maxf(p.length(), 1.0)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Lens {
+    radius: f32,
+    shape: ApertureShape,
+    cats_eye: f32,
+}
+
+impl Lens {
+    /// `cats_eye` is the amount of mechanical vignetting, at 1.0 the aperture seen from the image
+    /// corners is clipped to half its width.
+    pub fn new(radius: f32, shape: ApertureShape, cats_eye: f32) -> Lens {
+        Lens {
+            radius,
+            shape,
+            cats_eye,
+        }
+    }
+
+    /// Returns an offset on the lens in lens units for the normalised image coordinates `s` and
+    /// `t`.
+    pub fn sample(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Vec3 {
+        if self.radius == 0.0 {
+            return Vec3::zero();
+        }
+        if self.cats_eye <= 0.0 {
+            return self.radius * self.shape.sample(sampler);
+        }
+        // off axis the lens barrel clips the aperture with a disk shifted towards the image
+        // centre, giving highlights a cat's eye shape
+        let clip = self.cats_eye * vec3(1.0 - 2.0 * s, 1.0 - 2.0 * t, 0.0) * 0.5_f32.sqrt();
+        for _ in 0..MAX_ATTEMPTS {
+            let p = self.shape.sample(sampler);
+            if (p - clip).length_squared() < 1.0 {
+                return self.radius * p;
+            }
+        }
+        // rarely reached, an unclipped sample spreads the remainder over the aperture instead of
+        // piling it onto one point
+        self.radius * self.shape.sample(sampler)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::{SamplerKind, SamplerSource};
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256Plus;
+
+    const SAMPLES: u32 = 4000;
+
+    fn sampler() -> Box<dyn Sampler> {
+        SamplerSource::new(SamplerKind::Random, 1, 0).create(Xoshiro256Plus::seed_from_u64(1))
+    }
+
+    #[test]
+    fn polygon_samples_stay_inside_the_polygon() {
+        let mut sampler = sampler();
+        for blades in 3..9 {
+            let rotation = 15.0;
+            let shape = ApertureShape::Polygon { blades, rotation };
+            let corner = |k: u32| {
+                let theta = (rotation + k as f32 * 360.0 / blades as f32).to_radians();
+                vec3(theta.cos(), theta.sin(), 0.0)
+            };
+            for _ in 0..SAMPLES {
+                let p = shape.sample(sampler.as_mut());
+                // the corners go anticlockwise so the inside is left of every edge
+                for k in 0..blades {
+                    let (a, b) = (corner(k), corner(k + 1));
+                    let edge = b - a;
+                    let to_p = p - a;
+                    let cross = edge.get_x() * to_p.get_y() - edge.get_y() * to_p.get_x();
+                    assert!(cross >= -1.0e-5, "{:?} outside {} blades", p, blades);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mask_samples_land_on_non_zero_pixels() {
+        // two lit pixels of an 8x8 mask, one four times brighter than the other
+        let lit = [((2, 3), 255.0), ((5, 5), 63.75)];
+        let mut weights = vec![0.0; 64];
+        for &((i, j), weight) in lit.iter() {
+            weights[j * 8 + i] = weight;
+        }
+        let shape = ApertureShape::Mask(Arc::new(ApertureMask::new(8, 8, weights).unwrap()));
+        let mut sampler = sampler();
+        let mut counts = [0; 2];
+        for _ in 0..SAMPLES {
+            let p = shape.sample(sampler.as_mut());
+            let x = (p.get_x() + 1.0) * 4.0;
+            let y = (1.0 - p.get_y()) * 4.0;
+            let pixel = lit.iter().position(|&((i, j), _)| {
+                let inside = |v: f32, start: usize| {
+                    v >= start as f32 - 1.0e-4 && v <= start as f32 + 1.0 + 1.0e-4
+                };
+                inside(x, i) && inside(y, j)
+            });
+            counts[pixel.unwrap_or_else(|| panic!("{:?} isn't on a lit pixel", p))] += 1;
+        }
+        let bright = counts[0] as f32 / SAMPLES as f32;
+        assert!((bright - 0.8).abs() < 0.03, "{}", bright);
+    }
+
+    #[test]
+    fn black_masks_are_rejected() {
+        assert!(ApertureMask::new(4, 4, vec![0.0; 16]).is_err());
+        // only the corner is lit, which is outside the disk the mask is used in
+        let mut corner = vec![0.0; 64];
+        corner[0] = 1.0;
+        assert!(ApertureMask::new(8, 8, corner).is_err());
+    }
+
+    #[test]
+    fn cats_eye_samples_stay_inside_the_clip_disk() {
+        let mut sampler = sampler();
+        let radius = 2.0;
+        for shape in vec![
+            ApertureShape::Circle,
+            ApertureShape::Polygon {
+                blades: 5,
+                rotation: 0.0,
+            },
+        ] {
+            for &cats_eye in &[0.5, 1.0] {
+                let lens = Lens::new(radius, shape.clone(), cats_eye);
+                for &(s, t) in &[(0.0, 0.0), (1.0, 0.25), (0.5, 1.0), (0.9, 0.9)] {
+                    let clip = cats_eye * vec3(1.0 - 2.0 * s, 1.0 - 2.0 * t, 0.0) * 0.5_f32.sqrt();
+                    for _ in 0..SAMPLES / 4 {
+                        let p = lens.sample(s, t, sampler.as_mut()) / radius;
+                        assert!(p.length() <= 1.0 + 1.0e-5, "{:?} off the lens", p);
+                        assert!((p - clip).length() < 1.0, "{:?} outside {:?}", p, clip);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,605 @@
+use crate::stats::{elapsed_secs, json_string};
+use pathtrace_rs::{
+    camera::Projection,
+    error::{Error, Result},
+    film::Film,
+    presets,
+    scene::{Params, Tracing},
+    simd::TargetFeature,
+};
+use std::{collections::BTreeMap, fs, path::Path, time::SystemTime};
+
+/// Seed for every benchmark render, so runs trace the same rays.
+pub const SEED: u64 = 0x5eed;
+
+/// What to benchmark: every combination of preset, instruction set and way of tracing is
+/// rendered `runs` times.
+pub struct Bench {
+    pub presets: Vec<String>,
+    pub features: Vec<TargetFeature>,
+    pub tracings: Vec<Tracing>,
+    pub runs: u32,
+    pub params: Params,
+    /// Percentage drop in mean Mrays/s from the baseline reported as a regression.
+    pub tolerance: f64,
+}
+
+/// Mrays/s of each run of one preset with one instruction set and way of tracing.
+pub struct Measurement {
+    pub preset: String,
+    pub feature: TargetFeature,
+    pub tracing: Tracing,
+    pub mrays_per_sec: Vec<f64>,
+}
+
+impl Measurement {
+    pub fn mean(&self) -> f64 {
+        self.mrays_per_sec.iter().sum::<f64>() / self.mrays_per_sec.len().max(1) as f64
+    }
+
+    pub fn median(&self) -> f64 {
+        let mut sorted = self.mrays_per_sec.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let middle = sorted.len() / 2;
+        match sorted.len() {
+            0 => 0.0,
+            len if len % 2 == 0 => (sorted[middle - 1] + sorted[middle]) / 2.0,
+            _ => sorted[middle],
+        }
+    }
+
+    /// Sample standard deviation.
+    pub fn stddev(&self) -> f64 {
+        let len = self.mrays_per_sec.len();
+        if len < 2 {
+            return 0.0;
+        }
+        let mean = self.mean();
+        let sum_sq: f64 = self
+            .mrays_per_sec
+            .iter()
+            .map(|x| (x - mean) * (x - mean))
+            .sum();
+        (sum_sq / (len - 1) as f64).sqrt()
+    }
+}
+
+impl Bench {
+    fn measure(
+        &self,
+        preset: &str,
+        feature: TargetFeature,
+        tracing: Tracing,
+    ) -> Result<Measurement> {
+        let params = Params {
+            target_feature: feature,
+            tracing,
+            ..self.params
+        };
+        params.validate()?;
+        let (scene, camera_desc) = presets::from_name(preset, &params)?;
+        let camera = camera_desc.build(Projection::Perspective);
+        let mut mrays_per_sec = Vec::with_capacity(self.runs as usize);
+        // the first render warms up caches and the thread pool and isn't counted
+        for run in 0..=self.runs {
+            let mut film = Film::new(self.params.width, self.params.height, false);
+            let start = SystemTime::now();
+            let rays = scene.update(&params, camera.as_ref(), &mut film);
+            let secs = elapsed_secs(start);
+            if run > 0 {
+                mrays_per_sec.push(rays.total() as f64 / 1_000_000.0 / secs);
+            }
+        }
+        Ok(Measurement {
+            preset: preset.to_string(),
+            feature,
+            tracing,
+            mrays_per_sec,
+        })
+    }
+
+    /// Renders every preset with every instruction set and way of tracing, printing results as
+    /// they're measured, then how packets compare with single rays if both were measured.
+    pub fn run(&self) -> Result<Vec<Measurement>> {
+        println!(
+            "{} runs at {}x{} with {} samples per pixel",
+            self.runs, self.params.width, self.params.height, self.params.samples
+        );
+        println!(
+            "{:<10} {:<8} {:<8} {:>8} {:>8} {:>8}",
+            "preset", "simd", "tracing", "mean", "median", "stddev"
+        );
+        let mut measurements = Vec::new();
+        for preset in &self.presets {
+            for &feature in &self.features {
+                for &tracing in &self.tracings {
+                    let measurement = self.measure(preset, feature, tracing)?;
+                    println!(
+                        "{:<10} {:<8} {:<8} {:>8.3} {:>8.3} {:>8.3}",
+                        preset,
+                        feature.name(),
+                        tracing.name(),
+                        measurement.mean(),
+                        measurement.median(),
+                        measurement.stddev()
+                    );
+                    measurements.push(measurement);
+                }
+            }
+        }
+        println!("(Mrays/s)");
+
+        let packets: Vec<&Measurement> = measurements
+            .iter()
+            .filter(|measurement| measurement.tracing == Tracing::Packet)
+            .collect();
+        let mut printed_heading = false;
+        for packet in packets {
+            let single = measurements.iter().find(|measurement| {
+                measurement.tracing == Tracing::Single
+                    && measurement.preset == packet.preset
+                    && measurement.feature == packet.feature
+            });
+            if let Some(single) = single {
+                if !printed_heading {
+                    println!("packet compared to single ray tracing:");
+                    printed_heading = true;
+                }
+                println!(
+                    "{:<10} {:<8} {:>8.3} -> {:>8.3} {:>+7.1}%",
+                    packet.preset,
+                    packet.feature.name(),
+                    single.mean(),
+                    packet.mean(),
+                    (packet.mean() / single.mean() - 1.0) * 100.0
+                );
+            }
+        }
+        Ok(measurements)
+    }
+
+    pub fn to_json(&self, measurements: &[Measurement]) -> String {
+        let results: Vec<String> = measurements
+            .iter()
+            .map(|measurement| {
+                let runs: Vec<String> = measurement
+                    .mrays_per_sec
+                    .iter()
+                    .map(|x| format!("{:.3}", x))
+                    .collect();
+                format!(
+                    r#"    {{
+      "preset": {},
+      "simd": {},
+      "tracing": {},
+      "mean": {:.3},
+      "median": {:.3},
+      "stddev": {:.3},
+      "runs": [{}]
+    }}"#,
+                    json_string(&measurement.preset),
+                    json_string(measurement.feature.name()),
+                    json_string(measurement.tracing.name()),
+                    measurement.mean(),
+                    measurement.median(),
+                    measurement.stddev(),
+                    runs.join(", ")
+                )
+            })
+            .collect();
+        format!(
+            r#"{{
+  "width": {},
+  "height": {},
+  "samples_per_pixel": {},
+  "max_depth": {},
+  "seed": {},
+  "threads": {},
+  "results": [
+{}
+  ]
+}}
+"#,
+            self.params.width,
+            self.params.height,
+            self.params.samples,
+            self.params.max_depth,
+            self.params.seed,
+            rayon::current_num_threads(),
+            results.join(",\n")
+        )
+    }
+
+    /// Compares mean Mrays/s with a JSON file written by an earlier run, printing the change for
+    /// each result in both. Returns the number of regressions beyond the tolerance.
+    pub fn compare(&self, measurements: &[Measurement], baseline_path: &Path) -> Result<usize> {
+        let text = fs::read_to_string(baseline_path).map_err(Error::io(format!(
+            "couldn't read baseline {}",
+            baseline_path.display()
+        )))?;
+        let baseline = Json::parse(&text).map_err(|message| {
+            Error::InvalidParams(format!(
+                "couldn't read baseline {}: {}",
+                baseline_path.display(),
+                message
+            ))
+        })?;
+        for (key, value) in &[
+            ("width", self.params.width),
+            ("height", self.params.height),
+            ("samples_per_pixel", self.params.samples),
+            ("max_depth", self.params.max_depth),
+        ] {
+            if baseline.get(key).and_then(Json::as_f64) != Some(f64::from(*value)) {
+                println!(
+                    "warning: the baseline wasn't rendered with the same {}",
+                    key
+                );
+            }
+        }
+        let mut means = BTreeMap::new();
+        for result in baseline.get("results").map_or(&[][..], Json::as_array) {
+            let preset = result.get("preset").and_then(Json::as_str);
+            let feature = result.get("simd").and_then(Json::as_str);
+            // baselines from before packet tracing only traced single rays
+            let tracing = result
+                .get("tracing")
+                .and_then(Json::as_str)
+                .unwrap_or("single");
+            let mean = result.get("mean").and_then(Json::as_f64);
+            if let (Some(preset), Some(feature), Some(mean)) = (preset, feature, mean) {
+                means.insert(
+                    (preset.to_string(), feature.to_string(), tracing.to_string()),
+                    mean,
+                );
+            }
+        }
+
+        println!("compared to {}:", baseline_path.display());
+        let mut regressions = 0;
+        for measurement in measurements {
+            let key = (
+                measurement.preset.clone(),
+                measurement.feature.name().to_string(),
+                measurement.tracing.name().to_string(),
+            );
+            let baseline_mean = match means.get(&key) {
+                Some(mean) if *mean > 0.0 => *mean,
+                _ => continue,
+            };
+            let change = (measurement.mean() / baseline_mean - 1.0) * 100.0;
+            let regressed = change < -self.tolerance;
+            if regressed {
+                regressions += 1;
+            }
+            println!(
+                "{:<10} {:<8} {:<8} {:>8.3} -> {:>8.3} {:>+7.1}%{}",
+                measurement.preset,
+                measurement.feature.name(),
+                measurement.tracing.name(),
+                baseline_mean,
+                measurement.mean(),
+                change,
+                if regressed { "  REGRESSION" } else { "" }
+            );
+        }
+        Ok(regressions)
+    }
+}
+
+/// Just enough JSON to read back baselines and render reports.
+#[derive(Debug)]
+pub enum Json {
+    /// `true`, `false` or `null`, which baselines don't use.
+    Literal,
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(text: &str) -> std::result::Result<Json, String> {
+        let mut parser = JsonParser {
+            chars: text.chars().collect(),
+            position: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.position < parser.chars.len() {
+            return Err(parser.error("unexpected trailing characters"));
+        }
+        Ok(value)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[Json] {
+        match self {
+            Json::Array(values) => values,
+            _ => &[],
+        }
+    }
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl JsonParser {
+    fn error(&self, message: &str) -> String {
+        format!("{} at character {}", message, self.position)
+    }
+
+    /// Moves past characters while `accept` returns true for them.
+    fn skip_while(&mut self, accept: impl Fn(char) -> bool) {
+        while let Some(&c) = self.chars.get(self.position) {
+            if !accept(c) {
+                break;
+            }
+            self.position += 1;
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        self.skip_while(char::is_whitespace);
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.get(self.position).cloned();
+        self.position += 1;
+        c
+    }
+
+    fn expect(&mut self, expected: char) -> std::result::Result<(), String> {
+        self.skip_whitespace();
+        if self.next() == Some(expected) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", expected)))
+        }
+    }
+
+    fn literal(&mut self, word: &str) -> std::result::Result<Json, String> {
+        for expected in word.chars() {
+            if self.next() != Some(expected) {
+                return Err(self.error(&format!("expected '{}'", word)));
+            }
+        }
+        Ok(Json::Literal)
+    }
+
+    fn string(&mut self) -> std::result::Result<String, String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => match self.next() {
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some('r') => string.push('\r'),
+                    Some('u') => {
+                        let hex: String = (0..4).filter_map(|_| self.next()).collect();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(std::char::from_u32)
+                            .ok_or_else(|| self.error("bad unicode escape"))?;
+                        string.push(c);
+                    }
+                    Some(c) => string.push(c),
+                    None => break,
+                },
+                Some(c) => string.push(c),
+                None => break,
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    fn value(&mut self) -> std::result::Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.get(self.position) {
+            Some('{') => {
+                self.position += 1;
+                let mut members = Vec::new();
+                self.skip_whitespace();
+                if self.chars.get(self.position) == Some(&'}') {
+                    self.position += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    let name = self.string()?;
+                    self.expect(':')?;
+                    members.push((name, self.value()?));
+                    self.skip_whitespace();
+                    match self.next() {
+                        Some(',') => {}
+                        Some('}') => return Ok(Json::Object(members)),
+                        _ => return Err(self.error("expected ',' or '}'")),
+                    }
+                }
+            }
+            Some('[') => {
+                self.position += 1;
+                let mut values = Vec::new();
+                self.skip_whitespace();
+                if self.chars.get(self.position) == Some(&']') {
+                    self.position += 1;
+                    return Ok(Json::Array(values));
+                }
+                loop {
+                    values.push(self.value()?);
+                    self.skip_whitespace();
+                    match self.next() {
+                        Some(',') => {}
+                        Some(']') => return Ok(Json::Array(values)),
+                        _ => return Err(self.error("expected ',' or ']'")),
+                    }
+                }
+            }
+            Some('"') => self.string().map(Json::String),
+            Some('t') => self.literal("true"),
+            Some('f') => self.literal("false"),
+            Some('n') => self.literal("null"),
+            Some(_) => {
+                let start = self.position;
+                self.skip_while(|c| c.is_ascii_digit() || "+-.eE".contains(c));
+                let number: String = self.chars[start..self.position].iter().collect();
+                number
+                    .parse()
+                    .map(Json::Number)
+                    .map_err(|_| self.error("expected a value"))
+            }
+            None => Err(self.error("unexpected end of file")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn measurement(preset: &str, tracing: Tracing, mrays_per_sec: &[f64]) -> Measurement {
+        Measurement {
+            preset: preset.to_string(),
+            feature: TargetFeature::FallBack,
+            tracing,
+            mrays_per_sec: mrays_per_sec.to_vec(),
+        }
+    }
+
+    fn bench() -> Bench {
+        Bench {
+            presets: vec!["aras".to_string(), "small".to_string()],
+            features: vec![TargetFeature::FallBack],
+            tracings: vec![Tracing::Single, Tracing::Packet],
+            runs: 3,
+            params: Params::new(32, 18),
+            tolerance: 5.0,
+        }
+    }
+
+    /// Writes `text` to a baseline file, compares `measurements` with it and removes it.
+    fn compare(name: &str, text: &str, measurements: &[Measurement]) -> Result<usize> {
+        let path = env::temp_dir().join(format!("pathtrace-{}-{}.json", name, std::process::id()));
+        fs::write(&path, text).unwrap();
+        let result = bench().compare(measurements, &path);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn statistics() {
+        let measurement = measurement("aras", Tracing::Single, &[4.0, 1.0, 3.0, 2.0]);
+        assert_eq!(measurement.mean(), 2.5);
+        assert_eq!(measurement.median(), 2.5);
+        assert!((measurement.stddev() - (5.0f64 / 3.0).sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn parses_saved_results() {
+        let bench = bench();
+        let measurements = vec![
+            measurement("aras", Tracing::Single, &[10.0, 12.0, 11.0]),
+            measurement("small \"quoted\"", Tracing::Packet, &[20.5]),
+        ];
+        let json = Json::parse(&bench.to_json(&measurements)).unwrap();
+        assert_eq!(json.get("width").and_then(Json::as_f64), Some(32.0));
+        assert_eq!(json.get("seed").and_then(Json::as_f64), Some(0.0));
+        let results = json.get("results").map_or(&[][..], Json::as_array);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].get("mean").and_then(Json::as_f64), Some(11.0));
+        assert_eq!(
+            results[1].get("preset").and_then(Json::as_str),
+            Some("small \"quoted\"")
+        );
+        assert_eq!(
+            results[1].get("tracing").and_then(Json::as_str),
+            Some("packet")
+        );
+        let runs = results[0].get("runs").map_or(&[][..], Json::as_array);
+        let runs: Vec<f64> = runs.iter().filter_map(Json::as_f64).collect();
+        assert_eq!(runs, [10.0, 12.0, 11.0]);
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        for text in &[
+            "",
+            "{",
+            r#"{"a": 1,}"#,
+            r#"{"a" 1}"#,
+            r#"["unterminated]"#,
+            r#"{"a": 1} trailing"#,
+            r#"{"a": tru}"#,
+            r#"{"a": "\u12"}"#,
+        ] {
+            assert!(Json::parse(text).is_err(), "parsed {:?}", text);
+        }
+    }
+
+    #[test]
+    fn counts_regressions_beyond_tolerance() {
+        let baseline = bench().to_json(&[
+            measurement("aras", Tracing::Single, &[100.0]),
+            measurement("aras", Tracing::Packet, &[100.0]),
+            measurement("small", Tracing::Single, &[100.0]),
+        ]);
+        let measurements = [
+            // within the 5% tolerance
+            measurement("aras", Tracing::Single, &[96.0]),
+            // regressed
+            measurement("aras", Tracing::Packet, &[90.0]),
+            // faster
+            measurement("small", Tracing::Single, &[120.0]),
+            // not in the baseline
+            measurement("small", Tracing::Packet, &[1.0]),
+        ];
+        assert_eq!(compare("regressions", &baseline, &measurements).unwrap(), 1);
+    }
+
+    #[test]
+    fn old_baselines_count_as_single_rays() {
+        let baseline = r#"{
+  "width": 32, "height": 18, "samples_per_pixel": 4, "max_depth": 10,
+  "results": [{"preset": "aras", "simd": "scalar", "mean": 100.0}]
+}"#;
+        let slower = [measurement("aras", Tracing::Single, &[50.0])];
+        assert_eq!(compare("old-baseline", baseline, &slower).unwrap(), 1);
+        let packets = [measurement("aras", Tracing::Packet, &[50.0])];
+        assert_eq!(
+            compare("old-baseline-packet", baseline, &packets).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn unreadable_baseline_is_an_error() {
+        assert!(compare("bad-baseline", "not json", &[]).is_err());
+    }
+}
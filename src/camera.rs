@@ -1,24 +1,120 @@
 use crate::{
+    aperture::{ApertureShape, Lens},
     collision::{ray, Ray},
-    math::random_in_unit_disk,
+    error::{Error, Result},
+    sampler::Sampler,
+    simd::sinf_cosf,
 };
 use glam::Vec3;
-use rand::Rng;
 use std::f32;
 
-// #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    Perspective,
+    Orthographic,
+    /// Equidistant fisheye, the vertical field of view spans the image height.
+    Fisheye,
+    /// Full sphere latitude/longitude panorama, best rendered at a 2:1 aspect ratio.
+    Equirectangular,
+    /// Six 90 degree faces in a 3x2 grid, best rendered at a 3:2 aspect ratio.
+    Cubemap,
+}
+
+impl Projection {
+    /// Names accepted by `from_name`.
+    pub const NAMES: [&'static str; 5] = [
+        "perspective",
+        "orthographic",
+        "fisheye",
+        "equirectangular",
+        "cubemap",
+    ];
+
+    pub fn from_name(name: &str) -> Option<Projection> {
+        match name {
+            "perspective" => Some(Projection::Perspective),
+            "orthographic" => Some(Projection::Orthographic),
+            "fisheye" => Some(Projection::Fisheye),
+            "equirectangular" => Some(Projection::Equirectangular),
+            "cubemap" => Some(Projection::Cubemap),
+            _ => None,
+        }
+    }
+
+    /// Whether stereo pairs can be rendered with this projection. Fisheye and orthographic
+    /// views have no sensible parallax.
+    pub fn supports_stereo(self) -> bool {
+        match self {
+            Projection::Perspective | Projection::Equirectangular | Projection::Cubemap => true,
+            Projection::Orthographic | Projection::Fisheye => false,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Projection::Perspective => "perspective",
+            Projection::Orthographic => "orthographic",
+            Projection::Fisheye => "fisheye",
+            Projection::Equirectangular => "equirectangular",
+            Projection::Cubemap => "cubemap",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StereoLayout {
+    /// Left eye above the right eye.
+    TopBottom,
+    /// Left eye to the left of the right eye.
+    SideBySide,
+}
+
+impl StereoLayout {
+    pub fn from_name(name: &str) -> Option<StereoLayout> {
+        match name {
+            "top-bottom" => Some(StereoLayout::TopBottom),
+            "side-by-side" => Some(StereoLayout::SideBySide),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
-pub struct Camera {
-    origin: Vec3,
-    lower_left_corner: Vec3,
-    horizontal: Vec3,
-    vertical: Vec3,
-    u: Vec3,
-    v: Vec3,
-    lens_radius: f32,
+pub struct Stereo {
+    pub layout: StereoLayout,
+    /// Interpupillary distance in scene units.
+    pub ipd: f32,
+    /// Distance of the zero parallax plane, defaults to the focus distance.
+    pub convergence: Option<f32>,
 }
 
-impl Camera {
+pub trait Camera: Send + Sync {
+    /// Returns a ray through the normalised image coordinates `s` and `t`, with `t = 0` at the
+    /// bottom of the image.
+    fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Ray;
+}
+
+/// Camera placement and lens settings which are independent of the projection.
+// #[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug)]
+pub struct CameraDesc {
+    pub lookfrom: Vec3,
+    pub lookat: Vec3,
+    pub vup: Vec3,
+    pub vfov: f32,
+    pub aspect: f32,
+    pub aperture: f32,
+    pub focus_dist: f32,
+    pub aperture_shape: ApertureShape,
+    /// Amount of mechanical vignetting towards the image corners, from 0 to 1.
+    pub cats_eye: f32,
+    /// Sideways offset of the eye from `lookfrom`, negative for the left eye.
+    pub eye_offset: f32,
+    /// Distance at which the left and right eye views converge.
+    pub convergence: f32,
+}
+
+impl CameraDesc {
     pub fn new(
         lookfrom: Vec3,
         lookat: Vec3,
@@ -27,16 +123,108 @@ impl Camera {
         aspect: f32,
         aperture: f32,
         focus_dist: f32,
-    ) -> Camera {
-        let theta = vfov * f32::consts::PI / 180.0;
-        let half_height = (theta * 0.5).tan();
-        let half_width = aspect * half_height;
-        let w = (lookfrom - lookat).normalize();
-        let u = vup.cross(w).normalize();
+    ) -> CameraDesc {
+        CameraDesc {
+            lookfrom,
+            lookat,
+            vup,
+            vfov,
+            aspect,
+            aperture,
+            focus_dist,
+            aperture_shape: ApertureShape::Circle,
+            cats_eye: 0.0,
+            eye_offset: 0.0,
+            convergence: f32::INFINITY,
+        }
+    }
+
+    /// Checks the lens settings are in range.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: &str| Err(Error::InvalidParams(message.to_string()));
+        if let ApertureShape::Polygon { blades, .. } = self.aperture_shape {
+            if blades < 3 {
+                return invalid("the aperture needs at least 3 blades");
+            }
+        }
+        if !(0.0..=1.0).contains(&self.cats_eye) {
+            return invalid("cat's eye amount must be from 0 to 1");
+        }
+        Ok(())
+    }
+
+    /// Returns the camera for the left and right eyes.
+    pub fn stereo_pair(&self, stereo: &Stereo) -> (CameraDesc, CameraDesc) {
+        let convergence = stereo.convergence.unwrap_or(self.focus_dist);
+        let half_ipd = stereo.ipd * 0.5;
+        (
+            CameraDesc {
+                eye_offset: -half_ipd,
+                convergence,
+                ..self.clone()
+            },
+            CameraDesc {
+                eye_offset: half_ipd,
+                convergence,
+                ..self.clone()
+            },
+        )
+    }
+
+    pub fn build(&self, projection: Projection) -> Box<dyn Camera> {
+        match projection {
+            Projection::Perspective => Box::new(PerspectiveCamera::new(self)),
+            Projection::Orthographic => Box::new(OrthographicCamera::new(self)),
+            Projection::Fisheye => Box::new(FisheyeCamera::new(self)),
+            Projection::Equirectangular => Box::new(EquirectangularCamera::new(self)),
+            Projection::Cubemap => Box::new(CubemapCamera::new(self)),
+        }
+    }
+
+    fn lens(&self) -> Lens {
+        Lens::new(
+            self.aperture * 0.5,
+            self.aperture_shape.clone(),
+            self.cats_eye,
+        )
+    }
+
+    fn basis(&self) -> (Vec3, Vec3, Vec3) {
+        let w = (self.lookfrom - self.lookat).normalize();
+        let u = self.vup.cross(w).normalize();
         let v = w.cross(u);
-        Camera {
-            origin: lookfrom,
-            lower_left_corner: lookfrom
+        (u, v, w)
+    }
+
+    fn half_height(&self) -> f32 {
+        let theta = self.vfov * f32::consts::PI / 180.0;
+        (theta * 0.5).tan()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PerspectiveCamera {
+    origin: Vec3,
+    lower_left_corner: Vec3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    u: Vec3,
+    v: Vec3,
+    lens: Lens,
+}
+
+impl PerspectiveCamera {
+    pub fn new(desc: &CameraDesc) -> PerspectiveCamera {
+        let half_height = desc.half_height();
+        let half_width = desc.aspect * half_height;
+        let focus_dist = desc.focus_dist;
+        let (u, v, w) = desc.basis();
+        // shift the eye sideways keeping the view window at the convergence distance fixed
+        let eye = desc.eye_offset * u;
+        let window_shift = desc.eye_offset * (1.0 - focus_dist / desc.convergence) * u;
+        PerspectiveCamera {
+            origin: desc.lookfrom + eye,
+            lower_left_corner: desc.lookfrom + window_shift
                 - half_width * focus_dist * u
                 - half_height * focus_dist * v
                 - focus_dist * w,
@@ -44,12 +232,14 @@ impl Camera {
             vertical: 2.0 * half_height * focus_dist * v,
             u,
             v,
-            lens_radius: aperture * 0.5,
+            lens: desc.lens(),
         }
     }
+}
 
-    pub fn get_ray<T: Rng>(&self, s: f32, t: f32, rng: &mut T) -> Ray {
-        let rd = self.lens_radius * random_in_unit_disk(rng);
+impl Camera for PerspectiveCamera {
+    fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Ray {
+        let rd = self.lens.sample(s, t, sampler);
         let offset = self.u * rd.get_x() + self.v * rd.get_y();
         ray(
             self.origin + offset,
@@ -60,3 +250,456 @@ impl Camera {
         )
     }
 }
+
+/// Parallel projection, the view covers the same area as the perspective view does at the focus
+/// distance.
+#[derive(Clone, Debug)]
+pub struct OrthographicCamera {
+    lower_left_corner: Vec3,
+    horizontal: Vec3,
+    vertical: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    focus_dist: f32,
+    lens: Lens,
+}
+
+impl OrthographicCamera {
+    pub fn new(desc: &CameraDesc) -> OrthographicCamera {
+        let half_height = desc.half_height() * desc.focus_dist;
+        let half_width = desc.aspect * half_height;
+        let (u, v, w) = desc.basis();
+        OrthographicCamera {
+            lower_left_corner: desc.lookfrom + desc.eye_offset * u
+                - half_width * u
+                - half_height * v,
+            horizontal: 2.0 * half_width * u,
+            vertical: 2.0 * half_height * v,
+            u,
+            v,
+            w,
+            focus_dist: desc.focus_dist,
+            lens: desc.lens(),
+        }
+    }
+}
+
+impl Camera for OrthographicCamera {
+    fn get_ray(&self, s: f32, t: f32, sampler: &mut dyn Sampler) -> Ray {
+        let origin = self.lower_left_corner + s * self.horizontal + t * self.vertical;
+        let focus_point = origin - self.focus_dist * self.w;
+        let rd = self.lens.sample(s, t, sampler);
+        let offset = self.u * rd.get_x() + self.v * rd.get_y();
+        ray(origin + offset, (focus_point - origin - offset).normalize())
+    }
+}
+
+/// Equidistant fisheye, the angle from the view direction is proportional to the distance from
+/// the image centre. Pinhole only, there is no depth of field.
+#[derive(Copy, Clone, Debug)]
+pub struct FisheyeCamera {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    aspect: f32,
+    half_fov: f32,
+}
+
+impl FisheyeCamera {
+    pub fn new(desc: &CameraDesc) -> FisheyeCamera {
+        let (u, v, w) = desc.basis();
+        FisheyeCamera {
+            origin: desc.lookfrom + desc.eye_offset * u,
+            u,
+            v,
+            w,
+            aspect: desc.aspect,
+            half_fov: desc.vfov * f32::consts::PI / 360.0,
+        }
+    }
+}
+
+impl Camera for FisheyeCamera {
+    fn get_ray(&self, s: f32, t: f32, _: &mut dyn Sampler) -> Ray {
+        let x = (2.0 * s - 1.0) * self.aspect;
+        let y = 2.0 * t - 1.0;
+        let r = (x * x + y * y).sqrt();
+        let theta = (r * self.half_fov).min(f32::consts::PI);
+        let (sin_theta, cos_theta) = sinf_cosf(theta);
+        let (sin_phi, cos_phi) = if r > 0.0 { (y / r, x / r) } else { (0.0, 1.0) };
+        ray(
+            self.origin,
+            (self.u * (sin_theta * cos_phi) + self.v * (sin_theta * sin_phi) - self.w * cos_theta)
+                .normalize(),
+        )
+    }
+}
+
+/// Latitude/longitude panorama covering the full sphere, centred on the view direction. Stereo
+/// pairs use omni-directional stereo, where the eye offset rotates with the view direction.
+#[derive(Copy, Clone, Debug)]
+pub struct EquirectangularCamera {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    eye_offset: f32,
+    convergence: f32,
+}
+
+impl EquirectangularCamera {
+    pub fn new(desc: &CameraDesc) -> EquirectangularCamera {
+        let (u, v, w) = desc.basis();
+        EquirectangularCamera {
+            origin: desc.lookfrom,
+            u,
+            v,
+            w,
+            eye_offset: desc.eye_offset,
+            convergence: desc.convergence,
+        }
+    }
+}
+
+impl Camera for EquirectangularCamera {
+    fn get_ray(&self, s: f32, t: f32, _: &mut dyn Sampler) -> Ray {
+        let longitude = (s - 0.5) * 2.0 * f32::consts::PI;
+        let latitude = (t - 0.5) * f32::consts::PI;
+        let (sin_lon, cos_lon) = sinf_cosf(longitude);
+        let (sin_lat, cos_lat) = sinf_cosf(latitude);
+        let direction =
+            self.u * (cos_lat * sin_lon) + self.v * sin_lat - self.w * (cos_lat * cos_lon);
+        if self.eye_offset == 0.0 {
+            return ray(self.origin, direction.normalize());
+        }
+        let tangent = self.u * cos_lon + self.w * sin_lon;
+        omni_directional_ray(
+            self.origin,
+            direction,
+            tangent,
+            self.eye_offset,
+            self.convergence,
+        )
+    }
+}
+
+/// Offsets the eye along `tangent`, the horizontal tangent of the viewing circle for
+/// `direction`, and toes in towards the convergence distance.
+fn omni_directional_ray(
+    origin: Vec3,
+    direction: Vec3,
+    tangent: Vec3,
+    eye_offset: f32,
+    convergence: f32,
+) -> Ray {
+    let eye = eye_offset * tangent;
+    let direction = if convergence.is_finite() {
+        direction * convergence - eye
+    } else {
+        direction
+    };
+    ray(origin + eye, direction.normalize())
+}
+
+/// Cubemap faces laid out in a 3x2 grid. The top row is +X, -X, +Y and the bottom row is -Y, +Z,
+/// -Z where +X is right, +Y is up and +Z is the view direction. Stereo pairs use
+/// omni-directional stereo like the equirectangular projection.
+#[derive(Copy, Clone, Debug)]
+pub struct CubemapCamera {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    eye_offset: f32,
+    convergence: f32,
+}
+
+// (forward, right, up) for each face in camera space, x right, y up and z forward
+const CUBEMAP_FACES: [(Vec3Tuple, Vec3Tuple, Vec3Tuple); 6] = [
+    ((1.0, 0.0, 0.0), (0.0, 0.0, -1.0), (0.0, 1.0, 0.0)),
+    ((-1.0, 0.0, 0.0), (0.0, 0.0, 1.0), (0.0, 1.0, 0.0)),
+    ((0.0, 1.0, 0.0), (1.0, 0.0, 0.0), (0.0, 0.0, -1.0)),
+    ((0.0, -1.0, 0.0), (1.0, 0.0, 0.0), (0.0, 0.0, 1.0)),
+    ((0.0, 0.0, 1.0), (1.0, 0.0, 0.0), (0.0, 1.0, 0.0)),
+    ((0.0, 0.0, -1.0), (-1.0, 0.0, 0.0), (0.0, 1.0, 0.0)),
+];
+
+type Vec3Tuple = (f32, f32, f32);
+
+impl CubemapCamera {
+    pub fn new(desc: &CameraDesc) -> CubemapCamera {
+        let (u, v, w) = desc.basis();
+        CubemapCamera {
+            origin: desc.lookfrom,
+            u,
+            v,
+            w,
+            eye_offset: desc.eye_offset,
+            convergence: desc.convergence,
+        }
+    }
+
+    fn to_world(&self, dir: Vec3Tuple) -> Vec3 {
+        self.u * dir.0 + self.v * dir.1 - self.w * dir.2
+    }
+}
+
+impl Camera for CubemapCamera {
+    fn get_ray(&self, s: f32, t: f32, _: &mut dyn Sampler) -> Ray {
+        let column = ((s * 3.0) as usize).min(2);
+        let row = if t >= 0.5 { 0 } else { 1 };
+        let (forward, right, up) = CUBEMAP_FACES[row * 3 + column];
+        // position on the face in [-1, 1]
+        let a = (s * 3.0 - column as f32) * 2.0 - 1.0;
+        let b = (t * 2.0 - (1 - row) as f32) * 2.0 - 1.0;
+        let dir = self.to_world(forward) + self.to_world(right) * a + self.to_world(up) * b;
+        let direction = dir.normalize();
+        // the viewing circle's tangent follows the direction's longitude, straight up and down
+        // have no longitude so get no offset
+        let x = direction.dot(self.u);
+        let z = -direction.dot(self.w);
+        let horizontal = (x * x + z * z).sqrt();
+        if self.eye_offset == 0.0 || horizontal == 0.0 {
+            return ray(self.origin, direction);
+        }
+        let tangent = (self.u * z + self.w * x) / horizontal;
+        omni_directional_ray(
+            self.origin,
+            direction,
+            tangent,
+            self.eye_offset,
+            self.convergence,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::{SamplerKind, SamplerSource};
+    use glam::vec3;
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256Plus;
+
+    fn desc() -> CameraDesc {
+        CameraDesc::new(
+            vec3(0.0, 0.0, 0.0),
+            vec3(0.0, 0.0, -1.0),
+            vec3(0.0, 1.0, 0.0),
+            90.0,
+            1.0,
+            0.0,
+            1.0,
+        )
+    }
+
+    fn sampler() -> Box<dyn Sampler> {
+        SamplerSource::new(SamplerKind::Random, 1, 0).create(Xoshiro256Plus::seed_from_u64(1))
+    }
+
+    fn direction(camera: &dyn Camera, s: f32, t: f32) -> Vec3 {
+        camera.get_ray(s, t, sampler().as_mut()).direction
+    }
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1.0e-4
+    }
+
+    /// Image coordinates of `a` and `b` in [-1, 1] on cubemap face `face`.
+    fn cubemap_st(face: usize, a: f32, b: f32) -> (f32, f32) {
+        let (row, column) = (face / 3, face % 3);
+        (
+            (column as f32 + (a + 1.0) * 0.5) / 3.0,
+            ((b + 1.0) * 0.5 + (1 - row) as f32) * 0.5,
+        )
+    }
+
+    fn tuple(v: Vec3Tuple) -> Vec3 {
+        vec3(v.0, v.1, v.2)
+    }
+
+    #[test]
+    fn centre_pixels_look_forward() {
+        // the camera looks down -z with y up
+        let forward = vec3(0.0, 0.0, -1.0);
+        for &projection in &[
+            Projection::Perspective,
+            Projection::Orthographic,
+            Projection::Fisheye,
+            Projection::Equirectangular,
+        ] {
+            let camera = desc().build(projection);
+            let centre = direction(camera.as_ref(), 0.5, 0.5);
+            assert!(close(centre, forward), "{:?} {:?}", projection, centre);
+        }
+        // the middle of the bottom row is the +Z face
+        let cubemap = desc().build(Projection::Cubemap);
+        let (s, t) = cubemap_st(4, 0.0, 0.0);
+        assert!(close(direction(cubemap.as_ref(), s, t), forward));
+    }
+
+    #[test]
+    fn edge_directions() {
+        let up = vec3(0.0, 1.0, 0.0);
+        let right = vec3(1.0, 0.0, 0.0);
+        let forward = vec3(0.0, 0.0, -1.0);
+
+        // a 90 degree field of view reaches 45 degrees either side
+        let perspective = desc().build(Projection::Perspective);
+        let top = direction(perspective.as_ref(), 0.5, 1.0);
+        assert!(close(top, (up + forward).normalize()));
+        let side = direction(perspective.as_ref(), 1.0, 0.5);
+        assert!(close(side, (right + forward).normalize()));
+
+        // parallel rays starting across the view at the focus distance
+        let orthographic = desc().build(Projection::Orthographic);
+        let ray = orthographic.get_ray(1.0, 0.0, sampler().as_mut());
+        assert!(close(ray.direction, forward));
+        assert!(close(ray.origin, vec3(1.0, -1.0, 0.0)));
+
+        let fisheye = CameraDesc {
+            vfov: 180.0,
+            ..desc()
+        }
+        .build(Projection::Fisheye);
+        assert!(close(direction(fisheye.as_ref(), 0.5, 1.0), up));
+        assert!(close(direction(fisheye.as_ref(), 0.0, 0.5), -right));
+
+        let equirectangular = desc().build(Projection::Equirectangular);
+        let panorama = |s, t| direction(equirectangular.as_ref(), s, t);
+        assert!(close(panorama(0.5, 1.0), up));
+        assert!(close(panorama(0.5, 0.0), -up));
+        assert!(close(panorama(0.75, 0.5), right));
+        assert!(close(panorama(0.25, 0.5), -right));
+        // both ends of the image look backwards
+        assert!(close(panorama(0.0, 0.5), -forward));
+        assert!(close(panorama(1.0, 0.5), -forward));
+
+        // each face centre looks along its axis
+        let cubemap = desc().build(Projection::Cubemap);
+        for (face, &(axis, _, _)) in CUBEMAP_FACES.iter().enumerate() {
+            let (s, t) = cubemap_st(face, 0.0, 0.0);
+            // camera space z is forward, which is -z in the world
+            let expected = vec3(axis.0, axis.1, -axis.2);
+            let centre = direction(cubemap.as_ref(), s, t);
+            assert!(close(centre, expected), "face {} {:?}", face, centre);
+        }
+    }
+
+    #[test]
+    fn cubemap_seams_are_continuous() {
+        let cubemap = desc().build(Projection::Cubemap);
+        // just inside each edge of each face, away from the corners
+        let inset = 1.0 - 1.0e-3;
+        for face in 0..6 {
+            for i in 0..9 {
+                let along = (i as f32 - 4.0) * 0.2;
+                let edges = [
+                    (inset, along),
+                    (-inset, along),
+                    (along, inset),
+                    (along, -inset),
+                ];
+                for &(a, b) in edges.iter() {
+                    let (s, t) = cubemap_st(face, a, b);
+                    let here = direction(cubemap.as_ref(), s, t);
+                    // the same direction in camera space, and the other face it's closest to
+                    let camera_space = vec3(here.get_x(), here.get_y(), -here.get_z());
+                    let (neighbour, &(forward, right, up)) = CUBEMAP_FACES
+                        .iter()
+                        .enumerate()
+                        .filter(|&(other, _)| other != face)
+                        .max_by(|(_, x), (_, y)| {
+                            let x = camera_space.dot(tuple(x.0));
+                            let y = camera_space.dot(tuple(y.0));
+                            x.partial_cmp(&y).unwrap()
+                        })
+                        .unwrap();
+                    let depth = camera_space.dot(tuple(forward));
+                    let clamp = |x: f32| x.max(-inset).min(inset);
+                    let (s, t) = cubemap_st(
+                        neighbour,
+                        clamp(camera_space.dot(tuple(right)) / depth),
+                        clamp(camera_space.dot(tuple(up)) / depth),
+                    );
+                    let there = direction(cubemap.as_ref(), s, t);
+                    assert!(
+                        (here - there).length() < 1.0e-2,
+                        "face {} at ({}, {}) jumps to {:?} on face {}",
+                        face,
+                        a,
+                        b,
+                        there,
+                        neighbour
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stereo_pairs_are_ipd_apart_and_converge() {
+        let stereo = Stereo {
+            layout: StereoLayout::SideBySide,
+            ipd: 0.064,
+            convergence: Some(5.0),
+        };
+        let centre = CameraDesc {
+            focus_dist: 2.0,
+            ..desc()
+        };
+        let (left, right) = centre.stereo_pair(&stereo);
+        assert_eq!((left.eye_offset, right.eye_offset), (-0.032, 0.032));
+        assert_eq!((left.convergence, right.convergence), (5.0, 5.0));
+        let (left, _) = centre.stereo_pair(&Stereo {
+            convergence: None,
+            ..stereo
+        });
+        assert_eq!(left.convergence, 2.0);
+
+        // the rays through the same pixel for each eye meet at the convergence distance
+        let (left, right) = centre.stereo_pair(&stereo);
+        let views = [
+            (Projection::Perspective, 0.5, 0.5),
+            (Projection::Equirectangular, 0.5, 0.5),
+            (Projection::Equirectangular, 0.75, 0.6),
+            (Projection::Equirectangular, 0.1, 0.3),
+            (Projection::Cubemap, 0.5, 0.25),
+            (Projection::Cubemap, 0.1, 0.8),
+        ];
+        for &(projection, s, t) in views.iter() {
+            let target = direction(centre.build(projection).as_ref(), s, t) * 5.0;
+            let left_ray = left.build(projection).get_ray(s, t, sampler().as_mut());
+            let right_ray = right.build(projection).get_ray(s, t, sampler().as_mut());
+            let separation = (left_ray.origin - right_ray.origin).length();
+            assert!((separation - 0.064).abs() < 1.0e-5, "{:?}", projection);
+            for eye_ray in &[left_ray, right_ray] {
+                let miss = (target - eye_ray.origin).cross(eye_ray.direction).length();
+                assert!(miss < 1.0e-4, "{:?} at ({}, {})", projection, s, t);
+            }
+        }
+    }
+
+    #[test]
+    fn lens_settings_are_validated() {
+        let mut lens = desc();
+        assert!(lens.validate().is_ok());
+        lens.aperture_shape = ApertureShape::Polygon {
+            blades: 2,
+            rotation: 0.0,
+        };
+        assert!(lens.validate().is_err());
+        lens.aperture_shape = ApertureShape::Polygon {
+            blades: 3,
+            rotation: 0.0,
+        };
+        lens.cats_eye = 1.0;
+        assert!(lens.validate().is_ok());
+        for &cats_eye in &[-0.1, 1.5, f32::NAN] {
+            lens.cats_eye = cats_eye;
+            assert!(lens.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use pathtrace_rs::{
+    error::{Error, Result},
+    film::Film,
+};
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+const MAGIC: &[u8; 8] = b"PTCKPT02";
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Where and how often to save progressive render state so it can be resumed later.
+///
+/// Each frame's samples are seeded from the film's frame count, so together with the per pixel
+/// sample counts the film holds everything needed to continue the random sequences.
+#[derive(Clone, Debug)]
+pub struct Checkpoint {
+    pub path: PathBuf,
+    pub interval_secs: f32,
+    pub resume: bool,
+    /// Hash of the render settings, a checkpoint saved with different ones can't be resumed.
+    pub settings_hash: u64,
+    last_save: SystemTime,
+}
+
+impl Checkpoint {
+    pub fn new(path: PathBuf, interval_secs: f32, resume: bool, settings_hash: u64) -> Checkpoint {
+        Checkpoint {
+            path,
+            interval_secs,
+            resume,
+            settings_hash,
+            last_save: SystemTime::now(),
+        }
+    }
+
+    fn error(&self, action: &str) -> impl FnOnce(io::Error) -> Error {
+        Error::io(format!(
+            "couldn't {} checkpoint {}",
+            action,
+            self.path.display()
+        ))
+    }
+
+    /// Loads the film to continue from, or a new one if not resuming or there's no checkpoint
+    /// yet.
+    pub fn load_or_new(&self, width: u32, height: u32, aovs: bool) -> Result<Film> {
+        if !self.resume || !self.path.exists() {
+            return Ok(Film::new(width, height, aovs));
+        }
+        let film = self.load(width, height, aovs).map_err(self.error("load"))?;
+        println!(
+            "resuming from {} after {} frames",
+            self.path.display(),
+            film.frames
+        );
+        Ok(film)
+    }
+
+    fn load(&self, width: u32, height: u32, aovs: bool) -> io::Result<Film> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a checkpoint file, or one saved by an older version",
+            ));
+        }
+        let mut hash = [0; 8];
+        reader.read_exact(&mut hash)?;
+        if u64::from_le_bytes(hash) != self.settings_hash {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checkpoint was saved with different render settings",
+            ));
+        }
+        let saved_width = read_u32(&mut reader)?;
+        let saved_height = read_u32(&mut reader)?;
+        if saved_width != width || saved_height != height {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "checkpoint is {}x{} but the render is {}x{}",
+                    saved_width, saved_height, width, height
+                ),
+            ));
+        }
+        let film = Film::read(&mut reader, width, height)?;
+        if aovs && film.aovs.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "checkpoint was saved without AOVs",
+            ));
+        }
+        Ok(film)
+    }
+
+    /// Writes the film, first to a temporary file so an interrupted save can't corrupt the
+    /// previous checkpoint.
+    pub fn save(&mut self, width: u32, height: u32, film: &Film) -> Result<()> {
+        self.write(width, height, film)
+            .map_err(self.error("save"))?;
+        self.last_save = SystemTime::now();
+        Ok(())
+    }
+
+    fn write(&self, width: u32, height: u32, film: &Film) -> io::Result<()> {
+        let temp_path = self.path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            writer.write_all(MAGIC)?;
+            writer.write_all(&self.settings_hash.to_le_bytes())?;
+            writer.write_all(&width.to_le_bytes())?;
+            writer.write_all(&height.to_le_bytes())?;
+            film.write(&mut writer)?;
+            writer.flush()?;
+        }
+        fs::rename(&temp_path, &self.path)
+    }
+
+    /// Saves if at least `interval_secs` have passed since the last save.
+    pub fn save_if_due(&mut self, width: u32, height: u32, film: &Film) -> Result<()> {
+        let since_save = self
+            .last_save
+            .elapsed()
+            .expect("SystemTime elapsed time failed");
+        if since_save.as_secs() as f32 >= self.interval_secs {
+            self.save(width, height, film)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("pathtrace-{}-{}.ckpt", name, std::process::id()))
+    }
+
+    fn film(aovs: bool) -> Film {
+        let mut film = Film::new(3, 2, aovs);
+        film.frames = 5;
+        for (index, stats) in film.stats.iter_mut().enumerate() {
+            stats.add(index as f32);
+            stats.add(1.0);
+            film.color[index] = (index as f32, 0.5, 1.0);
+            film.weight[index] = 2.0;
+        }
+        film
+    }
+
+    fn load_error(checkpoint: &Checkpoint, width: u32, height: u32, aovs: bool) -> String {
+        match checkpoint.load_or_new(width, height, aovs) {
+            Ok(_) => panic!("loaded a mismatched checkpoint"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn save_then_resume() {
+        let path = temp_path("resume");
+        let mut checkpoint = Checkpoint::new(path.clone(), 0.0, true, 42);
+        let saved = film(true);
+        checkpoint.save(3, 2, &saved).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+
+        let resumed = checkpoint.load_or_new(3, 2, true).unwrap();
+        assert_eq!(resumed.frames, 5);
+        assert_eq!(resumed.color, saved.color);
+        assert_eq!(resumed.weight, saved.weight);
+        for (a, b) in resumed.stats.iter().zip(saved.stats.iter()) {
+            assert_eq!(a.samples, b.samples);
+            assert_eq!(a.relative_error(), b.relative_error());
+        }
+        assert!(resumed.aovs.is_some());
+
+        // without --resume the checkpoint is ignored
+        let fresh = Checkpoint::new(path.clone(), 0.0, false, 42);
+        assert_eq!(fresh.load_or_new(3, 2, true).unwrap().frames, 0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missing_checkpoint_starts_afresh() {
+        let checkpoint = Checkpoint::new(temp_path("missing"), 0.0, true, 42);
+        let film = checkpoint.load_or_new(3, 2, false).unwrap();
+        assert_eq!(film.frames, 0);
+        assert_eq!(film.color.len(), 6);
+    }
+
+    #[test]
+    fn mismatched_checkpoints_are_refused() {
+        let path = temp_path("mismatch");
+        let mut checkpoint = Checkpoint::new(path.clone(), 0.0, true, 42);
+        checkpoint.save(3, 2, &film(false)).unwrap();
+
+        let other_settings = Checkpoint::new(path.clone(), 0.0, true, 43);
+        assert!(load_error(&other_settings, 3, 2, false).contains("different render settings"));
+        assert!(load_error(&checkpoint, 2, 3, false).contains("is 3x2 but the render is 2x3"));
+        assert!(load_error(&checkpoint, 3, 2, true).contains("without AOVs"));
+
+        fs::write(&path, b"PTCKPT01 and then some").unwrap();
+        assert!(load_error(&checkpoint, 3, 2, false).contains("not a checkpoint file"));
+        fs::write(&path, &MAGIC[..4]).unwrap();
+        assert!(load_error(&checkpoint, 3, 2, false).contains(&path.display().to_string()));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,10 +1,11 @@
 use crate::{
+    error::{Error, Result},
     material::{Material, MaterialKind},
     math::align_to,
     simd::*,
 };
 use glam::{vec3, Vec3};
-use std::f32;
+use std::{cmp::Ordering, f32};
 
 #[inline]
 fn cttz_8bits_nonzero(x: u32) -> u32 {
@@ -108,6 +109,302 @@ pub fn sphere(
     )
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct Cuboid {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Cuboid {
+    pub fn centred(half_extents: Vec3) -> Cuboid {
+        Cuboid {
+            min: -half_extents,
+            max: half_extents,
+        }
+    }
+
+    pub fn union(&self, other: &Cuboid) -> Cuboid {
+        Cuboid {
+            min: vec3(
+                self.min.get_x().min(other.min.get_x()),
+                self.min.get_y().min(other.min.get_y()),
+                self.min.get_z().min(other.min.get_z()),
+            ),
+            max: vec3(
+                self.max.get_x().max(other.max.get_x()),
+                self.max.get_y().max(other.max.get_y()),
+                self.max.get_z().max(other.max.get_z()),
+            ),
+        }
+    }
+
+    pub fn intersection(&self, other: &Cuboid) -> Cuboid {
+        Cuboid {
+            min: vec3(
+                self.min.get_x().max(other.min.get_x()),
+                self.min.get_y().max(other.min.get_y()),
+                self.min.get_z().max(other.min.get_z()),
+            ),
+            max: vec3(
+                self.max.get_x().min(other.max.get_x()),
+                self.max.get_y().min(other.max.get_y()),
+                self.max.get_z().min(other.max.get_z()),
+            ),
+        }
+    }
+
+    pub fn expand(&self, amount: f32) -> Cuboid {
+        let amount = vec3(amount, amount, amount);
+        Cuboid {
+            min: self.min - amount,
+            max: self.max + amount,
+        }
+    }
+
+    /// Returns the entry and exit distance along the ray if it crosses the box.
+    pub fn ray_interval(&self, ray: &Ray) -> Option<(f32, f32)> {
+        cuboid_spans(self, ray)
+            .first()
+            .map(|span| (span.t_enter, span.t_exit))
+    }
+}
+
+/// The part of a ray inside a solid, with outward facing normals at the entry and exit points.
+#[derive(Clone, Copy, Debug)]
+pub struct Span {
+    pub t_enter: f32,
+    pub n_enter: Vec3,
+    pub t_exit: f32,
+    pub n_exit: Vec3,
+}
+
+#[derive(Clone, Debug)]
+pub enum Csg {
+    Sphere(Sphere),
+    Cuboid(Cuboid),
+    Union(Box<Csg>, Box<Csg>),
+    Intersection(Box<Csg>, Box<Csg>),
+    Difference(Box<Csg>, Box<Csg>),
+}
+
+impl Csg {
+    pub fn union(self, other: Csg) -> Csg {
+        Csg::Union(Box::new(self), Box::new(other))
+    }
+
+    pub fn intersection(self, other: Csg) -> Csg {
+        Csg::Intersection(Box::new(self), Box::new(other))
+    }
+
+    pub fn difference(self, other: Csg) -> Csg {
+        Csg::Difference(Box::new(self), Box::new(other))
+    }
+
+    /// Returns the sorted, non-overlapping spans along the whole line of the ray. Spans are not
+    /// clipped to a `t_min` or `t_max` so that rays starting inside a solid are handled correctly.
+    pub fn spans(&self, ray: &Ray) -> Vec<Span> {
+        match self {
+            Csg::Sphere(sphere) => sphere_spans(sphere, ray),
+            Csg::Cuboid(cuboid) => cuboid_spans(cuboid, ray),
+            Csg::Union(a, b) => union_spans(&a.spans(ray), &b.spans(ray)),
+            Csg::Intersection(a, b) => intersection_spans(&a.spans(ray), &b.spans(ray)),
+            Csg::Difference(a, b) => difference_spans(&a.spans(ray), &b.spans(ray)),
+        }
+    }
+
+    pub fn ray_hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(RayHit, f32)> {
+        for span in self.spans(ray) {
+            let (t, normal) = if span.t_enter > t_min {
+                (span.t_enter, span.n_enter)
+            } else if span.t_exit > t_min {
+                (span.t_exit, span.n_exit)
+            } else {
+                continue;
+            };
+            if t < t_max {
+                let point = ray.point_at_parameter(t);
+                return Some((RayHit { point, normal }, t));
+            }
+            break;
+        }
+        None
+    }
+}
+
+fn sphere_spans(sphere: &Sphere, ray: &Ray) -> Vec<Span> {
+    let radius = sphere.radius.abs();
+    let co = sphere.centre - ray.origin;
+    let nb = co.dot(ray.direction);
+    let c = co.dot(co) - radius * radius;
+    let discriminant = nb * nb - c;
+    if discriminant > 0.0 {
+        let discriminant_sqrt = discriminant.sqrt();
+        let t_enter = nb - discriminant_sqrt;
+        let t_exit = nb + discriminant_sqrt;
+        let radius_inv = 1.0 / radius;
+        vec![Span {
+            t_enter,
+            n_enter: (ray.point_at_parameter(t_enter) - sphere.centre) * radius_inv,
+            t_exit,
+            n_exit: (ray.point_at_parameter(t_exit) - sphere.centre) * radius_inv,
+        }]
+    } else {
+        vec![]
+    }
+}
+
+fn cuboid_spans(cuboid: &Cuboid, ray: &Ray) -> Vec<Span> {
+    let axes = [
+        vec3(1.0, 0.0, 0.0),
+        vec3(0.0, 1.0, 0.0),
+        vec3(0.0, 0.0, 1.0),
+    ];
+    let origin = [ray.origin.get_x(), ray.origin.get_y(), ray.origin.get_z()];
+    let direction = [
+        ray.direction.get_x(),
+        ray.direction.get_y(),
+        ray.direction.get_z(),
+    ];
+    let min = [cuboid.min.get_x(), cuboid.min.get_y(), cuboid.min.get_z()];
+    let max = [cuboid.max.get_x(), cuboid.max.get_y(), cuboid.max.get_z()];
+    let mut t_enter = f32::MIN;
+    let mut t_exit = f32::MAX;
+    let mut n_enter = Vec3::zero();
+    let mut n_exit = Vec3::zero();
+    for axis in 0..3 {
+        if direction[axis] == 0.0 {
+            // parallel to the slab, which either contains the whole ray or none of it, dividing
+            // would give 0 * inf = NaN for an origin on one of its planes
+            if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                return vec![];
+            }
+            continue;
+        }
+        let inv_d = 1.0 / direction[axis];
+        let t0 = (min[axis] - origin[axis]) * inv_d;
+        let t1 = (max[axis] - origin[axis]) * inv_d;
+        // the slab's near plane faces against the ray direction
+        let (t_near, t_far, n_near) = if t0 < t1 {
+            (t0, t1, -axes[axis])
+        } else {
+            (t1, t0, axes[axis])
+        };
+        if t_near > t_enter {
+            t_enter = t_near;
+            n_enter = n_near;
+        }
+        if t_far < t_exit {
+            t_exit = t_far;
+            n_exit = -n_near;
+        }
+    }
+    if t_enter < t_exit {
+        vec![Span {
+            t_enter,
+            n_enter,
+            t_exit,
+            n_exit,
+        }]
+    } else {
+        vec![]
+    }
+}
+
+fn union_spans(a: &[Span], b: &[Span]) -> Vec<Span> {
+    let mut spans: Vec<Span> = a.iter().chain(b.iter()).cloned().collect();
+    spans.sort_by(|lhs, rhs| {
+        lhs.t_enter
+            .partial_cmp(&rhs.t_enter)
+            .unwrap_or(Ordering::Equal)
+    });
+    let mut merged: Vec<Span> = Vec::with_capacity(spans.len());
+    for span in spans {
+        if let Some(last) = merged.last_mut() {
+            if span.t_enter <= last.t_exit {
+                if span.t_exit > last.t_exit {
+                    last.t_exit = span.t_exit;
+                    last.n_exit = span.n_exit;
+                }
+                continue;
+            }
+        }
+        merged.push(span);
+    }
+    merged
+}
+
+fn intersection_spans(a: &[Span], b: &[Span]) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let (sa, sb) = (&a[i], &b[j]);
+        let (t_enter, n_enter) = if sa.t_enter > sb.t_enter {
+            (sa.t_enter, sa.n_enter)
+        } else {
+            (sb.t_enter, sb.n_enter)
+        };
+        let (t_exit, n_exit) = if sa.t_exit < sb.t_exit {
+            (sa.t_exit, sa.n_exit)
+        } else {
+            (sb.t_exit, sb.n_exit)
+        };
+        if t_enter < t_exit {
+            spans.push(Span {
+                t_enter,
+                n_enter,
+                t_exit,
+                n_exit,
+            });
+        }
+        if sa.t_exit < sb.t_exit {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    spans
+}
+
+fn difference_spans(a: &[Span], b: &[Span]) -> Vec<Span> {
+    let mut spans = Vec::new();
+    for sa in a {
+        // the remaining part of sa still to be clipped against b
+        let mut t_enter = sa.t_enter;
+        let mut n_enter = sa.n_enter;
+        for sb in b {
+            if sb.t_exit <= t_enter {
+                continue;
+            }
+            if sb.t_enter >= sa.t_exit {
+                break;
+            }
+            // surfaces of the subtracted solid face inwards
+            if sb.t_enter > t_enter {
+                spans.push(Span {
+                    t_enter,
+                    n_enter,
+                    t_exit: sb.t_enter,
+                    n_exit: -sb.n_enter,
+                });
+            }
+            t_enter = sb.t_exit;
+            n_enter = -sb.n_exit;
+        }
+        if t_enter < sa.t_exit {
+            spans.push(Span {
+                t_enter,
+                n_enter,
+                t_exit: sa.t_exit,
+                n_exit: sa.n_exit,
+            });
+        }
+    }
+    spans
+}
+
+/// Number of rays intersected together by `SpheresSoA::ray_hit_packet`.
+pub const PACKET_SIZE: usize = 8;
+
 #[derive(Debug)]
 pub struct SpheresSoA {
     feature: TargetFeature,
@@ -121,12 +418,13 @@ pub struct SpheresSoA {
 }
 
 impl SpheresSoA {
-    pub fn new(spheres: &[Sphere]) -> SpheresSoA {
-        let feature = TargetFeature::detect();
-        feature.print_version();
-        // HACK: make sure there's enough entries for SIMD
-        // TODO: conditionally compile this
-        let chunk_size = TargetFeature::detect().get_bits() / 32;
+    /// Stores `spheres` for intersecting with `feature`, failing if the CPU doesn't support it.
+    pub fn new(spheres: &[Sphere], feature: TargetFeature) -> Result<SpheresSoA> {
+        if !feature.is_supported() {
+            return Err(Error::InvalidParams(feature.unsupported_reason()));
+        }
+        // pad to a whole number of SIMD lanes
+        let chunk_size = feature.get_bits() / 32;
         let num_spheres = spheres.len();
         let len = align_to(num_spheres, chunk_size);
         let mut centre_x = Vec::with_capacity(len);
@@ -149,7 +447,7 @@ impl SpheresSoA {
             radius_sq.push(0.0);
             radius_inv.push(0.0);
         }
-        SpheresSoA {
+        Ok(SpheresSoA {
             feature,
             centre_x,
             centre_y,
@@ -158,7 +456,11 @@ impl SpheresSoA {
             radius_inv,
             len,
             num_spheres,
-        }
+        })
+    }
+
+    pub fn target_feature(&self) -> TargetFeature {
+        self.feature
     }
 
     pub fn centre(&self, index: u32) -> Vec3 {
@@ -177,11 +479,54 @@ impl SpheresSoA {
         self.radius_sq[index as usize]
     }
 
+    /// Intersects up to `PACKET_SIZE` rays at once, testing one sphere against every ray per
+    /// step instead of several spheres against one ray. Each ray gets the same hit as
+    /// `hit_scalar`, slots past the end of `rays` are `None`.
+    pub fn ray_hit_packet(
+        &self,
+        rays: &[Ray],
+        t_min: f32,
+        t_max: f32,
+    ) -> [Option<(RayHit, u32)>; PACKET_SIZE] {
+        assert!(!rays.is_empty() && rays.len() <= PACKET_SIZE);
+        // unused lanes repeat the first ray and are dropped afterwards
+        let mut packet = [rays[0]; PACKET_SIZE];
+        packet[..rays.len()].copy_from_slice(rays);
+        let mut hits = match self.feature {
+            // a packet of eight rays fills an AVX2 register, wider ones gain nothing
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            TargetFeature::AVX512 | TargetFeature::AVX2 if TargetFeature::AVX2.is_supported() => unsafe {
+                self.hit_packet_avx2(&packet, t_min, t_max)
+            },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            TargetFeature::AVX512 | TargetFeature::SSE4_1 => unsafe {
+                self.hit_packet_sse4_1(&packet, t_min, t_max)
+            },
+            TargetFeature::Portable => self.hit_packet_portable(&packet, t_min, t_max),
+            TargetFeature::FallBack => self.hit_packet_scalar(&packet, t_min, t_max),
+            // `new` only accepts supported instruction sets
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        };
+        for hit in &mut hits[rays.len()..] {
+            *hit = None;
+        }
+        hits
+    }
+
     pub fn ray_hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(RayHit, u32)> {
         match self.feature {
+            #[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+            TargetFeature::AVX512 => unsafe { self.hit_avx512(ray, t_min, t_max) },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
             TargetFeature::AVX2 => unsafe { self.hit_avx2(ray, t_min, t_max) },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
             TargetFeature::SSE4_1 => unsafe { self.hit_sse4_1(ray, t_min, t_max) },
+            TargetFeature::Portable => self.hit_portable(ray, t_min, t_max),
             TargetFeature::FallBack => self.hit_scalar(ray, t_min, t_max),
+            // `new` only accepts supported instruction sets
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
         }
     }
 
@@ -227,6 +572,7 @@ impl SpheresSoA {
         }
     }
 
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     #[cfg_attr(
         any(target_arch = "x86", target_arch = "x86_64"),
         target_feature(enable = "sse4.1")
@@ -301,10 +647,17 @@ impl SpheresSoA {
         if min_hit_t < t_max {
             let min_mask = _mm_movemask_ps(_mm_cmpeq_ps(hit_t, _mm_set1_ps(min_hit_t)));
             if min_mask != 0 {
-                let hit_t_lane = cttz_4bits_nonzero(min_mask as u32) as usize;
-                debug_assert!(hit_t_lane < NUM_LANES);
-
                 let hit_index_array = I32x4 { simd: hit_index }.array;
+                let mut hit_t_lane = cttz_4bits_nonzero(min_mask as u32) as usize;
+                debug_assert!(hit_t_lane < NUM_LANES);
+                // ties between lanes go to the lowest index, like the scalar loop
+                for lane in hit_t_lane + 1..NUM_LANES {
+                    if min_mask & (1 << lane) != 0
+                        && hit_index_array[lane] < hit_index_array[hit_t_lane]
+                    {
+                        hit_t_lane = lane;
+                    }
+                }
                 let hit_t_array = F32x4 { simd: hit_t }.array;
 
                 let hit_index_scalar = *hit_index_array.get_unchecked(hit_t_lane) as usize;
@@ -325,6 +678,7 @@ impl SpheresSoA {
         None
     }
 
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     #[cfg_attr(
         any(target_arch = "x86", target_arch = "x86_64"),
         target_feature(enable = "avx2")
@@ -409,10 +763,17 @@ impl SpheresSoA {
             let min_mask =
                 _mm256_movemask_ps(_mm256_cmp_ps(hit_t, _mm256_set1_ps(min_hit_t), _CMP_EQ_OQ));
             if min_mask != 0 {
-                let hit_t_lane = cttz_8bits_nonzero(min_mask as u32) as usize;
-                debug_assert!(hit_t_lane < NUM_LANES);
-
                 let hit_index_array = I32x8 { simd: hit_index }.array;
+                let mut hit_t_lane = cttz_8bits_nonzero(min_mask as u32) as usize;
+                debug_assert!(hit_t_lane < NUM_LANES);
+                // ties between lanes go to the lowest index, like the scalar loop
+                for lane in hit_t_lane + 1..NUM_LANES {
+                    if min_mask & (1 << lane) != 0
+                        && hit_index_array[lane] < hit_index_array[hit_t_lane]
+                    {
+                        hit_t_lane = lane;
+                    }
+                }
                 let hit_t_array = F32x8 { simd: hit_t }.array;
 
                 let hit_index_scalar = *hit_index_array.get_unchecked(hit_t_lane) as usize;
@@ -432,4 +793,847 @@ impl SpheresSoA {
         }
         None
     }
+
+    /// Plain Rust version of the SIMD kernels, each iteration tests `NUM_LANES` spheres with
+    /// branchless code the compiler can vectorise for any target. Picks the same sphere as
+    /// `hit_scalar`.
+    pub fn hit_portable(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(RayHit, u32)> {
+        const NUM_LANES: usize = 8;
+        let ro_x = ray.origin.get_x();
+        let ro_y = ray.origin.get_y();
+        let ro_z = ray.origin.get_z();
+        let rd_x = ray.direction.get_x();
+        let rd_y = ray.direction.get_y();
+        let rd_z = ray.direction.get_z();
+        let mut hit_t = [t_max; NUM_LANES];
+        let mut hit_index = [0u32; NUM_LANES];
+        let chunks = self
+            .centre_x
+            .chunks_exact(NUM_LANES)
+            .zip(self.centre_y.chunks_exact(NUM_LANES))
+            .zip(self.centre_z.chunks_exact(NUM_LANES))
+            .zip(self.radius_sq.chunks_exact(NUM_LANES));
+        for (chunk_index, (((c_x, c_y), c_z), r_sq)) in chunks.enumerate() {
+            for lane in 0..NUM_LANES {
+                // let co = centre - ray.origin
+                let co_x = c_x[lane] - ro_x;
+                let co_y = c_y[lane] - ro_y;
+                let co_z = c_z[lane] - ro_z;
+                // let nb = dot(co, ray.direction);
+                let nb = co_x * rd_x + co_y * rd_y + co_z * rd_z;
+                // let c = dot(co, co) - radius_sq;
+                let c = (co_x * co_x + co_y * co_y + co_z * co_z) - r_sq[lane];
+                let discriminant = nb * nb - c;
+                // NaN for misses, which the mask below ignores
+                let discriminant_sqrt = discriminant.sqrt();
+                let t0 = nb - discriminant_sqrt;
+                let t = if t0 < t_min {
+                    nb + discriminant_sqrt
+                } else {
+                    t0
+                };
+                let mask = (discriminant > 0.0) & (t > t_min) & (t < hit_t[lane]);
+                hit_t[lane] = if mask { t } else { hit_t[lane] };
+                hit_index[lane] = if mask {
+                    (chunk_index * NUM_LANES + lane) as u32
+                } else {
+                    hit_index[lane]
+                };
+            }
+        }
+
+        // the nearest hit, on ties the lowest index like the scalar loop
+        let mut nearest: Option<(f32, u32)> = None;
+        for (&t, &index) in hit_t.iter().zip(hit_index.iter()) {
+            if t < t_max {
+                nearest = match nearest {
+                    Some((nearest_t, nearest_index))
+                        if nearest_t < t || (nearest_t == t && nearest_index < index) =>
+                    {
+                        Some((nearest_t, nearest_index))
+                    }
+                    _ => Some((t, index)),
+                };
+            }
+        }
+        nearest.map(|(hit_t, hit_index)| {
+            let hit_index = hit_index as usize;
+            let point = ray.point_at_parameter(hit_t);
+            let normal = (point
+                - vec3(
+                    self.centre_x[hit_index],
+                    self.centre_y[hit_index],
+                    self.centre_z[hit_index],
+                ))
+                * self.radius_inv[hit_index];
+            (RayHit { point, normal }, hit_index as u32)
+        })
+    }
+
+    #[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+    #[target_feature(enable = "avx512f")]
+    pub unsafe fn hit_avx512(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(RayHit, u32)> {
+        #[cfg(target_arch = "x86")]
+        use std::arch::x86::*;
+        #[cfg(target_arch = "x86_64")]
+        use std::arch::x86_64::*;
+        const NUM_LANES: usize = 16;
+        let t_min = _mm512_set1_ps(t_min);
+        let mut hit_t = _mm512_set1_ps(t_max);
+        let mut hit_index = _mm512_set1_epi32(-1);
+        // broadcast ray origin and direction
+        let ro_x = _mm512_set1_ps(ray.origin.get_x());
+        let ro_y = _mm512_set1_ps(ray.origin.get_y());
+        let ro_z = _mm512_set1_ps(ray.origin.get_z());
+        let rd_x = _mm512_set1_ps(ray.direction.get_x());
+        let rd_y = _mm512_set1_ps(ray.direction.get_y());
+        let rd_z = _mm512_set1_ps(ray.direction.get_z());
+        // current indices being processed
+        let mut index = _mm512_setr_epi32(0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15);
+        // loop over NUM_LANES spheres at a time
+        let num_chunks = self.len >> 4;
+        for chunk_index in (0..num_chunks).map(|i| i << 4) {
+            // load sphere centres
+            let c_x = _mm512_loadu_ps(self.centre_x.get_unchecked(chunk_index));
+            let c_y = _mm512_loadu_ps(self.centre_y.get_unchecked(chunk_index));
+            let c_z = _mm512_loadu_ps(self.centre_z.get_unchecked(chunk_index));
+            // load radius_sq
+            let r_sq = _mm512_loadu_ps(self.radius_sq.get_unchecked(chunk_index));
+            // let co = centre - ray.origin
+            let co_x = _mm512_sub_ps(c_x, ro_x);
+            let co_y = _mm512_sub_ps(c_y, ro_y);
+            let co_z = _mm512_sub_ps(c_z, ro_z);
+            // let nb = dot(co, ray.direction);
+            let nb = dot3_avx512(co_x, rd_x, co_y, rd_y, co_z, rd_z);
+            // let c = dot(co, co) - radius_sq;
+            let c = _mm512_sub_ps(dot3_avx512(co_x, co_x, co_y, co_y, co_z, co_z), r_sq);
+            // let discriminant = nb * nb - c;
+            let discr = _mm512_sub_ps(_mm512_mul_ps(nb, nb), c);
+            // if discr > 0.0
+            let pos_discr = _mm512_cmplt_ps_mask(_mm512_setzero_ps(), discr);
+            if pos_discr != 0 {
+                // let discr_sqrt = discr.sqrt();
+                let discr_sqrt = _mm512_sqrt_ps(discr);
+                // let t0 = nb - discr_sqrt;
+                let t0 = _mm512_sub_ps(nb, discr_sqrt);
+                // let t1 = nb + discr_sqrt;
+                let t1 = _mm512_add_ps(nb, discr_sqrt);
+                // let t = if t0 < t_min { t1 } else { t0 };
+                let t = _mm512_mask_blend_ps(_mm512_cmplt_ps_mask(t0, t_min), t0, t1);
+                // mask = discr > 0 && t > t_min && t < hit_t
+                let mask =
+                    pos_discr & _mm512_cmplt_ps_mask(t_min, t) & _mm512_cmplt_ps_mask(t, hit_t);
+                // hit_index = mask ? index : hit_index;
+                hit_index = _mm512_mask_blend_epi32(mask, hit_index, index);
+                // hit_t = mask ? t : hit_t;
+                hit_t = _mm512_mask_blend_ps(mask, hit_t, t);
+            }
+            // increment indices
+            index = _mm512_add_epi32(index, _mm512_set1_epi32(NUM_LANES as i32));
+        }
+
+        let min_hit_t = _mm512_reduce_min_ps(hit_t);
+        if min_hit_t < t_max {
+            // the lowest index of the lanes with the nearest hit, like the scalar loop
+            let min_mask = _mm512_cmpeq_ps_mask(hit_t, _mm512_set1_ps(min_hit_t));
+            let hit_index_scalar = _mm512_mask_reduce_min_epi32(min_mask, hit_index) as usize;
+            debug_assert!(hit_index_scalar < self.len);
+
+            let point = ray.point_at_parameter(min_hit_t);
+            let normal = (point
+                - vec3(
+                    *self.centre_x.get_unchecked(hit_index_scalar),
+                    *self.centre_y.get_unchecked(hit_index_scalar),
+                    *self.centre_z.get_unchecked(hit_index_scalar),
+                ))
+                * *self.radius_inv.get_unchecked(hit_index_scalar);
+            return Some((RayHit { point, normal }, hit_index_scalar as u32));
+        }
+        None
+    }
+
+    /// The hit for a ray given the nearest `t` and sphere index found by a kernel.
+    #[inline]
+    fn hit_at(&self, ray: &Ray, hit_t: f32, hit_index: usize) -> (RayHit, u32) {
+        let point = ray.point_at_parameter(hit_t);
+        let normal = (point
+            - vec3(
+                self.centre_x[hit_index],
+                self.centre_y[hit_index],
+                self.centre_z[hit_index],
+            ))
+            * self.radius_inv[hit_index];
+        (RayHit { point, normal }, hit_index as u32)
+    }
+
+    /// Plain Rust packet kernel, the inner loop over rays is branchless so the compiler can
+    /// vectorise it for any target.
+    pub fn hit_packet_portable(
+        &self,
+        rays: &[Ray; PACKET_SIZE],
+        t_min: f32,
+        t_max: f32,
+    ) -> [Option<(RayHit, u32)>; PACKET_SIZE] {
+        let mut ro_x = [0.0; PACKET_SIZE];
+        let mut ro_y = [0.0; PACKET_SIZE];
+        let mut ro_z = [0.0; PACKET_SIZE];
+        let mut rd_x = [0.0; PACKET_SIZE];
+        let mut rd_y = [0.0; PACKET_SIZE];
+        let mut rd_z = [0.0; PACKET_SIZE];
+        for (lane, ray) in rays.iter().enumerate() {
+            ro_x[lane] = ray.origin.get_x();
+            ro_y[lane] = ray.origin.get_y();
+            ro_z[lane] = ray.origin.get_z();
+            rd_x[lane] = ray.direction.get_x();
+            rd_y[lane] = ray.direction.get_y();
+            rd_z[lane] = ray.direction.get_z();
+        }
+        let mut hit_t = [t_max; PACKET_SIZE];
+        let mut hit_index = [0u32; PACKET_SIZE];
+        // the padding spheres can never be hit so are skipped
+        for index in 0..self.num_spheres {
+            let c_x = self.centre_x[index];
+            let c_y = self.centre_y[index];
+            let c_z = self.centre_z[index];
+            let r_sq = self.radius_sq[index];
+            for lane in 0..PACKET_SIZE {
+                // let co = centre - ray.origin
+                let co_x = c_x - ro_x[lane];
+                let co_y = c_y - ro_y[lane];
+                let co_z = c_z - ro_z[lane];
+                // let nb = dot(co, ray.direction);
+                let nb = co_x * rd_x[lane] + co_y * rd_y[lane] + co_z * rd_z[lane];
+                // let c = dot(co, co) - radius_sq;
+                let c = (co_x * co_x + co_y * co_y + co_z * co_z) - r_sq;
+                let discriminant = nb * nb - c;
+                // NaN for misses, which the mask below ignores
+                let discriminant_sqrt = discriminant.sqrt();
+                let t0 = nb - discriminant_sqrt;
+                let t = if t0 < t_min {
+                    nb + discriminant_sqrt
+                } else {
+                    t0
+                };
+                let mask = (discriminant > 0.0) & (t > t_min) & (t < hit_t[lane]);
+                hit_t[lane] = if mask { t } else { hit_t[lane] };
+                hit_index[lane] = if mask { index as u32 } else { hit_index[lane] };
+            }
+        }
+
+        let mut hits = [None; PACKET_SIZE];
+        for (lane, ray) in rays.iter().enumerate() {
+            if hit_t[lane] < t_max {
+                hits[lane] = Some(self.hit_at(ray, hit_t[lane], hit_index[lane] as usize));
+            }
+        }
+        hits
+    }
+
+    /// Packet kernel for `TargetFeature::FallBack`, intersecting each ray with `hit_scalar`.
+    pub fn hit_packet_scalar(
+        &self,
+        rays: &[Ray; PACKET_SIZE],
+        t_min: f32,
+        t_max: f32,
+    ) -> [Option<(RayHit, u32)>; PACKET_SIZE] {
+        let mut hits = [None; PACKET_SIZE];
+        for (hit, ray) in hits.iter_mut().zip(rays.iter()) {
+            *hit = self.hit_scalar(ray, t_min, t_max);
+        }
+        hits
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[cfg_attr(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature(enable = "sse4.1")
+    )]
+    pub unsafe fn hit_packet_sse4_1(
+        &self,
+        rays: &[Ray; PACKET_SIZE],
+        t_min: f32,
+        t_max: f32,
+    ) -> [Option<(RayHit, u32)>; PACKET_SIZE] {
+        #[cfg(target_arch = "x86")]
+        use std::arch::x86::*;
+        #[cfg(target_arch = "x86_64")]
+        use std::arch::x86_64::*;
+        const NUM_LANES: usize = 4;
+        // transpose the rays so each lane holds one
+        let mut ray_lanes = [[0.0; PACKET_SIZE]; 6];
+        for (lane, ray) in rays.iter().enumerate() {
+            ray_lanes[0][lane] = ray.origin.get_x();
+            ray_lanes[1][lane] = ray.origin.get_y();
+            ray_lanes[2][lane] = ray.origin.get_z();
+            ray_lanes[3][lane] = ray.direction.get_x();
+            ray_lanes[4][lane] = ray.direction.get_y();
+            ray_lanes[5][lane] = ray.direction.get_z();
+        }
+        let t_max_scalar = t_max;
+        let t_min = _mm_set1_ps(t_min);
+        let mut hit_t_array = [t_max; PACKET_SIZE];
+        let mut hit_index_array = [-1i32; PACKET_SIZE];
+        // a packet is two SSE registers wide, so each half is intersected in turn
+        for half in (0..PACKET_SIZE).step_by(NUM_LANES) {
+            let ro_x = _mm_loadu_ps(ray_lanes[0].as_ptr().add(half));
+            let ro_y = _mm_loadu_ps(ray_lanes[1].as_ptr().add(half));
+            let ro_z = _mm_loadu_ps(ray_lanes[2].as_ptr().add(half));
+            let rd_x = _mm_loadu_ps(ray_lanes[3].as_ptr().add(half));
+            let rd_y = _mm_loadu_ps(ray_lanes[4].as_ptr().add(half));
+            let rd_z = _mm_loadu_ps(ray_lanes[5].as_ptr().add(half));
+            let mut hit_t = _mm_set1_ps(t_max_scalar);
+            let mut hit_index = _mm_set1_epi32(-1);
+            // the padding spheres can never be hit so are skipped
+            for index in 0..self.num_spheres {
+                // broadcast the sphere to every lane
+                let c_x = _mm_set1_ps(*self.centre_x.get_unchecked(index));
+                let c_y = _mm_set1_ps(*self.centre_y.get_unchecked(index));
+                let c_z = _mm_set1_ps(*self.centre_z.get_unchecked(index));
+                let r_sq = _mm_set1_ps(*self.radius_sq.get_unchecked(index));
+                // let co = centre - ray.origin
+                let co_x = _mm_sub_ps(c_x, ro_x);
+                let co_y = _mm_sub_ps(c_y, ro_y);
+                let co_z = _mm_sub_ps(c_z, ro_z);
+                // let nb = dot(co, ray.direction);
+                let nb = dot3_sse2(co_x, rd_x, co_y, rd_y, co_z, rd_z);
+                // let c = dot(co, co) - radius_sq;
+                let c = _mm_sub_ps(dot3_sse2(co_x, co_x, co_y, co_y, co_z, co_z), r_sq);
+                // let discriminant = nb * nb - c;
+                let discr = _mm_sub_ps(_mm_mul_ps(nb, nb), c);
+                // if discr > 0.0
+                let pos_discr = _mm_cmpgt_ps(discr, _mm_set1_ps(0.0));
+                if _mm_movemask_ps(pos_discr) != 0 {
+                    // let discr_sqrt = discr.sqrt();
+                    let discr_sqrt = _mm_sqrt_ps(discr);
+                    // let t0 = nb - discr_sqrt;
+                    let t0 = _mm_sub_ps(nb, discr_sqrt);
+                    // let t1 = nb + discr_sqrt;
+                    let t1 = _mm_add_ps(nb, discr_sqrt);
+                    // let t = if t0 < t_min { t1 } else { t0 };
+                    let t = _mm_blendv_ps(t0, t1, _mm_cmplt_ps(t0, t_min));
+                    // mask = discr > 0 && t > t_min && t < hit_t
+                    let mask = _mm_and_ps(
+                        pos_discr,
+                        _mm_and_ps(_mm_cmpgt_ps(t, t_min), _mm_cmplt_ps(t, hit_t)),
+                    );
+                    // hit_index = mask ? index : hit_index;
+                    hit_index = _mm_blendv_epi8(
+                        hit_index,
+                        _mm_set1_epi32(index as i32),
+                        _mm_castps_si128(mask),
+                    );
+                    // hit_t = mask ? t : hit_t;
+                    hit_t = _mm_blendv_ps(hit_t, t, mask);
+                }
+            }
+            _mm_storeu_ps(hit_t_array.as_mut_ptr().add(half), hit_t);
+            _mm_storeu_si128(
+                hit_index_array.as_mut_ptr().add(half) as *mut __m128i,
+                hit_index,
+            );
+        }
+
+        let mut hits = [None; PACKET_SIZE];
+        for (lane, ray) in rays.iter().enumerate() {
+            if hit_t_array[lane] < t_max_scalar {
+                hits[lane] =
+                    Some(self.hit_at(ray, hit_t_array[lane], hit_index_array[lane] as usize));
+            }
+        }
+        hits
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[cfg_attr(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature(enable = "avx2")
+    )]
+    pub unsafe fn hit_packet_avx2(
+        &self,
+        rays: &[Ray; PACKET_SIZE],
+        t_min: f32,
+        t_max: f32,
+    ) -> [Option<(RayHit, u32)>; PACKET_SIZE] {
+        #[cfg(target_arch = "x86")]
+        use std::arch::x86::*;
+        #[cfg(target_arch = "x86_64")]
+        use std::arch::x86_64::*;
+        // transpose the rays so each lane holds one
+        let mut ray_lanes = [[0.0; PACKET_SIZE]; 6];
+        for (lane, ray) in rays.iter().enumerate() {
+            ray_lanes[0][lane] = ray.origin.get_x();
+            ray_lanes[1][lane] = ray.origin.get_y();
+            ray_lanes[2][lane] = ray.origin.get_z();
+            ray_lanes[3][lane] = ray.direction.get_x();
+            ray_lanes[4][lane] = ray.direction.get_y();
+            ray_lanes[5][lane] = ray.direction.get_z();
+        }
+        let ro_x = _mm256_loadu_ps(ray_lanes[0].as_ptr());
+        let ro_y = _mm256_loadu_ps(ray_lanes[1].as_ptr());
+        let ro_z = _mm256_loadu_ps(ray_lanes[2].as_ptr());
+        let rd_x = _mm256_loadu_ps(ray_lanes[3].as_ptr());
+        let rd_y = _mm256_loadu_ps(ray_lanes[4].as_ptr());
+        let rd_z = _mm256_loadu_ps(ray_lanes[5].as_ptr());
+        let t_max_scalar = t_max;
+        let t_min = _mm256_set1_ps(t_min);
+        let mut hit_t = _mm256_set1_ps(t_max);
+        let mut hit_index = _mm256_set1_epi32(-1);
+        // the padding spheres can never be hit so are skipped
+        for index in 0..self.num_spheres {
+            // broadcast the sphere to every lane
+            let c_x = _mm256_set1_ps(*self.centre_x.get_unchecked(index));
+            let c_y = _mm256_set1_ps(*self.centre_y.get_unchecked(index));
+            let c_z = _mm256_set1_ps(*self.centre_z.get_unchecked(index));
+            let r_sq = _mm256_set1_ps(*self.radius_sq.get_unchecked(index));
+            // let co = centre - ray.origin
+            let co_x = _mm256_sub_ps(c_x, ro_x);
+            let co_y = _mm256_sub_ps(c_y, ro_y);
+            let co_z = _mm256_sub_ps(c_z, ro_z);
+            // let nb = dot(co, ray.direction);
+            let nb = dot3_avx2(co_x, rd_x, co_y, rd_y, co_z, rd_z);
+            // let c = dot(co, co) - radius_sq;
+            let c = _mm256_sub_ps(dot3_avx2(co_x, co_x, co_y, co_y, co_z, co_z), r_sq);
+            // let discriminant = nb * nb - c;
+            let discr = _mm256_sub_ps(_mm256_mul_ps(nb, nb), c);
+            // if discr > 0.0
+            let pos_discr = _mm256_cmp_ps(discr, _mm256_set1_ps(0.0), _CMP_GT_OQ);
+            if _mm256_movemask_ps(pos_discr) != 0 {
+                // let discr_sqrt = discr.sqrt();
+                let discr_sqrt = _mm256_sqrt_ps(discr);
+                // let t0 = nb - discr_sqrt;
+                let t0 = _mm256_sub_ps(nb, discr_sqrt);
+                // let t1 = nb + discr_sqrt;
+                let t1 = _mm256_add_ps(nb, discr_sqrt);
+                // let t = if t0 < t_min { t1 } else { t0 };
+                let t = _mm256_blendv_ps(t0, t1, _mm256_cmp_ps(t0, t_min, _CMP_LT_OQ));
+                // mask = discr > 0 && t > t_min && t < hit_t
+                let mask = _mm256_and_ps(
+                    pos_discr,
+                    _mm256_and_ps(
+                        _mm256_cmp_ps(t, t_min, _CMP_GT_OQ),
+                        _mm256_cmp_ps(t, hit_t, _CMP_LT_OQ),
+                    ),
+                );
+                // hit_index = mask ? index : hit_index;
+                hit_index = _mm256_blendv_epi8(
+                    hit_index,
+                    _mm256_set1_epi32(index as i32),
+                    _mm256_castps_si256(mask),
+                );
+                // hit_t = mask ? t : hit_t;
+                hit_t = _mm256_blendv_ps(hit_t, t, mask);
+            }
+        }
+
+        let hit_t_array = F32x8 { simd: hit_t }.array;
+        let hit_index_array = I32x8 { simd: hit_index }.array;
+        let mut hits = [None; PACKET_SIZE];
+        for (lane, ray) in rays.iter().enumerate() {
+            if hit_t_array[lane] < t_max_scalar {
+                hits[lane] =
+                    Some(self.hit_at(ray, hit_t_array[lane], hit_index_array[lane] as usize));
+            }
+        }
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_xoshiro::Xoshiro256Plus;
+
+    const T_MIN: f32 = 0.001;
+    const T_MAX: f32 = f32::MAX;
+
+    type Hit = Option<(RayHit, u32)>;
+
+    fn bits(v: Vec3) -> [u32; 3] {
+        [
+            v.get_x().to_bits(),
+            v.get_y().to_bits(),
+            v.get_z().to_bits(),
+        ]
+    }
+
+    fn same_hit(a: Hit, b: Hit) -> bool {
+        match (a, b) {
+            (None, None) => true,
+            (Some((a, a_index)), Some((b, b_index))) => {
+                a_index == b_index
+                    && bits(a.point) == bits(b.point)
+                    && bits(a.normal) == bits(b.normal)
+            }
+            _ => false,
+        }
+    }
+
+    fn random_unit(rng: &mut Xoshiro256Plus) -> Vec3 {
+        loop {
+            let v = vec3(
+                rng.gen_range(-1.0, 1.0),
+                rng.gen_range(-1.0, 1.0),
+                rng.gen_range(-1.0, 1.0),
+            );
+            let length_sq = v.length_squared();
+            if length_sq > 0.01 && length_sq <= 1.0 {
+                return v.normalize();
+            }
+        }
+    }
+
+    fn random_spheres(rng: &mut Xoshiro256Plus, count: usize) -> Vec<Sphere> {
+        (0..count)
+            .map(|_| Sphere {
+                centre: vec3(
+                    rng.gen_range(-4.0, 4.0),
+                    rng.gen_range(-4.0, 4.0),
+                    rng.gen_range(-8.0, 0.0),
+                ),
+                radius: rng.gen_range(0.1, 2.0),
+            })
+            .collect()
+    }
+
+    /// Coincident spheres, including more copies than fit in a SIMD register so ties cross
+    /// lanes and chunks, and pairs of spheres touching at a single point.
+    fn degenerate_spheres() -> Vec<Sphere> {
+        let sphere = |x, y, z, radius| Sphere {
+            centre: vec3(x, y, z),
+            radius,
+        };
+        let mut spheres = vec![
+            sphere(0.0, 0.0, -5.0, 1.0),
+            sphere(0.0, 0.0, -5.0, 1.0),
+            sphere(0.0, 0.0, -5.0, 0.5),
+            sphere(3.0, 0.0, -5.0, 1.0),
+            sphere(5.0, 0.0, -5.0, 1.0),
+            sphere(0.0, 3.0, -5.0, 1.0),
+            sphere(0.0, 4.5, -5.0, 0.5),
+        ];
+        spheres.extend((0..19).map(|_| sphere(0.0, -3.0, -5.0, 1.0)));
+        spheres
+    }
+
+    /// Rays from random origins in random directions, plus rays grazing each sphere and rays
+    /// through the points where the degenerate spheres touch.
+    fn test_rays(rng: &mut Xoshiro256Plus, spheres: &[Sphere]) -> Vec<Ray> {
+        let mut rays: Vec<Ray> = (0..2000)
+            .map(|_| {
+                let origin = vec3(
+                    rng.gen_range(-3.0, 3.0),
+                    rng.gen_range(-3.0, 3.0),
+                    rng.gen_range(-6.0, 3.0),
+                );
+                ray(origin, random_unit(rng))
+            })
+            .collect();
+        for sphere in spheres {
+            for _ in 0..8 {
+                let direction = random_unit(rng);
+                let side = direction.cross(random_unit(rng)).normalize();
+                let grazed = sphere.centre + side * sphere.radius;
+                rays.push(ray(grazed - direction * 10.0, direction));
+            }
+        }
+        for &contact in &[vec3(4.0, 0.0, -5.0), vec3(0.0, 4.0, -5.0)] {
+            for _ in 0..64 {
+                let direction = random_unit(rng);
+                rays.push(ray(contact - direction * 10.0, direction));
+            }
+        }
+        rays
+    }
+
+    /// Random scenes of different sizes around multiples of the SIMD widths, and the degenerate
+    /// one.
+    fn test_scenes(rng: &mut Xoshiro256Plus) -> Vec<Vec<Sphere>> {
+        let mut scenes: Vec<Vec<Sphere>> = [1, 3, 8, 9, 16, 17, 33]
+            .iter()
+            .map(|&count| random_spheres(rng, count))
+            .collect();
+        scenes.push(degenerate_spheres());
+        scenes
+    }
+
+    /// Checks `kernel` finds bit for bit the same hits as `hit_scalar`, with the spheres laid
+    /// out for `feature`. Does nothing if the CPU doesn't support `feature`.
+    fn check_kernel(feature: TargetFeature, kernel: &dyn Fn(&SpheresSoA, &Ray) -> Hit) {
+        if !feature.is_supported() {
+            return;
+        }
+        let mut rng = Xoshiro256Plus::seed_from_u64(7);
+        for spheres in &test_scenes(&mut rng) {
+            let reference = SpheresSoA::new(spheres, TargetFeature::FallBack).unwrap();
+            let soa = SpheresSoA::new(spheres, feature).unwrap();
+            for ray in test_rays(&mut rng, spheres) {
+                assert!(
+                    same_hit(kernel(&soa, &ray), reference.hit_scalar(&ray, T_MIN, T_MAX)),
+                    "{} differs from scalar for {:?} with {} spheres",
+                    feature.name(),
+                    ray,
+                    spheres.len()
+                );
+            }
+        }
+    }
+
+    /// Checks the packet `kernel` finds bit for bit the same hit for every ray as `hit_scalar`,
+    /// with the spheres laid out for `feature`. Does nothing if the CPU doesn't support
+    /// `feature`.
+    fn check_packet_kernel(
+        feature: TargetFeature,
+        kernel: &dyn Fn(&SpheresSoA, &[Ray; PACKET_SIZE]) -> [Hit; PACKET_SIZE],
+    ) {
+        if !feature.is_supported() {
+            return;
+        }
+        let mut rng = Xoshiro256Plus::seed_from_u64(11);
+        for spheres in &test_scenes(&mut rng) {
+            let reference = SpheresSoA::new(spheres, TargetFeature::FallBack).unwrap();
+            let soa = SpheresSoA::new(spheres, feature).unwrap();
+            for chunk in test_rays(&mut rng, spheres).chunks(PACKET_SIZE) {
+                let mut packet = [chunk[0]; PACKET_SIZE];
+                packet[..chunk.len()].copy_from_slice(chunk);
+                let hits = kernel(&soa, &packet);
+                for (ray, hit) in packet.iter().zip(hits.iter()) {
+                    assert!(
+                        same_hit(*hit, reference.hit_scalar(ray, T_MIN, T_MAX)),
+                        "{} packet differs from scalar for {:?} with {} spheres",
+                        feature.name(),
+                        ray,
+                        spheres.len()
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn portable_matches_scalar() {
+        check_kernel(TargetFeature::Portable, &|soa, ray| {
+            soa.hit_portable(ray, T_MIN, T_MAX)
+        });
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn sse4_1_matches_scalar() {
+        check_kernel(TargetFeature::SSE4_1, &|soa, ray| unsafe {
+            soa.hit_sse4_1(ray, T_MIN, T_MAX)
+        });
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn avx2_matches_scalar() {
+        check_kernel(TargetFeature::AVX2, &|soa, ray| unsafe {
+            soa.hit_avx2(ray, T_MIN, T_MAX)
+        });
+    }
+
+    #[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+    #[test]
+    fn avx512_matches_scalar() {
+        check_kernel(TargetFeature::AVX512, &|soa, ray| unsafe {
+            soa.hit_avx512(ray, T_MIN, T_MAX)
+        });
+    }
+
+    #[test]
+    fn packet_portable_matches_scalar() {
+        check_packet_kernel(TargetFeature::Portable, &|soa, rays| {
+            soa.hit_packet_portable(rays, T_MIN, T_MAX)
+        });
+    }
+
+    #[test]
+    fn packet_scalar_matches_scalar() {
+        check_packet_kernel(TargetFeature::FallBack, &|soa, rays| {
+            soa.hit_packet_scalar(rays, T_MIN, T_MAX)
+        });
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn packet_sse4_1_matches_scalar() {
+        check_packet_kernel(TargetFeature::SSE4_1, &|soa, rays| unsafe {
+            soa.hit_packet_sse4_1(rays, T_MIN, T_MAX)
+        });
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn packet_avx2_matches_scalar() {
+        check_packet_kernel(TargetFeature::AVX2, &|soa, rays| unsafe {
+            soa.hit_packet_avx2(rays, T_MIN, T_MAX)
+        });
+    }
+
+    #[test]
+    fn packets_match_single_rays_for_every_feature() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(17);
+        for spheres in &test_scenes(&mut rng) {
+            let rays = test_rays(&mut rng, spheres);
+            for &feature in TargetFeature::ALL.iter() {
+                if !feature.is_supported() {
+                    continue;
+                }
+                let soa = SpheresSoA::new(spheres, feature).unwrap();
+                for chunk in rays.chunks(PACKET_SIZE) {
+                    let hits = soa.ray_hit_packet(chunk, T_MIN, T_MAX);
+                    for (ray, hit) in chunk.iter().zip(hits.iter()) {
+                        assert!(
+                            same_hit(*hit, soa.ray_hit(ray, T_MIN, T_MAX)),
+                            "{} packet differs from a single ray for {:?} with {} spheres",
+                            feature.name(),
+                            ray,
+                            spheres.len()
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn partial_packets_match_scalar() {
+        let mut rng = Xoshiro256Plus::seed_from_u64(13);
+        let spheres = degenerate_spheres();
+        let reference = SpheresSoA::new(&spheres, TargetFeature::FallBack).unwrap();
+        let rays = test_rays(&mut rng, &spheres);
+        for &feature in TargetFeature::ALL.iter() {
+            if !feature.is_supported() {
+                continue;
+            }
+            let soa = SpheresSoA::new(&spheres, feature).unwrap();
+            for len in 1..=PACKET_SIZE {
+                for chunk in rays.chunks(len) {
+                    let hits = soa.ray_hit_packet(chunk, T_MIN, T_MAX);
+                    for (lane, hit) in hits.iter().enumerate() {
+                        let expected = chunk
+                            .get(lane)
+                            .and_then(|ray| reference.hit_scalar(ray, T_MIN, T_MAX));
+                        assert!(
+                            same_hit(*hit, expected),
+                            "{} differs from scalar in lane {} of a packet of {}",
+                            feature.name(),
+                            lane,
+                            chunk.len()
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn close(a: Vec3, b: Vec3) -> bool {
+        (a - b).length() < 1.0e-5
+    }
+
+    fn along_x(x: f32) -> Ray {
+        ray(vec3(x, 0.0, 0.0), vec3(1.0, 0.0, 0.0))
+    }
+
+    fn sphere_on_x(x: f32, radius: f32) -> Csg {
+        Csg::Sphere(Sphere {
+            centre: vec3(x, 0.0, 0.0),
+            radius,
+        })
+    }
+
+    fn check_spans(spans: &[Span], expected: &[(f32, f32, f32, f32)]) {
+        assert_eq!(spans.len(), expected.len(), "{:?}", spans);
+        for (span, &(t_enter, n_enter, t_exit, n_exit)) in spans.iter().zip(expected) {
+            assert!((span.t_enter - t_enter).abs() < 1.0e-5, "{:?}", span);
+            assert!((span.t_exit - t_exit).abs() < 1.0e-5, "{:?}", span);
+            assert!(close(span.n_enter, vec3(n_enter, 0.0, 0.0)), "{:?}", span);
+            assert!(close(span.n_exit, vec3(n_exit, 0.0, 0.0)), "{:?}", span);
+        }
+    }
+
+    fn ray_with_direction(direction: Vec3) -> Ray {
+        ray(-5.0 * direction, direction)
+    }
+
+    #[test]
+    fn primitive_spans() {
+        let x_ray = along_x(-5.0);
+        check_spans(
+            &sphere_on_x(0.0, 1.0).spans(&x_ray),
+            &[(4.0, -1.0, 6.0, 1.0)],
+        );
+        let cuboid = Csg::Cuboid(Cuboid::centred(vec3(1.0, 2.0, 3.0)));
+        check_spans(&cuboid.spans(&x_ray), &[(4.0, -1.0, 6.0, 1.0)]);
+        let backwards = ray_with_direction(vec3(-1.0, 0.0, 0.0));
+        check_spans(&cuboid.spans(&backwards), &[(4.0, 1.0, 6.0, -1.0)]);
+        assert!(sphere_on_x(0.0, 1.0)
+            .spans(&ray(vec3(-5.0, 1.5, 0.0), vec3(1.0, 0.0, 0.0)))
+            .is_empty());
+    }
+
+    #[test]
+    fn rays_along_a_cuboid_face() {
+        // parallel to the y and z slabs with the origin on a y plane
+        let cuboid = centred_cuboid(vec3(1.0, 1.0, 1.0));
+        let grazing = ray(vec3(-5.0, 1.0, 0.0), vec3(1.0, 0.0, 0.0));
+        check_spans(&cuboid.spans(&grazing), &[(4.0, -1.0, 6.0, 1.0)]);
+        let outside = ray(vec3(-5.0, 1.5, 0.0), vec3(1.0, 0.0, 0.0));
+        assert!(cuboid.spans(&outside).is_empty());
+    }
+
+    #[test]
+    fn csg_operations_combine_spans() {
+        let x_ray = along_x(-5.0);
+        let a = || sphere_on_x(0.0, 1.0);
+        let overlapping = || sphere_on_x(1.0, 1.0);
+        check_spans(
+            &a().union(overlapping()).spans(&x_ray),
+            &[(4.0, -1.0, 7.0, 1.0)],
+        );
+        check_spans(
+            &a().union(sphere_on_x(3.0, 1.0)).spans(&x_ray),
+            &[(4.0, -1.0, 6.0, 1.0), (7.0, -1.0, 9.0, 1.0)],
+        );
+        check_spans(
+            &a().intersection(overlapping()).spans(&x_ray),
+            &[(5.0, -1.0, 6.0, 1.0)],
+        );
+        assert!(a()
+            .intersection(sphere_on_x(3.0, 1.0))
+            .spans(&x_ray)
+            .is_empty());
+        // the hole's walls face into the hole
+        let hollow =
+            Csg::Cuboid(Cuboid::centred(vec3(1.0, 1.0, 1.0))).difference(sphere_on_x(0.0, 0.5));
+        check_spans(
+            &hollow.spans(&x_ray),
+            &[(4.0, -1.0, 4.5, 1.0), (5.5, -1.0, 6.0, 1.0)],
+        );
+        check_spans(&a().difference(a()).spans(&x_ray), &[]);
+    }
+
+    #[test]
+    fn csg_hits_respect_the_ray_interval() {
+        let solid = sphere_on_x(0.0, 1.0);
+        let (hit, t) = solid.ray_hit(&along_x(-5.0), T_MIN, T_MAX).unwrap();
+        assert!((t - 4.0).abs() < 1.0e-5);
+        assert!(close(hit.point, vec3(-1.0, 0.0, 0.0)));
+        assert!(close(hit.normal, vec3(-1.0, 0.0, 0.0)));
+        assert!(solid.ray_hit(&along_x(-5.0), T_MIN, 3.9).is_none());
+        assert!(solid.ray_hit(&along_x(2.0), T_MIN, T_MAX).is_none());
+
+        // starting inside the solid hits its far side
+        let (hit, t) = solid.ray_hit(&along_x(0.0), T_MIN, T_MAX).unwrap();
+        assert!((t - 1.0).abs() < 1.0e-5);
+        assert!(close(hit.normal, vec3(1.0, 0.0, 0.0)));
+
+        // starting inside a hole skips the span behind the ray
+        let hollow =
+            Csg::Cuboid(Cuboid::centred(vec3(1.0, 1.0, 1.0))).difference(sphere_on_x(0.0, 0.5));
+        let (hit, t) = hollow.ray_hit(&along_x(0.0), T_MIN, T_MAX).unwrap();
+        assert!((t - 0.5).abs() < 1.0e-5);
+        assert!(close(hit.normal, vec3(-1.0, 0.0, 0.0)));
+    }
 }
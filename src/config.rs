@@ -0,0 +1,795 @@
+use crate::output::{ToneMap, ToneMapping};
+use clap::ArgMatches;
+use pathtrace_rs::{
+    camera::Projection,
+    error::{Error, Result},
+    film::AovFormat,
+    filter::FilterKind,
+    presets,
+    sampler::SamplerKind,
+    scene::{Params, Tracing},
+    simd::TargetFeature,
+};
+use std::{convert::TryFrom, fmt, fs, path::Path, str::FromStr};
+
+/// Read when `--config` isn't given, if it exists.
+pub const DEFAULT_PATH: &str = "pathtrace.toml";
+
+/// Profiles available without a config file, a profile in the file replaces the one here with
+/// the same name.
+const BUILTIN_PROFILES: &str = r#"
+[preview]
+width = 640
+height = 360
+samples = 1
+depth = 4
+denoise = true
+
+[final]
+width = 1920
+height = 1080
+samples = 64
+depth = 16
+filter = "blackman-harris"
+sampler = "sobol"
+output = "renders/{preset}-{width}x{height}-{spp}spp-{timestamp}.png"
+
+[benchmark]
+preset = "aras"
+width = 1280
+height = 720
+samples = 16
+depth = 10
+seed = 0
+output = "benchmark.png"
+"#;
+
+/// The type of value an option takes.
+#[derive(Copy, Clone, Debug)]
+enum Kind {
+    Bool,
+    /// A non-negative integer that fits in 32 bits.
+    Count,
+    /// Any non-negative integer, as a string if it's too big for a TOML integer.
+    Seed,
+    /// An integer or float.
+    Number,
+    Text,
+    /// One of a fixed set of names.
+    Choice(&'static [&'static str]),
+}
+
+/// Options a config file can set, by their command line names. Options choosing what kind of
+/// render to run, like `offline` or `coordinator`, can only be given on the command line.
+const KEYS: [(&str, Kind); 29] = [
+    ("preset", Kind::Choice(&presets::NAMES)),
+    ("width", Kind::Count),
+    ("height", Kind::Count),
+    ("samples", Kind::Count),
+    ("depth", Kind::Count),
+    ("random", Kind::Bool),
+    ("seed", Kind::Seed),
+    ("filter", Kind::Choice(&FilterKind::NAMES)),
+    ("filter-radius", Kind::Number),
+    ("sampler", Kind::Choice(&SamplerKind::NAMES)),
+    ("noise-threshold", Kind::Number),
+    ("time-limit", Kind::Number),
+    ("denoise", Kind::Bool),
+    ("aovs", Kind::Choice(&AovFormat::NAMES)),
+    ("float-output", Kind::Bool),
+    ("output", Kind::Text),
+    ("tone-map", Kind::Choice(&ToneMapping::NAMES)),
+    ("exposure", Kind::Number),
+    ("frames", Kind::Count),
+    ("snapshot-frames", Kind::Count),
+    ("snapshot-interval", Kind::Number),
+    ("projection", Kind::Choice(&Projection::NAMES)),
+    ("fov", Kind::Number),
+    ("aperture", Kind::Number),
+    ("blades", Kind::Count),
+    ("blade-rotation", Kind::Number),
+    ("cats-eye", Kind::Number),
+    ("simd", Kind::Choice(&TargetFeature::NAMES)),
+    ("tracing", Kind::Choice(&Tracing::NAMES)),
+];
+
+/// Options that need one of some others, the command line's `requires` rules applied again
+/// once config file values are merged in.
+const REQUIRES: [(&str, &[&str]); 4] = [
+    ("float-output", &["offline"]),
+    ("snapshot-frames", &["progressive"]),
+    ("snapshot-interval", &["progressive"]),
+    // workers render the AOVs their coordinator writes
+    ("aovs", &["offline", "worker"]),
+];
+
+/// Options that can't be used together, like `REQUIRES` for the `conflicts_with` rules.
+const CONFLICTS: [(&str, &[&str]); 5] = [
+    ("random", &["seed"]),
+    ("noise-threshold", &["coordinator", "worker", "stereo"]),
+    ("aovs", &["stereo"]),
+    ("blades", &["aperture-mask"]),
+    ("output", &["worker"]),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+}
+
+impl Value {
+    /// The value as it would be typed on the command line.
+    fn to_arg(&self) -> String {
+        match self {
+            Value::String(string) => string.clone(),
+            _ => self.to_string(),
+        }
+    }
+
+    fn check(&self, key: &str, kind: Kind) -> std::result::Result<(), String> {
+        let ok = match (kind, self) {
+            (Kind::Bool, Value::Bool(_)) => true,
+            (Kind::Count, Value::Integer(i)) => u32::try_from(*i).is_ok(),
+            (Kind::Seed, Value::Integer(i)) => *i >= 0,
+            (Kind::Seed, Value::String(s)) => s.parse::<u64>().is_ok(),
+            (Kind::Number, Value::Integer(_)) | (Kind::Number, Value::Float(_)) => true,
+            (Kind::Text, Value::String(_)) => true,
+            (Kind::Choice(names), Value::String(name)) => names.contains(&name.as_str()),
+            _ => false,
+        };
+        if ok {
+            return Ok(());
+        }
+        Err(match kind {
+            Kind::Bool => format!("'{}' must be true or false", key),
+            Kind::Count | Kind::Seed => format!("'{}' must be a non-negative integer", key),
+            Kind::Number => format!("'{}' must be a number", key),
+            Kind::Text => format!("'{}' must be a quoted string", key),
+            Kind::Choice(names) => format!("'{}' must be one of \"{}\"", key, names.join("\", \"")),
+        })
+    }
+}
+
+impl fmt::Display for Value {
+    /// Formats the value as TOML.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Integer(i) => write!(f, "{}", i),
+            // keep a decimal point so it reads back as a float
+            Value::Float(x) if x.fract() == 0.0 && x.is_finite() => write!(f, "{:.1}", x),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::String(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        _ => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+        }
+    }
+}
+
+type Table = Vec<(String, Value)>;
+
+/// Sets `key` in `table`, replacing any value it already has.
+fn set(table: &mut Table, key: &str, value: Value) {
+    match table.iter_mut().find(|(existing, _)| existing == key) {
+        Some(entry) => entry.1 = value,
+        None => table.push((key.to_string(), value)),
+    }
+}
+
+/// Parses a quoted string at the start of `text`, returning it and the text after the closing
+/// quote.
+fn parse_string(text: &str) -> std::result::Result<(String, &str), String> {
+    let mut string = String::new();
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((string, &text[i + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('"') => string.push('"'),
+                Some('\\') => string.push('\\'),
+                Some('n') => string.push('\n'),
+                Some('t') => string.push('\t'),
+                Some(c) => return Err(format!("unsupported escape '\\{}'", c)),
+                None => break,
+            },
+            _ => string.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+/// `text` up to any `#` comment, without surrounding whitespace.
+fn strip_comment(text: &str) -> &str {
+    text.split('#').next().unwrap_or("").trim()
+}
+
+fn parse_value(text: &str) -> std::result::Result<Value, String> {
+    if text.starts_with('"') {
+        let (string, rest) = parse_string(text)?;
+        if !strip_comment(rest).is_empty() {
+            return Err(format!("unexpected '{}' after string", rest.trim()));
+        }
+        return Ok(Value::String(string));
+    }
+    let text = strip_comment(text);
+    match text {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        "" => return Err("missing value".to_string()),
+        _ => {}
+    }
+    let number = text.replace('_', "");
+    if let Ok(i) = number.parse() {
+        Ok(Value::Integer(i))
+    } else if let Ok(x) = number.parse() {
+        Ok(Value::Float(x))
+    } else {
+        Err(format!(
+            "can't read '{}', values are numbers, true, false or quoted strings",
+            text
+        ))
+    }
+}
+
+fn is_bare_key(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Render settings from a TOML file. Options at the top of the file apply to every render and
+/// each `[name]` table is a profile whose options override them, picked with `--profile`. Only
+/// the subset of TOML needed for this is understood: tables, and keys with string, integer,
+/// float or boolean values.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    defaults: Table,
+    profiles: Vec<(String, Table)>,
+}
+
+impl Config {
+    /// Parses `text`, with errors naming `source` and the line.
+    pub fn parse(text: &str, source: &str) -> Result<Config> {
+        let mut config = Config::default();
+        // index into profiles of the table being read, `None` before the first table
+        let mut current: Option<usize> = None;
+        for (line_index, line) in text.lines().enumerate() {
+            let error = |message: String| {
+                Error::InvalidParams(format!("{}:{}: {}", source, line_index + 1, message))
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') {
+                let header = strip_comment(line);
+                if header.starts_with("[[") || !header.ends_with(']') {
+                    return Err(error(format!("can't read table header '{}'", header)));
+                }
+                let name = header[1..header.len() - 1].trim();
+                if !is_bare_key(name) {
+                    return Err(error(format!("bad profile name '{}'", name)));
+                }
+                if config.profiles.iter().any(|(existing, _)| existing == name) {
+                    return Err(error(format!("profile '{}' is defined twice", name)));
+                }
+                config.profiles.push((name.to_string(), Vec::new()));
+                current = Some(config.profiles.len() - 1);
+                continue;
+            }
+            let equals = line
+                .find('=')
+                .ok_or_else(|| error(format!("expected 'option = value', found '{}'", line)))?;
+            let key = line[..equals].trim();
+            let kind = KEYS
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, kind)| *kind)
+                .ok_or_else(|| {
+                    let names: Vec<&str> = KEYS.iter().map(|(name, _)| *name).collect();
+                    error(format!(
+                        "unknown option '{}', expected one of {}",
+                        key,
+                        names.join(", ")
+                    ))
+                })?;
+            let value = parse_value(line[equals + 1..].trim()).map_err(&error)?;
+            value.check(key, kind).map_err(&error)?;
+            let table = match current {
+                Some(index) => &mut config.profiles[index].1,
+                None => &mut config.defaults,
+            };
+            if table.iter().any(|(existing, _)| existing == key) {
+                return Err(error(format!("'{}' is set twice", key)));
+            }
+            table.push((key.to_string(), value));
+        }
+        Ok(config)
+    }
+
+    /// The built-in profiles, replaced or extended by the file at `path` or `DEFAULT_PATH`.
+    pub fn load(path: Option<&str>) -> Result<Config> {
+        let mut config = Config::parse(BUILTIN_PROFILES, "built-in profiles")?;
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_PATH).exists() => DEFAULT_PATH,
+            None => return Ok(config),
+        };
+        let text = fs::read_to_string(path)
+            .map_err(Error::io(format!("couldn't read config file {}", path)))?;
+        let file = Config::parse(&text, path)?;
+        config.defaults = file.defaults;
+        for (name, table) in file.profiles {
+            match config
+                .profiles
+                .iter_mut()
+                .find(|(existing, _)| *existing == name)
+            {
+                Some(profile) => profile.1 = table,
+                None => config.profiles.push((name, table)),
+            }
+        }
+        Ok(config)
+    }
+
+    /// The options set by the file's defaults and then `profile`, if given.
+    pub fn values(&self, profile: Option<&str>) -> Result<Vec<(String, Value)>> {
+        let mut values = self.defaults.clone();
+        if let Some(name) = profile {
+            let (_, table) = self
+                .profiles
+                .iter()
+                .find(|(existing, _)| existing == name)
+                .ok_or_else(|| {
+                    let names: Vec<&str> = self
+                        .profiles
+                        .iter()
+                        .map(|(name, _)| name.as_str())
+                        .collect();
+                    Error::InvalidParams(format!(
+                        "unknown profile '{}', expected one of {}",
+                        name,
+                        names.join(", ")
+                    ))
+                })?;
+            for (key, value) in table {
+                set(&mut values, key, value.clone());
+            }
+        }
+        Ok(values)
+    }
+}
+
+/// Command line arguments backed by config file values, options given on the command line win.
+pub struct Settings<'a> {
+    matches: &'a ArgMatches<'a>,
+    values: Vec<(String, Value)>,
+    /// Where `values` came from, for error messages.
+    origin: String,
+}
+
+impl<'a> Settings<'a> {
+    pub fn new(
+        matches: &'a ArgMatches<'a>,
+        values: Vec<(String, Value)>,
+        profile: Option<&str>,
+    ) -> Settings<'a> {
+        let origin = match profile {
+            Some(name) => format!("profile '{}'", name),
+            None => "the config file".to_string(),
+        };
+        Settings {
+            matches,
+            values,
+            origin,
+        }
+    }
+
+    fn config_value(&self, name: &str) -> Option<&Value> {
+        self.values
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn value_of(&self, name: &str) -> Option<String> {
+        self.matches
+            .value_of(name)
+            .map(str::to_string)
+            .or_else(|| self.config_value(name).map(Value::to_arg))
+    }
+
+    /// Whether a flag is set or an option has a value. Flags turned on by the config file are
+    /// turned off with `--no-<flag>`.
+    pub fn is_present(&self, name: &str) -> bool {
+        if self.matches.is_present(name) {
+            return true;
+        }
+        if self.matches.is_present(format!("no-{}", name)) {
+            return false;
+        }
+        match self.config_value(name) {
+            Some(Value::Bool(set)) => *set,
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// How to refer to option `name` in errors, by where it was set.
+    fn option(&self, name: &str) -> String {
+        if self.matches.is_present(name) {
+            format!("--{}", name)
+        } else {
+            format!("'{}' in {}", name, self.origin)
+        }
+    }
+
+    /// An error saying the value `text` of option `name` can't be used.
+    fn invalid(&self, name: &str, text: &str, expected: &str) -> Error {
+        Error::InvalidParams(format!(
+            "{} can't be '{}', {}",
+            self.option(name),
+            text,
+            expected
+        ))
+    }
+
+    /// Checks the options set by the file and command line together follow the rules the
+    /// command line enforces for options given there.
+    pub fn check(&self) -> Result<()> {
+        for (name, needed) in REQUIRES.iter() {
+            if self.is_present(name) && !needed.iter().any(|other| self.is_present(other)) {
+                let needed: Vec<String> =
+                    needed.iter().map(|other| format!("--{}", other)).collect();
+                return Err(Error::InvalidParams(format!(
+                    "{} needs {}",
+                    self.option(name),
+                    needed.join(" or ")
+                )));
+            }
+        }
+        // the preview window and its headless fallback stop at the time limit too
+        if self.is_present("time-limit")
+            && (self.is_present("offline") || self.is_present("worker"))
+            && !(self.is_present("noise-threshold") || self.is_present("progressive"))
+        {
+            return Err(Error::InvalidParams(format!(
+                "{} needs --noise-threshold or --progressive when rendering without a window",
+                self.option("time-limit")
+            )));
+        }
+        for (name, others) in CONFLICTS.iter() {
+            if !self.is_present(name) {
+                continue;
+            }
+            if let Some(other) = others.iter().find(|other| self.is_present(other)) {
+                return Err(Error::InvalidParams(format!(
+                    "{} can't be used with {}",
+                    self.option(name),
+                    self.option(other)
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The option's value parsed as `T`, `None` if it isn't set.
+    pub fn value<T: FromStr>(&self, name: &str) -> Result<Option<T>> {
+        match self.value_of(name) {
+            Some(text) => text
+                .parse()
+                .map(Some)
+                .map_err(|_| self.invalid(name, &text, "it doesn't parse")),
+            None => Ok(None),
+        }
+    }
+
+    /// The option's value looked up with `from_name`, `None` if it isn't set.
+    pub fn choice<T>(
+        &self,
+        name: &str,
+        names: &[&str],
+        from_name: impl Fn(&str) -> Option<T>,
+    ) -> Result<Option<T>> {
+        match self.value_of(name) {
+            Some(text) => from_name(&text).map(Some).ok_or_else(|| {
+                self.invalid(
+                    name,
+                    &text,
+                    &format!("expected one of {}", names.join(", ")),
+                )
+            }),
+            None => Ok(None),
+        }
+    }
+}
+
+/// The settings a render will use as a config file, including defaults for options that have
+/// them.
+pub fn effective(
+    settings: &Settings,
+    params: &Params,
+    preset: &str,
+    output: &str,
+    tone_map: ToneMap,
+    projection: Projection,
+) -> String {
+    let mut toml = String::new();
+    for (key, _) in KEYS.iter() {
+        let given = settings
+            .value_of(key)
+            .and_then(|text| parse_value(&text).ok());
+        let value = match *key {
+            "preset" => Some(Value::String(preset.to_string())),
+            "width" => Some(Value::Integer(params.width.into())),
+            "height" => Some(Value::Integer(params.height.into())),
+            "samples" => Some(Value::Integer(params.samples.into())),
+            "depth" => Some(Value::Integer(params.max_depth.into())),
+            "random" => Some(Value::Bool(params.random_seed)),
+            "seed" => Some(
+                i64::try_from(params.seed)
+                    .map_or_else(|_| Value::String(params.seed.to_string()), Value::Integer),
+            ),
+            "filter" => Some(Value::String(params.filter.kind.name().to_string())),
+            "filter-radius" => given.or_else(|| Some(Value::Float(params.filter.radius.into()))),
+            "sampler" => Some(Value::String(params.sampler.name().to_string())),
+            "denoise" => Some(Value::Bool(params.denoise)),
+            "aovs" => params
+                .aovs
+                .map(|format| Value::String(format.name().to_string())),
+            "float-output" => Some(Value::Bool(params.float_output)),
+            "output" => Some(Value::String(output.to_string())),
+            "tone-map" => Some(Value::String(tone_map.mapping.name().to_string())),
+            "exposure" => Some(Value::Float(tone_map.exposure.into())),
+            "projection" => Some(Value::String(projection.name().to_string())),
+            "simd" => Some(Value::String(
+                params.target_feature.name().to_ascii_lowercase(),
+            )),
+            "tracing" => Some(Value::String(params.tracing.name().to_string())),
+            _ => given,
+        };
+        if let Some(value) = value {
+            toml.push_str(&format!("{} = {}\n", key, value));
+        }
+    }
+    toml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pathtrace_rs::filter::Filter;
+
+    const FILE: &str = r#"
+# every render
+preset = "smallpt"
+samples = 8
+
+[final]
+samples = 256 # overrides the default
+denoise = true
+output = "renders/{preset}.png"
+"#;
+
+    fn settings<'a>(matches: &'a ArgMatches<'a>, profile: Option<&str>) -> Settings<'a> {
+        let config = Config::parse(FILE, "test.toml").unwrap();
+        Settings::new(matches, config.values(profile).unwrap(), profile)
+    }
+
+    #[test]
+    fn profile_overrides_defaults() {
+        let matches = crate::app().get_matches_from(vec!["pathtrace"]);
+        let defaults = settings(&matches, None);
+        assert_eq!(defaults.value::<u32>("samples").unwrap(), Some(8));
+        assert!(!defaults.is_present("denoise"));
+
+        let profile = settings(&matches, Some("final"));
+        assert_eq!(profile.value::<u32>("samples").unwrap(), Some(256));
+        assert_eq!(profile.value_of("preset"), Some("smallpt".to_string()));
+        assert!(profile.is_present("denoise"));
+
+        let config = Config::parse(FILE, "test.toml").unwrap();
+        assert!(config.values(Some("draft")).is_err());
+    }
+
+    #[test]
+    fn command_line_overrides_file() {
+        let matches = crate::app().get_matches_from(vec!["pathtrace", "-S", "2"]);
+        let settings = settings(&matches, Some("final"));
+        assert_eq!(settings.value::<u32>("samples").unwrap(), Some(2));
+        assert_eq!(
+            settings.value_of("output"),
+            Some("renders/{preset}.png".to_string())
+        );
+    }
+
+    #[test]
+    fn no_flag_overrides_file() {
+        let matches = crate::app().get_matches_from(vec!["pathtrace", "--no-denoise"]);
+        assert!(!settings(&matches, Some("final")).is_present("denoise"));
+    }
+
+    #[test]
+    fn bad_values_name_their_origin() {
+        let matches = crate::app().get_matches_from(vec!["pathtrace", "--width", "wide"]);
+        let err = settings(&matches, None).value::<u32>("width").unwrap_err();
+        assert!(err.to_string().contains("--width"), "{}", err);
+
+        let matches = crate::app().get_matches_from(vec!["pathtrace"]);
+        let values = vec![("simd".to_string(), Value::String("avx3".to_string()))];
+        let settings = Settings::new(&matches, values, Some("fast"));
+        let err = settings
+            .choice("simd", &TargetFeature::NAMES, TargetFeature::from_name)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("'simd' in profile 'fast'"),
+            "{}",
+            err
+        );
+    }
+
+    /// The result of checking `values` from profile 'fast' with the command line `args`.
+    fn check(values: &[(&str, Value)], args: &[&str]) -> Result<()> {
+        let mut args = args.to_vec();
+        args.insert(0, "pathtrace");
+        let matches = crate::app().get_matches_from(args);
+        let values = values
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect();
+        Settings::new(&matches, values, Some("fast")).check()
+    }
+
+    #[test]
+    fn file_values_follow_command_line_rules() {
+        let threshold = [("noise-threshold", Value::Float(0.01))];
+        assert!(check(&threshold, &["--offline"]).is_ok());
+        for args in [
+            &["--offline", "--coordinator", "0.0.0.0:7878"][..],
+            &["--worker", "localhost:7878"],
+            &["--offline", "--stereo", "top-bottom"],
+        ]
+        .iter()
+        {
+            let err = check(&threshold, args).unwrap_err().to_string();
+            assert!(
+                err.contains("'noise-threshold' in profile 'fast' can't be used with --"),
+                "{}",
+                err
+            );
+        }
+
+        let random = [("random", Value::Bool(true))];
+        let err = check(&random, &["--seed", "3"]).unwrap_err().to_string();
+        assert!(err.contains("can't be used with --seed"), "{}", err);
+        assert!(check(&random, &["--no-random", "--seed", "3"]).is_ok());
+        let both = [("random", Value::Bool(true)), ("seed", Value::Integer(3))];
+        assert!(check(&both, &[]).is_err());
+
+        for key in &["snapshot-frames", "snapshot-interval"] {
+            let snapshots = [(*key, Value::Integer(4))];
+            let err = check(&snapshots, &["--offline"]).unwrap_err().to_string();
+            assert!(err.ends_with("needs --progressive"), "{}", err);
+            assert!(check(&snapshots, &["--offline", "--progressive"]).is_ok());
+        }
+
+        let float_output = [("float-output", Value::Bool(true))];
+        let err = check(&float_output, &[]).unwrap_err().to_string();
+        assert!(
+            err.ends_with("'float-output' in profile 'fast' needs --offline"),
+            "{}",
+            err
+        );
+        assert!(check(&float_output, &["--no-float-output"]).is_ok());
+        assert!(check(&float_output, &["--offline"]).is_ok());
+    }
+
+    #[test]
+    fn time_limit_needs_a_render_that_stops_at_it() {
+        let limit = [("time-limit", Value::Integer(30))];
+        assert!(check(&limit, &[]).is_ok());
+        assert!(check(&limit, &["--offline", "--progressive"]).is_ok());
+        assert!(check(&limit, &["--offline", "--noise-threshold", "0.01"]).is_ok());
+        let err = check(&limit, &["--offline"]).unwrap_err().to_string();
+        assert!(
+            err.contains("'time-limit' in profile 'fast' needs"),
+            "{}",
+            err
+        );
+        assert!(check(&limit, &["--worker", "localhost:7878"]).is_err());
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        let cases = [
+            ("samples 4", "test.toml:1: expected 'option = value'"),
+            (
+                "samples = \"lots\"",
+                "'samples' must be a non-negative integer",
+            ),
+            ("width = -1", "'width' must be a non-negative integer"),
+            ("simd = \"avx3\"", "'simd' must be one of"),
+            (
+                "\n[final\nsamples = 4",
+                "test.toml:2: can't read table header",
+            ),
+            ("colour = 1", "unknown option 'colour'"),
+            ("output = \"open", "unterminated string"),
+            ("width = 1\nwidth = 2", "test.toml:2: 'width' is set twice"),
+        ];
+        for (text, message) in cases.iter() {
+            let err = Config::parse(text, "test.toml").unwrap_err().to_string();
+            assert!(err.contains(message), "{:?} gave {}", text, err);
+        }
+    }
+
+    #[test]
+    fn printed_config_reads_back() {
+        let matches = crate::app().get_matches_from(vec![
+            "pathtrace",
+            "--seed",
+            "18446744073709551615",
+            "--fov",
+            "35",
+        ]);
+        let settings = settings(&matches, Some("final"));
+        let mut params = Params::new(1920, 1080);
+        params.samples = 256;
+        params.seed = u64::MAX;
+        params.denoise = true;
+        params.filter = Filter::new(FilterKind::Mitchell);
+        let tone_map = ToneMap {
+            mapping: ToneMapping::Aces,
+            exposure: 0.5,
+        };
+        let output = "renders/{preset}.png";
+        let printed = effective(
+            &settings,
+            &params,
+            "smallpt",
+            output,
+            tone_map,
+            Projection::Fisheye,
+        );
+
+        let values = Config::parse(&printed, "printed")
+            .unwrap()
+            .values(None)
+            .unwrap();
+        let no_args = crate::app().get_matches_from(vec!["pathtrace"]);
+        let read_back = Settings::new(&no_args, values, None);
+        assert_eq!(read_back.value::<u64>("seed").unwrap(), Some(u64::MAX));
+        assert_eq!(read_back.value::<u32>("samples").unwrap(), Some(256));
+        assert_eq!(read_back.value::<f32>("fov").unwrap(), Some(35.0));
+        assert_eq!(read_back.value::<f32>("exposure").unwrap(), Some(0.5));
+        assert_eq!(read_back.value_of("filter"), Some("mitchell".to_string()));
+        assert_eq!(read_back.value_of("tone-map"), Some("aces".to_string()));
+        assert_eq!(read_back.value_of("output"), Some(output.to_string()));
+        assert!(read_back.is_present("denoise"));
+        assert_eq!(
+            effective(
+                &read_back,
+                &params,
+                "smallpt",
+                output,
+                tone_map,
+                Projection::Fisheye
+            ),
+            printed
+        );
+    }
+}
@@ -0,0 +1,228 @@
+use crate::film::Film;
+use glam::{vec3, Vec3};
+use rayon::prelude::*;
+
+/// Number of filter passes, the spacing between taps doubles each pass.
+const ITERATIONS: u32 = 5;
+/// Samples needed before a pixel's own variance estimate is trusted over its neighbours'.
+const MIN_TEMPORAL_SAMPLES: u32 = 4;
+/// B3 spline weights used for both axes of the 5x5 kernel.
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+// edge stopping sensitivities, smaller values preserve more detail
+const SIGMA_LUMINANCE: f32 = 4.0;
+const SIGMA_NORMAL: f32 = 0.1;
+const SIGMA_DEPTH: f32 = 0.1;
+const SIGMA_ALBEDO: f32 = 0.05;
+
+struct Guide {
+    albedo: Vec3,
+    normal: Vec3,
+    depth: f32,
+}
+
+fn luminance(color: Vec3) -> f32 {
+    color.dot(vec3(0.2126, 0.7152, 0.0722))
+}
+
+/// Estimates the variance of each pixel's mean luminance. Pixels with too few samples to
+/// measure their own variance fall back to the spread of luminance in their 3x3 neighbourhood.
+fn initial_variance(width: usize, height: usize, colors: &[Vec3], film: &Film) -> Vec<f32> {
+    (0..width * height)
+        .into_par_iter()
+        .map(|index| {
+            let stats = &film.stats[index];
+            if stats.samples >= MIN_TEMPORAL_SAMPLES {
+                if let Some(variance) = stats.mean_variance() {
+                    return variance;
+                }
+            }
+            let (x, y) = (index % width, index / width);
+            let mut sum = 0.0;
+            let mut sum_sq = 0.0;
+            let mut count = 0.0;
+            for qy in y.saturating_sub(1)..(y + 2).min(height) {
+                for qx in x.saturating_sub(1)..(x + 2).min(width) {
+                    let lum = luminance(colors[qy * width + qx]);
+                    sum += lum;
+                    sum_sq += lum * lum;
+                    count += 1.0;
+                }
+            }
+            let mean = sum / count;
+            (sum_sq / count - mean * mean).max(0.0) / stats.samples.max(1) as f32
+        })
+        .collect()
+}
+
+/// One edge avoiding à-trous pass at pixel `(x, y)`, returning the filtered colour and variance.
+fn filter_pixel(
+    width: usize,
+    height: usize,
+    texels: &[(Vec3, f32)],
+    guides: &[Guide],
+    x: usize,
+    y: usize,
+    step: isize,
+) -> (Vec3, f32) {
+    let index_p = y * width + x;
+    let (color_p, variance_p) = texels[index_p];
+    let guide_p = &guides[index_p];
+    let lum_p = luminance(color_p);
+    let lum_scale = SIGMA_LUMINANCE * variance_p.sqrt() + 1.0e-4;
+
+    let mut color_sum = Vec3::zero();
+    let mut variance_sum = 0.0;
+    let mut weight_sum = 0.0;
+    for (ky, kernel_y) in KERNEL.iter().enumerate() {
+        let qy = y as isize + (ky as isize - 2) * step;
+        if qy < 0 || qy >= height as isize {
+            continue;
+        }
+        for (kx, kernel_x) in KERNEL.iter().enumerate() {
+            let qx = x as isize + (kx as isize - 2) * step;
+            if qx < 0 || qx >= width as isize {
+                continue;
+            }
+            let index_q = qy as usize * width + qx as usize;
+            let (color_q, variance_q) = texels[index_q];
+            let guide_q = &guides[index_q];
+
+            // combine the edge stopping functions into a single exponential
+            let depth_scale = SIGMA_DEPTH * step as f32 * guide_p.depth.max(guide_q.depth) + 1.0e-4;
+            let exponent = (luminance(color_q) - lum_p).abs() / lum_scale
+                + (guide_p.normal - guide_q.normal).length_squared() / SIGMA_NORMAL
+                + (guide_p.depth - guide_q.depth).abs() / depth_scale
+                + (guide_p.albedo - guide_q.albedo).length_squared() / SIGMA_ALBEDO;
+            let weight = kernel_x * kernel_y * (-exponent).exp();
+
+            color_sum += color_q * weight;
+            variance_sum += variance_q * weight * weight;
+            weight_sum += weight;
+        }
+    }
+    // the centre tap always has a weight of at least 9/64
+    (
+        color_sum / weight_sum,
+        variance_sum / (weight_sum * weight_sum),
+    )
+}
+
+/// Denoises the film's colour with an edge avoiding à-trous wavelet filter guided by the
+/// albedo, normal and depth feature buffers and each pixel's luminance variance.
+pub fn denoise(width: u32, height: u32, film: &Film) -> Vec<(f32, f32, f32)> {
+    let width = width as usize;
+    let height = height as usize;
+    let guides: Vec<Guide> = film
+        .features
+        .iter()
+        .map(|features| Guide {
+            albedo: vec3(features.albedo.0, features.albedo.1, features.albedo.2),
+            normal: vec3(features.normal.0, features.normal.1, features.normal.2),
+            depth: features.depth,
+        })
+        .collect();
+    let colors: Vec<Vec3> = film
+        .color
+        .iter()
+        .map(|color| vec3(color.0, color.1, color.2))
+        .collect();
+    let variances = initial_variance(width, height, &colors, film);
+    let mut texels: Vec<(Vec3, f32)> = colors.into_iter().zip(variances).collect();
+
+    for iteration in 0..ITERATIONS {
+        let step = 1 << iteration;
+        let mut filtered = vec![(Vec3::zero(), 0.0); width * height];
+        filtered
+            .par_chunks_mut(width)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for (x, texel) in row.iter_mut().enumerate() {
+                    *texel = filter_pixel(width, height, &texels, &guides, x, y, step);
+                }
+            });
+        texels = filtered;
+    }
+
+    texels
+        .iter()
+        .map(|(color, _)| (color.get_x(), color.get_y(), color.get_z()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::film::PixelFeatures;
+
+    const WIDTH: usize = 8;
+    const HEIGHT: usize = 8;
+
+    /// A film whose pixels have the given colours, each estimated from 16 grey samples spread
+    /// evenly around it.
+    fn film(color: impl Fn(usize, usize) -> f32) -> Film {
+        let mut film = Film::new(WIDTH as u32, HEIGHT as u32, false);
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let index = y * WIDTH + x;
+                let value = color(x, y);
+                film.color[index] = (value, value, value);
+                for sample in 0..16 {
+                    let offset = if sample % 2 == 0 { -0.1 } else { 0.1 };
+                    film.stats[index].add(value + offset);
+                }
+            }
+        }
+        film
+    }
+
+    #[test]
+    fn constant_image_is_unchanged() {
+        let denoised = denoise(WIDTH as u32, HEIGHT as u32, &film(|_, _| 0.3));
+        for color in denoised {
+            assert!((color.0 - 0.3).abs() < 1e-5, "{:?}", color);
+            assert_eq!(color.0, color.1);
+            assert_eq!(color.1, color.2);
+        }
+    }
+
+    #[test]
+    fn noise_is_smoothed_without_changing_the_mean() {
+        let noisy = film(|x, y| if (x + y) % 2 == 0 { 0.4 } else { 0.6 });
+        let denoised = denoise(WIDTH as u32, HEIGHT as u32, &noisy);
+        let values: Vec<f32> = denoised.iter().map(|color| color.0).collect();
+        let min = values.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        assert!(max - min < 0.8 * 0.2, "{} to {}", min, max);
+        assert!((mean - 0.5).abs() < 1e-3, "{}", mean);
+    }
+
+    #[test]
+    fn feature_edges_are_preserved() {
+        let mut film = film(|x, _| if x < 4 { 1.0 } else { 0.0 });
+        for (index, features) in film.features.iter_mut().enumerate() {
+            let left = index % WIDTH < 4;
+            *features = PixelFeatures {
+                albedo: if left {
+                    (1.0, 1.0, 1.0)
+                } else {
+                    (0.1, 0.1, 0.1)
+                },
+                normal: if left {
+                    (1.0, 0.0, 0.0)
+                } else {
+                    (0.0, 0.0, 1.0)
+                },
+                depth: 2.0,
+            };
+        }
+        let denoised = denoise(WIDTH as u32, HEIGHT as u32, &film);
+        for (index, color) in denoised.iter().enumerate() {
+            if index % WIDTH < 4 {
+                assert!(color.0 > 0.99, "{} {:?}", index, color);
+            } else {
+                assert!(color.0 < 0.01, "{} {:?}", index, color);
+            }
+        }
+    }
+}
@@ -0,0 +1,510 @@
+use crate::stats::elapsed_secs;
+use pathtrace_rs::{
+    camera::Camera,
+    film::Film,
+    scene::{Params, RayCounts, Scene},
+};
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+const HELLO: &[u8; 4] = b"PTDW";
+const ACCEPTED: u32 = 1;
+const REJECTED: u32 = 0;
+// sent instead of a pass index when there's no work left
+const DONE: u32 = u32::MAX;
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// how long the coordinator waits with no workers connected before giving up
+const WORKER_TIMEOUT: Duration = Duration::from_secs(120);
+// how long either end of a new connection waits for the other's handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the coordinator waits on workers.
+#[derive(Copy, Clone, Debug)]
+pub struct Timeouts {
+    /// Give up when no workers have been connected for this long.
+    pub idle: Duration,
+    /// A worker taking longer than this to render and send a pass is dropped and its pass
+    /// handed to another worker.
+    pub pass: Duration,
+    /// A connection that doesn't complete the handshake within this long is dropped.
+    pub handshake: Duration,
+}
+
+/// Hashes a description of everything that affects the image, workers must match the
+/// coordinator's hash or they would render a different scene.
+pub fn job_hash(description: &str) -> u64 {
+    // FNV-1a, stable across builds and platforms unlike the std hasher
+    description
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+fn write_u32(writer: &mut impl Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_u64(writer: &mut impl Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+struct Job {
+    pending: Vec<u32>,
+    completed: u32,
+    // connections currently being served
+    workers: u32,
+    film: Film,
+    ray_counts: RayCounts,
+}
+
+enum NextPass {
+    Pass(u32),
+    Done,
+}
+
+fn next_pass(job: &Mutex<Job>, passes: u32) -> NextPass {
+    loop {
+        {
+            let mut job = job.lock().unwrap();
+            if let Some(pass) = job.pending.pop() {
+                return NextPass::Pass(pass);
+            }
+            if job.completed == passes {
+                return NextPass::Done;
+            }
+        }
+        // other workers still have passes in flight which may need handing out again
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn serve_worker(
+    stream: TcpStream,
+    params: &Params,
+    passes: u32,
+    hash: u64,
+    timeouts: Timeouts,
+    job: &Mutex<Job>,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    // don't hold up the end of the render for a client that never says hello
+    stream.set_read_timeout(Some(timeouts.handshake))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    let mut hello = [0; 4];
+    reader.read_exact(&mut hello)?;
+    if &hello != HELLO || read_u64(&mut reader)? != hash {
+        println!("rejected worker {} with different render settings", peer);
+        write_u32(&mut writer, REJECTED)?;
+        return writer.flush();
+    }
+    write_u32(&mut writer, ACCEPTED)?;
+    // the worker may wait about this long for passes held by a stalled worker
+    write_u64(&mut writer, timeouts.pass.as_millis() as u64)?;
+    // a stalled worker fails its reads, freeing its pass
+    writer.get_ref().set_read_timeout(Some(timeouts.pass))?;
+    println!("worker {} connected", peer);
+
+    loop {
+        let pass = match next_pass(job, passes) {
+            NextPass::Pass(pass) => pass,
+            NextPass::Done => {
+                write_u32(&mut writer, DONE)?;
+                return writer.flush();
+            }
+        };
+        let result = write_u32(&mut writer, pass)
+            .and_then(|_| writer.flush())
+            .and_then(|_| {
+                let ray_counts = RayCounts {
+                    camera: read_u64(&mut reader)? as usize,
+                    bounce: read_u64(&mut reader)? as usize,
+                    shadow: read_u64(&mut reader)? as usize,
+                };
+                let film = Film::read(&mut reader, params.width, params.height)?;
+                Ok((ray_counts, film))
+            });
+        match result {
+            Ok((ray_counts, film)) => {
+                let mut job = job.lock().unwrap();
+                job.film.merge(&film);
+                job.ray_counts += ray_counts;
+                job.completed += 1;
+                println!(
+                    "pass {} from {} ({} of {})",
+                    pass, peer, job.completed, passes
+                );
+            }
+            Err(err) => {
+                // give the pass to another worker
+                job.lock().unwrap().pending.push(pass);
+                return Err(err);
+            }
+        }
+    }
+}
+
+/// Listens for workers on `address` and hands out `passes` sample passes of `params.samples`
+/// each, merging the results into a single film. A pass that isn't returned within
+/// `pass_timeout` is handed to another worker. Fails if no workers are connected for two
+/// minutes.
+pub fn run_coordinator(
+    address: &str,
+    params: &Params,
+    passes: u32,
+    hash: u64,
+    pass_timeout: Duration,
+) -> io::Result<(Film, RayCounts)> {
+    let listener = TcpListener::bind(address)?;
+    let timeouts = Timeouts {
+        idle: WORKER_TIMEOUT,
+        pass: pass_timeout,
+        handshake: HANDSHAKE_TIMEOUT,
+    };
+    coordinate(listener, params, passes, hash, timeouts)
+}
+
+fn coordinate(
+    listener: TcpListener,
+    params: &Params,
+    passes: u32,
+    hash: u64,
+    timeouts: Timeouts,
+) -> io::Result<(Film, RayCounts)> {
+    if passes == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "passes must be at least 1",
+        ));
+    }
+    listener.set_nonblocking(true)?;
+    println!(
+        "waiting for workers on {} to render {} passes",
+        listener.local_addr()?,
+        passes
+    );
+
+    let job = Arc::new(Mutex::new(Job {
+        // passes are popped off the end, so hand out the first ones first
+        pending: (0..passes).rev().collect(),
+        completed: 0,
+        workers: 0,
+        film: Film::new(params.width, params.height, params.aovs.is_some()),
+        ray_counts: RayCounts::default(),
+    }));
+
+    let mut handlers = Vec::new();
+    let mut idle_since = SystemTime::now();
+    loop {
+        let (completed, workers) = {
+            let job = job.lock().unwrap();
+            (job.completed, job.workers)
+        };
+        if completed == passes {
+            break;
+        }
+        if workers > 0 {
+            idle_since = SystemTime::now();
+        } else if idle_since
+            .elapsed()
+            .map_or(false, |idle| idle >= timeouts.idle)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "no workers connected for {} seconds with {} of {} passes done",
+                    timeouts.idle.as_secs(),
+                    completed,
+                    passes
+                ),
+            ));
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                // a stalled worker fails its writes, freeing its pass
+                stream.set_write_timeout(Some(timeouts.pass))?;
+                let params = *params;
+                let job = job.clone();
+                job.lock().unwrap().workers += 1;
+                handlers.push(thread::spawn(move || {
+                    if let Err(err) = serve_worker(stream, &params, passes, hash, timeouts, &job) {
+                        println!("lost worker: {}", err);
+                    }
+                    job.lock().unwrap().workers -= 1;
+                }));
+            }
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(POLL_INTERVAL);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    // idle workers are told there's nothing left to do
+    for handler in handlers {
+        handler.join().expect("worker connection thread panicked");
+    }
+
+    let mut job = job.lock().unwrap();
+    let mut film = Film::new(params.width, params.height, params.aovs.is_some());
+    std::mem::swap(&mut film, &mut job.film);
+    film.frames = passes;
+    Ok((film, job.ray_counts))
+}
+
+/// Connects to a coordinator and renders the passes it hands out until there are none left.
+///
+/// Pass `n` is rendered exactly as the `n`th frame of a progressive render would be, so the
+/// merged image doesn't depend on which worker rendered what.
+pub fn run_worker(
+    address: &str,
+    params: &Params,
+    camera: &dyn Camera,
+    scene: &Scene,
+    hash: u64,
+) -> io::Result<()> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    writer.write_all(HELLO)?;
+    write_u64(&mut writer, hash)?;
+    writer.flush()?;
+    if read_u32(&mut reader)? != ACCEPTED {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "coordinator rejected this worker's render settings",
+        ));
+    }
+    let pass_timeout = Duration::from_millis(read_u64(&mut reader)?);
+    // while waiting for its next pass the coordinator may be waiting out a stalled worker
+    let wait_timeout = pass_timeout.checked_mul(2).unwrap_or(pass_timeout);
+    writer.get_ref().set_read_timeout(Some(wait_timeout))?;
+    writer.get_ref().set_write_timeout(Some(pass_timeout))?;
+    println!("connected to coordinator {}", address);
+
+    loop {
+        let pass = read_u32(&mut reader)?;
+        if pass == DONE {
+            println!("no passes left");
+            return Ok(());
+        }
+        let start_time = SystemTime::now();
+        let mut film = Film::new(params.width, params.height, params.aovs.is_some());
+        film.frames = pass;
+        film.sample_offset = pass * params.samples;
+        let ray_counts = scene.update(params, camera, &mut film);
+        println!(
+            "rendered pass {} in {:.2}secs",
+            pass,
+            elapsed_secs(start_time)
+        );
+
+        write_u64(&mut writer, ray_counts.camera as u64)?;
+        write_u64(&mut writer, ray_counts.bounce as u64)?;
+        write_u64(&mut writer, ray_counts.shadow as u64)?;
+        film.write(&mut writer)?;
+        writer.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pathtrace_rs::{camera::Projection, presets};
+
+    fn params() -> Params {
+        let mut params = Params::new(40, 24);
+        params.samples = 2;
+        params.max_depth = 4;
+        params
+    }
+
+    fn timeouts(idle: Duration) -> Timeouts {
+        Timeouts {
+            idle,
+            pass: Duration::from_secs(30),
+            handshake: Duration::from_secs(30),
+        }
+    }
+
+    fn render_worker(address: String, params: Params) -> io::Result<()> {
+        let (scene, camera_desc) = presets::from_name("aras", &params).unwrap();
+        let camera = camera_desc.build(Projection::Perspective);
+        run_worker(&address, &params, camera.as_ref(), &scene, 1)
+    }
+
+    #[test]
+    fn workers_match_progressive_render() {
+        let params = params();
+        let passes = 5;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let workers: Vec<_> = (0..3)
+            .map(|_| {
+                let address = address.clone();
+                thread::spawn(move || render_worker(address, params))
+            })
+            .collect();
+        let (merged, ray_counts) = coordinate(
+            listener,
+            &params,
+            passes,
+            1,
+            timeouts(Duration::from_secs(30)),
+        )
+        .unwrap();
+        for worker in workers {
+            worker.join().unwrap().unwrap();
+        }
+
+        let (scene, camera_desc) = presets::from_name("aras", &params).unwrap();
+        let camera = camera_desc.build(Projection::Perspective);
+        let mut film = Film::new(params.width, params.height, false);
+        let mut expected_rays = RayCounts::default();
+        for _ in 0..passes {
+            expected_rays += scene.update(&params, camera.as_ref(), &mut film);
+        }
+
+        assert_eq!(merged.frames, film.frames);
+        assert_eq!(ray_counts.total(), expected_rays.total());
+        for index in 0..film.color.len() {
+            assert_eq!(merged.stats[index].samples, film.stats[index].samples);
+            let (a, b) = (merged.color[index], film.color[index]);
+            for (x, y) in [(a.0, b.0), (a.1, b.1), (a.2, b.2)].iter() {
+                // merging blends the same frames in a different order
+                assert!((x - y).abs() <= 1e-4 * y.abs().max(1.0), "{} != {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn stalled_worker_pass_is_handed_out_again() {
+        let params = params();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let stalled = thread::spawn(move || {
+            // takes a pass and never sends it back, then lets a real worker connect
+            let stream = TcpStream::connect(&address).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = BufWriter::new(stream.try_clone().unwrap());
+            writer.write_all(HELLO).unwrap();
+            write_u64(&mut writer, 1).unwrap();
+            writer.flush().unwrap();
+            assert_eq!(read_u32(&mut reader).unwrap(), ACCEPTED);
+            read_u64(&mut reader).unwrap();
+            let pass = read_u32(&mut reader).unwrap();
+            let worker = thread::spawn(move || render_worker(address, params));
+            (stream, pass, worker)
+        });
+        let timeouts = Timeouts {
+            idle: Duration::from_secs(30),
+            pass: Duration::from_secs(2),
+            handshake: Duration::from_secs(30),
+        };
+        let result = coordinate(listener, &params, 3, 1, timeouts);
+        let (_stream, pass, worker) = stalled.join().unwrap();
+        worker.join().unwrap().unwrap();
+        let (merged, _) = result.unwrap();
+        assert_eq!(pass, 0);
+
+        let (scene, camera_desc) = presets::from_name("aras", &params).unwrap();
+        let camera = camera_desc.build(Projection::Perspective);
+        let mut film = Film::new(params.width, params.height, false);
+        for _ in 0..3 {
+            scene.update(&params, camera.as_ref(), &mut film);
+        }
+        for index in 0..film.stats.len() {
+            assert_eq!(merged.stats[index].samples, film.stats[index].samples);
+        }
+    }
+
+    #[test]
+    fn silent_clients_dont_hold_up_the_render() {
+        let params = params();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        // connects first and never sends its hello, staying open until the render is done
+        let silent = TcpStream::connect(&address).unwrap();
+        let worker = thread::spawn(move || render_worker(address, params));
+        let timeouts = Timeouts {
+            idle: Duration::from_secs(30),
+            pass: Duration::from_secs(60),
+            handshake: Duration::from_millis(500),
+        };
+        let start = SystemTime::now();
+        let result = coordinate(listener, &params, 2, 1, timeouts);
+        let elapsed = start.elapsed().unwrap();
+        worker.join().unwrap().unwrap();
+        drop(silent);
+        assert_eq!(result.unwrap().0.frames, 2);
+        assert!(elapsed < Duration::from_secs(30), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn rejects_different_settings() {
+        let params = params();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let worker = thread::spawn(move || render_worker(address, params));
+        let result = coordinate(
+            listener,
+            &params,
+            1,
+            2,
+            timeouts(Duration::from_millis(500)),
+        );
+        assert!(worker.join().unwrap().is_err());
+        assert_eq!(
+            result.err().map(|err| err.kind()),
+            Some(io::ErrorKind::TimedOut)
+        );
+    }
+
+    #[test]
+    fn zero_passes_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let result = coordinate(listener, &params(), 0, 1, timeouts(Duration::from_secs(1)));
+        assert_eq!(
+            result.err().map(|err| err.kind()),
+            Some(io::ErrorKind::InvalidInput)
+        );
+    }
+
+    #[test]
+    fn times_out_without_workers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let result = coordinate(
+            listener,
+            &params(),
+            1,
+            1,
+            timeouts(Duration::from_millis(200)),
+        );
+        assert_eq!(
+            result.err().map(|err| err.kind()),
+            Some(io::ErrorKind::TimedOut)
+        );
+    }
+}
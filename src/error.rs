@@ -0,0 +1,55 @@
+use std::{error, fmt, io};
+
+/// Everything that can go wrong setting up or running a render.
+#[derive(Debug)]
+pub enum Error {
+    /// A render setting is out of range, the message says which.
+    InvalidParams(String),
+    /// No scene preset has this name.
+    UnknownPreset(String),
+    /// Reading or writing a file or connection failed while doing what `context` describes.
+    Io { context: String, source: io::Error },
+    /// An image couldn't be decoded or encoded.
+    Image(String),
+    /// The preview window's OpenGL context or resources couldn't be created.
+    GlContext(String),
+    /// Ctrl-C stopped the render before what `stage` describes could be finished.
+    Interrupted { stage: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Wraps IO errors with a description of what was being done, for use with `map_err`.
+    pub fn io<S: Into<String>>(context: S) -> impl FnOnce(io::Error) -> Error {
+        let context = context.into();
+        move |source| Error::Io { context, source }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidParams(message) => write!(f, "invalid render settings: {}", message),
+            Error::UnknownPreset(name) => write!(
+                f,
+                "unknown preset '{}', expected one of {}",
+                name,
+                crate::presets::NAMES.join(", ")
+            ),
+            Error::Io { context, source } => write!(f, "{}: {}", context, source),
+            Error::Image(message) => write!(f, "{}", message),
+            Error::GlContext(message) => write!(f, "OpenGL error: {}", message),
+            Error::Interrupted { stage } => write!(f, "interrupted before {}", stage),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,413 @@
+use pathtrace_rs::scene::{MAX_DIMENSION, MAX_PIXELS};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+const MAGIC: u32 = 20_000_630;
+const VERSION: u32 = 2;
+const PIXEL_TYPE_FLOAT: u32 = 2;
+const NO_COMPRESSION: u8 = 0;
+const INCREASING_Y: u8 = 0;
+// version field flag marking a tiled rather than scanline image
+const TILED_FLAG: u32 = 0x200;
+// longest attribute or channel name allowed by the long names flag
+const MAX_NAME_LEN: usize = 255;
+
+/// A named channel of 32 bit float samples stored top row first. Multi-layer files use
+/// `layer.channel` names such as `albedo.R`.
+pub struct Channel<'a> {
+    pub name: String,
+    pub samples: &'a [f32],
+}
+
+fn write_attribute(
+    writer: &mut impl Write,
+    name: &str,
+    kind: &str,
+    value: &[u8],
+) -> io::Result<()> {
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(&[0])?;
+    writer.write_all(kind.as_bytes())?;
+    writer.write_all(&[0])?;
+    writer.write_all(&(value.len() as u32).to_le_bytes())?;
+    writer.write_all(value)
+}
+
+fn box2i(width: u32, height: u32) -> Vec<u8> {
+    let mut value = Vec::with_capacity(16);
+    for coord in &[0, 0, width as i32 - 1, height as i32 - 1] {
+        value.extend_from_slice(&coord.to_le_bytes());
+    }
+    value
+}
+
+/// An image read back with `read_exr`, channels are stored top row first.
+pub struct ExrImage {
+    pub width: u32,
+    pub height: u32,
+    pub channels: Vec<(String, Vec<f32>)>,
+    pub int_attributes: Vec<(String, i32)>,
+}
+
+impl ExrImage {
+    pub fn channel(&self, name: &str) -> Option<&[f32]> {
+        self.channels
+            .iter()
+            .find(|(channel_name, _)| channel_name == name)
+            .map(|(_, samples)| samples.as_slice())
+    }
+
+    pub fn int_attribute(&self, name: &str) -> Option<i32> {
+        self.int_attributes
+            .iter()
+            .find(|(attribute_name, _)| attribute_name == name)
+            .map(|(_, value)| *value)
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_string(reader: &mut impl Read) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    loop {
+        let mut byte = [0];
+        reader.read_exact(&mut byte)?;
+        if byte[0] == 0 {
+            break;
+        }
+        if bytes.len() == MAX_NAME_LEN {
+            return Err(invalid_data("attribute name is too long"));
+        }
+        bytes.push(byte[0]);
+    }
+    String::from_utf8(bytes).map_err(|_| invalid_data("attribute name isn't UTF-8"))
+}
+
+fn le_i32(bytes: &[u8]) -> i32 {
+    i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Reads the little endian `i32` at `offset` in an attribute value, failing if it's too short.
+fn attribute_i32(value: &[u8], offset: usize) -> io::Result<i32> {
+    value
+        .get(offset..offset + 4)
+        .map(le_i32)
+        .ok_or_else(|| invalid_data("truncated attribute value"))
+}
+
+/// Reads an uncompressed scanline OpenEXR image with 32 bit float channels, as written by
+/// `write_exr`. Other compression schemes and pixel types aren't supported.
+pub fn read_exr(path: &Path) -> io::Result<ExrImage> {
+    let mut reader = BufReader::new(File::open(path)?);
+    if read_u32(&mut reader)? != MAGIC {
+        return Err(invalid_data("not an OpenEXR file"));
+    }
+    if read_u32(&mut reader)? & TILED_FLAG != 0 {
+        return Err(invalid_data("tiled OpenEXR files aren't supported"));
+    }
+
+    let mut channel_names = Vec::new();
+    let mut data_window = None;
+    let mut int_attributes = Vec::new();
+    loop {
+        let name = read_string(&mut reader)?;
+        if name.is_empty() {
+            break;
+        }
+        let kind = read_string(&mut reader)?;
+        // read through `take` so a corrupt length can't allocate more than the file holds
+        let len = read_u32(&mut reader)?;
+        let mut value = Vec::new();
+        (&mut reader).take(len.into()).read_to_end(&mut value)?;
+        if value.len() != len as usize {
+            return Err(invalid_data("truncated attribute value"));
+        }
+        match (name.as_str(), kind.as_str()) {
+            ("channels", "chlist") => {
+                let mut list = &value[..];
+                while !list.is_empty() && list[0] != 0 {
+                    let end = list
+                        .iter()
+                        .position(|&byte| byte == 0)
+                        .ok_or_else(|| invalid_data("unterminated channel name"))?;
+                    let channel_name = String::from_utf8(list[..end].to_vec())
+                        .map_err(|_| invalid_data("channel name isn't UTF-8"))?;
+                    // pixel type, linear, reserved and x/y sampling follow the name
+                    if attribute_i32(list, end + 1)? as u32 != PIXEL_TYPE_FLOAT {
+                        return Err(invalid_data("only 32 bit float channels are supported"));
+                    }
+                    channel_names.push(channel_name);
+                    list = list
+                        .get(end + 17..)
+                        .ok_or_else(|| invalid_data("truncated channel list"))?;
+                }
+            }
+            ("compression", _) => {
+                if value.first() != Some(&NO_COMPRESSION) {
+                    return Err(invalid_data("compressed OpenEXR files aren't supported"));
+                }
+            }
+            ("dataWindow", "box2i") => {
+                data_window = Some((
+                    attribute_i32(&value, 0)?,
+                    attribute_i32(&value, 4)?,
+                    attribute_i32(&value, 8)?,
+                    attribute_i32(&value, 12)?,
+                ));
+            }
+            (_, "int") => {
+                let value = attribute_i32(&value, 0)?;
+                int_attributes.push((name, value));
+            }
+            _ => (),
+        }
+    }
+    let (x_min, y_min, x_max, y_max) =
+        data_window.ok_or_else(|| invalid_data("missing data window"))?;
+    let width = i64::from(x_max) - i64::from(x_min) + 1;
+    let height = i64::from(y_max) - i64::from(y_min) + 1;
+    if width <= 0
+        || height <= 0
+        || width > i64::from(MAX_DIMENSION)
+        || height > i64::from(MAX_DIMENSION)
+        || (width * height) as u64 > MAX_PIXELS
+    {
+        return Err(invalid_data("invalid data window"));
+    }
+    let (width, height) = (width as u32, height as u32);
+
+    // skip the offset table, the blocks follow it in order
+    let mut offsets = vec![0; height as usize * 8];
+    reader.read_exact(&mut offsets)?;
+
+    let row_len = width as usize;
+    let mut channels: Vec<(String, Vec<f32>)> = channel_names
+        .into_iter()
+        .map(|name| (name, vec![0.0; row_len * height as usize]))
+        .collect();
+    let mut bytes = vec![0; row_len * 4];
+    for _ in 0..height {
+        let y = read_u32(&mut reader)? as i32 - y_min;
+        if y < 0 || y >= height as i32 {
+            return Err(invalid_data("scanline outside the data window"));
+        }
+        let _data_len = read_u32(&mut reader)?;
+        let row_start = y as usize * row_len;
+        for (_, samples) in channels.iter_mut() {
+            reader.read_exact(&mut bytes)?;
+            for (sample, value) in samples[row_start..row_start + row_len]
+                .iter_mut()
+                .zip(bytes.chunks(4))
+            {
+                *sample = f32::from_bits(le_i32(value) as u32);
+            }
+        }
+    }
+
+    Ok(ExrImage {
+        width,
+        height,
+        channels,
+        int_attributes,
+    })
+}
+
+/// Writes an uncompressed single part scanline OpenEXR image with extra integer attributes
+/// in the header.
+pub fn write_exr(
+    path: &Path,
+    width: u32,
+    height: u32,
+    channels: &mut [Channel],
+    int_attributes: &[(&str, i32)],
+) -> io::Result<()> {
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("images can't be more than {} pixels across", MAX_DIMENSION),
+        ));
+    }
+    // channels must be stored in alphabetical order
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    // build the header in memory as its size is needed for the scanline offsets
+    let mut header = Vec::new();
+    header.write_all(&MAGIC.to_le_bytes())?;
+    header.write_all(&VERSION.to_le_bytes())?;
+
+    let mut channel_list = Vec::new();
+    for channel in channels.iter() {
+        channel_list.extend_from_slice(channel.name.as_bytes());
+        channel_list.push(0);
+        channel_list.extend_from_slice(&PIXEL_TYPE_FLOAT.to_le_bytes());
+        // linear flag and three reserved bytes, then x and y sampling
+        channel_list.extend_from_slice(&[0, 0, 0, 0]);
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+        channel_list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    channel_list.push(0);
+    write_attribute(&mut header, "channels", "chlist", &channel_list)?;
+    write_attribute(&mut header, "compression", "compression", &[NO_COMPRESSION])?;
+    write_attribute(&mut header, "dataWindow", "box2i", &box2i(width, height))?;
+    write_attribute(&mut header, "displayWindow", "box2i", &box2i(width, height))?;
+    write_attribute(&mut header, "lineOrder", "lineOrder", &[INCREASING_Y])?;
+    write_attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1.0f32.to_le_bytes(),
+    )?;
+    write_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+    write_attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1.0f32.to_le_bytes(),
+    )?;
+    for (name, value) in int_attributes {
+        write_attribute(&mut header, name, "int", &value.to_le_bytes())?;
+    }
+    header.push(0);
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&header)?;
+
+    // offset table pointing at each scanline block, each of which is the y coordinate, the data
+    // size and then every channel's samples for the row in turn
+    let row_data_len = channels.len() * width as usize * 4;
+    let first_block = header.len() + height as usize * 8;
+    for y in 0..height as usize {
+        let offset = (first_block + y * (8 + row_data_len)) as u64;
+        writer.write_all(&offset.to_le_bytes())?;
+    }
+    for y in 0..height as usize {
+        writer.write_all(&(y as i32).to_le_bytes())?;
+        writer.write_all(&(row_data_len as u32).to_le_bytes())?;
+        for channel in channels.iter() {
+            let row = &channel.samples[y * width as usize..(y + 1) * width as usize];
+            for sample in row {
+                writer.write_all(&sample.to_le_bytes())?;
+            }
+        }
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        env::temp_dir().join(format!("pathtrace-{}-{}.exr", name, std::process::id()))
+    }
+
+    #[test]
+    fn write_then_read() {
+        let red = [0.0, 0.25, 0.5, 1.0, 2.0, -1.0];
+        let albedo_green = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6];
+        let normal_x = [-1.0, 0.0, 1.0, 0.5, -0.5, 0.0];
+        let object_id = [-1.0, 0.0, 1.0, 2.0, 3.0, 4.0];
+        let mut channels = vec![
+            Channel {
+                name: "R".to_string(),
+                samples: &red,
+            },
+            Channel {
+                name: "objectId".to_string(),
+                samples: &object_id,
+            },
+            Channel {
+                name: "albedo.G".to_string(),
+                samples: &albedo_green,
+            },
+            Channel {
+                name: "N.X".to_string(),
+                samples: &normal_x,
+            },
+        ];
+        let path = temp_path("round-trip");
+        write_exr(
+            &path,
+            3,
+            2,
+            &mut channels,
+            &[("samples", 12), ("frames", 3)],
+        )
+        .unwrap();
+        let image = read_exr(&path);
+        fs::remove_file(&path).unwrap();
+        let image = image.unwrap();
+
+        assert_eq!((image.width, image.height), (3, 2));
+        let names: Vec<&str> = image
+            .channels
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["N.X", "R", "albedo.G", "objectId"]);
+        assert_eq!(image.channel("R"), Some(&red[..]));
+        assert_eq!(image.channel("albedo.G"), Some(&albedo_green[..]));
+        assert_eq!(image.channel("N.X"), Some(&normal_x[..]));
+        assert_eq!(image.channel("objectId"), Some(&object_id[..]));
+        assert_eq!(image.int_attribute("samples"), Some(12));
+        assert_eq!(image.int_attribute("frames"), Some(3));
+    }
+
+    #[test]
+    fn widest_image_reads_back() {
+        let samples: Vec<f32> = (0..MAX_DIMENSION).map(|x| x as f32).collect();
+        let mut channels = vec![Channel {
+            name: "Y".to_string(),
+            samples: &samples,
+        }];
+        let path = temp_path("widest");
+        write_exr(&path, MAX_DIMENSION, 1, &mut channels, &[]).unwrap();
+        let image = read_exr(&path);
+        fs::remove_file(&path).unwrap();
+        let image = image.unwrap();
+        assert_eq!(image.width, MAX_DIMENSION);
+        assert_eq!(image.channel("Y"), Some(&samples[..]));
+    }
+
+    #[test]
+    fn huge_attribute_length_is_invalid_data() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC.to_le_bytes());
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(b"channels\0chlist\0");
+        bytes.extend_from_slice(&0xffff_fff0u32.to_le_bytes());
+        bytes.extend_from_slice(b"R\0");
+        let path = temp_path("truncated");
+        fs::write(&path, &bytes).unwrap();
+        let result = read_exr(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn truncated_int_attribute_is_invalid_data() {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&MAGIC.to_le_bytes());
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(b"samples\0int\0");
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        bytes.extend_from_slice(&[1, 0, 0]);
+        let path = temp_path("short-int");
+        fs::write(&path, &bytes).unwrap();
+        let result = read_exr(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,423 @@
+use std::{
+    f32,
+    io::{self, Read, Write},
+};
+
+/// Fraction of pixels allowed to remain above the noise threshold when converged, so a handful
+/// of fireflies don't keep the render going forever.
+const CONVERGED_FRACTION: f32 = 0.001;
+// stands in for a missing object or material id when serialized
+const NO_ID: u32 = u32::MAX;
+
+/// Running mean and variance of the luminance of the samples taken inside a pixel.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PixelStats {
+    pub samples: u32,
+    mean: f32,
+    m2: f32,
+}
+
+impl PixelStats {
+    pub fn add(&mut self, luminance: f32) {
+        // Welford's online algorithm
+        self.samples += 1;
+        let delta = luminance - self.mean;
+        self.mean += delta / self.samples as f32;
+        self.m2 += delta * (luminance - self.mean);
+    }
+
+    /// Standard error of the mean luminance relative to the mean. The mean is floored at 0.1 so
+    /// that dark pixels aren't held to an impossible standard.
+    pub fn relative_error(&self) -> f32 {
+        if self.samples < 2 {
+            return f32::INFINITY;
+        }
+        let n = self.samples as f32;
+        let variance = self.m2 / (n - 1.0);
+        (variance / n).sqrt() / self.mean.max(0.1)
+    }
+
+    /// Combines the statistics of two disjoint sets of samples.
+    pub fn merge(&mut self, other: &PixelStats) {
+        if other.samples == 0 {
+            return;
+        }
+        let n_a = self.samples as f32;
+        let n_b = other.samples as f32;
+        let n = n_a + n_b;
+        let delta = other.mean - self.mean;
+        self.mean += delta * n_b / n;
+        self.m2 += other.m2 + delta * delta * n_a * n_b / n;
+        self.samples += other.samples;
+    }
+
+    /// Variance of the mean luminance, or `None` if there aren't enough samples to estimate it.
+    pub fn mean_variance(&self) -> Option<f32> {
+        if self.samples < 2 {
+            return None;
+        }
+        let n = self.samples as f32;
+        Some(self.m2 / ((n - 1.0) * n))
+    }
+}
+
+/// Auxiliary surface properties at the first hit of each camera ray, used to guide the
+/// denoiser. Camera rays that escape to the sky have zero normal and depth.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PixelFeatures {
+    pub albedo: (f32, f32, f32),
+    pub normal: (f32, f32, f32),
+    pub depth: f32,
+}
+
+impl PixelFeatures {
+    /// Folds in the features of a new sample, `samples` being the pixel's count including it.
+    pub fn add(&mut self, samples: u32, sample: &PixelFeatures) {
+        let t = 1.0 / samples as f32;
+        self.albedo = lerp3(self.albedo, sample.albedo, t);
+        self.normal = lerp3(self.normal, sample.normal, t);
+        self.depth += (sample.depth - self.depth) * t;
+    }
+}
+
+/// How arbitrary output variables are written alongside the beauty image.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AovFormat {
+    /// One image per variable.
+    Png,
+    /// A single multi-layer OpenEXR image holding the beauty pass and every variable.
+    Exr,
+}
+
+impl AovFormat {
+    /// Names accepted by `from_name`.
+    pub const NAMES: [&'static str; 2] = ["png", "exr"];
+
+    pub fn from_name(name: &str) -> Option<AovFormat> {
+        match name {
+            "png" => Some(AovFormat::Png),
+            "exr" => Some(AovFormat::Exr),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AovFormat::Png => "png",
+            AovFormat::Exr => "exr",
+        }
+    }
+}
+
+/// Arbitrary output variables for compositing and debugging, only recorded when requested.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PixelAovs {
+    /// Object hit by the pixel's first camera ray, `None` for the sky.
+    pub object_id: Option<u32>,
+    /// Material of that object, objects with identical materials share an id.
+    pub material_id: Option<u32>,
+    /// Mean light emitted by or sampled directly at the first hit.
+    pub direct: (f32, f32, f32),
+    /// Mean light reaching the first hit after further bounces.
+    pub indirect: (f32, f32, f32),
+}
+
+impl PixelAovs {
+    pub fn add(&mut self, samples: u32, direct: (f32, f32, f32), indirect: (f32, f32, f32)) {
+        let t = 1.0 / samples as f32;
+        self.direct = lerp3(self.direct, direct, t);
+        self.indirect = lerp3(self.indirect, indirect, t);
+    }
+}
+
+fn lerp3(a: (f32, f32, f32), b: (f32, f32, f32), t: f32) -> (f32, f32, f32) {
+    (
+        a.0 + (b.0 - a.0) * t,
+        a.1 + (b.1 - a.1) * t,
+        a.2 + (b.2 - a.2) * t,
+    )
+}
+
+/// Accumulated render output and the per pixel state needed to keep refining it.
+pub struct Film {
+    /// Number of frames accumulated so far, which also seeds the next frame's samples.
+    pub frames: u32,
+    /// Added to each pixel's sample count to index its sample sequence, so that passes rendered
+    /// separately and merged later draw distinct samples.
+    pub sample_offset: u32,
+    /// Current estimate of each pixel's linear colour.
+    pub color: Vec<(f32, f32, f32)>,
+    /// Total reconstruction filter weight accumulated in each pixel.
+    pub weight: Vec<f32>,
+    pub stats: Vec<PixelStats>,
+    pub features: Vec<PixelFeatures>,
+    pub aovs: Option<Vec<PixelAovs>>,
+}
+
+impl Film {
+    pub fn new(width: u32, height: u32, aovs: bool) -> Film {
+        let len = width as usize * height as usize;
+        Film {
+            frames: 0,
+            sample_offset: 0,
+            color: vec![(0.0, 0.0, 0.0); len],
+            weight: vec![0.0; len],
+            stats: vec![PixelStats::default(); len],
+            features: vec![PixelFeatures::default(); len],
+            aovs: if aovs {
+                Some(vec![PixelAovs::default(); len])
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn noisy_fraction(&self, threshold: f32) -> f32 {
+        let noisy = self
+            .stats
+            .iter()
+            .filter(|stats| stats.relative_error() > threshold)
+            .count();
+        noisy as f32 / self.stats.len() as f32
+    }
+
+    pub fn is_converged(&self, threshold: f32) -> bool {
+        self.noisy_fraction(threshold) <= CONVERGED_FRACTION
+    }
+
+    /// Adds the samples accumulated in another film of the same size, weighting colour by filter
+    /// weight and everything else by sample count. Frame counts are left to the caller.
+    pub fn merge(&mut self, other: &Film) {
+        for index in 0..self.color.len() {
+            let weight_a = self.weight[index];
+            let weight_b = other.weight[index];
+            if weight_b > 0.0 {
+                let t = weight_b / (weight_a + weight_b);
+                self.color[index] = lerp3(self.color[index], other.color[index], t);
+                self.weight[index] = weight_a + weight_b;
+            }
+
+            let samples_a = self.stats[index].samples;
+            let samples_b = other.stats[index].samples;
+            if samples_b == 0 {
+                continue;
+            }
+            let t = samples_b as f32 / (samples_a + samples_b) as f32;
+            let features = &mut self.features[index];
+            let other_features = &other.features[index];
+            features.albedo = lerp3(features.albedo, other_features.albedo, t);
+            features.normal = lerp3(features.normal, other_features.normal, t);
+            features.depth += (other_features.depth - features.depth) * t;
+            if let (Some(aovs), Some(other_aovs)) = (self.aovs.as_mut(), other.aovs.as_ref()) {
+                let aov = &mut aovs[index];
+                let other_aov = &other_aovs[index];
+                if samples_a == 0 {
+                    aov.object_id = other_aov.object_id;
+                    aov.material_id = other_aov.material_id;
+                }
+                aov.direct = lerp3(aov.direct, other_aov.direct, t);
+                aov.indirect = lerp3(aov.indirect, other_aov.indirect, t);
+            }
+            self.stats[index].merge(&other.stats[index]);
+        }
+    }
+
+    /// Serializes the accumulation state in little endian binary.
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        write_u32(writer, self.frames)?;
+        write_u32(writer, self.aovs.is_some() as u32)?;
+        for (index, color) in self.color.iter().enumerate() {
+            let stats = &self.stats[index];
+            let features = &self.features[index];
+            write_f32s(writer, &[color.0, color.1, color.2, self.weight[index]])?;
+            write_u32(writer, stats.samples)?;
+            write_f32s(writer, &[stats.mean, stats.m2])?;
+            write_f32s(
+                writer,
+                &[
+                    features.albedo.0,
+                    features.albedo.1,
+                    features.albedo.2,
+                    features.normal.0,
+                    features.normal.1,
+                    features.normal.2,
+                    features.depth,
+                ],
+            )?;
+            if let Some(aovs) = self.aovs.as_ref() {
+                let aov = &aovs[index];
+                write_u32(writer, aov.object_id.unwrap_or(NO_ID))?;
+                write_u32(writer, aov.material_id.unwrap_or(NO_ID))?;
+                write_f32s(
+                    writer,
+                    &[
+                        aov.direct.0,
+                        aov.direct.1,
+                        aov.direct.2,
+                        aov.indirect.0,
+                        aov.indirect.1,
+                        aov.indirect.2,
+                    ],
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a film written by `write` with the given dimensions.
+    pub fn read(reader: &mut impl Read, width: u32, height: u32) -> io::Result<Film> {
+        let frames = read_u32(reader)?;
+        let has_aovs = read_u32(reader)? != 0;
+        let mut film = Film::new(width, height, has_aovs);
+        film.frames = frames;
+        for index in 0..film.color.len() {
+            let mut values = [0.0; 7];
+            read_f32s(reader, &mut values[..4])?;
+            film.color[index] = (values[0], values[1], values[2]);
+            film.weight[index] = values[3];
+            let stats = &mut film.stats[index];
+            stats.samples = read_u32(reader)?;
+            read_f32s(reader, &mut values[..2])?;
+            stats.mean = values[0];
+            stats.m2 = values[1];
+            read_f32s(reader, &mut values)?;
+            film.features[index] = PixelFeatures {
+                albedo: (values[0], values[1], values[2]),
+                normal: (values[3], values[4], values[5]),
+                depth: values[6],
+            };
+            if let Some(aovs) = film.aovs.as_mut() {
+                let aov = &mut aovs[index];
+                let id = |id| if id == NO_ID { None } else { Some(id) };
+                aov.object_id = id(read_u32(reader)?);
+                aov.material_id = id(read_u32(reader)?);
+                read_f32s(reader, &mut values[..6])?;
+                aov.direct = (values[0], values[1], values[2]);
+                aov.indirect = (values[3], values[4], values[5]);
+            }
+        }
+        Ok(film)
+    }
+}
+
+fn write_u32(writer: &mut impl Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+fn write_f32s(writer: &mut impl Write, values: &[f32]) -> io::Result<()> {
+    for value in values {
+        write_u32(writer, value.to_bits())?;
+    }
+    Ok(())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_f32s(reader: &mut impl Read, values: &mut [f32]) -> io::Result<()> {
+    for value in values.iter_mut() {
+        *value = f32::from_bits(read_u32(reader)?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats_of(luminances: &[f32]) -> PixelStats {
+        let mut stats = PixelStats::default();
+        for &luminance in luminances {
+            stats.add(luminance);
+        }
+        stats
+    }
+
+    #[test]
+    fn constant_film_converges() {
+        let mut film = Film::new(4, 3, false);
+        assert!(
+            !film.is_converged(0.01),
+            "a film without samples has no error estimate"
+        );
+        for stats in film.stats.iter_mut() {
+            for _ in 0..4 {
+                stats.add(0.75);
+            }
+        }
+        assert_eq!(film.noisy_fraction(0.01), 0.0);
+        assert!(film.is_converged(0.01));
+        assert!(film.is_converged(0.0));
+    }
+
+    #[test]
+    fn a_noisy_pixel_keeps_the_film_going() {
+        let mut film = Film::new(4, 3, false);
+        for stats in film.stats.iter_mut() {
+            *stats = stats_of(&[0.5; 16]);
+        }
+        film.stats[5] = stats_of(&[0.0, 1.0].repeat(8));
+        assert!(film.stats[5].relative_error() > 0.2);
+        assert_eq!(film.noisy_fraction(0.05), 1.0 / 12.0);
+        assert!(!film.is_converged(0.05));
+        assert!(film.is_converged(0.5));
+    }
+
+    #[test]
+    fn welford_matches_the_sample_variance() {
+        let luminances = [0.2, 0.9, 0.4, 0.4, 1.5, 0.0, 0.7];
+        let stats = stats_of(&luminances);
+        let n = luminances.len() as f32;
+        let mean = luminances.iter().sum::<f32>() / n;
+        let variance = luminances
+            .iter()
+            .map(|l| (l - mean) * (l - mean))
+            .sum::<f32>()
+            / (n - 1.0);
+        assert!((stats.mean_variance().unwrap() - variance / n).abs() < 1e-6);
+        assert!((stats.relative_error() - (variance / n).sqrt() / mean).abs() < 1e-5);
+        assert_eq!(stats_of(&[0.3]).mean_variance(), None);
+        assert_eq!(stats_of(&[0.3]).relative_error(), f32::INFINITY);
+    }
+
+    #[test]
+    fn merged_stats_match_stats_of_all_samples() {
+        let luminances = [0.2, 0.9, 0.4, 0.4, 1.5, 0.0, 0.7, 2.0, 0.1];
+        let all = stats_of(&luminances);
+        let mut merged = stats_of(&luminances[..4]);
+        merged.merge(&stats_of(&luminances[4..]));
+        merged.merge(&PixelStats::default());
+        assert_eq!(merged.samples, all.samples);
+        assert!((merged.mean_variance().unwrap() - all.mean_variance().unwrap()).abs() < 1e-6);
+        assert!((merged.relative_error() - all.relative_error()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn write_then_read() {
+        let mut film = Film::new(3, 2, true);
+        film.frames = 7;
+        for (index, stats) in film.stats.iter_mut().enumerate() {
+            *stats = stats_of(&[index as f32, 1.0, 0.5]);
+            film.color[index] = (index as f32, 0.5, 0.25);
+            film.weight[index] = 3.0;
+        }
+        film.aovs.as_mut().unwrap()[2].object_id = Some(4);
+        let mut bytes = Vec::new();
+        film.write(&mut bytes).unwrap();
+        let read = Film::read(&mut bytes.as_slice(), 3, 2).unwrap();
+        assert_eq!(read.frames, 7);
+        assert_eq!(read.color, film.color);
+        assert_eq!(read.weight, film.weight);
+        for (a, b) in read.stats.iter().zip(film.stats.iter()) {
+            assert_eq!(a.samples, b.samples);
+            assert_eq!(a.relative_error(), b.relative_error());
+        }
+        let aovs = read.aovs.unwrap();
+        assert_eq!(aovs[2].object_id, Some(4));
+        assert_eq!(aovs[0].object_id, None);
+        assert!(Film::read(&mut &bytes[..bytes.len() - 1], 3, 2).is_err());
+    }
+}
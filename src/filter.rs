@@ -0,0 +1,206 @@
+use std::f32;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FilterKind {
+    Box,
+    Tent,
+    Gaussian,
+    /// Mitchell-Netravali with B = C = 1/3.
+    Mitchell,
+    BlackmanHarris,
+}
+
+impl FilterKind {
+    /// Names accepted by `from_name`.
+    pub const NAMES: [&'static str; 5] = ["box", "tent", "gaussian", "mitchell", "blackman-harris"];
+
+    pub fn from_name(name: &str) -> Option<FilterKind> {
+        match name {
+            "box" => Some(FilterKind::Box),
+            "tent" => Some(FilterKind::Tent),
+            "gaussian" => Some(FilterKind::Gaussian),
+            "mitchell" => Some(FilterKind::Mitchell),
+            "blackman-harris" => Some(FilterKind::BlackmanHarris),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FilterKind::Box => "box",
+            FilterKind::Tent => "tent",
+            FilterKind::Gaussian => "gaussian",
+            FilterKind::Mitchell => "mitchell",
+            FilterKind::BlackmanHarris => "blackman-harris",
+        }
+    }
+
+    /// Radius in pixels giving a reasonable trade off between sharpness and aliasing.
+    pub fn default_radius(self) -> f32 {
+        match self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+            FilterKind::BlackmanHarris => 2.0,
+        }
+    }
+}
+
+/// Pixel reconstruction filter. Filters are separable, the weight of a sample is the product of
+/// `evaluate` for its x and y distance from the pixel centre.
+#[derive(Copy, Clone, Debug)]
+pub struct Filter {
+    pub kind: FilterKind,
+    /// Radius in pixels.
+    pub radius: f32,
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind) -> Filter {
+        Filter {
+            kind,
+            radius: kind.default_radius(),
+        }
+    }
+
+    /// Number of neighbouring pixels either side of a sample's pixel that it can contribute to.
+    pub fn reach(&self) -> usize {
+        (self.radius - 0.5).ceil().max(0.0) as usize
+    }
+
+    pub fn evaluate(&self, d: f32) -> f32 {
+        let d = d.abs();
+        if d >= self.radius {
+            return 0.0;
+        }
+        let r = self.radius;
+        match self.kind {
+            FilterKind::Box => 1.0,
+            FilterKind::Tent => 1.0 - d / r,
+            FilterKind::Gaussian => {
+                // offset so the weight falls to zero at the radius
+                let sigma = r / 3.0;
+                let alpha = 0.5 / (sigma * sigma);
+                (-alpha * d * d).exp() - (-alpha * r * r).exp()
+            }
+            FilterKind::Mitchell => {
+                const B: f32 = 1.0 / 3.0;
+                const C: f32 = 1.0 / 3.0;
+                let x = 2.0 * d / r;
+                let x2 = x * x;
+                let x3 = x2 * x;
+                if x < 1.0 {
+                    ((12.0 - 9.0 * B - 6.0 * C) * x3
+                        + (-18.0 + 12.0 * B + 6.0 * C) * x2
+                        + (6.0 - 2.0 * B))
+                        / 6.0
+                } else {
+                    ((-B - 6.0 * C) * x3
+                        + (6.0 * B + 30.0 * C) * x2
+                        + (-12.0 * B - 48.0 * C) * x
+                        + (8.0 * B + 24.0 * C))
+                        / 6.0
+                }
+            }
+            FilterKind::BlackmanHarris => {
+                const A0: f32 = 0.358_75;
+                const A1: f32 = 0.488_29;
+                const A2: f32 = 0.141_28;
+                const A3: f32 = 0.011_68;
+                let t = 2.0 * f32::consts::PI * (0.5 + 0.5 * d / r);
+                A0 - A1 * t.cos() + A2 * (2.0 * t).cos() - A3 * (3.0 * t).cos()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters() -> Vec<Filter> {
+        [
+            FilterKind::Box,
+            FilterKind::Tent,
+            FilterKind::Gaussian,
+            FilterKind::Mitchell,
+            FilterKind::BlackmanHarris,
+        ]
+        .iter()
+        .map(|&kind| Filter::new(kind))
+        .collect()
+    }
+
+    /// Sample positions across a pixel, as offsets from its left edge.
+    fn offsets() -> impl Iterator<Item = f32> {
+        (0..64).map(|i| (i as f32 + 0.5) / 64.0)
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for name in FilterKind::NAMES.iter() {
+            assert_eq!(FilterKind::from_name(name).unwrap().name(), *name);
+        }
+        assert_eq!(FilterKind::from_name("lanczos"), None);
+    }
+
+    #[test]
+    fn weights_peak_at_the_centre_and_vanish_at_the_radius() {
+        for filter in filters() {
+            let peak = filter.evaluate(0.0);
+            assert!(peak > 0.0, "{:?}", filter);
+            for i in 0..100 {
+                let d = filter.radius * i as f32 / 100.0;
+                assert!(filter.evaluate(d) <= peak, "{:?} at {}", filter, d);
+                assert_eq!(filter.evaluate(d), filter.evaluate(-d));
+            }
+            assert_eq!(filter.evaluate(filter.radius), 0.0);
+            assert_eq!(filter.evaluate(filter.radius + 1.0), 0.0);
+        }
+    }
+
+    #[test]
+    fn reach_covers_the_filter() {
+        for filter in filters() {
+            let reach = filter.reach() as i32;
+            for x in offsets() {
+                for px in -4..=4 {
+                    if filter.evaluate(px as f32 + 0.5 - x) != 0.0 {
+                        assert!(px.abs() <= reach, "{:?} reaches {}", filter, px);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn splat_weights_normalise() {
+        // every sample must add positive weight around its pixel, or dividing the splats by
+        // the total weight wouldn't give back the sample's colour
+        for filter in filters() {
+            let reach = filter.reach() as i32;
+            for x in offsets() {
+                for y in offsets() {
+                    let mut total = 0.0;
+                    for py in -reach..=reach {
+                        for px in -reach..=reach {
+                            total += filter.evaluate(px as f32 + 0.5 - x)
+                                * filter.evaluate(py as f32 + 0.5 - y);
+                        }
+                    }
+                    assert!(total > 0.0, "{:?} at ({}, {})", filter, x, y);
+                }
+            }
+        }
+        // the Mitchell filter is a partition of unity, its weights sum to 1 wherever the
+        // sample is
+        let mitchell = Filter::new(FilterKind::Mitchell);
+        for x in offsets() {
+            let total: f32 = (-2..=2)
+                .map(|px| mitchell.evaluate(px as f32 + 0.5 - x))
+                .sum();
+            assert!((total - 1.0).abs() < 1e-5, "{} at {}", total, x);
+        }
+    }
+}
@@ -1,41 +1,87 @@
 use crate::{
-    camera::Camera,
-    scene::{Params, Scene},
+    checkpoint::Checkpoint,
+    output::{save_rgb, Output},
 };
 use glium::{
     self,
-    glutin::{Api, GlProfile, GlRequest},
+    glutin::{Api, EventsLoop, GlProfile, GlRequest},
     index::{NoIndices, PrimitiveType},
     texture::buffer_texture::{BufferTexture, BufferTextureType},
     uniform,
     vertex::EmptyVertexAttributes,
-    Surface,
+    Display, Surface,
+};
+use pathtrace_rs::{
+    camera::Camera,
+    denoise::denoise,
+    error::{Error, Result},
+    film::Film,
+    scene::{Params, Scene},
 };
-use image;
 use std::{
+    panic,
     sync::mpsc::{channel, RecvTimeoutError},
     thread,
     time::{Duration, SystemTime},
 };
 
-pub fn start_loop(params: Params, camera: Camera, scene: Scene, max_frames: Option<u32>) {
-    let mut events_loop = glium::glutin::EventsLoop::new();
-    let window = glium::glutin::WindowBuilder::new()
-        .with_dimensions((params.width, params.height).into())
-        .with_title("pathtrace-rs");
-    let context = glium::glutin::ContextBuilder::new()
-        .with_vsync(true)
-        .with_gl(GlRequest::Specific(Api::OpenGl, (3, 2)))
-        .with_gl_profile(GlProfile::Core);
-    let display =
-        glium::Display::new(window, context, &events_loop).expect("Failed to create display");
+/// A preview window with an OpenGL context, opened before the render starts so callers can
+/// fall back to rendering offline when there's no display.
+pub struct Window {
+    events_loop: EventsLoop,
+    display: Display,
+}
+
+impl Window {
+    pub fn open(width: u32, height: u32) -> Result<Window> {
+        // winit panics rather than returning an error when there's no display server, so catch
+        // that without printing the panic message
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| ()));
+        let events_loop = panic::catch_unwind(EventsLoop::new);
+        panic::set_hook(default_hook);
+        let events_loop =
+            events_loop.map_err(|_| Error::GlContext("no display server available".to_string()))?;
+
+        let window = glium::glutin::WindowBuilder::new()
+            .with_dimensions((width, height).into())
+            .with_title("pathtrace-rs");
+        let context = glium::glutin::ContextBuilder::new()
+            .with_vsync(true)
+            .with_gl(GlRequest::Specific(Api::OpenGl, (3, 2)))
+            .with_gl_profile(GlProfile::Core);
+        let display = Display::new(window, context, &events_loop)
+            .map_err(|err| Error::GlContext(format!("couldn't create the window: {}", err)))?;
+        Ok(Window {
+            events_loop,
+            display,
+        })
+    }
+}
+
+/// Shows the image refining in `window` until it's closed, when the image is saved to
+/// `save_on_close` if given, or until `max_frames` or `time_limit` seconds are reached.
+pub fn start_loop(
+    window: Window,
+    params: Params,
+    camera: Box<dyn Camera>,
+    scene: Scene,
+    max_frames: Option<u32>,
+    time_limit: Option<f32>,
+    mut checkpoint: Option<Checkpoint>,
+    save_on_close: Option<Output>,
+) -> Result<()> {
+    let Window {
+        mut events_loop,
+        display,
+    } = window;
 
     let mut buffer_texture: BufferTexture<(u8, u8, u8, u8)> = BufferTexture::empty_persistent(
         &display,
-        (params.width * params.height * 4) as usize,
+        params.width as usize * params.height as usize * 4,
         BufferTextureType::Float,
     )
-    .expect("Failed to create rgb_buffer texture");
+    .map_err(|err| Error::GlContext(format!("couldn't create the image texture: {:?}", err)))?;
     {
         // init buffer texture to something
         let mut mapping = buffer_texture.map();
@@ -74,25 +120,35 @@ pub fn start_loop(params: Params, camera: Camera, scene: Scene, max_frames: Opti
         ",
         None,
     )
-    .expect("Failed to create shader");
+    .map_err(|err| Error::GlContext(format!("couldn't compile the display shader: {}", err)))?;
 
-    let mut rgb_buffer = Some(vec![
-        (0.0, 0.0, 0.0);
-        (params.width * params.height) as usize
-    ]);
+    let mut film = Some(match checkpoint.as_ref() {
+        Some(checkpoint) => checkpoint.load_or_new(params.width, params.height, false)?,
+        None => Film::new(params.width, params.height, false),
+    });
 
-    let (main_send, worker_recv) = channel::<Option<Vec<(f32, f32, f32)>>>();
-    let (worker_send, main_recv) = channel::<Vec<(f32, f32, f32)>>();
+    let (main_send, worker_recv) = channel::<Option<Film>>();
+    let (worker_send, main_recv) = channel::<Film>();
 
     thread::spawn(move || {
         let mut frame_num = 0;
         let mut elapsed_secs = 0.0;
         let mut ray_count = 0;
+        let render_start = SystemTime::now();
+        let mut finished = false;
         loop {
-            let rgb_buffer = worker_recv.recv().unwrap();
-            if let Some(mut rgb_buffer) = rgb_buffer {
+            // the window may have closed early on an error
+            let film = worker_recv.recv().unwrap_or(None);
+            if let Some(mut film) = film {
+                if finished {
+                    // nothing left to do, avoid spinning while the window stays open
+                    thread::sleep(Duration::from_millis(100));
+                    worker_send.send(film).unwrap();
+                    continue;
+                }
+
                 let start_time = SystemTime::now();
-                ray_count += scene.update(&params, &camera, frame_num, &mut rgb_buffer);
+                ray_count += scene.update(&params, camera.as_ref(), &mut film).total();
                 frame_num += 1;
 
                 let elapsed = start_time
@@ -117,16 +173,35 @@ pub fn start_loop(params: Params, camera: Camera, scene: Scene, max_frames: Opti
                     ray_count = 0;
                 }
 
-                worker_send.send(rgb_buffer).unwrap();
+                if let Some(adaptive) = params.adaptive {
+                    let total_secs = render_start
+                        .elapsed()
+                        .expect("SystemTime elapsed time failed")
+                        .as_secs() as f32;
+                    if film.is_converged(adaptive.threshold) {
+                        println!("reached noise target after {} frames", film.frames);
+                        finished = true;
+                    } else if adaptive
+                        .time_limit
+                        .map_or(false, |limit| total_secs >= limit)
+                    {
+                        println!("reached time limit after {} frames", film.frames);
+                        finished = true;
+                    }
+                }
+
+                worker_send.send(film).unwrap();
             } else {
                 break;
             }
         }
     });
 
+    let loop_start = SystemTime::now();
     let mut frame_num = 0;
     let mut quit = false;
     let mut save = false;
+    let mut denoise_enabled = params.denoise;
     while !quit {
         events_loop.poll_events(|event| {
             use glium::glutin::{ElementState, Event, VirtualKeyCode, WindowEvent};
@@ -138,9 +213,19 @@ pub fn start_loop(params: Params, camera: Camera, scene: Scene, max_frames: Opti
                     }
                     WindowEvent::KeyboardInput { input, .. } => {
                         if let ElementState::Released = input.state {
-                            if let Some(VirtualKeyCode::Escape) = input.virtual_keycode {
-                                quit = true;
-                                save = true;
+                            match input.virtual_keycode {
+                                Some(VirtualKeyCode::Escape) => {
+                                    quit = true;
+                                    save = true;
+                                }
+                                Some(VirtualKeyCode::D) => {
+                                    denoise_enabled = !denoise_enabled;
+                                    println!(
+                                        "denoising {}",
+                                        if denoise_enabled { "on" } else { "off" }
+                                    );
+                                }
+                                _ => (),
                             }
                         }
                     }
@@ -153,19 +238,26 @@ pub fn start_loop(params: Params, camera: Camera, scene: Scene, max_frames: Opti
             break;
         }
 
-        // if we own the buffer then send it back to the worker thread
-        if let Some(rgb_buffer) = rgb_buffer {
+        // if we own the film then send it back to the worker thread
+        if let Some(film) = film.take() {
             // send data to worker thread
-            main_send.send(Some(rgb_buffer)).unwrap();
+            main_send.send(Some(film)).unwrap();
         }
 
         // poll the worker thread to see if it's done
-        rgb_buffer = match main_recv.recv_timeout(Duration::from_millis(100)) {
-            Ok(rgb_buffer) => {
+        film = match main_recv.recv_timeout(Duration::from_millis(100)) {
+            Ok(film) => {
                 // data received - copy to buffer texture
                 {
+                    let denoised;
+                    let color = if denoise_enabled {
+                        denoised = denoise(params.width, params.height, &film);
+                        &denoised
+                    } else {
+                        &film.color
+                    };
                     let mut mapping = buffer_texture.map();
-                    for (texel, rgb) in mapping.iter_mut().zip(rgb_buffer.iter()) {
+                    for (texel, rgb) in mapping.iter_mut().zip(color.iter()) {
                         *texel = (
                             (255.99 * rgb.0.min(1.0).max(0.0)) as u8,
                             (255.99 * rgb.1.min(1.0).max(0.0)) as u8,
@@ -185,8 +277,10 @@ pub fn start_loop(params: Params, camera: Camera, scene: Scene, max_frames: Opti
                         &uniform! { tex: &buffer_texture, stride: params.width as i32 },
                         &Default::default(),
                     )
-                    .unwrap();
-                target.finish().unwrap();
+                    .map_err(|err| Error::GlContext(format!("couldn't draw the image: {}", err)))?;
+                target
+                    .finish()
+                    .map_err(|err| Error::GlContext(format!("couldn't swap buffers: {}", err)))?;
 
                 frame_num += 1;
                 if let Some(max_frames) = max_frames {
@@ -194,26 +288,53 @@ pub fn start_loop(params: Params, camera: Camera, scene: Scene, max_frames: Opti
                         quit = true;
                     }
                 }
+                if let Some(time_limit) = time_limit {
+                    let total_secs = loop_start
+                        .elapsed()
+                        .expect("SystemTime elapsed time failed")
+                        .as_secs_f32();
+                    if total_secs >= time_limit {
+                        println!("reached time limit after {} frames", film.frames);
+                        quit = true;
+                    }
+                }
 
-                Some(rgb_buffer)
+                if let Some(checkpoint) = checkpoint.as_mut() {
+                    checkpoint.save_if_due(params.width, params.height, &film)?;
+                }
+
+                Some(film)
             }
             Err(RecvTimeoutError::Timeout) => None,
             Err(RecvTimeoutError::Disconnected) => break,
         };
     }
 
-    if save {
-        // reading the front rgb_buffer into an image
-        let image: glium::texture::RawImage2d<u8> = display.read_front_buffer();
-        let image =
-            image::ImageBuffer::from_raw(image.width, image.height, image.data.into_owned())
-                .unwrap();
-        let image = image::DynamicImage::ImageRgba8(image).flipv().to_rgb();
-        image
-            .save("output.png")
-            .expect("Failed to save output image");
+    let save_output = if save { save_on_close } else { None };
+    let film = if checkpoint.is_some() || save_output.is_some() {
+        // the worker thread may still be holding the film, wait for it to finish the frame
+        film.or_else(|| main_recv.recv().ok())
+    } else {
+        film
+    };
+
+    if let (Some(checkpoint), Some(film)) = (checkpoint.as_mut(), film.as_ref()) {
+        checkpoint.save(params.width, params.height, film)?;
+    }
+
+    if let (Some(output), Some(film)) = (save_output, film.as_ref()) {
+        // save what's shown in the window
+        let rgb_buffer = if denoise_enabled {
+            denoise(params.width, params.height, film)
+        } else {
+            film.color.clone()
+        };
+        let path = output.path(&params, film.frames);
+        save_rgb(&path, &output, params.width, params.height, &rgb_buffer)?;
+        println!("saved {}", path.display());
     }
 
     // tell the worker to exit
     main_send.send(None).unwrap();
+    Ok(())
 }
@@ -0,0 +1,303 @@
+#![cfg_attr(feature = "core_intrinsics", feature(core_intrinsics))] // for cttz
+#![cfg_attr(feature = "bench", feature(test))] // for bench
+
+//! A toy path tracer. Build a `Scene` from spheres, CSG solids and signed distance fields or
+//! take one from `presets`, describe the view with a `CameraDesc` and render it with
+//! `render_into`, or frame by frame into a `Film` with `Scene::update`.
+
+#[cfg(all(feature = "bench", test))]
+extern crate test;
+
+pub mod aperture;
+pub mod camera;
+pub mod collision;
+pub mod denoise;
+pub mod error;
+pub mod film;
+pub mod filter;
+pub mod material;
+pub mod math;
+pub mod presets;
+pub mod sampler;
+pub mod scene;
+pub mod sdf;
+pub mod simd;
+
+use crate::{
+    camera::Camera,
+    error::{Error, Result},
+    film::Film,
+    scene::{Params, RayCounts, Scene},
+};
+use std::time::SystemTime;
+
+/// How far through a `render_into` or `render_film` call the renderer is.
+#[derive(Copy, Clone, Debug)]
+pub struct RenderProgress {
+    /// The frame being rendered, counting from zero.
+    pub frame: u32,
+    /// The frame limit, if there is one.
+    pub frames: Option<u32>,
+    /// Tiles of the current frame finished so far.
+    pub tiles_done: usize,
+    pub tiles_total: usize,
+}
+
+impl RenderProgress {
+    /// Fraction of the whole render finished, from 0 to 1, or of the current frame when there's
+    /// no frame limit.
+    pub fn fraction(&self) -> f32 {
+        let frame_fraction = self.tiles_done as f32 / self.tiles_total.max(1) as f32;
+        match self.frames {
+            Some(frames) => (self.frame as f32 + frame_fraction) / frames.max(1) as f32,
+            None => frame_fraction,
+        }
+    }
+}
+
+/// When `render_film` stops, besides meeting the adaptive sampling noise target.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RenderLimits {
+    /// Stop after this many frames.
+    pub frames: Option<u32>,
+    /// Stop after this many seconds, defaults to the adaptive sampling time limit.
+    pub time_limit: Option<f32>,
+}
+
+/// Why `render_film` stopped.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StopReason {
+    NoiseTarget,
+    TimeLimit,
+    FrameLimit,
+    Cancelled,
+}
+
+impl StopReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            StopReason::NoiseTarget => "noise target",
+            StopReason::TimeLimit => "time limit",
+            StopReason::FrameLimit => "frame limit",
+            StopReason::Cancelled => "cancelled",
+        }
+    }
+}
+
+fn elapsed_secs(start: SystemTime) -> f32 {
+    let elapsed = start.elapsed().expect("SystemTime elapsed time failed");
+    elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1_000_000_000.0
+}
+
+/// Renders frames of `params.samples` samples per pixel into `film`, which may already hold
+/// earlier frames, until one of `limits` or the adaptive noise target is reached or `cancel`
+/// returns true. Without any limit or adaptive sampling it renders until cancelled.
+///
+/// `progress` is called from the render threads as tiles finish. `after_frame` is called after
+/// each complete frame with the reason rendering is about to stop if it's the last one, and an
+/// error from it stops the render. A cancelled frame is left partially rendered in `film`.
+/// Returns the rays traced and why rendering stopped.
+pub fn render_film(
+    scene: &Scene,
+    camera: &dyn Camera,
+    params: &Params,
+    film: &mut Film,
+    limits: RenderLimits,
+    progress: &(dyn Fn(RenderProgress) + Sync),
+    cancel: &(dyn Fn() -> bool + Sync),
+    after_frame: &mut dyn FnMut(&Film, Option<StopReason>) -> Result<()>,
+) -> Result<(RayCounts, StopReason)> {
+    params.validate()?;
+    let pixels = params.width as usize * params.height as usize;
+    if film.color.len() != pixels {
+        return Err(Error::InvalidParams(format!(
+            "film holds {} pixels but the image has {}",
+            film.color.len(),
+            pixels
+        )));
+    }
+    let mut ray_counts = RayCounts::default();
+    if limits.frames == Some(0) {
+        return Ok((ray_counts, StopReason::FrameLimit));
+    }
+    let start_time = SystemTime::now();
+    let time_limit = limits
+        .time_limit
+        .or_else(|| params.adaptive.and_then(|adaptive| adaptive.time_limit));
+    let mut frame = 0;
+    loop {
+        ray_counts += scene.update_with_progress(
+            params,
+            camera,
+            film,
+            &|tiles_done, tiles_total| {
+                progress(RenderProgress {
+                    frame,
+                    frames: limits.frames,
+                    tiles_done,
+                    tiles_total,
+                })
+            },
+            cancel,
+        );
+        if cancel() {
+            return Ok((ray_counts, StopReason::Cancelled));
+        }
+        frame += 1;
+
+        let stop_reason = if params
+            .adaptive
+            .map_or(false, |adaptive| film.is_converged(adaptive.threshold))
+        {
+            Some(StopReason::NoiseTarget)
+        } else if time_limit.map_or(false, |limit| elapsed_secs(start_time) >= limit) {
+            Some(StopReason::TimeLimit)
+        } else if limits.frames.map_or(false, |frames| frame >= frames) {
+            Some(StopReason::FrameLimit)
+        } else {
+            None
+        };
+        after_frame(film, stop_reason)?;
+        if let Some(stop_reason) = stop_reason {
+            return Ok((ray_counts, stop_reason));
+        }
+    }
+}
+
+/// Renders up to `frames` progressive frames of `params.samples` samples per pixel and writes
+/// the linear colour of each pixel, bottom row first, into `buffer` which must hold
+/// `params.width * params.height` pixels. The image is denoised if `params.denoise` is set.
+///
+/// With adaptive sampling enabled rendering also stops once the noise target is met or its time
+/// limit is reached. The `progress` callback is called from the render threads as tiles finish,
+/// and once `cancel` returns true rendering stops as soon as possible leaving a partial image in
+/// `buffer`. Returns the film with the render's per-pixel state and the rays traced, or an error
+/// if the params are invalid or the buffer is the wrong size.
+pub fn render_into(
+    scene: &Scene,
+    camera: &dyn Camera,
+    params: &Params,
+    frames: u32,
+    buffer: &mut [(f32, f32, f32)],
+    progress: &(dyn Fn(RenderProgress) + Sync),
+    cancel: &(dyn Fn() -> bool + Sync),
+) -> Result<(Film, RayCounts)> {
+    params.validate()?;
+    let pixels = params.width as usize * params.height as usize;
+    if buffer.len() != pixels {
+        return Err(Error::InvalidParams(format!(
+            "buffer holds {} pixels but the image has {}",
+            buffer.len(),
+            pixels
+        )));
+    }
+    let mut film = Film::new(params.width, params.height, params.aovs.is_some());
+    let limits = RenderLimits {
+        frames: Some(frames),
+        ..RenderLimits::default()
+    };
+    let (ray_counts, _) = render_film(
+        scene,
+        camera,
+        params,
+        &mut film,
+        limits,
+        progress,
+        cancel,
+        &mut |_, _| Ok(()),
+    )?;
+
+    if params.denoise {
+        buffer.copy_from_slice(&denoise::denoise(params.width, params.height, &film));
+    } else {
+        buffer.copy_from_slice(&film.color);
+    }
+    Ok((film, ray_counts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{camera::Projection, scene::Adaptive};
+
+    fn params() -> Params {
+        let mut params = Params::new(40, 24);
+        params.samples = 2;
+        params.max_depth = 4;
+        params
+    }
+
+    #[test]
+    fn render_into_matches_update() {
+        let params = params();
+        let (scene, camera_desc) = presets::from_name("aras", &params).unwrap();
+        let camera = camera_desc.build(Projection::Perspective);
+        let mut buffer = vec![(0.0, 0.0, 0.0); 40 * 24];
+        let (film, ray_counts) = render_into(
+            &scene,
+            camera.as_ref(),
+            &params,
+            3,
+            &mut buffer,
+            &|_| (),
+            &|| false,
+        )
+        .unwrap();
+
+        let mut expected = Film::new(params.width, params.height, false);
+        let mut expected_rays = RayCounts::default();
+        for _ in 0..3 {
+            expected_rays += scene.update(&params, camera.as_ref(), &mut expected);
+        }
+        assert_eq!(film.frames, 3);
+        assert_eq!(ray_counts.total(), expected_rays.total());
+        assert_eq!(buffer, expected.color);
+    }
+
+    #[test]
+    fn render_into_stops_at_time_limit() {
+        let mut params = params();
+        // a noise target that's never met
+        params.adaptive = Some(Adaptive {
+            threshold: 1e-9,
+            time_limit: Some(0.2),
+        });
+        let (scene, camera_desc) = presets::from_name("aras", &params).unwrap();
+        let camera = camera_desc.build(Projection::Perspective);
+        let mut buffer = vec![(0.0, 0.0, 0.0); 40 * 24];
+        let start_time = SystemTime::now();
+        let (film, _) = render_into(
+            &scene,
+            camera.as_ref(),
+            &params,
+            u32::MAX,
+            &mut buffer,
+            &|_| (),
+            &|| false,
+        )
+        .unwrap();
+        assert!(film.frames >= 1);
+        assert!(elapsed_secs(start_time) < 10.0);
+    }
+
+    #[test]
+    fn render_into_rejects_wrong_buffer_size() {
+        let params = params();
+        let (scene, camera_desc) = presets::from_name("aras", &params).unwrap();
+        let camera = camera_desc.build(Projection::Perspective);
+        let mut buffer = vec![(0.0, 0.0, 0.0); 10];
+        let result = render_into(
+            &scene,
+            camera.as_ref(),
+            &params,
+            1,
+            &mut buffer,
+            &|_| (),
+            &|| false,
+        );
+        assert!(match result {
+            Err(Error::InvalidParams(_)) => true,
+            _ => false,
+        });
+    }
+}
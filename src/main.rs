@@ -1,20 +1,57 @@
-#![cfg_attr(feature = "core_intrinsics", feature(core_intrinsics))] // for cttz
-#![cfg_attr(feature = "bench", feature(test))] // for bench
-
-mod camera;
-mod collision;
+mod bench;
+mod checkpoint;
+mod config;
+mod distributed;
+mod exr;
 mod glium_window;
-mod material;
-mod math;
+mod merge;
 mod offline;
-mod presets;
-mod scene;
-mod simd;
+mod output;
+mod progress;
+mod stats;
+
+use clap::{value_t, App, Arg, ArgMatches, ErrorKind, SubCommand};
+use pathtrace_rs::{
+    aperture, camera,
+    error::{Error, Result},
+    film, filter, presets, sampler, scene, simd,
+};
+use std::{path::PathBuf, process, str::FromStr};
 
-use clap::{value_t, App, Arg};
+// seconds between image writes when falling back to rendering without a window
+const SNAPSHOT_SECS: f32 = 10.0;
+// frames rendered without a window when neither --frames nor a time limit is given, as there's
+// no window to close
+const HEADLESS_FRAMES: u32 = 100;
 
 fn main() {
-    let matches = App::new("Toy Path Tracer")
+    if let Err(err) = run() {
+        eprintln!("error: {}", err);
+        // mistakes on the command line exit like clap's own usage errors
+        let code = match err {
+            Error::InvalidParams(_) | Error::UnknownPreset(_) => 2,
+            _ => 1,
+        };
+        process::exit(code);
+    }
+}
+
+/// A subcommand option parsed as `T`, `default` if it isn't given.
+fn value_or<T: FromStr>(matches: &ArgMatches, name: &str, default: T) -> Result<T> {
+    match value_t!(matches, name, T) {
+        Ok(value) => Ok(value),
+        Err(ref err) if err.kind == ErrorKind::ArgumentNotFound => Ok(default),
+        Err(_) => Err(Error::InvalidParams(format!(
+            "--{} can't be '{}', it doesn't parse",
+            name,
+            matches.value_of(name).unwrap_or("")
+        ))),
+    }
+}
+
+/// The command line arguments and subcommands.
+fn app() -> App<'static, 'static> {
+    App::new("Toy Path Tracer")
         .version("0.1")
         .args(&[
             Arg::with_name("width")
@@ -41,44 +78,667 @@ fn main() {
                 .help("Use a random seed")
                 .short("R")
                 .long("random"),
+            Arg::with_name("no-random")
+                .help("Don't use a random seed, even if the config file turns it on")
+                .long("no-random")
+                .conflicts_with("random"),
+            Arg::with_name("seed")
+                .help("Seed for sample generation, renders with different seeds can be merged")
+                .long("seed")
+                .takes_value(true)
+                .conflicts_with("random"),
+            Arg::with_name("float-output")
+                .help("Also write the linear image to output.exr for merging")
+                .long("float-output")
+                .requires("offline"),
+            Arg::with_name("no-float-output")
+                .help("Don't write output.exr, even if the config file turns it on")
+                .long("no-float-output")
+                .conflicts_with("float-output"),
+            Arg::with_name("filter")
+                .help("Pixel reconstruction filter")
+                .long("filter")
+                .possible_values(&filter::FilterKind::NAMES)
+                .takes_value(true),
+            Arg::with_name("filter-radius")
+                .help("Reconstruction filter radius in pixels")
+                .long("filter-radius")
+                .takes_value(true),
+            Arg::with_name("sampler")
+                .help("Sample generator")
+                .long("sampler")
+                .possible_values(&sampler::SamplerKind::NAMES)
+                .takes_value(true),
+            Arg::with_name("noise-threshold")
+                .help("Enable adaptive sampling, refining pixels until their relative error is below this")
+                .long("noise-threshold")
+                .takes_value(true),
+            Arg::with_name("time-limit")
+                .help("Stop adaptive sampling, a progressive render or the preview window after this many seconds")
+                .long("time-limit")
+                .takes_value(true),
+            Arg::with_name("progressive")
+                .help("Keep rendering frames offline until a limit is reached or Ctrl-C is pressed")
+                .long("progressive")
+                .requires("offline")
+                .conflicts_with_all(&["coordinator", "stereo"]),
+            Arg::with_name("snapshot-frames")
+                .help("Write the progressive image every this many frames")
+                .long("snapshot-frames")
+                .takes_value(true)
+                .requires("progressive"),
+            Arg::with_name("snapshot-interval")
+                .help("Write the progressive image every this many seconds")
+                .long("snapshot-interval")
+                .takes_value(true)
+                .requires("progressive"),
+            Arg::with_name("denoise")
+                .help("Denoise the image, toggled with D in the window")
+                .long("denoise"),
+            Arg::with_name("no-denoise")
+                .help("Don't denoise, even if the config file turns it on")
+                .long("no-denoise")
+                .conflicts_with("denoise"),
+            Arg::with_name("stats")
+                .help("Write a JSON report of timings and ray counts next to the image")
+                .long("stats")
+                .requires("offline"),
+            Arg::with_name("checkpoint")
+                .help("Periodically save the render state to this file")
+                .long("checkpoint")
+                .takes_value(true)
+                .conflicts_with("stereo"),
+            Arg::with_name("checkpoint-interval")
+                .help("Seconds between checkpoint saves")
+                .long("checkpoint-interval")
+                .takes_value(true)
+                .requires("checkpoint"),
+            Arg::with_name("resume")
+                .help("Continue the render saved in the checkpoint file")
+                .long("resume")
+                .requires("checkpoint"),
+            Arg::with_name("coordinator")
+                .help("Listen on this address and hand out sample passes to workers")
+                .long("coordinator")
+                .takes_value(true)
+                .requires("offline")
+                .conflicts_with_all(&["noise-threshold", "stereo", "checkpoint"]),
+            Arg::with_name("passes")
+                .help("Number of sample passes to distribute")
+                .long("passes")
+                .takes_value(true)
+                .requires("coordinator"),
+            Arg::with_name("pass-timeout")
+                .help("Seconds a worker may take to return a pass before it's given to another worker")
+                .long("pass-timeout")
+                .takes_value(true)
+                .requires("coordinator"),
+            Arg::with_name("worker")
+                .help("Render passes for the coordinator at this address")
+                .long("worker")
+                .takes_value(true)
+                .conflicts_with_all(&[
+                    "coordinator",
+                    "offline",
+                    "noise-threshold",
+                    "stereo",
+                    "checkpoint",
+                ]),
+            Arg::with_name("aovs")
+                .help("Also write normal, depth, albedo, id, lighting and sample count outputs, workers must be given it too")
+                .long("aovs")
+                .possible_values(&film::AovFormat::NAMES)
+                .takes_value(true)
+                .conflicts_with("stereo"),
             Arg::with_name("preset")
                 .help("Scene preset to render")
                 .short("P")
                 .long("preset")
                 .takes_value(true),
             Arg::with_name("frames")
-                .help("Process a fixed number of frames and exit")
+                .help("Process a fixed number of frames and exit, in the window or progressively")
                 .short("F")
                 .long("frames")
                 .takes_value(true),
+            Arg::with_name("projection")
+                .help("Camera projection")
+                .long("projection")
+                .possible_values(&camera::Projection::NAMES)
+                .takes_value(true),
+            Arg::with_name("fov")
+                .help("Override the preset's vertical field of view in degrees")
+                .long("fov")
+                .takes_value(true),
+            Arg::with_name("aperture")
+                .help("Override the preset's lens aperture diameter")
+                .long("aperture")
+                .takes_value(true),
+            Arg::with_name("blades")
+                .help("Number of aperture blades, giving polygonal bokeh")
+                .long("blades")
+                .takes_value(true),
+            Arg::with_name("blade-rotation")
+                .help("Rotation of the aperture blades in degrees")
+                .long("blade-rotation")
+                .takes_value(true),
+            Arg::with_name("aperture-mask")
+                .help("Greyscale image to use as the aperture shape")
+                .long("aperture-mask")
+                .takes_value(true)
+                .conflicts_with("blades"),
+            Arg::with_name("cats-eye")
+                .help("Amount of cat's eye vignetting of the aperture, from 0 to 1")
+                .long("cats-eye")
+                .takes_value(true),
+            Arg::with_name("stereo")
+                .help("Render a stereo pair into a single image, with the perspective, equirectangular or cubemap projection")
+                .long("stereo")
+                .possible_values(&["top-bottom", "side-by-side"])
+                .takes_value(true)
+                .requires("offline"),
+            Arg::with_name("ipd")
+                .help("Stereo interpupillary distance in scene units")
+                .long("ipd")
+                .takes_value(true),
+            Arg::with_name("convergence")
+                .help("Stereo convergence distance, defaults to the focus distance")
+                .long("convergence")
+                .takes_value(true),
+            Arg::with_name("output")
+                .help(
+                    "Image file to write, the extension picks png, bmp, ppm or exr. May contain \
+                     {preset}, {width}, {height}, {spp}, {frame} and {timestamp}",
+                )
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .conflicts_with("worker"),
+            Arg::with_name("tone-map")
+                .help("Tone mapping applied to PNG, BMP and PPM images")
+                .long("tone-map")
+                .possible_values(&output::ToneMapping::NAMES)
+                .takes_value(true),
+            Arg::with_name("exposure")
+                .help("Exposure adjustment in stops applied before tone mapping")
+                .long("exposure")
+                .takes_value(true),
+            Arg::with_name("no-save-on-close")
+                .help("Don't write the image when the preview window is closed")
+                .long("no-save-on-close")
+                .conflicts_with("offline"),
+            Arg::with_name("config")
+                .help("TOML file of render settings and profiles, defaults to pathtrace.toml if it exists")
+                .long("config")
+                .takes_value(true),
+            Arg::with_name("profile")
+                .help("Render with a profile from the config file, or the built-in preview, final or benchmark")
+                .long("profile")
+                .takes_value(true),
+            Arg::with_name("print-config")
+                .help("Print the settings the config file and command line add up to and exit")
+                .long("print-config"),
+            Arg::with_name("simd")
+                .help("SIMD instruction set to intersect spheres with, defaults to the best supported")
+                .long("simd")
+                .possible_values(&simd::TargetFeature::NAMES)
+                .takes_value(true),
+            Arg::with_name("tracing")
+                .help("Trace rays one at a time, or camera and shadow rays in packets")
+                .long("tracing")
+                .possible_values(&scene::Tracing::NAMES)
+                .takes_value(true),
             Arg::with_name("offline")
                 .help("Don't create a preview render window")
                 .short("O")
                 .long("offline"),
         ])
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Merge float renders made with different seeds into one image")
+                .args(&[
+                    Arg::with_name("inputs")
+                        .help("EXR files written with --float-output")
+                        .multiple(true)
+                        .required(true),
+                    Arg::with_name("output")
+                        .help("Merged EXR file, a PNG preview is written next to it")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true),
+                ]),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about(
+                    "Measure Mrays/s rendering presets at a fixed seed with each SIMD path, \
+                     exits with status 3 if slower than the baseline",
+                )
+                .args(&[
+                    Arg::with_name("presets")
+                        .help("Presets to render, defaults to all of them")
+                        .short("P")
+                        .long("presets")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .possible_values(&presets::NAMES),
+                    Arg::with_name("simd")
+                        .help("SIMD paths to force, defaults to all the CPU supports")
+                        .long("simd")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .possible_values(&simd::TargetFeature::NAMES),
+                    Arg::with_name("tracing")
+                        .help("Ways of tracing rays to measure, defaults to single")
+                        .long("tracing")
+                        .takes_value(true)
+                        .multiple(true)
+                        .use_delimiter(true)
+                        .possible_values(&scene::Tracing::NAMES),
+                    Arg::with_name("runs")
+                        .help("Timed renders of each preset and path")
+                        .short("n")
+                        .long("runs")
+                        .takes_value(true),
+                    Arg::with_name("width")
+                        .help("Image width to render")
+                        .short("W")
+                        .long("width")
+                        .takes_value(true),
+                    Arg::with_name("height")
+                        .help("Image height to render")
+                        .short("H")
+                        .long("height")
+                        .takes_value(true),
+                    Arg::with_name("samples")
+                        .help("Number of samples per pixel")
+                        .short("S")
+                        .long("samples")
+                        .takes_value(true),
+                    Arg::with_name("save")
+                        .help("Write the results to this JSON file for use as a baseline")
+                        .long("save")
+                        .takes_value(true),
+                    Arg::with_name("baseline")
+                        .help("Compare with results saved by an earlier run")
+                        .long("baseline")
+                        .takes_value(true),
+                    Arg::with_name("tolerance")
+                        .help("Percentage slowdown from the baseline counted as a regression")
+                        .long("tolerance")
+                        .takes_value(true)
+                        .requires("baseline"),
+                ]),
+        )
+}
+
+fn run() -> Result<()> {
+    let matches = app().get_matches();
+
+    if let Some(matches) = matches.subcommand_matches("merge") {
+        let inputs: Vec<&str> = matches.values_of("inputs").unwrap().collect();
+        let output = matches.value_of("output").unwrap_or("merged.exr");
+        return merge::merge_renders(&inputs, output);
+    }
+
+    if let Some(matches) = matches.subcommand_matches("bench") {
+        let features = match matches.values_of("simd") {
+            Some(names) => names
+                .map(|name| simd::TargetFeature::from_name(name).unwrap())
+                .collect(),
+            None => simd::TargetFeature::ALL
+                .iter()
+                .cloned()
+                .filter(|feature| feature.is_supported())
+                .collect(),
+        };
+        let mut params = scene::Params::new(
+            value_or(matches, "width", 320)?,
+            value_or(matches, "height", 180)?,
+        );
+        params.samples = value_or(matches, "samples", 4)?;
+        params.seed = bench::SEED;
+        params.validate()?;
+        let tolerance = value_or(matches, "tolerance", 5.0)?;
+        if !(tolerance >= 0.0 && f64::is_finite(tolerance)) {
+            return Err(Error::InvalidParams(
+                "--tolerance must be a non-negative percentage".to_string(),
+            ));
+        }
+        let bench = bench::Bench {
+            presets: matches.values_of("presets").map_or(
+                presets::NAMES.iter().map(|name| name.to_string()).collect(),
+                |names| names.map(str::to_string).collect(),
+            ),
+            features,
+            tracings: matches
+                .values_of("tracing")
+                .map_or(vec![scene::Tracing::Single], |names| {
+                    names
+                        .map(|name| scene::Tracing::from_name(name).unwrap())
+                        .collect()
+                }),
+            runs: value_or(matches, "runs", 5u32)?.max(1),
+            params,
+            tolerance,
+        };
+        let measurements = bench.run()?;
+        if let Some(path) = matches.value_of("save") {
+            std::fs::write(path, bench.to_json(&measurements))
+                .map_err(Error::io(format!("couldn't write {}", path)))?;
+        }
+        if let Some(path) = matches.value_of("baseline") {
+            let regressions = bench.compare(&measurements, path.as_ref())?;
+            if regressions > 0 {
+                eprintln!("{} results regressed", regressions);
+                process::exit(3);
+            }
+        }
+        return Ok(());
+    }
 
+    let config = config::Config::load(matches.value_of("config"))?;
+    let profile = matches.value_of("profile");
+    let settings = config::Settings::new(&matches, config.values(profile)?, profile);
+
+    let mut filter = filter::Filter::new(
+        settings
+            .choice(
+                "filter",
+                &filter::FilterKind::NAMES,
+                filter::FilterKind::from_name,
+            )?
+            .unwrap_or(filter::FilterKind::Box),
+    );
+    if let Some(radius) = settings.value::<f32>("filter-radius")? {
+        filter.radius = radius;
+    }
+
+    let time_limit = settings.value::<f32>("time-limit")?;
     let params = scene::Params {
-        width: value_t!(matches, "width", u32).unwrap_or(1280),
-        height: value_t!(matches, "height", u32).unwrap_or(720),
-        samples: value_t!(matches, "samples", u32).unwrap_or(4),
-        max_depth: value_t!(matches, "depth", u32).unwrap_or(10),
-        random_seed: matches.is_present("random"),
+        width: settings.value::<u32>("width")?.unwrap_or(1280),
+        height: settings.value::<u32>("height")?.unwrap_or(720),
+        samples: settings.value::<u32>("samples")?.unwrap_or(4),
+        max_depth: settings.value::<u32>("depth")?.unwrap_or(10),
+        random_seed: settings.is_present("random"),
+        seed: settings.value::<u64>("seed")?.unwrap_or(0),
+        filter,
+        sampler: settings
+            .choice(
+                "sampler",
+                &sampler::SamplerKind::NAMES,
+                sampler::SamplerKind::from_name,
+            )?
+            .unwrap_or(sampler::SamplerKind::Random),
+        adaptive: settings
+            .value::<f32>("noise-threshold")?
+            .map(|threshold| scene::Adaptive {
+                threshold,
+                time_limit,
+            }),
+        denoise: settings.is_present("denoise"),
+        aovs: settings.choice("aovs", &film::AovFormat::NAMES, film::AovFormat::from_name)?,
+        float_output: settings.is_present("float-output"),
+        target_feature: settings
+            .choice(
+                "simd",
+                &simd::TargetFeature::NAMES,
+                simd::TargetFeature::from_name,
+            )?
+            .unwrap_or_else(simd::TargetFeature::detect),
+        tracing: settings
+            .choice("tracing", &scene::Tracing::NAMES, scene::Tracing::from_name)?
+            .unwrap_or(scene::Tracing::Single),
+    };
+    let preset = settings
+        .value_of("preset")
+        .unwrap_or_else(|| "aras".to_string());
+    let output_template = settings
+        .value_of("output")
+        .unwrap_or_else(|| "output.png".to_string());
+    let projection = settings
+        .choice(
+            "projection",
+            &camera::Projection::NAMES,
+            camera::Projection::from_name,
+        )?
+        .unwrap_or(camera::Projection::Perspective);
+    let tone_map = output::ToneMap {
+        mapping: settings
+            .choice(
+                "tone-map",
+                &output::ToneMapping::NAMES,
+                output::ToneMapping::from_name,
+            )?
+            .unwrap_or(output::ToneMapping::Clamp),
+        exposure: settings.value::<f32>("exposure")?.unwrap_or(0.0),
     };
+    // read before --print-config so it doesn't print values that can't be used
+    let fov = settings.value::<f32>("fov")?;
+    let aperture = settings.value::<f32>("aperture")?;
+    let blades = settings.value::<u32>("blades")?;
+    let blade_rotation = settings.value::<f32>("blade-rotation")?;
+    let cats_eye = settings.value::<f32>("cats-eye")?;
+    let max_frames = settings.value::<u32>("frames")?;
+    let snapshot_frames = settings.value::<u32>("snapshot-frames")?;
+    let snapshot_secs = settings.value::<f32>("snapshot-interval")?;
+
+    if matches.is_present("print-config") {
+        print!(
+            "{}",
+            config::effective(
+                &settings,
+                &params,
+                &preset,
+                &output_template,
+                tone_map,
+                projection
+            )
+        );
+        return Ok(());
+    }
 
-    let preset = matches.value_of("preset").unwrap_or("aras");
+    params.validate()?;
+    settings.check()?;
+    let preset = preset.as_str();
 
     println!(
         "generating '{}' preset at {}x{} with {} samples per pixel",
         preset, params.width, params.height, params.samples
     );
+    params.target_feature.print_version();
+
+    let mut output = output::Output::new(&output_template, preset)?;
+    output.tone_map = tone_map;
 
-    let (scene, camera) = presets::from_name(preset, &params).expect("unrecognised preset");
+    let build_start = std::time::SystemTime::now();
+    let (scene, mut camera_desc) = presets::from_name(preset, &params)?;
+    let mut report = stats::RenderReport::new(preset, &params, scene.target_feature());
+    report.times.scene_build = stats::elapsed_secs(build_start);
+    if let Some(fov) = fov {
+        camera_desc.vfov = fov;
+    }
+    if let Some(aperture) = aperture {
+        camera_desc.aperture = aperture;
+    }
+    if let Some(blades) = blades {
+        camera_desc.aperture_shape = aperture::ApertureShape::Polygon {
+            blades,
+            rotation: blade_rotation.unwrap_or(0.0),
+        };
+    }
+    if let Some(path) = matches.value_of("aperture-mask") {
+        let mask = aperture::ApertureMask::open(path)?;
+        camera_desc.aperture_shape = aperture::ApertureShape::Mask(std::sync::Arc::new(mask));
+    }
+    camera_desc.cats_eye = cats_eye.unwrap_or(0.0);
+    camera_desc.validate()?;
+    let stereo = match matches
+        .value_of("stereo")
+        .and_then(camera::StereoLayout::from_name)
+    {
+        Some(layout) => Some(camera::Stereo {
+            layout,
+            ipd: settings.value::<f32>("ipd")?.unwrap_or(0.064),
+            convergence: settings.value::<f32>("convergence")?,
+        }),
+        None => None,
+    };
 
-    if matches.is_present("offline") {
-        offline::render_offline(&params, &camera, &scene);
+    if stereo.is_some() && !projection.supports_stereo() {
+        return Err(Error::InvalidParams(format!(
+            "stereo isn't supported with the {} projection",
+            projection.name()
+        )));
+    }
+
+    // everything that changes the image, distributed workers must agree with the coordinator
+    // and a resumed checkpoint with the render continuing it
+    let job_hash = distributed::job_hash(&format!(
+        "{} {}x{} samples={} depth={} seed={} {:?} {:?} {:?} {:?} aovs={:?} tracing={}",
+        preset,
+        params.width,
+        params.height,
+        params.samples,
+        params.max_depth,
+        params.seed,
+        params.filter,
+        params.sampler,
+        projection,
+        camera_desc,
+        params.aovs.is_some(),
+        params.tracing.name(),
+    ));
+
+    let checkpoint_interval = settings.value::<f32>("checkpoint-interval")?;
+    let mut checkpoint = matches.value_of("checkpoint").map(|path| {
+        checkpoint::Checkpoint::new(
+            path.into(),
+            checkpoint_interval.unwrap_or(60.0),
+            matches.is_present("resume"),
+            job_hash,
+        )
+    });
+
+    if let Some(address) = matches.value_of("coordinator") {
+        let passes = settings.value::<u32>("passes")?.unwrap_or(4);
+        let pass_timeout = settings.value::<f32>("pass-timeout")?.unwrap_or(600.0);
+        if !(0.001..=1.0e9).contains(&pass_timeout) {
+            return Err(Error::InvalidParams(
+                "pass timeout must be a positive number of seconds".to_string(),
+            ));
+        }
+        offline::render_distributed(
+            &params,
+            address,
+            passes,
+            std::time::Duration::from_secs_f32(pass_timeout),
+            job_hash,
+            &output,
+            &mut report,
+        )?;
+    } else if let Some(address) = matches.value_of("worker") {
+        let camera = camera_desc.build(projection);
+        distributed::run_worker(address, &params, camera.as_ref(), &scene, job_hash)
+            .map_err(Error::io(format!("worker for {} failed", address)))?;
+    } else if let Some(stereo) = stereo {
+        let (left, right) = camera_desc.stereo_pair(&stereo);
+        offline::render_offline_stereo(
+            &params,
+            left.build(projection).as_ref(),
+            right.build(projection).as_ref(),
+            stereo.layout,
+            &scene,
+            &output,
+            &mut report,
+        )?;
+    } else if matches.is_present("progressive") {
+        let progressive = offline::Progressive {
+            max_frames,
+            time_limit,
+            snapshot_frames,
+            snapshot_secs,
+        };
+        progressive.validate()?;
+        let camera = camera_desc.build(projection);
+        offline::render_progressive(
+            &params,
+            camera.as_ref(),
+            &scene,
+            &progressive,
+            checkpoint.as_mut(),
+            &output,
+            &mut report,
+        )?;
+    } else if matches.is_present("offline") {
+        let camera = camera_desc.build(projection);
+        offline::render_offline(
+            &params,
+            camera.as_ref(),
+            &scene,
+            checkpoint.as_mut(),
+            &output,
+            &mut report,
+        )?;
     } else {
-        let max_frames = value_t!(matches, "frames", u32).ok().and_then(Some);
-        glium_window::start_loop(params, camera, scene, max_frames);
+        let camera = camera_desc.build(projection);
+        // also rejects --frames 0 before a window opens
+        let progressive = offline::Progressive {
+            max_frames: match (max_frames, time_limit) {
+                (None, None) => Some(HEADLESS_FRAMES),
+                _ => max_frames,
+            },
+            time_limit,
+            snapshot_secs: Some(SNAPSHOT_SECS),
+            ..offline::Progressive::default()
+        };
+        progressive.validate()?;
+        match glium_window::Window::open(params.width, params.height) {
+            Ok(window) => {
+                let save_on_close = if matches.is_present("no-save-on-close") {
+                    None
+                } else {
+                    Some(output)
+                };
+                glium_window::start_loop(
+                    window,
+                    params,
+                    camera,
+                    scene,
+                    max_frames,
+                    time_limit,
+                    checkpoint,
+                    save_on_close,
+                )?
+            }
+            Err(err) => {
+                // headless machines get the same progressive render, written to disk instead
+                println!("{}, rendering offline instead", err);
+                offline::render_progressive(
+                    &params,
+                    camera.as_ref(),
+                    &scene,
+                    &progressive,
+                    checkpoint.as_mut(),
+                    &output,
+                    &mut report,
+                )?;
+            }
+        }
+    }
+
+    if matches.is_present("stats") {
+        // next to the image so parallel runs don't overwrite each other's reports
+        let path = report
+            .image
+            .as_ref()
+            .map_or(PathBuf::from("output.json"), |image| {
+                image.with_extension("json")
+            });
+        report
+            .save(&path)
+            .map_err(Error::io(format!("couldn't write {}", path.display())))?;
     }
+    Ok(())
 }
@@ -1,10 +1,9 @@
 use crate::{
     collision::{ray, Ray, RayHit},
-    math::{random_in_unit_sphere, random_unit_vector, reflect, refract, schlick},
+    math::{reflect, refract, schlick, uniform_sample_ball, uniform_sample_sphere},
+    sampler::Sampler,
 };
 use glam::{vec3, Vec3};
-use rand::Rng;
-use rand_xoshiro::Xoshiro256Plus;
 
 // #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 #[derive(Clone, Copy, Debug)]
@@ -25,9 +24,9 @@ impl MaterialKind {
         albedo: Vec3,
         _: &Ray,
         ray_hit: &RayHit,
-        rng: &mut Xoshiro256Plus,
+        sampler: &mut dyn Sampler,
     ) -> Option<(Vec3, Ray, bool)> {
-        let target = ray_hit.point + ray_hit.normal + random_unit_vector(rng);
+        let target = ray_hit.point + ray_hit.normal + uniform_sample_sphere(sampler.get_2d());
         Some((
             albedo,
             ray(ray_hit.point, (target - ray_hit.point).normalize()),
@@ -39,7 +38,7 @@ impl MaterialKind {
         fuzz: f32,
         ray_in: &Ray,
         ray_hit: &RayHit,
-        rng: &mut Xoshiro256Plus,
+        sampler: &mut dyn Sampler,
     ) -> Option<(Vec3, Ray, bool)> {
         let reflected = reflect(ray_in.direction, ray_hit.normal);
         if reflected.dot(ray_hit.normal) > 0.0 {
@@ -47,7 +46,8 @@ impl MaterialKind {
                 albedo,
                 ray(
                     ray_hit.point,
-                    (reflected + fuzz * random_in_unit_sphere(rng)).normalize(),
+                    (reflected + fuzz * uniform_sample_ball(sampler.get_2d(), sampler.get_1d()))
+                        .normalize(),
                 ),
                 false,
             ))
@@ -59,7 +59,7 @@ impl MaterialKind {
         ref_idx: f32,
         ray_in: &Ray,
         ray_hit: &RayHit,
-        rng: &mut Xoshiro256Plus,
+        sampler: &mut dyn Sampler,
     ) -> Option<(Vec3, Ray, bool)> {
         let attenuation = vec3(1.0, 1.0, 1.0);
         let rdotn = ray_in.direction.dot(ray_hit.normal);
@@ -73,7 +73,7 @@ impl MaterialKind {
         };
         if let Some(refracted) = refract(ray_in.direction, outward_normal, ni_over_nt) {
             let reflect_prob = schlick(cosine, ref_idx);
-            if rng.gen::<f32>() > reflect_prob {
+            if sampler.get_1d() > reflect_prob {
                 return Some((
                     attenuation,
                     ray(ray_hit.point, refracted.normalize()),
@@ -92,22 +92,59 @@ impl MaterialKind {
     }
 }
 
+fn vec3_eq(a: Vec3, b: Vec3) -> bool {
+    a.get_x() == b.get_x() && a.get_y() == b.get_y() && a.get_z() == b.get_z()
+}
+
 impl Material {
+    /// True if both materials have the same parameters.
+    pub fn matches(&self, other: &Material) -> bool {
+        let kind_matches = match (self.kind, other.kind) {
+            (MaterialKind::Lambertian { albedo: a }, MaterialKind::Lambertian { albedo: b }) => {
+                vec3_eq(a, b)
+            }
+            (
+                MaterialKind::Metal {
+                    albedo: a,
+                    fuzz: fuzz_a,
+                },
+                MaterialKind::Metal {
+                    albedo: b,
+                    fuzz: fuzz_b,
+                },
+            ) => vec3_eq(a, b) && fuzz_a == fuzz_b,
+            (MaterialKind::Dielectric { ref_idx: a }, MaterialKind::Dielectric { ref_idx: b }) => {
+                a == b
+            }
+            _ => false,
+        };
+        kind_matches && vec3_eq(self.emissive, other.emissive)
+    }
+
+    /// Surface colour independent of lighting, dielectrics are treated as white.
+    pub fn albedo(&self) -> Vec3 {
+        match self.kind {
+            MaterialKind::Lambertian { albedo } => albedo,
+            MaterialKind::Metal { albedo, .. } => albedo,
+            MaterialKind::Dielectric { .. } => vec3(1.0, 1.0, 1.0),
+        }
+    }
+
     pub fn scatter(
         &self,
         ray: &Ray,
         ray_hit: &RayHit,
-        rng: &mut Xoshiro256Plus,
+        sampler: &mut dyn Sampler,
     ) -> Option<(Vec3, Ray, bool)> {
         match self.kind {
             MaterialKind::Lambertian { albedo } => {
-                MaterialKind::scatter_lambertian(albedo, ray, ray_hit, rng)
+                MaterialKind::scatter_lambertian(albedo, ray, ray_hit, sampler)
             }
             MaterialKind::Metal { albedo, fuzz } => {
-                MaterialKind::scatter_metal(albedo, fuzz, ray, ray_hit, rng)
+                MaterialKind::scatter_metal(albedo, fuzz, ray, ray_hit, sampler)
             }
             MaterialKind::Dielectric { ref_idx } => {
-                MaterialKind::scatter_dielectric(ref_idx, ray, ray_hit, rng)
+                MaterialKind::scatter_dielectric(ref_idx, ray, ray_hit, sampler)
             }
         }
     }
@@ -1,38 +1,37 @@
 use crate::simd::sinf_cosf;
 use glam::{vec3, Vec3};
-use rand::Rng;
 use std::f32;
 
-pub fn random_in_unit_disk<T: Rng>(rng: &mut T) -> Vec3 {
-    loop {
-        let p = 2.0 * vec3(rng.gen(), rng.gen(), 0.0) - vec3(1.0, 1.0, 0.0);
-        if p.dot(p) < 1.0 {
-            return p;
-        }
-    }
-}
-
-pub fn random_in_unit_sphere<T: Rng>(rng: &mut T) -> Vec3 {
-    loop {
-        let p = vec3(
-            2.0 * rng.gen::<f32>() - 1.0,
-            2.0 * rng.gen::<f32>() - 1.0,
-            2.0 * rng.gen::<f32>() - 1.0,
-        );
-        if p.length_squared() < 1.0 {
-            return p;
-        }
+/// Maps a point in the unit square to the unit disk, keeping any stratification of the input.
+pub fn concentric_sample_disk(u: (f32, f32)) -> Vec3 {
+    let x = 2.0 * u.0 - 1.0;
+    let y = 2.0 * u.1 - 1.0;
+    if x == 0.0 && y == 0.0 {
+        return Vec3::zero();
     }
+    let (r, theta) = if x.abs() > y.abs() {
+        (x, f32::consts::FRAC_PI_4 * (y / x))
+    } else {
+        (y, f32::consts::FRAC_PI_2 - f32::consts::FRAC_PI_4 * (x / y))
+    };
+    let (sin_theta, cos_theta) = sinf_cosf(theta);
+    vec3(r * cos_theta, r * sin_theta, 0.0)
 }
 
-pub fn random_unit_vector<T: Rng>(rng: &mut T) -> Vec3 {
-    let z = rng.gen::<f32>() * 2.0 - 1.0;
-    let a = rng.gen::<f32>() * 2.0 * f32::consts::PI;
+/// Maps a point in the unit square to a direction on the unit sphere.
+pub fn uniform_sample_sphere(u: (f32, f32)) -> Vec3 {
+    let z = u.0 * 2.0 - 1.0;
+    let a = u.1 * 2.0 * f32::consts::PI;
     let r = (1.0 - z * z).sqrt();
     let (sina, cosa) = sinf_cosf(a);
     vec3(r * cosa, r * sina, z)
 }
 
+/// Maps a point in the unit cube to a point inside the unit sphere.
+pub fn uniform_sample_ball(u: (f32, f32), w: f32) -> Vec3 {
+    uniform_sample_sphere(u) * w.cbrt()
+}
+
 pub fn linear_to_srgb(rgb: (f32, f32, f32)) -> (u8, u8, u8) {
     let rgb = (rgb.0.max(0.0), rgb.1.max(0.0), rgb.2.max(0.0));
     let srgb = (
@@ -0,0 +1,194 @@
+use crate::exr::{read_exr, write_exr, Channel};
+use image;
+use pathtrace_rs::{
+    error::{Error, Result},
+    math::linear_to_srgb,
+};
+use std::path::Path;
+
+/// Combines float renders of the same scene made with different seeds, weighting each pixel by
+/// its sample count. Counts come from the `sampleCount` channel when present, so adaptively
+/// sampled renders merge correctly, or else from the `samples` header attribute.
+pub fn merge_renders(inputs: &[&str], output: &str) -> Result<()> {
+    let mut merged: Vec<(f32, f32, f32)> = Vec::new();
+    let mut counts: Vec<f32> = Vec::new();
+    let mut dimensions = None;
+    let mut total_samples = 0;
+    for input in inputs {
+        let image = read_exr(Path::new(input))
+            .map_err(|err| Error::Image(format!("couldn't read {}: {}", input, err)))?;
+        match dimensions {
+            None => {
+                dimensions = Some((image.width, image.height));
+                let len = image.width as usize * image.height as usize;
+                merged = vec![(0.0, 0.0, 0.0); len];
+                counts = vec![0.0; len];
+            }
+            Some((width, height)) if (width, height) != (image.width, image.height) => {
+                return Err(Error::Image(format!(
+                    "{} is {}x{} but the other renders are {}x{}",
+                    input, image.width, image.height, width, height
+                )));
+            }
+            _ => (),
+        }
+        let samples = image
+            .int_attribute("samples")
+            .ok_or_else(|| Error::Image(format!("{} has no sample count metadata", input)))?;
+        if samples <= 0 {
+            return Err(Error::Image(format!(
+                "{} has an invalid sample count of {}",
+                input, samples
+            )));
+        }
+        let (red, green, blue) = match (image.channel("R"), image.channel("G"), image.channel("B"))
+        {
+            (Some(red), Some(green), Some(blue)) => (red, green, blue),
+            _ => {
+                return Err(Error::Image(format!(
+                    "{} has no R, G and B channels",
+                    input
+                )))
+            }
+        };
+        let pixel_samples = image.channel("sampleCount");
+        for (index, (rgb, count)) in merged.iter_mut().zip(counts.iter_mut()).enumerate() {
+            let n = pixel_samples.map_or(samples as f32, |pixel_samples| pixel_samples[index]);
+            rgb.0 += red[index] * n;
+            rgb.1 += green[index] * n;
+            rgb.2 += blue[index] * n;
+            *count += n;
+        }
+        total_samples = i32::checked_add(total_samples, samples).ok_or_else(|| {
+            Error::Image("the merged sample count is too large to record".to_string())
+        })?;
+    }
+    let (width, height) =
+        dimensions.ok_or_else(|| Error::InvalidParams("no renders to merge".to_string()))?;
+
+    for (rgb, count) in merged.iter_mut().zip(counts.iter()) {
+        if *count > 0.0 {
+            *rgb = (rgb.0 / count, rgb.1 / count, rgb.2 / count);
+        }
+    }
+
+    let red: Vec<f32> = merged.iter().map(|rgb| rgb.0).collect();
+    let green: Vec<f32> = merged.iter().map(|rgb| rgb.1).collect();
+    let blue: Vec<f32> = merged.iter().map(|rgb| rgb.2).collect();
+    let mut channels = vec![
+        Channel {
+            name: "R".to_string(),
+            samples: &red,
+        },
+        Channel {
+            name: "G".to_string(),
+            samples: &green,
+        },
+        Channel {
+            name: "B".to_string(),
+            samples: &blue,
+        },
+        Channel {
+            name: "sampleCount".to_string(),
+            samples: &counts,
+        },
+    ];
+    write_exr(
+        Path::new(output),
+        width,
+        height,
+        &mut channels,
+        &[("samples", total_samples)],
+    )
+    .map_err(|err| Error::Image(format!("couldn't write {}: {}", output, err)))?;
+
+    // an sRGB preview alongside, EXR rows are already top down
+    let mut image_bytes = Vec::with_capacity(merged.len() * 3);
+    for rgb in &merged {
+        let srgb = linear_to_srgb(*rgb);
+        image_bytes.extend_from_slice(&[srgb.0, srgb.1, srgb.2]);
+    }
+    let preview = Path::new(output).with_extension("png");
+    image::save_buffer(&preview, &image_bytes, width, height, image::RGB(8))
+        .map_err(|err| Error::Image(format!("couldn't write {}: {}", preview.display(), err)))?;
+
+    println!(
+        "merged {} renders into {} with {} samples per pixel",
+        inputs.len(),
+        output,
+        total_samples
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    fn write_render(path: &Path, red: &[f32], sample_count: Option<&[f32]>, samples: i32) {
+        let green = vec![0.0; red.len()];
+        let blue = vec![1.0; red.len()];
+        let mut channels = vec![
+            Channel {
+                name: "R".to_string(),
+                samples: red,
+            },
+            Channel {
+                name: "G".to_string(),
+                samples: &green,
+            },
+            Channel {
+                name: "B".to_string(),
+                samples: &blue,
+            },
+        ];
+        if let Some(sample_count) = sample_count {
+            channels.push(Channel {
+                name: "sampleCount".to_string(),
+                samples: sample_count,
+            });
+        }
+        write_exr(path, 2, 1, &mut channels, &[("samples", samples)]).unwrap();
+    }
+
+    #[test]
+    fn merge_weights_by_sample_count() {
+        let dir = env::temp_dir().join(format!("pathtrace-merge-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("first.exr");
+        let second = dir.join("second.exr");
+        let merged = dir.join("merged.exr");
+        // the first render has the nominal 4 samples everywhere, the second was adaptively
+        // sampled so its per pixel counts win over its nominal 12
+        write_render(&first, &[1.0, 1.0], None, 4);
+        write_render(&second, &[3.0, 3.0], Some(&[12.0, 4.0]), 12);
+        let result = merge_renders(
+            &[first.to_str().unwrap(), second.to_str().unwrap()],
+            merged.to_str().unwrap(),
+        );
+        let image = read_exr(&merged);
+        fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        let image = image.unwrap();
+
+        assert_eq!(image.channel("R"), Some(&[2.5, 2.0][..]));
+        assert_eq!(image.channel("B"), Some(&[1.0, 1.0][..]));
+        assert_eq!(image.channel("sampleCount"), Some(&[16.0, 8.0][..]));
+        assert_eq!(image.int_attribute("samples"), Some(16));
+    }
+
+    #[test]
+    fn merge_rejects_bad_sample_counts() {
+        let dir = env::temp_dir().join(format!("pathtrace-merge-bad-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let negative = dir.join("negative.exr");
+        write_render(&negative, &[1.0, 1.0], None, -4);
+        let result = merge_renders(
+            &[negative.to_str().unwrap()],
+            dir.join("merged.exr").to_str().unwrap(),
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -1,22 +1,167 @@
 use crate::{
-    camera::Camera,
+    checkpoint::Checkpoint,
+    distributed,
+    exr::Channel,
+    output::{save_channels, save_pixels, save_rgb, with_suffix, ImageFormat, Output},
+    progress::{catch_interrupt, ProgressBar},
+    stats::{elapsed_secs, RenderReport},
+};
+use pathtrace_rs::{
+    camera::{Camera, StereoLayout},
+    denoise::denoise,
+    error::{Error, Result},
+    film::{AovFormat, Film},
     math::linear_to_srgb,
+    render_film,
     scene::{Params, Scene},
+    RenderLimits, StopReason,
+};
+use std::{
+    convert::TryFrom,
+    f32,
+    path::Path,
+    sync::{atomic::Ordering, Mutex},
+    time::{Duration, SystemTime},
 };
-use image;
-use std::time::SystemTime;
 
-pub fn render_offline(params: &Params, camera: &Camera, scene: &Scene) {
-    let mut rgb_buffer = vec![(0.0, 0.0, 0.0); (params.width * params.height) as usize];
+/// Settings for rendering frame after frame without a window, as the preview window does.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Progressive {
+    /// Stop after this many frames.
+    pub max_frames: Option<u32>,
+    /// Stop after this many seconds.
+    pub time_limit: Option<f32>,
+    /// Write the image rendered so far every this many frames.
+    pub snapshot_frames: Option<u32>,
+    /// Write the image rendered so far every this many seconds.
+    pub snapshot_secs: Option<f32>,
+}
 
+impl Progressive {
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: &str| Err(Error::InvalidParams(message.to_string()));
+        if self.max_frames == Some(0) {
+            return invalid("frame limit must be at least 1");
+        }
+        if self.snapshot_frames == Some(0) {
+            return invalid("snapshot frame interval must be at least 1");
+        }
+        if let Some(time_limit) = self.time_limit {
+            if !(time_limit > 0.0 && time_limit.is_finite()) {
+                return invalid("time limit must be a positive number of seconds");
+            }
+        }
+        if let Some(snapshot_secs) = self.snapshot_secs {
+            if !(snapshot_secs > 0.0 && snapshot_secs.is_finite()) {
+                return invalid("snapshot interval must be a positive number of seconds");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renders a single frame, or refines until the noise target is met when sampling adaptively.
+/// With `progressive` it keeps going until one of its limits or the noise target is reached,
+/// or until interrupted.
+fn render(
+    params: &Params,
+    camera: &dyn Camera,
+    scene: &Scene,
+    progressive: Option<&Progressive>,
+    mut checkpoint: Option<&mut Checkpoint>,
+    output: &Output,
+    report: &mut RenderReport,
+) -> Result<Film> {
+    let aovs = params.aovs.is_some();
+    let mut film = match checkpoint.as_ref() {
+        Some(checkpoint) => checkpoint.load_or_new(params.width, params.height, aovs)?,
+        None => Film::new(params.width, params.height, aovs),
+    };
+
+    let interrupted = catch_interrupt();
     let start_time = SystemTime::now();
-    let frame_num = 0; // only ever processing 1 frame in offline
-    let ray_count = scene.update(&params, &camera, frame_num, &mut rgb_buffer);
-    let elapsed = start_time
-        .elapsed()
-        .expect("SystemTime elapsed time failed");
-    let elapsed_secs =
-        elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0;
+    let start_frames = film.frames;
+    let mut last_snapshot = start_time;
+    let limits = match progressive {
+        Some(progressive) => RenderLimits {
+            frames: progressive.max_frames,
+            time_limit: progressive.time_limit,
+        },
+        // only processing 1 frame unless refining adaptively
+        None if params.adaptive.is_none() => RenderLimits {
+            frames: Some(1),
+            ..RenderLimits::default()
+        },
+        None => RenderLimits::default(),
+    };
+    // a bar per frame, started by the first tile and finished once the frame is done. With a
+    // frame limit the bars show and time the whole render rather than the frame.
+    let progress: Mutex<Option<ProgressBar>> = Mutex::new(None);
+    let (ray_counts, stop_reason) = render_film(
+        scene,
+        camera,
+        params,
+        &mut film,
+        limits,
+        &|render_progress| {
+            let mut progress = progress.lock().unwrap();
+            progress
+                .get_or_insert_with(|| {
+                    let label = format!("frame {}", start_frames + render_progress.frame + 1);
+                    match render_progress.frames {
+                        Some(_) => ProgressBar::new(label, start_time),
+                        None => ProgressBar::new(label, SystemTime::now()),
+                    }
+                })
+                .update(f64::from(render_progress.fraction()));
+        },
+        &|| interrupted.load(Ordering::SeqCst),
+        &mut |film, stop_reason| {
+            if let Some(progress) = progress.lock().unwrap().take() {
+                progress.finish();
+            }
+            if let Some(checkpoint) = checkpoint.as_mut() {
+                checkpoint.save_if_due(params.width, params.height, film)?;
+            }
+            if let Some(adaptive) = params.adaptive {
+                println!(
+                    "frame {}: {:.2}% of pixels above the noise threshold",
+                    film.frames,
+                    film.noisy_fraction(adaptive.threshold) * 100.0
+                );
+            }
+            let progressive = match (progressive, stop_reason) {
+                (Some(progressive), None) => progressive,
+                (Some(_), Some(reason)) => {
+                    println!("reached the {} after {} frames", reason.name(), film.frames);
+                    return Ok(());
+                }
+                (None, _) => return Ok(()),
+            };
+
+            let frames = film.frames - start_frames;
+            let frames_due = progressive
+                .snapshot_frames
+                .map_or(false, |snapshot_frames| frames % snapshot_frames == 0);
+            let secs_due = progressive.snapshot_secs.map_or(false, |snapshot_secs| {
+                elapsed_secs(last_snapshot) as f32 >= snapshot_secs
+            });
+            if frames_due || secs_due {
+                save_outputs(params, film, output, report)?;
+                println!("saved snapshot after {} frames", film.frames);
+                last_snapshot = SystemTime::now();
+            }
+            Ok(())
+        },
+    )?;
+    if let Some(progress) = progress.into_inner().unwrap() {
+        progress.finish();
+    }
+    if stop_reason == StopReason::Cancelled {
+        println!("interrupted, saving the partial image");
+    }
+    let elapsed_secs = elapsed_secs(start_time);
+    let ray_count = ray_counts.total();
 
     println!(
         "{:.2}secs {}rays {:.2}Mrays/s",
@@ -25,21 +170,445 @@ pub fn render_offline(params: &Params, camera: &Camera, scene: &Scene) {
         ray_count as f64 / 1_000_000.0 / elapsed_secs
     );
 
-    let mut image_bytes = Vec::with_capacity(rgb_buffer.len() * 3);
-    for row in rgb_buffer.chunks(params.width as usize).rev() {
-        for rgb in row {
-            let srgb = linear_to_srgb(*rgb);
-            image_bytes.push(srgb.0);
-            image_bytes.push(srgb.1);
-            image_bytes.push(srgb.2);
+    if let Some(checkpoint) = checkpoint {
+        checkpoint.save(params.width, params.height, &film)?;
+    }
+
+    report.frames += film.frames - start_frames;
+    report.rays += ray_counts;
+    report.times.render += elapsed_secs;
+
+    Ok(film)
+}
+
+/// The final colour image, denoised if requested.
+fn beauty(params: &Params, film: &Film, report: &mut RenderReport) -> Vec<(f32, f32, f32)> {
+    if params.denoise {
+        let denoise_start = SystemTime::now();
+        let rgb_buffer = denoise(params.width, params.height, &film);
+        let denoise_secs = elapsed_secs(denoise_start);
+        println!("denoised in {:.2}secs", denoise_secs);
+        report.times.denoise += denoise_secs;
+        rgb_buffer
+    } else {
+        film.color.clone()
+    }
+}
+
+fn unit_to_byte(x: f32) -> u8 {
+    (x.max(0.0).min(1.0) * 255.99) as u8
+}
+
+fn grey(x: f32) -> (u8, u8, u8) {
+    let value = unit_to_byte(x);
+    (value, value, value)
+}
+
+/// An arbitrary but stable colour for each id, black for the sky.
+fn id_color(id: Option<u32>) -> (u8, u8, u8) {
+    match id {
+        Some(id) => {
+            let hash = (id + 1).wrapping_mul(0x9e37_79b9);
+            ((hash >> 24) as u8, (hash >> 16) as u8, (hash >> 8) as u8)
         }
+        None => (0, 0, 0),
+    }
+}
+
+/// Writes the AOVs as one PNG per variable next to the image at `path`, normalising depth and
+/// sample count to their maximum.
+fn save_aov_pngs(params: &Params, film: &Film, path: &Path) -> Result<()> {
+    let (width, height) = (params.width, params.height);
+    let aovs = film.aovs.as_ref().expect("AOVs weren't recorded");
+    let max_depth = film
+        .features
+        .iter()
+        .fold(0.0, |max, features| features.depth.max(max));
+    let max_samples = film
+        .stats
+        .iter()
+        .map(|stats| stats.samples)
+        .max()
+        .unwrap_or(0);
+
+    let albedo: Vec<_> = film
+        .features
+        .iter()
+        .map(|features| linear_to_srgb(features.albedo))
+        .collect();
+    save_pixels(&with_suffix(path, "_albedo", "png"), width, height, &albedo)?;
+    let normal: Vec<_> = film
+        .features
+        .iter()
+        .map(|features| {
+            let n = features.normal;
+            (
+                unit_to_byte(n.0 * 0.5 + 0.5),
+                unit_to_byte(n.1 * 0.5 + 0.5),
+                unit_to_byte(n.2 * 0.5 + 0.5),
+            )
+        })
+        .collect();
+    save_pixels(&with_suffix(path, "_normal", "png"), width, height, &normal)?;
+    let depth: Vec<_> = film
+        .features
+        .iter()
+        .map(|features| grey(features.depth / max_depth))
+        .collect();
+    save_pixels(&with_suffix(path, "_depth", "png"), width, height, &depth)?;
+    let material_id: Vec<_> = aovs.iter().map(|aov| id_color(aov.material_id)).collect();
+    save_pixels(
+        &with_suffix(path, "_material_id", "png"),
+        width,
+        height,
+        &material_id,
+    )?;
+    let object_id: Vec<_> = aovs.iter().map(|aov| id_color(aov.object_id)).collect();
+    save_pixels(
+        &with_suffix(path, "_object_id", "png"),
+        width,
+        height,
+        &object_id,
+    )?;
+    let direct: Vec<_> = aovs.iter().map(|aov| linear_to_srgb(aov.direct)).collect();
+    save_pixels(&with_suffix(path, "_direct", "png"), width, height, &direct)?;
+    let indirect: Vec<_> = aovs
+        .iter()
+        .map(|aov| linear_to_srgb(aov.indirect))
+        .collect();
+    save_pixels(
+        &with_suffix(path, "_indirect", "png"),
+        width,
+        height,
+        &indirect,
+    )?;
+    let samples: Vec<_> = film
+        .stats
+        .iter()
+        .map(|stats| grey(stats.samples as f32 / max_samples as f32))
+        .collect();
+    save_pixels(
+        &with_suffix(path, "_samples", "png"),
+        width,
+        height,
+        &samples,
+    )
+}
+
+/// Writes the undenoised beauty pass and per pixel sample counts as float channels of an EXR,
+/// along with the denoised image and every AOV when they're enabled. The nominal samples per
+/// pixel are stored in the `samples` header attribute so that renders can be merged later.
+fn save_exr(
+    params: &Params,
+    film: &Film,
+    rgb_buffer: &[(f32, f32, f32)],
+    path: &Path,
+) -> Result<()> {
+    let (width, height) = (params.width as usize, params.height as usize);
+    // the film is stored bottom row first but EXR scanlines go top down
+    let order: Vec<usize> = (0..height)
+        .rev()
+        .flat_map(|y| (0..width).map(move |x| y * width + x))
+        .collect();
+    let channel = |f: &dyn Fn(usize) -> f32| -> Vec<f32> { order.iter().map(|&i| f(i)).collect() };
+    let id = |id: Option<u32>| id.map_or(-1.0, |id| id as f32);
+
+    let mut data: Vec<(&str, Vec<f32>)> = vec![
+        ("R", channel(&|i| film.color[i].0)),
+        ("G", channel(&|i| film.color[i].1)),
+        ("B", channel(&|i| film.color[i].2)),
+        ("sampleCount", channel(&|i| film.stats[i].samples as f32)),
+    ];
+    if params.denoise {
+        data.push(("denoised.R", channel(&|i| rgb_buffer[i].0)));
+        data.push(("denoised.G", channel(&|i| rgb_buffer[i].1)));
+        data.push(("denoised.B", channel(&|i| rgb_buffer[i].2)));
     }
-    image::save_buffer(
-        "output.png",
-        &image_bytes,
+    if let (Some(AovFormat::Exr), Some(aovs)) = (params.aovs, film.aovs.as_ref()) {
+        data.extend(vec![
+            ("albedo.R", channel(&|i| film.features[i].albedo.0)),
+            ("albedo.G", channel(&|i| film.features[i].albedo.1)),
+            ("albedo.B", channel(&|i| film.features[i].albedo.2)),
+            ("N.X", channel(&|i| film.features[i].normal.0)),
+            ("N.Y", channel(&|i| film.features[i].normal.1)),
+            ("N.Z", channel(&|i| film.features[i].normal.2)),
+            (
+                "Z",
+                channel(&|i| {
+                    let depth = film.features[i].depth;
+                    if depth > 0.0 {
+                        depth
+                    } else {
+                        f32::INFINITY
+                    }
+                }),
+            ),
+            ("materialId", channel(&|i| id(aovs[i].material_id))),
+            ("objectId", channel(&|i| id(aovs[i].object_id))),
+            ("direct.R", channel(&|i| aovs[i].direct.0)),
+            ("direct.G", channel(&|i| aovs[i].direct.1)),
+            ("direct.B", channel(&|i| aovs[i].direct.2)),
+            ("indirect.R", channel(&|i| aovs[i].indirect.0)),
+            ("indirect.G", channel(&|i| aovs[i].indirect.1)),
+            ("indirect.B", channel(&|i| aovs[i].indirect.2)),
+        ]);
+    }
+    let mut channels: Vec<Channel> = data
+        .iter()
+        .map(|(name, samples)| Channel {
+            name: name.to_string(),
+            samples,
+        })
+        .collect();
+    let samples = film
+        .frames
+        .checked_mul(params.samples)
+        .and_then(|samples| i32::try_from(samples).ok())
+        .ok_or_else(|| {
+            Error::Image(format!(
+                "couldn't write {}: {} frames of {} samples per pixel is too many to record",
+                path.display(),
+                film.frames,
+                params.samples
+            ))
+        })?;
+    save_channels(
+        path,
         params.width,
         params.height,
-        image::RGB(8),
+        &mut channels,
+        &[("samples", samples)],
     )
-    .expect("Failed to save output image");
+}
+
+pub fn render_offline(
+    params: &Params,
+    camera: &dyn Camera,
+    scene: &Scene,
+    checkpoint: Option<&mut Checkpoint>,
+    output: &Output,
+    report: &mut RenderReport,
+) -> Result<()> {
+    let film = render(params, camera, scene, None, checkpoint, output, report)?;
+    save_outputs(params, &film, output, report)
+}
+
+/// Renders frame after frame like the preview window would, writing the image periodically
+/// and once more when done.
+pub fn render_progressive(
+    params: &Params,
+    camera: &dyn Camera,
+    scene: &Scene,
+    progressive: &Progressive,
+    checkpoint: Option<&mut Checkpoint>,
+    output: &Output,
+    report: &mut RenderReport,
+) -> Result<()> {
+    let film = render(
+        params,
+        camera,
+        scene,
+        Some(progressive),
+        checkpoint,
+        output,
+        report,
+    )?;
+    save_outputs(params, &film, output, report)
+}
+
+/// Renders `passes` frames on remote workers and saves the merged result.
+pub fn render_distributed(
+    params: &Params,
+    address: &str,
+    passes: u32,
+    pass_timeout: Duration,
+    hash: u64,
+    output: &Output,
+    report: &mut RenderReport,
+) -> Result<()> {
+    let start_time = SystemTime::now();
+    let (film, ray_counts) =
+        distributed::run_coordinator(address, params, passes, hash, pass_timeout)
+            .map_err(Error::io("distributed render failed"))?;
+    let elapsed_secs = elapsed_secs(start_time);
+    println!(
+        "{:.2}secs {}rays {:.2}Mrays/s",
+        elapsed_secs,
+        ray_counts.total(),
+        ray_counts.total() as f64 / 1_000_000.0 / elapsed_secs
+    );
+    report.frames += film.frames;
+    report.rays += ray_counts;
+    report.times.render += elapsed_secs;
+    save_outputs(params, &film, output, report)
+}
+
+/// Writes the image and any float or AOV outputs that go alongside it.
+fn save_outputs(
+    params: &Params,
+    film: &Film,
+    output: &Output,
+    report: &mut RenderReport,
+) -> Result<()> {
+    let rgb_buffer = beauty(params, &film, report);
+    let write_start = SystemTime::now();
+    let path = output.path(params, film.frames);
+    if output.format == ImageFormat::Exr {
+        save_exr(params, film, &rgb_buffer, &path)?;
+    } else {
+        save_rgb(&path, output, params.width, params.height, &rgb_buffer)?;
+        if params.float_output || params.aovs == Some(AovFormat::Exr) {
+            save_exr(params, film, &rgb_buffer, &path.with_extension("exr"))?;
+        }
+    }
+    if params.aovs == Some(AovFormat::Png) {
+        save_aov_pngs(params, film, &path)?;
+    }
+    report.image = Some(path);
+    report.times.write += elapsed_secs(write_start);
+    Ok(())
+}
+
+/// Lays out the per pixel values of the left and right eyes side by side or one above the
+/// other, as rows bottom up like the film.
+fn stereo_pixels<T: Clone>(left: &[T], right: &[T], width: usize, layout: StereoLayout) -> Vec<T> {
+    match layout {
+        // rows are stored bottom up, so the right eye comes first
+        StereoLayout::TopBottom => right.iter().chain(left.iter()).cloned().collect(),
+        StereoLayout::SideBySide => left
+            .chunks(width)
+            .zip(right.chunks(width))
+            .flat_map(|(left_row, right_row)| left_row.iter().chain(right_row.iter()))
+            .cloned()
+            .collect(),
+    }
+}
+
+/// Renders each eye at the full `params` resolution and writes both to a single image, and to
+/// a float image when `params.float_output` is set.
+pub fn render_offline_stereo(
+    params: &Params,
+    left: &dyn Camera,
+    right: &dyn Camera,
+    layout: StereoLayout,
+    scene: &Scene,
+    output: &Output,
+    report: &mut RenderReport,
+) -> Result<()> {
+    let left_film = render(params, left, scene, None, None, output, report)?;
+    // the right eye would come out black, so don't write a mismatched pair
+    if catch_interrupt().load(Ordering::SeqCst) {
+        return Err(Error::Interrupted {
+            stage: "the right eye was rendered, no image was saved".to_string(),
+        });
+    }
+    let left_buffer = beauty(params, &left_film, report);
+    let right_film = render(params, right, scene, None, None, output, report)?;
+    let right_buffer = beauty(params, &right_film, report);
+    let write_start = SystemTime::now();
+    let path = output.path(params, left_film.frames);
+
+    let width = params.width as usize;
+    let rgb_buffer = stereo_pixels(&left_buffer, &right_buffer, width, layout);
+    let stereo_params = match layout {
+        StereoLayout::TopBottom => Params {
+            height: params.height * 2,
+            ..*params
+        },
+        StereoLayout::SideBySide => Params {
+            width: params.width * 2,
+            ..*params
+        },
+    };
+    // AOVs can't be used with stereo so only the beauty pass and sample counts are kept
+    let film = Film {
+        frames: left_film.frames,
+        sample_offset: left_film.sample_offset,
+        color: stereo_pixels(&left_film.color, &right_film.color, width, layout),
+        weight: stereo_pixels(&left_film.weight, &right_film.weight, width, layout),
+        stats: stereo_pixels(&left_film.stats, &right_film.stats, width, layout),
+        features: stereo_pixels(&left_film.features, &right_film.features, width, layout),
+        aovs: None,
+    };
+    if output.format == ImageFormat::Exr {
+        save_exr(&stereo_params, &film, &rgb_buffer, &path)?;
+    } else {
+        save_rgb(
+            &path,
+            output,
+            stereo_params.width,
+            stereo_params.height,
+            &rgb_buffer,
+        )?;
+        if params.float_output {
+            save_exr(
+                &stereo_params,
+                &film,
+                &rgb_buffer,
+                &path.with_extension("exr"),
+            )?;
+        }
+    }
+    report.image = Some(path);
+    report.times.write += elapsed_secs(write_start);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exr::read_exr;
+    use std::{env, fs};
+
+    #[test]
+    fn stereo_pixels_follow_the_layout() {
+        // 2x2 eyes, rows bottom up
+        let left = [1, 2, 3, 4];
+        let right = [5, 6, 7, 8];
+        assert_eq!(
+            stereo_pixels(&left, &right, 2, StereoLayout::SideBySide),
+            vec![1, 2, 5, 6, 3, 4, 7, 8]
+        );
+        // the left eye is on top, which is the end of a bottom up buffer
+        assert_eq!(
+            stereo_pixels(&left, &right, 2, StereoLayout::TopBottom),
+            vec![5, 6, 7, 8, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn exr_keeps_aovs_and_sample_count() {
+        let mut params = Params::new(2, 2);
+        params.aovs = Some(AovFormat::Exr);
+        let mut film = Film::new(2, 2, true);
+        film.frames = 3;
+        // the film's first pixel is bottom left, the start of the last EXR scanline
+        film.color[0] = (0.5, 0.25, 2.0);
+        film.features[0].albedo = (0.8, 0.6, 0.4);
+        film.features[0].normal = (0.0, 1.0, 0.0);
+        film.features[0].depth = 4.0;
+        film.aovs.as_mut().unwrap()[0].object_id = Some(7);
+        let path = env::temp_dir().join(format!("pathtrace-aovs-{}.exr", std::process::id()));
+        let result = save_exr(&params, &film, &film.color, &path);
+        let image = read_exr(&path);
+        fs::remove_file(&path).unwrap();
+        result.unwrap();
+        let image = image.unwrap();
+
+        assert_eq!(image.int_attribute("samples"), Some(12));
+        let pixel = |name: &str| image.channel(name).unwrap()[2];
+        assert_eq!((pixel("R"), pixel("G"), pixel("B")), (0.5, 0.25, 2.0));
+        assert_eq!(
+            (pixel("albedo.R"), pixel("albedo.G"), pixel("albedo.B")),
+            (0.8, 0.6, 0.4)
+        );
+        assert_eq!((pixel("N.X"), pixel("N.Y"), pixel("N.Z")), (0.0, 1.0, 0.0));
+        assert_eq!(pixel("Z"), 4.0);
+        assert_eq!(pixel("objectId"), 7.0);
+        assert_eq!(pixel("materialId"), -1.0);
+        assert_eq!(image.channel("Z").unwrap()[0], f32::INFINITY);
+        for name in &["direct.R", "indirect.B", "sampleCount"] {
+            assert!(image.channel(name).is_some(), "no {} channel", name);
+        }
+    }
 }
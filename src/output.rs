@@ -0,0 +1,385 @@
+use crate::exr::{write_exr, Channel};
+use image;
+use pathtrace_rs::{
+    error::{Error, Result},
+    math::linear_to_srgb,
+    scene::Params,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const PLACEHOLDERS: [&str; 6] = ["preset", "width", "height", "spp", "frame", "timestamp"];
+
+/// Image file formats the render can be written in, chosen by the output file's extension.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Bmp,
+    Ppm,
+    /// Linear floating point OpenEXR, with the same channels as `--float-output`.
+    Exr,
+}
+
+impl ImageFormat {
+    pub fn from_extension(extension: &str) -> Option<ImageFormat> {
+        match extension.to_ascii_lowercase().as_str() {
+            "png" => Some(ImageFormat::Png),
+            "bmp" => Some(ImageFormat::Bmp),
+            "ppm" => Some(ImageFormat::Ppm),
+            "exr" => Some(ImageFormat::Exr),
+            _ => None,
+        }
+    }
+}
+
+/// How linear colours are brought into the displayable range before 8 bit images are written.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ToneMapping {
+    /// Colours above 1 are clamped.
+    Clamp,
+    /// `c / (1 + c)` per channel.
+    Reinhard,
+    /// Narkowicz's fit of the ACES filmic curve.
+    Aces,
+}
+
+impl ToneMapping {
+    pub const NAMES: [&'static str; 3] = ["clamp", "reinhard", "aces"];
+
+    pub fn from_name(name: &str) -> Option<ToneMapping> {
+        match name {
+            "clamp" => Some(ToneMapping::Clamp),
+            "reinhard" => Some(ToneMapping::Reinhard),
+            "aces" => Some(ToneMapping::Aces),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ToneMapping::Clamp => "clamp",
+            ToneMapping::Reinhard => "reinhard",
+            ToneMapping::Aces => "aces",
+        }
+    }
+}
+
+/// The tone mapping applied to PNG, BMP and PPM images, EXR images stay linear.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ToneMap {
+    pub mapping: ToneMapping,
+    /// Exposure adjustment in stops, applied before `mapping`.
+    pub exposure: f32,
+}
+
+impl Default for ToneMap {
+    fn default() -> ToneMap {
+        ToneMap {
+            mapping: ToneMapping::Clamp,
+            exposure: 0.0,
+        }
+    }
+}
+
+impl ToneMap {
+    fn map(&self, rgb: (f32, f32, f32)) -> (f32, f32, f32) {
+        let scale = self.exposure.exp2();
+        let curve = |c: f32| {
+            let c = c * scale;
+            match self.mapping {
+                ToneMapping::Clamp => c,
+                ToneMapping::Reinhard => c / (1.0 + c),
+                ToneMapping::Aces => (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14),
+            }
+        };
+        (curve(rgb.0), curve(rgb.1), curve(rgb.2))
+    }
+}
+
+/// Converts seconds since the Unix epoch to a UTC `YYYYMMDD-HHMMSS` string.
+fn format_timestamp(secs: u64) -> String {
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let days = secs / 86400 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    let secs_of_day = secs % 86400;
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+/// Where rendered images are written, from a template such as
+/// `renders/{preset}-{width}x{height}-{spp}spp.png`. The placeholders are `{preset}`, `{width}`,
+/// `{height}`, `{spp}`, `{frame}` and `{timestamp}`, the time the render started.
+#[derive(Clone, Debug)]
+pub struct Output {
+    template: String,
+    pub format: ImageFormat,
+    pub tone_map: ToneMap,
+    preset: String,
+    timestamp: String,
+}
+
+impl Output {
+    pub fn new(template: &str, preset: &str) -> Result<Output> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}').ok_or_else(|| {
+                Error::InvalidParams(format!("unclosed '{{' in output path '{}'", template))
+            })? + start;
+            let name = &rest[start + 1..end];
+            if !PLACEHOLDERS.contains(&name) {
+                return Err(Error::InvalidParams(format!(
+                    "unknown placeholder {{{}}} in output path, expected one of {{{}}}",
+                    name,
+                    PLACEHOLDERS.join("}, {")
+                )));
+            }
+            rest = &rest[end + 1..];
+        }
+        let extension = Path::new(template)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("");
+        let format = ImageFormat::from_extension(extension).ok_or_else(|| {
+            Error::InvalidParams(format!(
+                "can't write '{}' images, use a png, bmp, ppm or exr extension",
+                extension
+            ))
+        })?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        Ok(Output {
+            template: template.to_string(),
+            format,
+            tone_map: ToneMap::default(),
+            preset: preset.to_string(),
+            timestamp: format_timestamp(now),
+        })
+    }
+
+    /// The image path for a render with `params` after `frames` frames.
+    pub fn path(&self, params: &Params, frames: u32) -> PathBuf {
+        PathBuf::from(
+            self.template
+                .replace("{preset}", &self.preset)
+                .replace("{width}", &params.width.to_string())
+                .replace("{height}", &params.height.to_string())
+                .replace(
+                    "{spp}",
+                    &(u64::from(frames) * u64::from(params.samples)).to_string(),
+                )
+                .replace("{frame}", &frames.to_string())
+                .replace("{timestamp}", &self.timestamp),
+        )
+    }
+}
+
+/// `path` with `suffix` appended to the file name and its extension replaced, for writing extra
+/// images next to the main one.
+pub fn with_suffix(path: &Path, suffix: &str, extension: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map_or(String::new(), |stem| stem.to_string_lossy().into_owned());
+    path.with_file_name(format!("{}{}.{}", stem, suffix, extension))
+}
+
+fn create_parent(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs::create_dir_all(parent).map_err(
+            Error::io(format!("couldn't create directory {}", parent.display())),
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Writes 8 bit pixels stored bottom row first, in the format given by the path's extension.
+pub fn save_pixels(path: &Path, width: u32, height: u32, pixels: &[(u8, u8, u8)]) -> Result<()> {
+    create_parent(path)?;
+    let mut image_bytes = Vec::with_capacity(pixels.len() * 3);
+    for row in pixels.chunks(width as usize).rev() {
+        for rgb in row {
+            image_bytes.push(rgb.0);
+            image_bytes.push(rgb.1);
+            image_bytes.push(rgb.2);
+        }
+    }
+    image::save_buffer(path, &image_bytes, width, height, image::RGB(8))
+        .map_err(|err| Error::Image(format!("couldn't write {}: {}", path.display(), err)))
+}
+
+/// Writes float channels stored top row first as an OpenEXR file.
+pub fn save_channels(
+    path: &Path,
+    width: u32,
+    height: u32,
+    channels: &mut [Channel],
+    int_attributes: &[(&str, i32)],
+) -> Result<()> {
+    create_parent(path)?;
+    write_exr(path, width, height, channels, int_attributes)
+        .map_err(|err| Error::Image(format!("couldn't write {}: {}", path.display(), err)))
+}
+
+/// Writes linear colours stored bottom row first in `output`'s format, tone mapped and converted
+/// to sRGB unless it's EXR.
+pub fn save_rgb(
+    path: &Path,
+    output: &Output,
+    width: u32,
+    height: u32,
+    rgb_buffer: &[(f32, f32, f32)],
+) -> Result<()> {
+    if output.format != ImageFormat::Exr {
+        let pixels: Vec<(u8, u8, u8)> = rgb_buffer
+            .iter()
+            .map(|rgb| linear_to_srgb(output.tone_map.map(*rgb)))
+            .collect();
+        return save_pixels(path, width, height, &pixels);
+    }
+    let top_down: Vec<(f32, f32, f32)> = rgb_buffer
+        .chunks(width as usize)
+        .rev()
+        .flat_map(|row| row.iter().cloned())
+        .collect();
+    let red: Vec<f32> = top_down.iter().map(|rgb| rgb.0).collect();
+    let green: Vec<f32> = top_down.iter().map(|rgb| rgb.1).collect();
+    let blue: Vec<f32> = top_down.iter().map(|rgb| rgb.2).collect();
+    let mut channels = vec![
+        Channel {
+            name: "R".to_string(),
+            samples: &red,
+        },
+        Channel {
+            name: "G".to_string(),
+            samples: &green,
+        },
+        Channel {
+            name: "B".to_string(),
+            samples: &blue,
+        },
+    ];
+    save_channels(path, width, height, &mut channels, &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn path_expands_every_placeholder() {
+        let output = Output::new(
+            "renders/{preset}/{width}x{height}-{spp}spp-{frame}-{timestamp}.png",
+            "cornell",
+        )
+        .unwrap();
+        let mut params = Params::new(320, 240);
+        params.samples = 16;
+        let expected = format!("renders/cornell/320x240-48spp-3-{}.png", output.timestamp);
+        assert_eq!(output.path(&params, 3), PathBuf::from(expected));
+        assert_eq!(output.timestamp.len(), "YYYYMMDD-HHMMSS".len());
+
+        let plain = Output::new("out.png", "cornell").unwrap();
+        assert_eq!(plain.path(&params, 3), PathBuf::from("out.png"));
+    }
+
+    #[test]
+    fn spp_does_not_overflow() {
+        let output = Output::new("{spp}.exr", "cornell").unwrap();
+        let mut params = Params::new(1, 1);
+        params.samples = u32::MAX;
+        assert_eq!(
+            output.path(&params, 4),
+            PathBuf::from(format!("{}.exr", 4 * u64::from(u32::MAX)))
+        );
+    }
+
+    #[test]
+    fn format_comes_from_the_extension() {
+        let format = |template| Output::new(template, "cornell").map(|output| output.format);
+        assert_eq!(format("a.png").unwrap(), ImageFormat::Png);
+        assert_eq!(format("a.BMP").unwrap(), ImageFormat::Bmp);
+        assert_eq!(format("dir.d/a.ppm").unwrap(), ImageFormat::Ppm);
+        assert_eq!(format("{preset}.Exr").unwrap(), ImageFormat::Exr);
+        assert!(format("a.jpg").is_err());
+        assert!(format("a").is_err());
+        assert!(format("a.png/b").is_err());
+    }
+
+    #[test]
+    fn bad_placeholders_are_rejected() {
+        for template in ["{size}.png", "{preset.png", "a-{}.png", "{Width}.png"].iter() {
+            match Output::new(template, "cornell") {
+                Err(Error::InvalidParams(_)) => {}
+                result => panic!("{}: {:?}", template, result.map(|output| output.template)),
+            }
+        }
+    }
+
+    #[test]
+    fn timestamps_are_utc_calendar_dates() {
+        assert_eq!(format_timestamp(0), "19700101-000000");
+        assert_eq!(format_timestamp(951_786_061), "20000229-010101");
+        assert_eq!(format_timestamp(1_700_000_000), "20231114-221320");
+        assert_eq!(format_timestamp(4_102_444_799), "20991231-235959");
+    }
+
+    #[test]
+    fn suffixes_replace_the_extension() {
+        assert_eq!(
+            with_suffix(Path::new("renders/a.png"), "-albedo", "exr"),
+            PathBuf::from("renders/a-albedo.exr")
+        );
+        assert_eq!(
+            with_suffix(Path::new("a"), "-depth", "png"),
+            PathBuf::from("a-depth.png")
+        );
+    }
+
+    #[test]
+    fn tone_maps_keep_black_and_order() {
+        let names = ToneMapping::NAMES.iter();
+        for mapping in names.map(|name| ToneMapping::from_name(name).unwrap()) {
+            let tone_map = ToneMap {
+                mapping,
+                exposure: 0.0,
+            };
+            assert_eq!(ToneMapping::from_name(mapping.name()), Some(mapping));
+            assert!(tone_map.map((0.0, 0.0, 0.0)).0.abs() < 1e-6);
+            let (dark, mid, bright) = tone_map.map((0.1, 0.5, 4.0));
+            assert!(0.0 < dark && dark < mid && mid < bright, "{:?}", mapping);
+        }
+        let brighter = ToneMap {
+            mapping: ToneMapping::Clamp,
+            exposure: 1.0,
+        };
+        assert_eq!(brighter.map((0.25, 0.5, 1.0)), (0.5, 1.0, 2.0));
+        assert_eq!(ToneMap::default().map((0.25, 0.5, 1.0)), (0.25, 0.5, 1.0));
+        let reinhard = ToneMap {
+            mapping: ToneMapping::Reinhard,
+            exposure: 0.0,
+        };
+        assert_eq!(reinhard.map((1.0, 3.0, 0.0)), (0.5, 0.75, 0.0));
+    }
+}
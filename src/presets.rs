@@ -1,24 +1,33 @@
 use crate::{
-    camera::Camera,
-    collision::sphere,
-    material::MaterialKind,
+    camera::CameraDesc,
+    collision::{sphere, Csg, Cuboid, Sphere},
+    error::{Error, Result},
+    material::{Material, MaterialKind},
     scene::{Params, Scene},
+    sdf::{Sdf, SdfSolid},
 };
-use glam::vec3;
+use glam::{vec3, Vec3};
 use rand::{Rng, SeedableRng};
 use rand_xoshiro::Xoshiro256Plus;
 
-pub fn from_name(name: &str, params: &Params) -> Option<(Scene, Camera)> {
+/// Names accepted by `from_name`.
+pub const NAMES: [&str; 6] = ["random", "small", "aras", "smallpt", "csg", "sdf"];
+
+/// Builds the preset called `name`, failing if there's no such preset or the CPU doesn't support
+/// `params.target_feature`.
+pub fn from_name(name: &str, params: &Params) -> Result<(Scene, CameraDesc)> {
     match name {
-        "random" => Some(random(params)),
-        "small" => Some(small(params)),
-        "aras" => Some(aras_p(params)),
-        "smallpt" => Some(smallpt(params)),
-        _ => None,
+        "random" => random(params),
+        "small" => small(params),
+        "aras" => aras_p(params),
+        "smallpt" => smallpt(params),
+        "csg" => csg(params),
+        "sdf" => sdf(params),
+        _ => Err(Error::UnknownPreset(name.to_string())),
     }
 }
 
-pub fn random(params: &Params) -> (Scene, Camera) {
+pub fn random(params: &Params) -> Result<(Scene, CameraDesc)> {
     let mut rng = if params.random_seed {
         Xoshiro256Plus::from_seed(rand::random())
     } else {
@@ -29,7 +38,7 @@ pub fn random(params: &Params) -> (Scene, Camera) {
     let lookat = vec3(0.0, 0.0, 0.0);
     let dist_to_focus = 10.0;
     let aperture = 0.1;
-    let camera = Camera::new(
+    let camera = CameraDesc::new(
         lookfrom,
         lookat,
         vec3(0.0, 1.0, 0.0),
@@ -118,16 +127,16 @@ pub fn random(params: &Params) -> (Scene, Camera) {
         None,
     ));
 
-    let scene = Scene::new(&spheres);
-    (scene, camera)
+    let scene = Scene::new(&spheres, params.target_feature)?;
+    Ok((scene, camera))
 }
 
-pub fn small(params: &Params) -> (Scene, Camera) {
+pub fn small(params: &Params) -> Result<(Scene, CameraDesc)> {
     let lookfrom = vec3(3.0, 3.0, 2.0);
     let lookat = vec3(0.0, 0.0, -1.0);
     let dist_to_focus = (lookfrom - lookat).length();
     let aperture = 0.1;
-    let camera = Camera::new(
+    let camera = CameraDesc::new(
         lookfrom,
         lookat,
         vec3(0.0, 1.0, 0.0),
@@ -177,17 +186,17 @@ pub fn small(params: &Params) -> (Scene, Camera) {
         ),
     ];
 
-    let scene = Scene::new(&spheres);
-    (scene, camera)
+    let scene = Scene::new(&spheres, params.target_feature)?;
+    Ok((scene, camera))
 }
 
-pub fn aras_p(params: &Params) -> (Scene, Camera) {
+pub fn aras_p(params: &Params) -> Result<(Scene, CameraDesc)> {
     let lookfrom = vec3(0.0, 2.0, 3.0);
     let lookat = vec3(0.0, 0.0, 0.0);
     let dist_to_focus = 3.0;
     let aperture = 0.02;
     let fov = 60.0;
-    let camera = Camera::new(
+    let camera = CameraDesc::new(
         lookfrom,
         lookat,
         vec3(0.0, 1.0, 0.0),
@@ -589,17 +598,17 @@ pub fn aras_p(params: &Params) -> (Scene, Camera) {
         ),
     ];
 
-    let scene = Scene::new(&spheres);
-    (scene, camera)
+    let scene = Scene::new(&spheres, params.target_feature)?;
+    Ok((scene, camera))
 }
 
-pub fn smallpt(params: &Params) -> (Scene, Camera) {
+pub fn smallpt(params: &Params) -> Result<(Scene, CameraDesc)> {
     let lookfrom = vec3(50.0, 52.0, 295.6);
     let lookat = vec3(50.0, 33.0, 0.0);
     let dist_to_focus = 100.0;
     let aperture = 0.05;
     let fov = 30.0;
-    let camera = Camera::new(
+    let camera = CameraDesc::new(
         lookfrom,
         lookat,
         vec3(0.0, 1.0, 0.0),
@@ -683,6 +692,232 @@ pub fn smallpt(params: &Params) -> (Scene, Camera) {
         ), //Lite
     ];
 
-    let scene = Scene::new(&spheres);
-    (scene, camera)
+    let scene = Scene::new(&spheres, params.target_feature)?;
+    Ok((scene, camera))
+}
+
+pub fn csg(params: &Params) -> Result<(Scene, CameraDesc)> {
+    let lookfrom = vec3(0.0, 1.5, 4.0);
+    let lookat = vec3(0.0, 0.25, 0.0);
+    let dist_to_focus = (lookfrom - lookat).length();
+    let aperture = 0.02;
+    let fov = 40.0;
+    let camera = CameraDesc::new(
+        lookfrom,
+        lookat,
+        vec3(0.0, 1.0, 0.0),
+        fov,
+        params.width as f32 / params.height as f32,
+        aperture,
+        dist_to_focus,
+    );
+
+    let spheres = [
+        sphere(
+            vec3(0.0, -100.5, -1.0),
+            100.0,
+            MaterialKind::Lambertian {
+                albedo: vec3(0.8, 0.8, 0.8),
+            },
+            None,
+        ),
+        sphere(
+            vec3(-1.0, 4.0, 1.0),
+            0.5,
+            MaterialKind::Lambertian {
+                albedo: vec3(0.0, 0.0, 0.0),
+            },
+            Some(vec3(15.0, 15.0, 15.0)),
+        ),
+    ];
+
+    let mut scene = Scene::new(&spheres, params.target_feature)?;
+
+    // biconvex lens
+    let lens = Csg::Sphere(Sphere {
+        centre: vec3(-1.2, 0.1, -0.85),
+        radius: 1.0,
+    })
+    .intersection(Csg::Sphere(Sphere {
+        centre: vec3(-1.2, 0.1, 0.85),
+        radius: 1.0,
+    }));
+    scene.add_solid(
+        lens,
+        Material {
+            kind: MaterialKind::Dielectric { ref_idx: 1.5 },
+            emissive: Vec3::zero(),
+        },
+    );
+
+    // hollow glass shell with the top cut off
+    let shell = Csg::Sphere(Sphere {
+        centre: vec3(0.0, 0.0, 0.0),
+        radius: 0.5,
+    })
+    .difference(Csg::Sphere(Sphere {
+        centre: vec3(0.0, 0.0, 0.0),
+        radius: 0.45,
+    }))
+    .difference(Csg::Cuboid(Cuboid {
+        min: vec3(-1.0, 0.3, -1.0),
+        max: vec3(1.0, 1.0, 1.0),
+    }));
+    scene.add_solid(
+        shell,
+        Material {
+            kind: MaterialKind::Dielectric { ref_idx: 1.5 },
+            emissive: Vec3::zero(),
+        },
+    );
+
+    // metal box with a spherical bite taken out of the corner
+    let bitten = Csg::Cuboid(Cuboid {
+        min: vec3(0.8, -0.5, -0.4),
+        max: vec3(1.6, 0.3, 0.4),
+    })
+    .difference(Csg::Sphere(Sphere {
+        centre: vec3(0.8, 0.3, 0.4),
+        radius: 0.45,
+    }))
+    .union(Csg::Sphere(Sphere {
+        centre: vec3(1.2, 0.45, 0.0),
+        radius: 0.15,
+    }));
+    scene.add_solid(
+        bitten,
+        Material {
+            kind: MaterialKind::Metal {
+                albedo: vec3(0.8, 0.6, 0.4),
+                fuzz: 0.1,
+            },
+            emissive: Vec3::zero(),
+        },
+    );
+
+    Ok((scene, camera))
+}
+
+pub fn sdf(params: &Params) -> Result<(Scene, CameraDesc)> {
+    let lookfrom = vec3(0.0, 2.0, 4.5);
+    let lookat = vec3(0.0, 0.3, 0.0);
+    let dist_to_focus = (lookfrom - lookat).length();
+    let aperture = 0.02;
+    let fov = 40.0;
+    let camera = CameraDesc::new(
+        lookfrom,
+        lookat,
+        vec3(0.0, 1.0, 0.0),
+        fov,
+        params.width as f32 / params.height as f32,
+        aperture,
+        dist_to_focus,
+    );
+
+    let spheres = [
+        sphere(
+            vec3(0.0, -100.5, -1.0),
+            100.0,
+            MaterialKind::Lambertian {
+                albedo: vec3(0.8, 0.8, 0.8),
+            },
+            None,
+        ),
+        sphere(
+            vec3(1.0, 4.0, 2.0),
+            0.5,
+            MaterialKind::Lambertian {
+                albedo: vec3(0.0, 0.0, 0.0),
+            },
+            Some(vec3(15.0, 15.0, 15.0)),
+        ),
+        sphere(
+            vec3(1.6, 0.0, 0.8),
+            0.5,
+            MaterialKind::Dielectric { ref_idx: 1.5 },
+            None,
+        ),
+    ];
+
+    let mut scene = Scene::new(&spheres, params.target_feature)?;
+
+    const EPSILON: f32 = 0.0005;
+    const MAX_STEPS: u32 = 256;
+
+    // rounded box trimmed by a sphere with a dimple cut out of the top
+    let rounded_box = Sdf::RoundBox {
+        half_extents: vec3(0.3, 0.3, 0.3),
+        radius: 0.1,
+    }
+    .intersection(Sdf::Sphere { radius: 0.5 })
+    .difference(Sdf::Sphere { radius: 0.15 }.translate(vec3(0.0, 0.45, 0.0)))
+    .translate(vec3(-1.6, -0.1, 0.0));
+    scene.add_sdf(
+        SdfSolid::new(rounded_box, EPSILON, MAX_STEPS),
+        Material {
+            kind: MaterialKind::Lambertian {
+                albedo: vec3(0.8, 0.3, 0.3),
+            },
+            emissive: Vec3::zero(),
+        },
+    );
+
+    // a pair of stacked rings
+    let torus = Sdf::Torus {
+        major_radius: 0.4,
+        minor_radius: 0.1,
+    }
+    .union(
+        Sdf::Torus {
+            major_radius: 0.25,
+            minor_radius: 0.07,
+        }
+        .translate(vec3(0.0, 0.17, 0.0)),
+    )
+    .translate(vec3(-0.6, -0.4, 0.9));
+    scene.add_sdf(
+        SdfSolid::new(torus, EPSILON, MAX_STEPS),
+        Material {
+            kind: MaterialKind::Metal {
+                albedo: vec3(0.9, 0.8, 0.5),
+                fuzz: 0.05,
+            },
+            emissive: Vec3::zero(),
+        },
+    );
+
+    let blob = Sdf::Sphere { radius: 0.3 }
+        .translate(vec3(-0.15, 0.0, 0.0))
+        .smooth_union(
+            Sdf::Sphere { radius: 0.2 }.translate(vec3(0.25, 0.15, 0.0)),
+            0.2,
+        )
+        .translate(vec3(0.8, -0.2, -0.6));
+    scene.add_sdf(
+        SdfSolid::new(blob, EPSILON, MAX_STEPS),
+        Material {
+            kind: MaterialKind::Lambertian {
+                albedo: vec3(0.3, 0.4, 0.8),
+            },
+            emissive: Vec3::zero(),
+        },
+    );
+
+    let mandelbulb = Sdf::Mandelbulb {
+        power: 8.0,
+        iterations: 8,
+    }
+    .scale(0.6)
+    .translate(vec3(0.0, 0.2, 0.0));
+    scene.add_sdf(
+        SdfSolid::new(mandelbulb, EPSILON, MAX_STEPS),
+        Material {
+            kind: MaterialKind::Lambertian {
+                albedo: vec3(0.7, 0.7, 0.7),
+            },
+            emissive: Vec3::zero(),
+        },
+    );
+
+    Ok((scene, camera))
 }
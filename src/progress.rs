@@ -0,0 +1,172 @@
+use crate::stats::elapsed_secs;
+use std::{
+    io::{self, Write},
+    sync::{atomic::AtomicBool, Mutex, Once},
+    time::SystemTime,
+};
+
+const BAR_WIDTH: usize = 30;
+// minimum seconds between redraws so worker threads don't fight over the terminal
+const REDRAW_INTERVAL: f64 = 0.1;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static INSTALL_HANDLER: Once = Once::new();
+
+#[cfg(unix)]
+mod platform {
+    use super::INTERRUPTED;
+    use std::sync::atomic::Ordering;
+
+    const SIGINT: i32 = 2;
+    const SIG_DFL: usize = 0;
+
+    extern "C" {
+        fn signal(signum: i32, handler: usize) -> usize;
+    }
+
+    extern "C" fn on_interrupt(_: i32) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        // restore the default handler so a second Ctrl-C kills the process
+        unsafe {
+            signal(SIGINT, SIG_DFL);
+        }
+    }
+
+    pub fn install() {
+        unsafe {
+            signal(SIGINT, on_interrupt as extern "C" fn(i32) as usize);
+        }
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::INTERRUPTED;
+    use std::sync::atomic::Ordering;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn SetConsoleCtrlHandler(
+            handler: Option<unsafe extern "system" fn(u32) -> i32>,
+            add: i32,
+        ) -> i32;
+    }
+
+    unsafe extern "system" fn on_interrupt(_: u32) -> i32 {
+        // returning false on a second Ctrl-C lets the default handler kill the process
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            0
+        } else {
+            1
+        }
+    }
+
+    pub fn install() {
+        unsafe {
+            SetConsoleCtrlHandler(Some(on_interrupt), 1);
+        }
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+mod platform {
+    pub fn install() {}
+}
+
+/// Makes Ctrl-C set the returned flag rather than killing the process, so a render can stop
+/// early and still save what it has. Pressing it again exits immediately. The handler is only
+/// installed by the first call.
+pub fn catch_interrupt() -> &'static AtomicBool {
+    INSTALL_HANDLER.call_once(platform::install);
+    &INTERRUPTED
+}
+
+fn format_secs(secs: f64) -> String {
+    if secs < 60.0 {
+        format!("{:.1}s", secs)
+    } else {
+        let secs = secs as u64;
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    }
+}
+
+/// Seconds left to finish when `fraction` of the work took `elapsed` seconds.
+fn remaining_secs(elapsed: f64, fraction: f64) -> Option<f64> {
+    if fraction > 0.0 {
+        Some(elapsed / fraction - elapsed)
+    } else {
+        None
+    }
+}
+
+/// A single line terminal progress bar with an estimate of the time remaining.
+pub struct ProgressBar {
+    label: String,
+    start_time: SystemTime,
+    last_draw: Mutex<Option<f64>>,
+}
+
+impl ProgressBar {
+    /// A bar for work that began at `start_time`, which the estimate is based on.
+    pub fn new(label: String, start_time: SystemTime) -> ProgressBar {
+        ProgressBar {
+            label,
+            start_time,
+            last_draw: Mutex::new(None),
+        }
+    }
+
+    /// Redraws the bar with `fraction` of the work done, may be called from any thread.
+    pub fn update(&self, fraction: f64) {
+        let elapsed = elapsed_secs(self.start_time);
+        let mut last_draw = self.last_draw.lock().unwrap();
+        if let Some(last_draw) = *last_draw {
+            if fraction < 1.0 && elapsed - last_draw < REDRAW_INTERVAL {
+                return;
+            }
+        }
+        *last_draw = Some(elapsed);
+
+        let fraction = fraction.max(0.0).min(1.0);
+        let filled = (fraction * BAR_WIDTH as f64) as usize;
+        let eta = remaining_secs(elapsed, fraction).map_or_else(|| "?".to_string(), format_secs);
+        let mut stderr = io::stderr();
+        let _ = write!(
+            stderr,
+            "\r{} [{}{}] {:5.1}% {} elapsed, ETA {}   ",
+            self.label,
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            fraction * 100.0,
+            format_secs(elapsed),
+            eta
+        );
+        let _ = stderr.flush();
+    }
+
+    /// Ends the line so later output doesn't overwrite the bar.
+    pub fn finish(&self) {
+        eprintln!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secs_are_formatted() {
+        assert_eq!(format_secs(0.0), "0.0s");
+        assert_eq!(format_secs(59.5), "59.5s");
+        assert_eq!(format_secs(60.0), "1m00s");
+        assert_eq!(format_secs(3725.0), "62m05s");
+    }
+
+    #[test]
+    fn remaining_time_scales_with_the_fraction_done() {
+        assert_eq!(remaining_secs(10.0, 0.0), None);
+        assert_eq!(remaining_secs(10.0, 0.25), Some(30.0));
+        assert_eq!(remaining_secs(10.0, 0.5), Some(10.0));
+        assert_eq!(remaining_secs(10.0, 1.0), Some(0.0));
+    }
+}
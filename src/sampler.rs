@@ -0,0 +1,694 @@
+use rand::{Rng, SeedableRng};
+use rand_xoshiro::Xoshiro256Plus;
+use std::sync::Arc;
+
+/// Source of sample values in [0, 1). Each camera sample starts with `start_sample` and then
+/// requests dimensions one or two at a time, in the same order for every sample.
+pub trait Sampler {
+    fn start_sample(&mut self, x: u32, y: u32, sample_index: u32);
+    fn get_1d(&mut self) -> f32;
+    fn get_2d(&mut self) -> (f32, f32);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SamplerKind {
+    Random,
+    Stratified,
+    Halton,
+    /// Owen scrambled Sobol, padded to higher dimensions with independently shuffled 2D pairs.
+    Sobol,
+    /// Sobol sequence decorrelated between pixels by a blue noise mask, so the remaining error is
+    /// distributed as blue noise in screen space.
+    BlueNoise,
+}
+
+impl SamplerKind {
+    /// Names accepted by `from_name`.
+    pub const NAMES: [&'static str; 5] = ["random", "stratified", "halton", "sobol", "blue-noise"];
+
+    pub fn from_name(name: &str) -> Option<SamplerKind> {
+        match name {
+            "random" => Some(SamplerKind::Random),
+            "stratified" => Some(SamplerKind::Stratified),
+            "halton" => Some(SamplerKind::Halton),
+            "sobol" => Some(SamplerKind::Sobol),
+            "blue-noise" => Some(SamplerKind::BlueNoise),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SamplerKind::Random => "random",
+            SamplerKind::Stratified => "stratified",
+            SamplerKind::Halton => "halton",
+            SamplerKind::Sobol => "sobol",
+            SamplerKind::BlueNoise => "blue-noise",
+        }
+    }
+}
+
+/// Creates samplers for each worker, holding any data shared between them.
+#[derive(Clone)]
+pub struct SamplerSource {
+    kind: SamplerKind,
+    samples_per_pixel: u32,
+    seed: u32,
+    blue_noise: Option<Arc<BlueNoise>>,
+}
+
+impl SamplerSource {
+    pub fn new(kind: SamplerKind, samples_per_pixel: u32, seed: u32) -> SamplerSource {
+        let blue_noise = if kind == SamplerKind::BlueNoise {
+            Some(Arc::new(BlueNoise::generate(seed)))
+        } else {
+            None
+        };
+        SamplerSource {
+            kind,
+            samples_per_pixel,
+            seed,
+            blue_noise,
+        }
+    }
+
+    /// Whether this was made by `new` with the same arguments, so can be reused.
+    pub fn is_for(&self, kind: SamplerKind, samples_per_pixel: u32, seed: u32) -> bool {
+        self.kind == kind && self.samples_per_pixel == samples_per_pixel && self.seed == seed
+    }
+
+    /// `rng` supplies the random sampler and any dimensions beyond what the low discrepancy
+    /// samplers support.
+    pub fn create(&self, rng: Xoshiro256Plus) -> Box<dyn Sampler> {
+        match self.kind {
+            SamplerKind::Random => Box::new(RandomSampler { rng }),
+            SamplerKind::Stratified => Box::new(StratifiedSampler {
+                samples_per_pixel: self.samples_per_pixel.max(1),
+                seed: self.seed,
+                pixel_hash: 0,
+                sample_index: 0,
+                dimension: 0,
+                rng,
+            }),
+            SamplerKind::Halton => Box::new(HaltonSampler {
+                seed: self.seed,
+                pixel_hash: 0,
+                sample_index: 0,
+                dimension: 0,
+                rng,
+            }),
+            SamplerKind::Sobol => Box::new(SobolSampler {
+                seed: self.seed,
+                pixel_hash: 0,
+                sample_index: 0,
+                dimension: 0,
+            }),
+            SamplerKind::BlueNoise => Box::new(BlueNoiseSampler {
+                blue_noise: self.blue_noise.clone().unwrap(),
+                seed: self.seed,
+                x: 0,
+                y: 0,
+                sample_index: 0,
+                dimension: 0,
+            }),
+        }
+    }
+}
+
+#[inline]
+fn hash(mut x: u32) -> u32 {
+    // lowbias32 from https://nullprogram.com/blog/2018/07/31/
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    x
+}
+
+#[inline]
+fn hash_combine(seed: u32, value: u32) -> u32 {
+    hash(seed ^ value.wrapping_add(0x9e37_79b9))
+}
+
+#[inline]
+fn pixel_hash(seed: u32, x: u32, y: u32) -> u32 {
+    hash_combine(hash_combine(seed, x), y)
+}
+
+#[inline]
+fn to_unit_float(x: u32) -> f32 {
+    // use the top 24 bits so the result is always less than 1.0
+    (x >> 8) as f32 * (1.0 / (1 << 24) as f32)
+}
+
+pub struct RandomSampler {
+    rng: Xoshiro256Plus,
+}
+
+impl Sampler for RandomSampler {
+    fn start_sample(&mut self, _: u32, _: u32, _: u32) {}
+
+    fn get_1d(&mut self) -> f32 {
+        self.rng.gen()
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.rng.gen(), self.rng.gen())
+    }
+}
+
+/// Random permutation of `index` in `0..len` selected by `seed`, from Andrew Kensler's
+/// "Correlated Multi-Jittered Sampling".
+fn permute(mut index: u32, len: u32, seed: u32) -> u32 {
+    let mut w = len - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        index ^= seed;
+        index = index.wrapping_mul(0xe170_893d);
+        index ^= seed >> 16;
+        index ^= (index & w) >> 4;
+        index ^= seed >> 8;
+        index = index.wrapping_mul(0x0929_eb3f);
+        index ^= seed >> 23;
+        index ^= (index & w) >> 1;
+        index = index.wrapping_mul(1 | seed >> 27);
+        index = index.wrapping_mul(0x6935_fa69);
+        index ^= (index & w) >> 11;
+        index = index.wrapping_mul(0x74dc_b303);
+        index ^= (index & w) >> 2;
+        index = index.wrapping_mul(0x9e50_1cc3);
+        index ^= (index & w) >> 2;
+        index = index.wrapping_mul(0xc860_a3df);
+        index &= w;
+        index ^= index >> 5;
+        if index < len {
+            return (index.wrapping_add(seed)) % len;
+        }
+    }
+}
+
+/// Jittered strata for each dimension with the strata shuffled independently per dimension, so
+/// every set of `samples_per_pixel` samples is stratified. 2D samples use a grid with at least
+/// `samples_per_pixel` cells, each pass picks a random subset of them when it has more.
+pub struct StratifiedSampler {
+    samples_per_pixel: u32,
+    seed: u32,
+    pixel_hash: u32,
+    sample_index: u32,
+    dimension: u32,
+    rng: Xoshiro256Plus,
+}
+
+impl StratifiedSampler {
+    /// The stratum out of `strata`, at least `samples_per_pixel`, for the current sample and
+    /// dimension. Samples in a pass get distinct strata.
+    fn stratum(&self, strata: u32) -> u32 {
+        // each pass of samples_per_pixel samples gets its own shuffle
+        let pass = self.sample_index / self.samples_per_pixel;
+        let seed = hash_combine(hash_combine(self.pixel_hash, self.dimension), pass);
+        permute(self.sample_index % self.samples_per_pixel, strata, seed)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, x: u32, y: u32, sample_index: u32) {
+        self.pixel_hash = pixel_hash(self.seed, x, y);
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let stratum = self.stratum(self.samples_per_pixel);
+        self.dimension += 1;
+        (stratum as f32 + self.rng.gen::<f32>()) / self.samples_per_pixel as f32
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        // as close to square as possible, permuting over every cell so that when there are more
+        // cells than samples each one is equally likely to be used
+        let nx = (self.samples_per_pixel as f32).sqrt().ceil() as u32;
+        let ny = (self.samples_per_pixel + nx - 1) / nx;
+        let stratum = self.stratum(nx * ny);
+        self.dimension += 1;
+        (
+            ((stratum % nx) as f32 + self.rng.gen::<f32>()) / nx as f32,
+            ((stratum / nx) as f32 + self.rng.gen::<f32>()) / ny as f32,
+        )
+    }
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+/// Radical inverse with each digit randomly permuted, the permutation depends on the digit
+/// position and `seed`. Without scrambling the large bases give badly correlated dimensions at
+/// low sample counts.
+fn scrambled_radical_inverse(base: u32, mut index: u32, seed: u32) -> f32 {
+    let inv_base = 1.0 / f64::from(base);
+    let mut inv_base_n = 1.0;
+    let mut result = 0.0;
+    let mut position = 0;
+    // keep going past the last non-zero digit as zero digits are permuted too
+    while inv_base_n > 1.0 / (1 << 24) as f64 {
+        let next = index / base;
+        let digit = index - next * base;
+        let digit = permute(digit, base, hash_combine(seed, position));
+        inv_base_n *= inv_base;
+        result += f64::from(digit) * inv_base_n;
+        index = next;
+        position += 1;
+    }
+    (result as f32).min(0.999_999_94)
+}
+
+/// Halton sequence with a prime base per dimension, decorrelated between pixels by scrambling
+/// the digits with a different permutation per pixel.
+pub struct HaltonSampler {
+    seed: u32,
+    pixel_hash: u32,
+    sample_index: u32,
+    dimension: u32,
+    rng: Xoshiro256Plus,
+}
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, x: u32, y: u32, sample_index: u32) {
+        self.pixel_hash = pixel_hash(self.seed, x, y);
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.dimension as usize;
+        self.dimension += 1;
+        if dimension >= PRIMES.len() {
+            return self.rng.gen();
+        }
+        scrambled_radical_inverse(
+            PRIMES[dimension],
+            self.sample_index,
+            hash_combine(self.pixel_hash, dimension as u32),
+        )
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+fn sobol_dim1(mut index: u32) -> u32 {
+    // generator matrix of the second Sobol dimension
+    let mut v = 1 << 31;
+    let mut result = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= v;
+        }
+        index >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x
+}
+
+/// Owen scrambling by hashing, from Brent Burley's "Practical Hash-based Owen Scrambling".
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+/// Returns the scrambled 2D Sobol point for `index`, with the index shuffled by `seed`.
+fn shuffled_scrambled_sobol_2d(index: u32, seed: u32) -> (u32, u32) {
+    let index = nested_uniform_scramble(index, seed);
+    (
+        nested_uniform_scramble(index.reverse_bits(), hash_combine(seed, 0)),
+        nested_uniform_scramble(sobol_dim1(index), hash_combine(seed, 1)),
+    )
+}
+
+pub struct SobolSampler {
+    seed: u32,
+    pixel_hash: u32,
+    sample_index: u32,
+    dimension: u32,
+}
+
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, x: u32, y: u32, sample_index: u32) {
+        self.pixel_hash = pixel_hash(self.seed, x, y);
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let seed = hash_combine(self.pixel_hash, self.dimension);
+        self.dimension += 1;
+        let (x, _) = shuffled_scrambled_sobol_2d(self.sample_index, seed);
+        to_unit_float(x)
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let seed = hash_combine(self.pixel_hash, self.dimension);
+        self.dimension += 1;
+        let (x, y) = shuffled_scrambled_sobol_2d(self.sample_index, seed);
+        (to_unit_float(x), to_unit_float(y))
+    }
+}
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Tileable blue noise dither mask generated with Ulichney's void and cluster method.
+pub struct BlueNoise {
+    values: Vec<f32>,
+}
+
+impl BlueNoise {
+    pub fn generate(seed: u32) -> BlueNoise {
+        const N: usize = BLUE_NOISE_SIZE * BLUE_NOISE_SIZE;
+        const SIGMA: f32 = 1.5;
+        // gaussian energy for each toroidal offset
+        let mut kernel = vec![0.0; N];
+        for y in 0..BLUE_NOISE_SIZE {
+            for x in 0..BLUE_NOISE_SIZE {
+                let dx = x.min(BLUE_NOISE_SIZE - x) as f32;
+                let dy = y.min(BLUE_NOISE_SIZE - y) as f32;
+                kernel[y * BLUE_NOISE_SIZE + x] =
+                    (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp();
+            }
+        }
+        let mut energy = vec![0.0; N];
+        let mut set = vec![false; N];
+        let update = |energy: &mut Vec<f32>, index: usize, sign: f32| {
+            let (px, py) = (index % BLUE_NOISE_SIZE, index / BLUE_NOISE_SIZE);
+            for y in 0..BLUE_NOISE_SIZE {
+                let ky = (y + BLUE_NOISE_SIZE - py) % BLUE_NOISE_SIZE;
+                for x in 0..BLUE_NOISE_SIZE {
+                    let kx = (x + BLUE_NOISE_SIZE - px) % BLUE_NOISE_SIZE;
+                    energy[y * BLUE_NOISE_SIZE + x] += sign * kernel[ky * BLUE_NOISE_SIZE + kx];
+                }
+            }
+        };
+        let tightest_cluster = |energy: &[f32], set: &[bool]| {
+            (0..N)
+                .filter(|&i| set[i])
+                .max_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap())
+                .unwrap()
+        };
+        let largest_void = |energy: &[f32], set: &[bool]| {
+            (0..N)
+                .filter(|&i| !set[i])
+                .min_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap())
+                .unwrap()
+        };
+
+        // random initial pattern with 10% of the pixels set
+        let mut rng = Xoshiro256Plus::seed_from_u64(u64::from(seed));
+        let initial = N / 10;
+        let mut count = 0;
+        while count < initial {
+            let index = rng.gen_range(0, N);
+            if !set[index] {
+                set[index] = true;
+                update(&mut energy, index, 1.0);
+                count += 1;
+            }
+        }
+
+        // move points from the tightest clusters to the largest voids until stable
+        for _ in 0..N {
+            let cluster = tightest_cluster(&energy, &set);
+            set[cluster] = false;
+            update(&mut energy, cluster, -1.0);
+            let void = largest_void(&energy, &set);
+            set[void] = true;
+            update(&mut energy, void, 1.0);
+            if void == cluster {
+                break;
+            }
+        }
+
+        let mut ranks = vec![0; N];
+        // rank the initial points by removing the tightest clusters
+        {
+            let mut set = set.clone();
+            let mut energy = energy.clone();
+            for rank in (0..initial).rev() {
+                let cluster = tightest_cluster(&energy, &set);
+                set[cluster] = false;
+                update(&mut energy, cluster, -1.0);
+                ranks[cluster] = rank;
+            }
+        }
+        // then rank the remaining points by filling the largest voids
+        for rank in initial..N {
+            let void = largest_void(&energy, &set);
+            set[void] = true;
+            update(&mut energy, void, 1.0);
+            ranks[void] = rank;
+        }
+
+        BlueNoise {
+            values: ranks
+                .iter()
+                .map(|&rank| (rank as f32 + 0.5) / N as f32)
+                .collect(),
+        }
+    }
+
+    fn get(&self, x: u32, y: u32) -> f32 {
+        let x = x as usize % BLUE_NOISE_SIZE;
+        let y = y as usize % BLUE_NOISE_SIZE;
+        self.values[y * BLUE_NOISE_SIZE + x]
+    }
+}
+
+/// The same scrambled Sobol sequence in every pixel, shifted by the blue noise mask. Each
+/// dimension reads the mask at a different offset so dimensions stay decorrelated.
+pub struct BlueNoiseSampler {
+    blue_noise: Arc<BlueNoise>,
+    seed: u32,
+    x: u32,
+    y: u32,
+    sample_index: u32,
+    dimension: u32,
+}
+
+impl BlueNoiseSampler {
+    fn shift(&self, component: u32) -> f32 {
+        let offset = hash_combine(hash_combine(self.seed, self.dimension), component);
+        self.blue_noise
+            .get(self.x + (offset & 0xffff), self.y + (offset >> 16))
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start_sample(&mut self, x: u32, y: u32, sample_index: u32) {
+        self.x = x;
+        self.y = y;
+        self.sample_index = sample_index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.get_2d().0
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let seed = hash_combine(self.seed, self.dimension);
+        let (x, y) = shuffled_scrambled_sobol_2d(self.sample_index, seed);
+        let x = to_unit_float(x) + self.shift(0);
+        let y = to_unit_float(y) + self.shift(1);
+        self.dimension += 1;
+        (
+            if x >= 1.0 { x - 1.0 } else { x },
+            if y >= 1.0 { y - 1.0 } else { y },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rng() -> Xoshiro256Plus {
+        Xoshiro256Plus::seed_from_u64(1)
+    }
+
+    /// Whether each of the `points`, `2^k` of them, is alone in its cell for every way of
+    /// dividing the unit square into `2^k` cells of `2^a` by `2^(k - a)`.
+    fn is_net(points: &[(f32, f32)], k: u32) -> bool {
+        (0..=k).all(|a| {
+            let (nx, ny) = (1 << a, 1 << (k - a));
+            let mut cells = vec![false; 1 << k];
+            points.iter().all(|&(x, y)| {
+                let cell = (y * ny as f32) as usize * nx + (x * nx as f32) as usize;
+                !std::mem::replace(&mut cells[cell], true)
+            })
+        })
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for name in SamplerKind::NAMES.iter() {
+            assert_eq!(SamplerKind::from_name(name).unwrap().name(), *name);
+        }
+        assert_eq!(SamplerKind::from_name("sobol2"), None);
+    }
+
+    #[test]
+    fn sobol_generator_matches_known_points() {
+        let to_unit = |x: u32| f64::from(x) / f64::from(1u32 << 31) / 2.0;
+        let dim0: Vec<f64> = (0..8u32).map(|i| to_unit(i.reverse_bits())).collect();
+        let dim1: Vec<f64> = (0..8).map(|i| to_unit(sobol_dim1(i))).collect();
+        assert_eq!(dim0, [0.0, 0.5, 0.25, 0.75, 0.125, 0.625, 0.375, 0.875]);
+        assert_eq!(dim1, [0.0, 0.5, 0.75, 0.25, 0.625, 0.125, 0.375, 0.875]);
+    }
+
+    #[test]
+    fn scrambled_sobol_prefixes_are_nets() {
+        for &seed in &[0, 1, 0xdead_beef] {
+            let points: Vec<(f32, f32)> = (0..64)
+                .map(|index| {
+                    let (x, y) = shuffled_scrambled_sobol_2d(index, seed);
+                    (to_unit_float(x), to_unit_float(y))
+                })
+                .collect();
+            for k in 0..=6 {
+                assert!(is_net(&points[..1 << k], k), "seed {} k {}", seed, k);
+            }
+        }
+    }
+
+    #[test]
+    fn scrambled_halton_keeps_radical_inverse_strata() {
+        // digit scrambling moves points between strata of 1 / base^n but never puts two of the
+        // first base^n points in the same one
+        for &base in &[2, 3, 5, 7] {
+            let len = base * base;
+            let mut strata = vec![false; len as usize];
+            for index in 0..len {
+                let x = scrambled_radical_inverse(base, index, 0x1234);
+                let stratum = (x * len as f32) as usize;
+                assert!(!strata[stratum], "base {} index {}", base, index);
+                strata[stratum] = true;
+            }
+        }
+    }
+
+    #[test]
+    fn permute_is_a_permutation() {
+        for &len in &[1, 2, 3, 7, 16, 100] {
+            for &seed in &[0, 7, 0x9e37_79b9] {
+                let mut seen = vec![false; len as usize];
+                for index in 0..len {
+                    let permuted = permute(index, len, seed) as usize;
+                    assert!(!seen[permuted]);
+                    seen[permuted] = true;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stratified_pass_fills_every_stratum() {
+        let samples = 9;
+        let source = SamplerSource::new(SamplerKind::Stratified, samples, 3);
+        let mut sampler = source.create(rng());
+        let mut strata_1d = vec![false; samples as usize];
+        let mut cells_2d = vec![false; samples as usize];
+        for index in 0..samples {
+            sampler.start_sample(5, 7, index);
+            let x = sampler.get_1d();
+            let (u, v) = sampler.get_2d();
+            let stratum = (x * samples as f32) as usize;
+            assert!(!std::mem::replace(&mut strata_1d[stratum], true));
+            let cell = (v * 3.0) as usize * 3 + (u * 3.0) as usize;
+            assert!(!std::mem::replace(&mut cells_2d[cell], true));
+        }
+    }
+
+    #[test]
+    fn stratified_passes_use_every_grid_cell() {
+        // 5 samples are spread over a 3x2 grid, each pass leaves out a different cell
+        let samples = 5;
+        let source = SamplerSource::new(SamplerKind::Stratified, samples, 3);
+        let mut sampler = source.create(rng());
+        let mut used = vec![false; 6];
+        for pass in 0..16 {
+            let mut cells = vec![false; 6];
+            for index in 0..samples {
+                sampler.start_sample(5, 7, pass * samples + index);
+                let (u, v) = sampler.get_2d();
+                let cell = (v * 2.0) as usize * 3 + (u * 3.0) as usize;
+                assert!(!std::mem::replace(&mut cells[cell], true));
+                used[cell] = true;
+            }
+        }
+        assert!(used.iter().all(|&used| used));
+    }
+
+    #[test]
+    fn samples_are_in_the_unit_interval_and_repeatable() {
+        for &kind in &[
+            SamplerKind::Random,
+            SamplerKind::Stratified,
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+            SamplerKind::BlueNoise,
+        ] {
+            let source = SamplerSource::new(kind, 4, 11);
+            let mut first = source.create(rng());
+            let mut second = source.create(rng());
+            for index in 0..32 {
+                first.start_sample(index % 5, index / 5, index);
+                second.start_sample(index % 5, index / 5, index);
+                for _ in 0..40 {
+                    let (a, b) = (first.get_2d(), second.get_2d());
+                    assert_eq!(a, b, "{:?}", kind);
+                    let x = first.get_1d();
+                    assert_eq!(x, second.get_1d(), "{:?}", kind);
+                    for value in &[a.0, a.1, x] {
+                        assert!(*value >= 0.0 && *value < 1.0, "{:?} gave {}", kind, value);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sources_are_only_reused_with_the_same_settings() {
+        let source = SamplerSource::new(SamplerKind::BlueNoise, 16, 3);
+        assert!(source.is_for(SamplerKind::BlueNoise, 16, 3));
+        assert!(!source.is_for(SamplerKind::Sobol, 16, 3));
+        assert!(!source.is_for(SamplerKind::BlueNoise, 8, 3));
+        assert!(!source.is_for(SamplerKind::BlueNoise, 16, 4));
+    }
+
+    #[test]
+    fn blue_noise_mask_ranks_every_pixel_once() {
+        let mask = BlueNoise::generate(5);
+        let n = BLUE_NOISE_SIZE * BLUE_NOISE_SIZE;
+        let mut ranks: Vec<usize> = mask
+            .values
+            .iter()
+            .map(|value| (value * n as f32) as usize)
+            .collect();
+        ranks.sort_unstable();
+        assert_eq!(ranks, (0..n).collect::<Vec<_>>());
+        assert_eq!(mask.get(3, 4), mask.get(3 + BLUE_NOISE_SIZE as u32, 4));
+    }
+}
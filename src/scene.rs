@@ -1,21 +1,77 @@
 use crate::{
     camera::Camera,
-    collision::{ray, Ray, RayHit, Sphere, SpheresSoA},
+    collision::{ray, Csg, Ray, RayHit, Sphere, SpheresSoA, PACKET_SIZE},
+    error::{Error, Result},
+    film::{AovFormat, Film, PixelAovs, PixelFeatures, PixelStats},
+    filter::{Filter, FilterKind},
     material::Material,
     math::maxf,
-    simd::sinf_cosf,
+    sampler::{Sampler, SamplerKind, SamplerSource},
+    sdf::SdfSolid,
+    simd::{sinf_cosf, TargetFeature},
 };
 use glam::{vec3, Vec3};
-use rand::{Rng, SeedableRng};
+use rand::SeedableRng;
 use rand_xoshiro::Xoshiro256Plus;
 use rayon::prelude::*;
 use std::{
     f32,
-    sync::atomic::{AtomicUsize, Ordering},
+    ops::AddAssign,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
 };
 
 const MAX_T: f32 = f32::MAX;
 const MIN_T: f32 = 0.001;
+// width and height in pixels of the tiles rendered as parallel work items
+const TILE_SIZE: usize = 32;
+// limit on adaptive samples per pixel per frame as a multiple of the regular sample count
+const MAX_ADAPTIVE_MULTIPLIER: u32 = 4;
+/// Largest image width or height accepted, well beyond anything sensible.
+pub const MAX_DIMENSION: u32 = 65536;
+/// Largest number of pixels accepted, a 16384 pixel square, so film buffers stay allocatable.
+pub const MAX_PIXELS: u64 = 1 << 28;
+
+/// How camera and shadow rays are traced.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Tracing {
+    /// One ray at a time, with SIMD across spheres.
+    Single,
+    /// Camera rays for neighbouring pixels and the shadow rays from their first hits are traced
+    /// `PACKET_SIZE` at a time, with SIMD across rays. Each pixel gets its own sampler so the
+    /// image is different to, but converges to the same result as, single ray tracing.
+    Packet,
+}
+
+impl Tracing {
+    /// Names accepted by `from_name`.
+    pub const NAMES: [&'static str; 2] = ["single", "packet"];
+
+    pub fn from_name(name: &str) -> Option<Tracing> {
+        match name {
+            "single" => Some(Tracing::Single),
+            "packet" => Some(Tracing::Packet),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Tracing::Single => "single",
+            Tracing::Packet => "packet",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Adaptive {
+    /// Relative error of a pixel above which it gets extra samples, also the noise target.
+    pub threshold: f32,
+    /// Stop refining after this many seconds even if the noise target isn't met.
+    pub time_limit: Option<f32>,
+}
 
 #[derive(Copy, Clone)]
 pub struct Params {
@@ -24,17 +80,399 @@ pub struct Params {
     pub samples: u32,
     pub max_depth: u32,
     pub random_seed: bool,
+    /// Seeds sample generation, renders of a scene with different seeds can be merged.
+    pub seed: u64,
+    pub filter: Filter,
+    pub sampler: SamplerKind,
+    pub adaptive: Option<Adaptive>,
+    pub denoise: bool,
+    pub aovs: Option<AovFormat>,
+    /// Also write the undenoised linear image to an EXR for merging.
+    pub float_output: bool,
+    /// SIMD instruction set scenes are built to intersect spheres with.
+    pub target_feature: TargetFeature,
+    /// Whether camera rays and their shadow rays are traced in packets.
+    pub tracing: Tracing,
+}
+
+fn is_positive(x: f32) -> bool {
+    x > 0.0 && x.is_finite()
+}
+
+impl Params {
+    /// Settings for a `width` by `height` render with the same defaults as the command line.
+    pub fn new(width: u32, height: u32) -> Params {
+        Params {
+            width,
+            height,
+            samples: 4,
+            max_depth: 10,
+            random_seed: false,
+            seed: 0,
+            filter: Filter::new(FilterKind::Box),
+            sampler: SamplerKind::Random,
+            adaptive: None,
+            denoise: false,
+            aovs: None,
+            float_output: false,
+            target_feature: TargetFeature::detect(),
+            tracing: Tracing::Single,
+        }
+    }
+
+    /// Checks the settings describe a render that can actually be made.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: &str| Err(Error::InvalidParams(message.to_string()));
+        if self.width == 0 || self.height == 0 {
+            return invalid("width and height must be at least 1");
+        }
+        if self.width > MAX_DIMENSION || self.height > MAX_DIMENSION {
+            return invalid(&format!(
+                "width and height can't be more than {}",
+                MAX_DIMENSION
+            ));
+        }
+        if u64::from(self.width) * u64::from(self.height) > MAX_PIXELS {
+            return invalid(&format!(
+                "images can't have more than {} pixels",
+                MAX_PIXELS
+            ));
+        }
+        if self.samples == 0 {
+            return invalid("samples per pixel must be at least 1");
+        }
+        // smaller filters leave gaps between samples that no pixel picks up
+        if !(self.filter.radius >= 0.5 && self.filter.radius.is_finite()) {
+            return invalid("filter radius must be at least half a pixel");
+        }
+        if !self.target_feature.is_supported() {
+            return invalid(&self.target_feature.unsupported_reason());
+        }
+        if let Some(adaptive) = self.adaptive {
+            if !is_positive(adaptive.threshold) {
+                return invalid("noise threshold must be a positive number");
+            }
+            if let Some(time_limit) = adaptive.time_limit {
+                if !is_positive(time_limit) {
+                    return invalid("time limit must be a positive number of seconds");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Number of rays traced, by the reason they were cast.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RayCounts {
+    pub camera: usize,
+    pub bounce: usize,
+    pub shadow: usize,
+}
+
+impl RayCounts {
+    pub fn total(&self) -> usize {
+        self.camera + self.bounce + self.shadow
+    }
+
+    /// Mean number of segments in each path, counting the camera ray but not shadow rays.
+    pub fn average_path_length(&self) -> f32 {
+        if self.camera == 0 {
+            return 0.0;
+        }
+        (self.camera + self.bounce) as f32 / self.camera as f32
+    }
+}
+
+impl AddAssign for RayCounts {
+    fn add_assign(&mut self, other: RayCounts) {
+        self.camera += other.camera;
+        self.bounce += other.bounce;
+        self.shadow += other.shadow;
+    }
+}
+
+/// What a sample's camera ray saw at its first hit.
+#[derive(Copy, Clone)]
+struct FirstHit {
+    features: PixelFeatures,
+    object_id: Option<u32>,
+    direct: Vec3,
+}
+
+impl FirstHit {
+    fn new() -> FirstHit {
+        FirstHit {
+            features: PixelFeatures::default(),
+            object_id: None,
+            direct: Vec3::zero(),
+        }
+    }
+
+    /// Records the surface `ray_in` hit, with its material's emission as the direct light
+    /// until any light sampling is added.
+    fn surface(&mut self, ray_in: &Ray, ray_hit: &RayHit, hit_index: u32, material: &Material) {
+        let albedo = material.albedo();
+        let normal = ray_hit.normal;
+        self.features = PixelFeatures {
+            albedo: (albedo.get_x(), albedo.get_y(), albedo.get_z()),
+            normal: (normal.get_x(), normal.get_y(), normal.get_z()),
+            depth: (ray_hit.point - ray_in.origin).dot(ray_in.direction),
+        };
+        self.object_id = Some(hit_index);
+        self.direct = material.emissive;
+    }
+
+    fn sky(&mut self, sky: Vec3) {
+        self.features = PixelFeatures {
+            albedo: (sky.get_x(), sky.get_y(), sky.get_z()),
+            ..PixelFeatures::default()
+        };
+        self.direct = sky;
+    }
+}
+
+fn sky(ray_in: &Ray) -> Vec3 {
+    let t = 0.5 * (ray_in.direction.get_y() + 1.0);
+    (1.0 - t) * vec3(1.0, 1.0, 1.0) + t * vec3(0.5, 0.7, 1.0) * 0.3
+}
+
+/// A shadow ray from the first hit of one lane of a packet towards an emissive sphere.
+#[derive(Copy, Clone)]
+struct ShadowRay {
+    lane: usize,
+    light_index: u32,
+    cos_a_max: f32,
+}
+
+/// A lane of a packet whose path continues after its first hit.
+#[derive(Copy, Clone)]
+struct PendingBounce {
+    ray_hit: RayHit,
+    attenuation: Vec3,
+    scattered: Ray,
+    do_light_sampling: bool,
+    light_emission: Vec3,
+}
+
+/// A rectangle of pixels rendered as one parallel work item.
+#[derive(Copy, Clone, Debug)]
+struct Tile {
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
+}
+
+impl Tile {
+    fn len(&self) -> usize {
+        (self.x1 - self.x0) * (self.y1 - self.y0)
+    }
+}
+
+/// A rendered tile's splats, padded by the filter reach, and its pixels' updated state.
+struct TileResult {
+    tile: Tile,
+    splats: Vec<(f32, f32, f32, f32)>,
+    stats: Vec<PixelStats>,
+    features: Vec<PixelFeatures>,
+    aovs: Option<Vec<PixelAovs>>,
+    ray_counts: RayCounts,
+}
+
+impl TileResult {
+    /// Copies the tile's pixel state from `film`, which is `width` pixels wide, and clears
+    /// splats covering the `reach` of the filter around it.
+    fn new(tile: Tile, film: &Film, width: usize, reach: usize) -> TileResult {
+        let mut stats = Vec::with_capacity(tile.len());
+        let mut features = Vec::with_capacity(tile.len());
+        let mut aovs = film.aovs.as_ref().map(|_| Vec::with_capacity(tile.len()));
+        for j in tile.y0..tile.y1 {
+            let row = j * width + tile.x0..j * width + tile.x1;
+            stats.extend_from_slice(&film.stats[row.clone()]);
+            features.extend_from_slice(&film.features[row.clone()]);
+            if let (Some(aovs), Some(film_aovs)) = (aovs.as_mut(), film.aovs.as_ref()) {
+                aovs.extend_from_slice(&film_aovs[row]);
+            }
+        }
+        // samples are splatted into a weighted accumulator padded to cover the neighbouring
+        // pixels the filter reaches
+        let splat_width = tile.x1 - tile.x0 + 2 * reach;
+        let splats = vec![(0.0, 0.0, 0.0, 0.0); (tile.y1 - tile.y0 + 2 * reach) * splat_width];
+        TileResult {
+            tile,
+            splats,
+            stats,
+            features,
+            aovs,
+            ray_counts: RayCounts::default(),
+        }
+    }
+
+    fn pixel_index(&self, i: usize, j: usize) -> usize {
+        (j - self.tile.y0) * (self.tile.x1 - self.tile.x0) + i - self.tile.x0
+    }
+
+    /// Samples taken so far by pixel `(i, j)`.
+    fn samples(&self, i: usize, j: usize) -> u32 {
+        self.stats[self.pixel_index(i, j)].samples
+    }
+
+    /// Adds sample `col` taken at `(x, y)` in pixel `(i, j)` to the pixel's state and splats it
+    /// into the pixels the filter reaches.
+    fn add_sample(
+        &mut self,
+        scene: &Scene,
+        params: &Params,
+        (i, j): (usize, usize),
+        (x, y): (f32, f32),
+        col: Vec3,
+        first_hit: &FirstHit,
+    ) {
+        let pixel_index = self.pixel_index(i, j);
+        let pixel_stats = &mut self.stats[pixel_index];
+        pixel_stats.add(0.2126 * col.get_x() + 0.7152 * col.get_y() + 0.0722 * col.get_z());
+        self.features[pixel_index].add(pixel_stats.samples, &first_hit.features);
+        if let Some(aovs) = self.aovs.as_mut() {
+            let pixel_aovs = &mut aovs[pixel_index];
+            if pixel_stats.samples == 1 {
+                pixel_aovs.object_id = first_hit.object_id;
+                pixel_aovs.material_id = first_hit
+                    .object_id
+                    .map(|index| scene.material_ids[index as usize]);
+            }
+            let direct = first_hit.direct;
+            let indirect = col - direct;
+            pixel_aovs.add(
+                pixel_stats.samples,
+                (direct.get_x(), direct.get_y(), direct.get_z()),
+                (indirect.get_x(), indirect.get_y(), indirect.get_z()),
+            );
+        }
+
+        let width = params.width as usize;
+        let height = params.height as usize;
+        let filter = params.filter;
+        let reach = filter.reach();
+        let tile = self.tile;
+        let splat_width = tile.x1 - tile.x0 + 2 * reach;
+        for py in j.saturating_sub(reach)..(j + reach + 1).min(height) {
+            let weight_y = filter.evaluate(py as f32 + 0.5 - y);
+            if weight_y == 0.0 {
+                continue;
+            }
+            let splat_row = (py + reach - tile.y0) * splat_width;
+            for px in i.saturating_sub(reach)..(i + reach + 1).min(width) {
+                let weight = weight_y * filter.evaluate(px as f32 + 0.5 - x);
+                if weight != 0.0 {
+                    let splat = &mut self.splats[splat_row + px + reach - tile.x0];
+                    splat.0 += col.get_x() * weight;
+                    splat.1 += col.get_y() * weight;
+                    splat.2 += col.get_z() * weight;
+                    splat.3 += weight;
+                }
+            }
+        }
+    }
+}
+
+/// Appends the cells of the rectangle at `(x, y)` spanned by `major` and `minor`, each along one
+/// axis, in generalised Hilbert curve order so consecutive cells are neighbours. An odd long side
+/// with an even short side forces a single diagonal step.
+fn hilbert_cells(
+    cells: &mut Vec<(usize, usize)>,
+    (x, y): (isize, isize),
+    (ax, ay): (isize, isize),
+    (bx, by): (isize, isize),
+) {
+    let width = (ax + ay).abs();
+    let height = (bx + by).abs();
+    let (dax, day) = (ax.signum(), ay.signum());
+    let (dbx, dby) = (bx.signum(), by.signum());
+    if height == 1 || width == 1 {
+        let (dx, dy, len) = if height == 1 {
+            (dax, day, width)
+        } else {
+            (dbx, dby, height)
+        };
+        for i in 0..len {
+            cells.push(((x + i * dx) as usize, (y + i * dy) as usize));
+        }
+        return;
+    }
+    let (mut ax2, mut ay2) = (ax.div_euclid(2), ay.div_euclid(2));
+    let (mut bx2, mut by2) = (bx.div_euclid(2), by.div_euclid(2));
+    if 2 * width > 3 * height {
+        // split the long side in two, the first half even so it ends next to the second
+        if (ax2 + ay2).abs() % 2 == 1 && width > 2 {
+            ax2 += dax;
+            ay2 += day;
+        }
+        hilbert_cells(cells, (x, y), (ax2, ay2), (bx, by));
+        hilbert_cells(cells, (x + ax2, y + ay2), (ax - ax2, ay - ay2), (bx, by));
+    } else {
+        // go up the first half of the short side, along the long side and back down
+        if (bx2 + by2).abs() % 2 == 1 && height > 2 {
+            bx2 += dbx;
+            by2 += dby;
+        }
+        hilbert_cells(cells, (x, y), (bx2, by2), (ax2, ay2));
+        hilbert_cells(cells, (x + bx2, y + by2), (ax, ay), (bx - bx2, by - by2));
+        hilbert_cells(
+            cells,
+            (x + (ax - dax) + (bx2 - dbx), y + (ay - day) + (by2 - dby)),
+            (-bx2, -by2),
+            (-(ax - ax2), -(ay - ay2)),
+        );
+    }
+}
+
+/// Splits the image into square tiles ordered along a Hilbert curve, so consecutive tiles are
+/// neighbours and share more of the scene in cache.
+fn tiles_in_hilbert_order(width: usize, height: usize) -> Vec<Tile> {
+    let columns = (width + TILE_SIZE - 1) / TILE_SIZE;
+    let rows = (height + TILE_SIZE - 1) / TILE_SIZE;
+    if columns == 0 || rows == 0 {
+        return Vec::new();
+    }
+    let mut cells = Vec::with_capacity(columns * rows);
+    // the curve runs along the longer side
+    let (columns_span, rows_span) = ((columns as isize, 0), (0, rows as isize));
+    if columns >= rows {
+        hilbert_cells(&mut cells, (0, 0), columns_span, rows_span);
+    } else {
+        hilbert_cells(&mut cells, (0, 0), rows_span, columns_span);
+    }
+    cells
+        .into_iter()
+        .map(|(column, row)| Tile {
+            x0: column * TILE_SIZE,
+            y0: row * TILE_SIZE,
+            x1: ((column + 1) * TILE_SIZE).min(width),
+            y1: ((row + 1) * TILE_SIZE).min(height),
+        })
+        .collect()
 }
 
 pub struct Scene {
     spheres: SpheresSoA,
+    solids: Vec<(Csg, u32)>,
+    sdfs: Vec<(SdfSolid, u32)>,
     materials: Vec<Material>,
+    // the first object index using an identical material, for the material id AOV
+    material_ids: Vec<u32>,
     emissive: Vec<u32>,
-    ray_count: AtomicUsize,
+    // kept between frames as generating the blue noise mask is slow
+    sampler_source: Mutex<Option<SamplerSource>>,
 }
 
 impl Scene {
-    pub fn new(sphere_materials: &[(Sphere, Material)]) -> Scene {
+    /// A scene of spheres, intersected with the SIMD instruction set `target_feature`. Fails if
+    /// the CPU doesn't support it.
+    pub fn new(
+        sphere_materials: &[(Sphere, Material)],
+        target_feature: TargetFeature,
+    ) -> Result<Scene> {
         let (spheres, materials): (Vec<Sphere>, Vec<Material>) =
             sphere_materials.iter().cloned().unzip();
         let mut emissive = vec![];
@@ -43,12 +481,146 @@ impl Scene {
                 emissive.push(index as u32);
             }
         }
-        Scene {
-            spheres: SpheresSoA::new(&spheres),
-            materials,
+        let mut scene = Scene {
+            spheres: SpheresSoA::new(&spheres, target_feature)?,
+            solids: Vec::new(),
+            sdfs: Vec::new(),
+            materials: Vec::with_capacity(materials.len()),
+            material_ids: Vec::with_capacity(materials.len()),
             emissive,
-            ray_count: AtomicUsize::new(0),
+            sampler_source: Mutex::new(None),
+        };
+        for material in materials {
+            scene.push_material(material);
+        }
+        Ok(scene)
+    }
+
+    /// Adds a constructive solid geometry object. Solids can't be emissive light sources.
+    pub fn add_solid(&mut self, solid: Csg, material: Material) {
+        let index = self.push_material(material);
+        self.solids.push((solid, index));
+    }
+
+    /// Adds a signed distance field object. These can't be emissive light sources either.
+    pub fn add_sdf(&mut self, sdf: SdfSolid, material: Material) {
+        let index = self.push_material(material);
+        self.sdfs.push((sdf, index));
+    }
+
+    /// Adds the material for a new object, returning the object's index.
+    fn push_material(&mut self, material: Material) -> u32 {
+        let index = self.materials.len() as u32;
+        let material_id = self
+            .materials
+            .iter()
+            .position(|existing| existing.matches(&material))
+            .map_or(index, |position| self.material_ids[position]);
+        self.materials.push(material);
+        self.material_ids.push(material_id);
+        index
+    }
+
+    /// The SIMD instruction set used to intersect spheres.
+    pub fn target_feature(&self) -> TargetFeature {
+        self.spheres.target_feature()
+    }
+
+    fn ray_hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(RayHit, u32)> {
+        self.hit_solids(ray, t_min, t_max, self.spheres.ray_hit(ray, t_min, t_max))
+    }
+
+    /// Like `ray_hit` for up to `PACKET_SIZE` rays, spheres are intersected as a packet.
+    fn ray_hit_packet(
+        &self,
+        rays: &[Ray],
+        t_min: f32,
+        t_max: f32,
+    ) -> [Option<(RayHit, u32)>; PACKET_SIZE] {
+        let mut hits = self.spheres.ray_hit_packet(rays, t_min, t_max);
+        if !self.solids.is_empty() || !self.sdfs.is_empty() {
+            for (ray, hit) in rays.iter().zip(hits.iter_mut()) {
+                *hit = self.hit_solids(ray, t_min, t_max, *hit);
+            }
+        }
+        hits
+    }
+
+    /// Intersects the solids and signed distance fields, keeping `nearest` from the spheres
+    /// unless one of them is closer.
+    fn hit_solids(
+        &self,
+        ray: &Ray,
+        t_min: f32,
+        t_max: f32,
+        mut nearest: Option<(RayHit, u32)>,
+    ) -> Option<(RayHit, u32)> {
+        let mut t_max = match nearest {
+            Some((ray_hit, _)) => (ray_hit.point - ray.origin).dot(ray.direction),
+            None => t_max,
+        };
+        for (solid, material_index) in &self.solids {
+            if let Some((ray_hit, t)) = solid.ray_hit(ray, t_min, t_max) {
+                t_max = t;
+                nearest = Some((ray_hit, *material_index));
+            }
+        }
+        for (sdf, material_index) in &self.sdfs {
+            if let Some((ray_hit, t)) = sdf.ray_hit(ray, t_min, t_max) {
+                t_max = t;
+                nearest = Some((ray_hit, *material_index));
+            }
         }
+        nearest
+    }
+
+    /// Picks a direction from `point` towards emissive sphere `index`, uniformly over the solid
+    /// angle the sphere covers. Also returns the cosine of that cone's half angle.
+    fn sample_light(&self, point: Vec3, index: u32, sampler: &mut dyn Sampler) -> (Vec3, f32) {
+        // create a random direction towards sphere
+        // coord system for sampling: sw, su, sv
+        let sphere_centre = self.spheres.centre(index);
+        let sphere_radius_sq = self.spheres.radius_sq(index);
+        let sw = (sphere_centre - point).normalize();
+        let su = (if sw.get_x().abs() > 0.01 {
+            vec3(0.0, 1.0, 0.0)
+        } else {
+            vec3(1.0, 0.0, 0.0)
+        })
+        .cross(sw)
+        .normalize();
+        let sv = sw.cross(su);
+        // sample sphere by solid angle
+        let cos_a_max = (1.0 - sphere_radius_sq / (point - sphere_centre).length_squared()).sqrt();
+        let (eps1, eps2) = sampler.get_2d();
+        let cos_a = 1.0 - eps1 + eps1 * cos_a_max;
+        let sin_a = (1.0 - cos_a * cos_a).sqrt();
+        let phi = 2.0 * f32::consts::PI * eps2;
+        let (sin_phi, cos_phi) = sinf_cosf(phi);
+        let l = su * (cos_phi * sin_a) + sv * (sin_phi * sin_a) + sw * cos_a;
+        //l = normalize(l); // NOTE(fg): This is already normalized, by construction.
+        (l, cos_a_max)
+    }
+
+    /// The light reflected from emissive sphere `index` when a shadow ray along `l` reaches it.
+    fn light_contribution(
+        &self,
+        ray_in: &Ray,
+        ray_in_hit: &RayHit,
+        attenuation: Vec3,
+        index: u32,
+        l: Vec3,
+        cos_a_max: f32,
+    ) -> Vec3 {
+        let omega = 2.0 * f32::consts::PI * (1.0 - cos_a_max);
+        let rdir = ray_in.direction;
+        let nl = if ray_in_hit.normal.dot(rdir) < 0.0 {
+            ray_in_hit.normal
+        } else {
+            -ray_in_hit.normal
+        };
+        let light_emission = self.materials[index as usize].emissive;
+        (attenuation * light_emission) * (maxf(0.0, l.dot(nl)) * omega / f32::consts::PI)
     }
 
     fn sample_lights(
@@ -57,8 +629,8 @@ impl Scene {
         ray_in_hit: &RayHit,
         in_hit_index: u32,
         attenuation: Vec3,
-        rng: &mut Xoshiro256Plus,
-        ray_count: &mut usize,
+        sampler: &mut dyn Sampler,
+        ray_counts: &mut RayCounts,
     ) -> Vec3 {
         let mut emissive_out = Vec3::zero();
         for index in &self.emissive {
@@ -66,47 +638,19 @@ impl Scene {
                 // skip self
                 continue;
             }
-
-            // create a random direction towards sphere
-            // coord system for sampling: sw, su, sv
-            let sphere_centre = self.spheres.centre(*index);
-            let sphere_radius_sq = self.spheres.radius_sq(*index);
-            let sw = (sphere_centre - ray_in_hit.point).normalize();
-            let su = (if sw.get_x().abs() > 0.01 {
-                vec3(0.0, 1.0, 0.0)
-            } else {
-                vec3(1.0, 0.0, 0.0)
-            })
-            .cross(sw)
-            .normalize();
-            let sv = sw.cross(su);
-            // sample sphere by solid angle
-            let cos_a_max = (1.0
-                - sphere_radius_sq / (ray_in_hit.point - sphere_centre).length_squared())
-            .sqrt();
-            let eps1 = rng.gen::<f32>();
-            let eps2 = rng.gen::<f32>();
-            let cos_a = 1.0 - eps1 + eps1 * cos_a_max;
-            let sin_a = (1.0 - cos_a * cos_a).sqrt();
-            let phi = 2.0 * f32::consts::PI * eps2;
-            let (sin_phi, cos_phi) = sinf_cosf(phi);
-            let l = su * (cos_phi * sin_a) + sv * (sin_phi * sin_a) + sw * cos_a;
-            //l = normalize(l); // NOTE(fg): This is already normalized, by construction.
-
-            *ray_count += 1;
+            let (l, cos_a_max) = self.sample_light(ray_in_hit.point, *index, sampler);
+            ray_counts.shadow += 1;
             let ray_out = ray(ray_in_hit.point, l);
-            if let Some((_, out_hit_index)) = self.spheres.ray_hit(&ray_out, MIN_T, MAX_T) {
+            if let Some((_, out_hit_index)) = self.ray_hit(&ray_out, MIN_T, MAX_T) {
                 if *index == out_hit_index {
-                    let omega = 2.0 * f32::consts::PI * (1.0 - cos_a_max);
-                    let rdir = ray_in.direction;
-                    let nl = if ray_in_hit.normal.dot(rdir) < 0.0 {
-                        ray_in_hit.normal
-                    } else {
-                        -ray_in_hit.normal
-                    };
-                    let light_emission = self.materials[*index as usize].emissive;
-                    emissive_out += (attenuation * light_emission)
-                        * (maxf(0.0, l.dot(nl)) * omega / f32::consts::PI);
+                    emissive_out += self.light_contribution(
+                        ray_in,
+                        ray_in_hit,
+                        attenuation,
+                        *index,
+                        l,
+                        cos_a_max,
+                    );
                 }
             }
         }
@@ -119,18 +663,33 @@ impl Scene {
         depth: u32,
         max_depth: u32,
         do_material_emission: bool,
-        rng: &mut Xoshiro256Plus,
-        ray_count: &mut usize,
+        sampler: &mut dyn Sampler,
+        mut first_hit: Option<&mut FirstHit>,
+        ray_counts: &mut RayCounts,
     ) -> Vec3 {
-        *ray_count += 1;
-        if let Some((ray_hit, hit_index)) = self.spheres.ray_hit(ray_in, MIN_T, MAX_T) {
+        if depth == 0 {
+            ray_counts.camera += 1;
+        } else {
+            ray_counts.bounce += 1;
+        }
+        if let Some((ray_hit, hit_index)) = self.ray_hit(ray_in, MIN_T, MAX_T) {
             let material = &self.materials[hit_index as usize];
+            if let Some(first_hit) = first_hit.as_mut() {
+                first_hit.surface(ray_in, &ray_hit, hit_index, material);
+            }
             if depth < max_depth {
                 if let Some((attenuation, scattered, do_light_sampling)) =
-                    material.scatter(ray_in, &ray_hit, rng)
+                    material.scatter(ray_in, &ray_hit, sampler)
                 {
                     let light_emission = if do_light_sampling {
-                        self.sample_lights(ray_in, &ray_hit, hit_index, attenuation, rng, ray_count)
+                        self.sample_lights(
+                            ray_in,
+                            &ray_hit,
+                            hit_index,
+                            attenuation,
+                            sampler,
+                            ray_counts,
+                        )
                     } else {
                         Vec3::zero()
                     };
@@ -141,6 +700,9 @@ impl Scene {
                     } else {
                         Vec3::zero()
                     };
+                    if let Some(first_hit) = first_hit {
+                        first_hit.direct = material_emission + light_emission;
+                    }
                     let do_material_emission = !do_light_sampling;
                     return material_emission
                         + light_emission
@@ -150,117 +712,652 @@ impl Scene {
                                 depth + 1,
                                 max_depth,
                                 do_material_emission,
-                                rng,
-                                ray_count,
+                                sampler,
+                                None,
+                                ray_counts,
                             );
                 }
             }
             return material.emissive;
         } else {
-            // sky
-            let t = 0.5 * (ray_in.direction.get_y() + 1.0);
-            (1.0 - t) * vec3(1.0, 1.0, 1.0) + t * vec3(0.5, 0.7, 1.0) * 0.3
+            let sky = sky(ray_in);
+            if let Some(first_hit) = first_hit {
+                first_hit.sky(sky);
+            }
+            sky
         }
     }
 
-    pub fn update(
+    /// Traces a packet of camera rays, ray `k` using sampler `lanes[k]`, returning each
+    /// sample's colour and first hit indexed like `rays`. The first hits are found together and
+    /// so are the shadow rays cast from them, then each path continues with `ray_trace`. Every
+    /// sampler is used in the same order as for a single ray.
+    fn trace_packet(
         &self,
-        params: &Params,
-        camera: &Camera,
-        frame_num: u32,
-        buffer: &mut [(f32, f32, f32)],
-    ) -> usize {
-        self.ray_count.store(0, Ordering::Relaxed);
+        rays: &[Ray],
+        lanes: &[usize],
+        max_depth: u32,
+        samplers: &mut [Box<dyn Sampler>],
+        ray_counts: &mut RayCounts,
+    ) -> [(Vec3, FirstHit); PACKET_SIZE] {
+        ray_counts.camera += rays.len();
+        let hits = self.ray_hit_packet(rays, MIN_T, MAX_T);
+        let mut samples = [(Vec3::zero(), FirstHit::new()); PACKET_SIZE];
+        let mut pending: [Option<PendingBounce>; PACKET_SIZE] = [None; PACKET_SIZE];
+        // shadow rays are traced a packet at a time as they're generated
+        let mut shadow_rays = [rays[0]; PACKET_SIZE];
+        let mut shadow_lanes = [ShadowRay {
+            lane: 0,
+            light_index: 0,
+            cos_a_max: 0.0,
+        }; PACKET_SIZE];
+        let mut num_shadow_rays = 0;
+        for (lane, ray_in) in rays.iter().enumerate() {
+            let (col, first_hit) = &mut samples[lane];
+            let (ray_hit, hit_index) = match hits[lane] {
+                Some(hit) => hit,
+                None => {
+                    let sky = sky(ray_in);
+                    first_hit.sky(sky);
+                    *col = sky;
+                    continue;
+                }
+            };
+            let material = &self.materials[hit_index as usize];
+            first_hit.surface(ray_in, &ray_hit, hit_index, material);
+            *col = material.emissive;
+            if max_depth == 0 {
+                continue;
+            }
+            let sampler = samplers[lanes[lane]].as_mut();
+            if let Some((attenuation, scattered, do_light_sampling)) =
+                material.scatter(ray_in, &ray_hit, sampler)
+            {
+                pending[lane] = Some(PendingBounce {
+                    ray_hit,
+                    attenuation,
+                    scattered,
+                    do_light_sampling,
+                    light_emission: Vec3::zero(),
+                });
+                if do_light_sampling {
+                    for &light_index in &self.emissive {
+                        if light_index != hit_index {
+                            let (l, cos_a_max) =
+                                self.sample_light(ray_hit.point, light_index, sampler);
+                            shadow_rays[num_shadow_rays] = ray(ray_hit.point, l);
+                            shadow_lanes[num_shadow_rays] = ShadowRay {
+                                lane,
+                                light_index,
+                                cos_a_max,
+                            };
+                            num_shadow_rays += 1;
+                            if num_shadow_rays == PACKET_SIZE {
+                                self.trace_shadow_packet(
+                                    rays,
+                                    &shadow_rays,
+                                    &shadow_lanes,
+                                    &mut pending,
+                                    ray_counts,
+                                );
+                                num_shadow_rays = 0;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if num_shadow_rays > 0 {
+            self.trace_shadow_packet(
+                rays,
+                &shadow_rays[..num_shadow_rays],
+                &shadow_lanes[..num_shadow_rays],
+                &mut pending,
+                ray_counts,
+            );
+        }
+
+        for (lane, bounce) in pending.iter().enumerate() {
+            let bounce = match bounce {
+                Some(bounce) => bounce,
+                None => continue,
+            };
+            let (col, first_hit) = &mut samples[lane];
+            // camera rays always include material emission
+            let material_emission = *col;
+            first_hit.direct = material_emission + bounce.light_emission;
+            *col = material_emission
+                + bounce.light_emission
+                + bounce.attenuation
+                    * self.ray_trace(
+                        &bounce.scattered,
+                        1,
+                        max_depth,
+                        !bounce.do_light_sampling,
+                        samplers[lanes[lane]].as_mut(),
+                        None,
+                        ray_counts,
+                    );
+        }
+        samples
+    }
 
+    /// Traces up to `PACKET_SIZE` shadow rays from the first hits of a packet of camera `rays`,
+    /// adding the light from each one that reaches its light to the lane's pending bounce.
+    fn trace_shadow_packet(
+        &self,
+        rays: &[Ray],
+        shadow_rays: &[Ray],
+        shadow_lanes: &[ShadowRay],
+        pending: &mut [Option<PendingBounce>; PACKET_SIZE],
+        ray_counts: &mut RayCounts,
+    ) {
+        ray_counts.shadow += shadow_rays.len();
+        let shadow_hits = self.ray_hit_packet(shadow_rays, MIN_T, MAX_T);
+        for ((shadow_ray, shadow), shadow_hit) in shadow_rays
+            .iter()
+            .zip(shadow_lanes.iter())
+            .zip(shadow_hits.iter())
+        {
+            if let Some((_, out_hit_index)) = shadow_hit {
+                if *out_hit_index == shadow.light_index {
+                    let bounce = pending[shadow.lane]
+                        .as_mut()
+                        .expect("shadow ray without a pending bounce");
+                    bounce.light_emission += self.light_contribution(
+                        &rays[shadow.lane],
+                        &bounce.ray_hit,
+                        bounce.attenuation,
+                        shadow.light_index,
+                        shadow_ray.direction,
+                        shadow.cos_a_max,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Traces the samples for one tile, returning its padded splat buffer and the updated
+    /// per-pixel state, which is copied so tiles can be rendered in parallel.
+    fn render_tile<F>(
+        &self,
+        params: &Params,
+        camera: &dyn Camera,
+        sampler_source: &SamplerSource,
+        seed: u64,
+        film: &Film,
+        tile: Tile,
+        samples_for_pixel: &F,
+    ) -> TileResult
+    where
+        F: Fn(usize) -> u32 + Sync,
+    {
+        let width = params.width as usize;
         let inv_nx = 1.0 / params.width as f32;
         let inv_ny = 1.0 / params.height as f32;
-        let inv_ns = 1.0 / params.samples as f32;
-
-        let mix_prev = frame_num as f32 / (frame_num + 1) as f32;
-        let mix_new = 1.0 - mix_prev;
-
-        // parallel iterate each row of pixels
-        buffer
-            .par_chunks_mut(params.width as usize)
-            .enumerate()
-            .for_each(|(j, row)| {
-                let mut ray_count = 0;
-                let mut rng = if params.random_seed {
-                    Xoshiro256Plus::seed_from_u64(rand::random())
-                } else {
-                    Xoshiro256Plus::seed_from_u64((j as u64 * 9781 + frame_num as u64 * 6271) | 1)
-                };
-                row.iter_mut().enumerate().for_each(|(i, color_out)| {
-                    let mut col = Vec3::zero();
-                    for _ in 0..params.samples {
-                        let u = (i as f32 + rng.gen::<f32>()) * inv_nx;
-                        let v = (j as f32 + rng.gen::<f32>()) * inv_ny;
-                        let ray = camera.get_ray(u, v, &mut rng);
-                        col += self.ray_trace(
-                            &ray,
-                            0,
+        let mut result = TileResult::new(tile, film, width, params.filter.reach());
+        // seeded by position so the image doesn't depend on the order tiles are rendered in
+        let sampler_rng = |pixel: usize| {
+            if params.random_seed {
+                Xoshiro256Plus::seed_from_u64(rand::random())
+            } else {
+                Xoshiro256Plus::seed_from_u64((pixel as u64 * 9781).wrapping_add(seed) | 1)
+            }
+        };
+        // the rays of each packet round, the lane of the pixel each belongs to and its position
+        // on the film, reused between rounds
+        let mut rays = [ray(Vec3::zero(), Vec3::zero()); PACKET_SIZE];
+        let mut lanes = [0; PACKET_SIZE];
+        let mut positions = [(0.0, 0.0); PACKET_SIZE];
+        // a sampler per lane for the whole tile, as the single ray path has one per row
+        let mut samplers: Vec<Box<dyn Sampler>> = if params.tracing == Tracing::Packet {
+            (0..PACKET_SIZE)
+                .map(|lane| sampler_source.create(sampler_rng(tile.y0 * width + tile.x0 + lane)))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        for j in tile.y0..tile.y1 {
+            if params.tracing == Tracing::Packet {
+                for x0 in (tile.x0..tile.x1).step_by(PACKET_SIZE) {
+                    let pixels = x0..(x0 + PACKET_SIZE).min(tile.x1);
+                    let mut sample_counts = [0; PACKET_SIZE];
+                    for (lane, i) in pixels.clone().enumerate() {
+                        sample_counts[lane] = samples_for_pixel(j * width + i);
+                    }
+                    let max_samples = sample_counts.iter().cloned().max().unwrap_or(0);
+                    // each round traces one sample for every pixel still needing one
+                    for round in 0..max_samples {
+                        let mut num_rays = 0;
+                        for (lane, i) in pixels.clone().enumerate() {
+                            if round >= sample_counts[lane] {
+                                continue;
+                            }
+                            let sampler = samplers[lane].as_mut();
+                            // continue each pixel's sample sequence from previous frames
+                            sampler.start_sample(
+                                i as u32,
+                                j as u32,
+                                film.sample_offset + result.samples(i, j),
+                            );
+                            let (jitter_x, jitter_y) = sampler.get_2d();
+                            let x = i as f32 + jitter_x;
+                            let y = j as f32 + jitter_y;
+                            rays[num_rays] = camera.get_ray(x * inv_nx, y * inv_ny, sampler);
+                            lanes[num_rays] = lane;
+                            positions[num_rays] = (x, y);
+                            num_rays += 1;
+                        }
+                        let samples = self.trace_packet(
+                            &rays[..num_rays],
+                            &lanes[..num_rays],
                             params.max_depth,
-                            true,
-                            &mut rng,
-                            &mut ray_count,
+                            &mut samplers,
+                            &mut result.ray_counts,
                         );
+                        for k in 0..num_rays {
+                            let (col, first_hit) = &samples[k];
+                            result.add_sample(
+                                self,
+                                params,
+                                (x0 + lanes[k], j),
+                                positions[k],
+                                *col,
+                                first_hit,
+                            );
+                        }
                     }
-                    col *= inv_ns;
-                    color_out.0 = color_out.0 * mix_prev + col.get_x() * mix_new;
-                    color_out.1 = color_out.1 * mix_prev + col.get_y() * mix_new;
-                    color_out.2 = color_out.2 * mix_prev + col.get_z() * mix_new;
-                });
-                self.ray_count.fetch_add(ray_count, Ordering::Relaxed);
+                }
+                continue;
+            }
+            let mut sampler = sampler_source.create(sampler_rng(j * width + tile.x0));
+            for i in tile.x0..tile.x1 {
+                for _ in 0..samples_for_pixel(j * width + i) {
+                    // continue each pixel's sample sequence from previous frames
+                    sampler.start_sample(
+                        i as u32,
+                        j as u32,
+                        film.sample_offset + result.samples(i, j),
+                    );
+                    let (jitter_x, jitter_y) = sampler.get_2d();
+                    let x = i as f32 + jitter_x;
+                    let y = j as f32 + jitter_y;
+                    let ray = camera.get_ray(x * inv_nx, y * inv_ny, sampler.as_mut());
+                    let mut first_hit = FirstHit::new();
+                    let col = self.ray_trace(
+                        &ray,
+                        0,
+                        params.max_depth,
+                        true,
+                        sampler.as_mut(),
+                        Some(&mut first_hit),
+                        &mut result.ray_counts,
+                    );
+                    result.add_sample(self, params, (i, j), (x, y), col, &first_hit);
+                }
+            }
+        }
+        result
+    }
+
+    /// The sampler source for `params`, made on the first frame and reused after that.
+    fn sampler_source(&self, params: &Params) -> SamplerSource {
+        // the scramble seed must stay the same between frames so progressive renders continue
+        // the low discrepancy sequences rather than restarting them
+        let scramble_seed = (params.seed ^ (params.seed >> 32)) as u32;
+        let mut cached = self.sampler_source.lock().unwrap();
+        match cached.as_ref() {
+            Some(source) if source.is_for(params.sampler, params.samples, scramble_seed) => {
+                source.clone()
+            }
+            _ => {
+                let source = SamplerSource::new(params.sampler, params.samples, scramble_seed);
+                *cached = Some(source.clone());
+                source
+            }
+        }
+    }
+
+    /// Traces the samples for one pass over the image, returning the weighted colour sum and
+    /// total filter weight for each pixel along with the rays traced.
+    ///
+    /// Tiles are handed out in order from a shared counter so the image fills in along the
+    /// curve, `progress` is called with the number finished after each one. Once `cancel`
+    /// returns true no more tiles are started, pixels in the ones skipped get no samples.
+    fn render_pass<F>(
+        &self,
+        params: &Params,
+        camera: &dyn Camera,
+        sampler_source: &SamplerSource,
+        seed: u64,
+        film: &mut Film,
+        tiles: &[Tile],
+        samples_for_pixel: F,
+        progress: &(dyn Fn(usize) + Sync),
+        cancel: &(dyn Fn() -> bool + Sync),
+    ) -> (Vec<(f32, f32, f32, f32)>, RayCounts)
+    where
+        F: Fn(usize) -> u32 + Sync,
+    {
+        let width = params.width as usize;
+        let height = params.height as usize;
+        let reach = params.filter.reach();
+        let next_tile = AtomicUsize::new(0);
+        let finished_tiles = AtomicUsize::new(0);
+
+        let results: Vec<TileResult> = {
+            let film: &Film = film;
+            (0..rayon::current_num_threads())
+                .into_par_iter()
+                .flat_map(|_| {
+                    let mut results = Vec::new();
+                    while !cancel() {
+                        let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                        if index >= tiles.len() {
+                            break;
+                        }
+                        results.push(self.render_tile(
+                            params,
+                            camera,
+                            sampler_source,
+                            seed,
+                            film,
+                            tiles[index],
+                            &samples_for_pixel,
+                        ));
+                        progress(finished_tiles.fetch_add(1, Ordering::Relaxed) + 1);
+                    }
+                    results
+                })
+                .collect()
+        };
+
+        // write back the per-pixel state and merge the overlapping splats
+        let mut accum = vec![(0.0, 0.0, 0.0, 0.0); width * height];
+        let mut ray_counts = RayCounts::default();
+        for result in results {
+            let tile = result.tile;
+            let tile_width = tile.x1 - tile.x0;
+            ray_counts += result.ray_counts;
+            for j in tile.y0..tile.y1 {
+                let row = j * width + tile.x0..j * width + tile.x1;
+                let tile_row = (j - tile.y0) * tile_width..(j - tile.y0 + 1) * tile_width;
+                film.stats[row.clone()].copy_from_slice(&result.stats[tile_row.clone()]);
+                film.features[row.clone()].copy_from_slice(&result.features[tile_row.clone()]);
+                if let (Some(film_aovs), Some(aovs)) = (film.aovs.as_mut(), result.aovs.as_ref()) {
+                    film_aovs[row].copy_from_slice(&aovs[tile_row]);
+                }
+            }
+            let splat_width = tile_width + 2 * reach;
+            let first_row = tile.y0 as isize - reach as isize;
+            let first_column = tile.x0 as isize - reach as isize;
+            for (row_index, splat_row) in result.splats.chunks(splat_width).enumerate() {
+                let py = first_row + row_index as isize;
+                if py < 0 || py >= height as isize {
+                    continue;
+                }
+                for (column_index, s) in splat_row.iter().enumerate() {
+                    let px = first_column + column_index as isize;
+                    if px < 0 || px >= width as isize {
+                        continue;
+                    }
+                    let a = &mut accum[py as usize * width + px as usize];
+                    a.0 += s.0;
+                    a.1 += s.1;
+                    a.2 += s.2;
+                    a.3 += s.3;
+                }
+            }
+        }
+        (accum, ray_counts)
+    }
+
+    pub fn update(&self, params: &Params, camera: &dyn Camera, film: &mut Film) -> RayCounts {
+        self.update_with_progress(params, camera, film, &|_, _| (), &|| false)
+    }
+
+    /// Renders a frame, calling `progress` with the number of tiles finished and the total as
+    /// it goes. Once `cancel` returns true the frame stops early, leaving the remaining tiles
+    /// unrendered.
+    pub fn update_with_progress(
+        &self,
+        params: &Params,
+        camera: &dyn Camera,
+        film: &mut Film,
+        progress: &(dyn Fn(usize, usize) + Sync),
+        cancel: &(dyn Fn() -> bool + Sync),
+    ) -> RayCounts {
+        let sampler_source = self.sampler_source(params);
+        let tiles = tiles_in_hilbert_order(params.width as usize, params.height as usize);
+        let passes = if params.adaptive.is_some() { 2 } else { 1 };
+        let total_tiles = tiles.len() * passes;
+
+        let frame_seed = params
+            .seed
+            .wrapping_mul(0x9e37_79b9_7f4a_7c15)
+            .wrapping_add(film.frames as u64 * 6271);
+        let (mut accum, mut ray_counts) = self.render_pass(
+            params,
+            camera,
+            &sampler_source,
+            frame_seed,
+            film,
+            &tiles,
+            |_| params.samples,
+            &|finished| progress(finished, total_tiles),
+            cancel,
+        );
+
+        if let Some(adaptive) = params.adaptive {
+            // give pixels that are still noisy extra samples, the error falls with the square
+            // root of the sample count so estimate how many more they need
+            let extra_samples: Vec<u32> = film
+                .stats
+                .iter()
+                .map(|stats| {
+                    let error = stats.relative_error();
+                    if error <= adaptive.threshold {
+                        return 0;
+                    }
+                    let needed = stats.samples as f32 * (error / adaptive.threshold).powi(2);
+                    (needed as u32)
+                        .saturating_sub(stats.samples)
+                        .min(params.samples * MAX_ADAPTIVE_MULTIPLIER)
+                })
+                .collect();
+            let (adaptive_accum, adaptive_ray_counts) = self.render_pass(
+                params,
+                camera,
+                &sampler_source,
+                frame_seed.wrapping_add(7919),
+                film,
+                &tiles,
+                |index| extra_samples[index],
+                &|finished| progress(tiles.len() + finished, total_tiles),
+                cancel,
+            );
+            ray_counts += adaptive_ray_counts;
+            for (a, b) in accum.iter_mut().zip(adaptive_accum.iter()) {
+                a.0 += b.0;
+                a.1 += b.1;
+                a.2 += b.2;
+                a.3 += b.3;
+            }
+        }
+
+        film.color
+            .par_iter_mut()
+            .zip(film.weight.par_iter_mut())
+            .zip(accum.par_iter())
+            .for_each(|((color_out, weight), a)| {
+                if a.3 > 0.0 {
+                    // blend by accumulated filter weight, with a uniform box filter this is the
+                    // same as weighting each frame equally
+                    let mix_prev = *weight / (*weight + a.3);
+                    let mix_new = 1.0 - mix_prev;
+                    let inv_weight = 1.0 / a.3;
+                    color_out.0 = color_out.0 * mix_prev + a.0 * inv_weight * mix_new;
+                    color_out.1 = color_out.1 * mix_prev + a.1 * inv_weight * mix_new;
+                    color_out.2 = color_out.2 * mix_prev + a.2 * inv_weight * mix_new;
+                    *weight += a.3;
+                }
             });
-        self.ray_count.load(Ordering::Relaxed)
+        film.frames += 1;
+        ray_counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hilbert_tiles_cover_the_image_in_neighbouring_order() {
+        let sizes = [
+            (1, 1),
+            (32, 32),
+            (100, 70),
+            (33, 1000),
+            (1000, 33),
+            (200, 150),
+            (150, 200),
+            (1920, 1080),
+            (1023, 97),
+            (150, 100),
+        ];
+        for &(width, height) in sizes.iter() {
+            let tiles = tiles_in_hilbert_order(width, height);
+            let mut covered = vec![0; width * height];
+            for tile in &tiles {
+                assert!(tile.x0 < tile.x1 && tile.x1 <= width);
+                assert!(tile.y0 < tile.y1 && tile.y1 <= height);
+                for y in tile.y0..tile.y1 {
+                    for x in tile.x0..tile.x1 {
+                        covered[y * width + x] += 1;
+                    }
+                }
+            }
+            assert!(
+                covered.iter().all(|&count| count == 1),
+                "{}x{}",
+                width,
+                height
+            );
+            assert_eq!((tiles[0].x0, tiles[0].y0), (0, 0));
+
+            let columns = (width + TILE_SIZE - 1) / TILE_SIZE;
+            let rows = (height + TILE_SIZE - 1) / TILE_SIZE;
+            let (long, short) = (columns.max(rows), columns.min(rows));
+            let mut diagonal_steps = 0;
+            for pair in tiles.windows(2) {
+                let dx = (pair[0].x0 as isize - pair[1].x0 as isize).abs() / TILE_SIZE as isize;
+                let dy = (pair[0].y0 as isize - pair[1].y0 as isize).abs() / TILE_SIZE as isize;
+                assert!(dx <= 1 && dy <= 1 && dx + dy > 0, "{}x{}", width, height);
+                diagonal_steps += (dx + dy == 2) as usize;
+            }
+            // only an odd long side with an even short side can't be walked without one
+            let parity_allows = long % 2 == 1 && short % 2 == 0;
+            assert!(
+                diagonal_steps <= parity_allows as usize,
+                "{}x{}",
+                width,
+                height
+            );
+        }
     }
 }
 
 #[cfg(all(feature = "bench", test))]
 mod bench {
-    use presets;
-    use rand::{SeedableRng, XorShiftRng};
-    use scene::{Params, MAX_T, MIN_T};
-    use simd::TargetFeature;
+    use crate::{
+        camera::Projection,
+        collision::{Ray, PACKET_SIZE},
+        presets,
+        sampler::{SamplerKind, SamplerSource},
+        scene::{Params, Scene, MAX_T, MIN_T},
+        simd::TargetFeature,
+    };
+    use rand::SeedableRng;
+    use rand_xoshiro::Xoshiro256Plus;
     use test::{black_box, Bencher};
 
-    const FIXED_SEED: [u32; 4] = [0x193a_6754, 0xa8a7_d469, 0x9783_0e05, 0x113b_a7bb];
-    const PARAMS: Params = Params {
-        width: 200,
-        height: 100,
-        samples: 10,
-        max_depth: 10,
-        random_seed: false,
-    };
+    const FIXED_SEED: u64 = 0x113b_a7bb_9783_0e05;
+
+    /// The `aras` preset with its spheres laid out for `feature` and a ray through the middle
+    /// of its view, or `None` if the CPU doesn't support `feature`.
+    fn centre_ray(feature: TargetFeature) -> Option<(Scene, Ray)> {
+        let mut params = Params::new(200, 100);
+        params.target_feature = feature;
+        let (scene, camera_desc) = presets::aras_p(&params).ok()?;
+        let sampler_source = SamplerSource::new(SamplerKind::Random, 1, 0);
+        let mut sampler =
+            sampler_source.create(Xoshiro256Plus::seed_from_u64(black_box(FIXED_SEED)));
+        sampler.start_sample(0, 0, 0);
+        let camera = camera_desc.build(Projection::Perspective);
+        let ray = camera.get_ray(0.5, 0.5, sampler.as_mut());
+        Some((scene, ray))
+    }
 
     #[bench]
     fn ray_hit_scalar(b: &mut Bencher) {
-        let seed = black_box(FIXED_SEED);
-        let mut rng = XorShiftRng::from_seed(seed);
-        let (scene, camera) = presets::aras_p(&PARAMS);
-        let ray = camera.get_ray(0.5, 0.5, &mut rng);
+        let (scene, ray) = centre_ray(TargetFeature::FallBack).unwrap();
         b.iter(|| scene.spheres.hit_scalar(&ray, MIN_T, MAX_T));
     }
 
+    #[bench]
+    fn ray_hit_portable(b: &mut Bencher) {
+        let (scene, ray) = centre_ray(TargetFeature::Portable).unwrap();
+        b.iter(|| scene.spheres.hit_portable(&ray, MIN_T, MAX_T));
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     #[bench]
     fn ray_hit_sse4_1(b: &mut Bencher) {
-        let seed = black_box(FIXED_SEED);
-        let mut rng = XorShiftRng::from_seed(seed);
-        let (scene, camera) = presets::aras_p(&PARAMS);
-        let ray = camera.get_ray(0.5, 0.5, &mut rng);
-        if scene.feature != TargetFeature::FallBack {
+        if let Some((scene, ray)) = centre_ray(TargetFeature::SSE4_1) {
             b.iter(|| unsafe { scene.spheres.hit_sse4_1(&ray, MIN_T, MAX_T) });
         }
     }
 
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     #[bench]
     fn ray_hit_avx2(b: &mut Bencher) {
-        let seed = black_box(FIXED_SEED);
-        let mut rng = XorShiftRng::from_seed(seed);
-        let (scene, camera) = presets::aras_p(&PARAMS);
-        let ray = camera.get_ray(0.5, 0.5, &mut rng);
-        if scene.feature == TargetFeature::AVX2 {
+        if let Some((scene, ray)) = centre_ray(TargetFeature::AVX2) {
             b.iter(|| unsafe { scene.spheres.hit_avx2(&ray, MIN_T, MAX_T) });
         }
     }
+
+    #[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+    #[bench]
+    fn ray_hit_avx512(b: &mut Bencher) {
+        if let Some((scene, ray)) = centre_ray(TargetFeature::AVX512) {
+            b.iter(|| unsafe { scene.spheres.hit_avx512(&ray, MIN_T, MAX_T) });
+        }
+    }
+
+    // the packet benches trace `PACKET_SIZE` rays per iteration
+
+    #[bench]
+    fn ray_hit_packet_portable(b: &mut Bencher) {
+        let (scene, ray) = centre_ray(TargetFeature::Portable).unwrap();
+        let rays = [ray; PACKET_SIZE];
+        b.iter(|| scene.spheres.hit_packet_portable(&rays, MIN_T, MAX_T));
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[bench]
+    fn ray_hit_packet_sse4_1(b: &mut Bencher) {
+        if let Some((scene, ray)) = centre_ray(TargetFeature::SSE4_1) {
+            let rays = [ray; PACKET_SIZE];
+            b.iter(|| unsafe { scene.spheres.hit_packet_sse4_1(&rays, MIN_T, MAX_T) });
+        }
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[bench]
+    fn ray_hit_packet_avx2(b: &mut Bencher) {
+        if let Some((scene, ray)) = centre_ray(TargetFeature::AVX2) {
+            let rays = [ray; PACKET_SIZE];
+            b.iter(|| unsafe { scene.spheres.hit_packet_avx2(&rays, MIN_T, MAX_T) });
+        }
+    }
 }
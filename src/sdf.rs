@@ -0,0 +1,400 @@
+use crate::{
+    collision::{Cuboid, Ray, RayHit},
+    math::maxf,
+};
+use glam::{vec3, Vec3};
+use std::f32;
+
+/// A signed distance field, positive outside the surface and negative inside. Shapes are centred
+/// on the origin and positioned with `translate`.
+#[derive(Clone, Debug)]
+pub enum Sdf {
+    Sphere {
+        radius: f32,
+    },
+    RoundBox {
+        half_extents: Vec3,
+        radius: f32,
+    },
+    /// Torus lying in the XZ plane.
+    Torus {
+        major_radius: f32,
+        minor_radius: f32,
+    },
+    Mandelbulb {
+        power: f32,
+        iterations: u32,
+    },
+    Translate(Vec3, Box<Sdf>),
+    Scale(f32, Box<Sdf>),
+    Union(Box<Sdf>, Box<Sdf>),
+    SmoothUnion(Box<Sdf>, Box<Sdf>, f32),
+    Intersection(Box<Sdf>, Box<Sdf>),
+    Difference(Box<Sdf>, Box<Sdf>),
+}
+
+fn length3(x: f32, y: f32, z: f32) -> f32 {
+    (x * x + y * y + z * z).sqrt()
+}
+
+fn mandelbulb_distance(p: Vec3, power: f32, iterations: u32) -> f32 {
+    let mut z = p;
+    let mut dr = 1.0;
+    let mut r = 0.0;
+    for _ in 0..iterations {
+        r = z.length();
+        if r > 2.0 || r == 0.0 {
+            break;
+        }
+        // convert to polar coordinates, scale and rotate, then back to cartesian
+        let theta = (z.get_z() / r).acos() * power;
+        let phi = z.get_y().atan2(z.get_x()) * power;
+        let zr = r.powf(power);
+        dr = r.powf(power - 1.0) * power * dr + 1.0;
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        z = zr * vec3(sin_theta * cos_phi, sin_phi * sin_theta, cos_theta) + p;
+    }
+    if r == 0.0 {
+        return 0.0;
+    }
+    0.5 * r.ln() * r / dr
+}
+
+impl Sdf {
+    pub fn translate(self, offset: Vec3) -> Sdf {
+        Sdf::Translate(offset, Box::new(self))
+    }
+
+    /// Uniformly scales the field. Panics unless `scale` is positive and finite, as a negative
+    /// scale would flip the distances and the bounds.
+    pub fn scale(self, scale: f32) -> Sdf {
+        assert!(
+            scale > 0.0 && scale.is_finite(),
+            "SDF scale must be positive, not {}",
+            scale
+        );
+        Sdf::Scale(scale, Box::new(self))
+    }
+
+    pub fn union(self, other: Sdf) -> Sdf {
+        Sdf::Union(Box::new(self), Box::new(other))
+    }
+
+    /// Polynomial smooth minimum of the two fields, `k` controls the blend distance.
+    pub fn smooth_union(self, other: Sdf, k: f32) -> Sdf {
+        Sdf::SmoothUnion(Box::new(self), Box::new(other), k)
+    }
+
+    pub fn intersection(self, other: Sdf) -> Sdf {
+        Sdf::Intersection(Box::new(self), Box::new(other))
+    }
+
+    pub fn difference(self, other: Sdf) -> Sdf {
+        Sdf::Difference(Box::new(self), Box::new(other))
+    }
+
+    pub fn distance(&self, p: Vec3) -> f32 {
+        match self {
+            Sdf::Sphere { radius } => p.length() - radius,
+            Sdf::RoundBox {
+                half_extents,
+                radius,
+            } => {
+                let qx = p.get_x().abs() - half_extents.get_x();
+                let qy = p.get_y().abs() - half_extents.get_y();
+                let qz = p.get_z().abs() - half_extents.get_z();
+                let outside = length3(maxf(qx, 0.0), maxf(qy, 0.0), maxf(qz, 0.0));
+                let inside = maxf(qx, maxf(qy, qz)).min(0.0);
+                outside + inside - radius
+            }
+            Sdf::Torus {
+                major_radius,
+                minor_radius,
+            } => {
+                let qx = (p.get_x() * p.get_x() + p.get_z() * p.get_z()).sqrt() - major_radius;
+                (qx * qx + p.get_y() * p.get_y()).sqrt() - minor_radius
+            }
+            Sdf::Mandelbulb { power, iterations } => mandelbulb_distance(p, *power, *iterations),
+            Sdf::Translate(offset, sdf) => sdf.distance(p - *offset),
+            Sdf::Scale(scale, sdf) => sdf.distance(p * (1.0 / scale)) * scale,
+            Sdf::Union(a, b) => a.distance(p).min(b.distance(p)),
+            Sdf::SmoothUnion(a, b, k) => {
+                let d1 = a.distance(p);
+                let d2 = b.distance(p);
+                let h = (0.5 + 0.5 * (d2 - d1) / k).max(0.0).min(1.0);
+                d2 + (d1 - d2) * h - k * h * (1.0 - h)
+            }
+            Sdf::Intersection(a, b) => maxf(a.distance(p), b.distance(p)),
+            Sdf::Difference(a, b) => maxf(a.distance(p), -b.distance(p)),
+        }
+    }
+
+    /// Conservative axis aligned bounds of the surface.
+    pub fn bounds(&self) -> Cuboid {
+        match self {
+            Sdf::Sphere { radius } => Cuboid::centred(vec3(*radius, *radius, *radius)),
+            Sdf::RoundBox {
+                half_extents,
+                radius,
+            } => Cuboid::centred(*half_extents + vec3(*radius, *radius, *radius)),
+            Sdf::Torus {
+                major_radius,
+                minor_radius,
+            } => {
+                let outer = major_radius + minor_radius;
+                Cuboid::centred(vec3(outer, *minor_radius, outer))
+            }
+            // the power 8 bulb fits inside a radius of ~1.14, lower powers are a little larger
+            Sdf::Mandelbulb { .. } => Cuboid::centred(vec3(1.5, 1.5, 1.5)),
+            Sdf::Translate(offset, sdf) => {
+                let bounds = sdf.bounds();
+                Cuboid {
+                    min: bounds.min + *offset,
+                    max: bounds.max + *offset,
+                }
+            }
+            Sdf::Scale(scale, sdf) => {
+                let bounds = sdf.bounds();
+                Cuboid {
+                    min: bounds.min * *scale,
+                    max: bounds.max * *scale,
+                }
+            }
+            Sdf::Union(a, b) => a.bounds().union(&b.bounds()),
+            Sdf::SmoothUnion(a, b, k) => a.bounds().union(&b.bounds()).expand(*k),
+            Sdf::Intersection(a, b) => a.bounds().intersection(&b.bounds()),
+            Sdf::Difference(a, _) => a.bounds(),
+        }
+    }
+}
+
+/// A distance field positioned in a scene and rendered by sphere tracing.
+#[derive(Clone, Debug)]
+pub struct SdfSolid {
+    sdf: Sdf,
+    bounds: Cuboid,
+    epsilon: f32,
+    max_steps: u32,
+}
+
+impl SdfSolid {
+    /// `epsilon` is the distance from the surface that counts as a hit and `max_steps` limits
+    /// the number of sphere tracing iterations per ray.
+    pub fn new(sdf: Sdf, epsilon: f32, max_steps: u32) -> SdfSolid {
+        // padded so that rays leaving the solid reach the surface before the bounds end
+        let bounds = sdf.bounds().expand(epsilon);
+        SdfSolid {
+            sdf,
+            bounds,
+            epsilon,
+            max_steps,
+        }
+    }
+
+    fn normal(&self, p: Vec3) -> Vec3 {
+        // tetrahedral central differences, 4 evaluations instead of 6
+        let h = self.epsilon;
+        let k0 = vec3(1.0, -1.0, -1.0);
+        let k1 = vec3(-1.0, -1.0, 1.0);
+        let k2 = vec3(-1.0, 1.0, -1.0);
+        let k3 = vec3(1.0, 1.0, 1.0);
+        (k0 * self.sdf.distance(p + k0 * h)
+            + k1 * self.sdf.distance(p + k1 * h)
+            + k2 * self.sdf.distance(p + k2 * h)
+            + k3 * self.sdf.distance(p + k3 * h))
+        .normalize()
+    }
+
+    pub fn ray_hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<(RayHit, f32)> {
+        let (t_enter, t_exit) = self.bounds.ray_interval(ray)?;
+        let mut t = maxf(t_min, t_enter);
+        let t_end = t_exit.min(t_max);
+        let mut steps = 0;
+        // rays bouncing off the surface start within epsilon of it, so hits from inside the
+        // bounds only count once the ray has been further away than that
+        let mut left_surface = t_enter > t_min;
+        while t < t_end && steps < self.max_steps {
+            let point = ray.point_at_parameter(t);
+            // step by the absolute distance so rays travelling inside the surface find the exit
+            let distance = self.sdf.distance(point).abs();
+            if distance < self.epsilon {
+                if left_surface {
+                    let normal = self.normal(point);
+                    return Some((RayHit { point, normal }, t));
+                }
+                t += self.epsilon;
+            } else {
+                left_surface = true;
+                t += distance;
+            }
+            steps += 1;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collision::ray;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1.0e-5
+    }
+
+    fn contains(bounds: &Cuboid, p: Vec3) -> bool {
+        let (min, max) = (bounds.min, bounds.max);
+        min.get_x() <= p.get_x()
+            && p.get_x() <= max.get_x()
+            && min.get_y() <= p.get_y()
+            && p.get_y() <= max.get_y()
+            && min.get_z() <= p.get_z()
+            && p.get_z() <= max.get_z()
+    }
+
+    #[test]
+    fn primitive_distances() {
+        let sphere = Sdf::Sphere { radius: 1.0 };
+        assert!(close(sphere.distance(vec3(2.0, 0.0, 0.0)), 1.0));
+        assert!(close(sphere.distance(Vec3::zero()), -1.0));
+
+        let cube = Sdf::RoundBox {
+            half_extents: vec3(1.0, 1.0, 1.0),
+            radius: 0.0,
+        };
+        assert!(close(cube.distance(vec3(2.0, 0.0, 0.0)), 1.0));
+        assert!(close(cube.distance(vec3(2.0, 2.0, 0.0)), 2.0f32.sqrt()));
+        assert!(close(cube.distance(vec3(0.5, 0.0, 0.0)), -0.5));
+        let rounded = Sdf::RoundBox {
+            half_extents: vec3(1.0, 1.0, 1.0),
+            radius: 0.25,
+        };
+        assert!(close(rounded.distance(vec3(2.0, 0.0, 0.0)), 0.75));
+
+        let torus = Sdf::Torus {
+            major_radius: 2.0,
+            minor_radius: 0.5,
+        };
+        assert!(close(torus.distance(vec3(0.0, 0.0, 2.0)), -0.5));
+        assert!(close(torus.distance(Vec3::zero()), 1.5));
+        assert!(close(torus.distance(vec3(2.0, 1.0, 0.0)), 0.5));
+    }
+
+    #[test]
+    fn transforms_and_booleans() {
+        let sphere = || Sdf::Sphere { radius: 1.0 };
+        let moved = sphere().translate(vec3(3.0, 0.0, 0.0));
+        assert!(close(moved.distance(vec3(3.0, 0.0, 0.0)), -1.0));
+        assert!(close(
+            sphere().scale(2.0).distance(vec3(3.0, 0.0, 0.0)),
+            1.0
+        ));
+
+        let p = vec3(0.5, 0.0, 0.0);
+        assert!(close(sphere().union(moved.clone()).distance(p), -0.5));
+        assert!(close(sphere().intersection(moved.clone()).distance(p), 1.5));
+        assert!(close(sphere().difference(moved.clone()).distance(p), -0.5));
+        assert!(close(
+            sphere().difference(sphere()).distance(Vec3::zero()),
+            1.0
+        ));
+
+        // the smooth union only differs from the union near where the surfaces meet
+        let smooth = sphere().smooth_union(moved, 0.5);
+        assert!(smooth.distance(vec3(1.5, 0.0, 0.0)) < 0.5);
+        assert!(close(smooth.distance(vec3(-2.0, 0.0, 0.0)), 1.0));
+    }
+
+    #[test]
+    #[should_panic(expected = "SDF scale must be positive")]
+    fn negative_scale_is_rejected() {
+        Sdf::Sphere { radius: 1.0 }.scale(-2.0);
+    }
+
+    #[test]
+    fn bounds_contain_the_surface() {
+        let solids = vec![
+            Sdf::Torus {
+                major_radius: 2.0,
+                minor_radius: 0.5,
+            }
+            .translate(vec3(1.0, 2.0, 3.0)),
+            Sdf::Sphere { radius: 1.0 }.scale(0.5).smooth_union(
+                Sdf::Sphere { radius: 1.0 }.translate(vec3(1.0, 0.0, 0.0)),
+                0.5,
+            ),
+            Sdf::RoundBox {
+                half_extents: vec3(1.0, 0.5, 2.0),
+                radius: 0.1,
+            }
+            .difference(Sdf::Sphere { radius: 0.8 }),
+            Sdf::Sphere { radius: 1.0 }.intersection(Sdf::RoundBox {
+                half_extents: vec3(2.0, 0.5, 2.0),
+                radius: 0.0,
+            }),
+        ];
+        for sdf in solids {
+            let bounds = sdf.bounds();
+            // everywhere inside the solid lies inside its bounds
+            for i in 0..4096 {
+                let f = |shift: u32| ((i >> shift) & 15) as f32 / 15.0 * 12.0 - 6.0;
+                let p = vec3(f(0), f(4), f(8));
+                if sdf.distance(p) <= 0.0 {
+                    assert!(contains(&bounds, p), "{:?} outside {:?}", p, bounds);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sphere_tracing_finds_the_surface() {
+        let solid = SdfSolid::new(Sdf::Sphere { radius: 1.0 }, 1.0e-4, 64);
+        let direction = vec3(1.0, 0.0, 0.0);
+        let (hit, t) = solid
+            .ray_hit(&ray(vec3(-5.0, 0.0, 0.0), direction), 0.001, f32::MAX)
+            .unwrap();
+        assert!((t - 4.0).abs() < 1.0e-3);
+        assert!((hit.normal - vec3(-1.0, 0.0, 0.0)).length() < 1.0e-2);
+        assert!(solid
+            .ray_hit(&ray(vec3(-5.0, 0.0, 0.0), direction), 0.001, 3.0)
+            .is_none());
+        assert!(solid
+            .ray_hit(&ray(vec3(-5.0, 1.5, 0.0), direction), 0.001, f32::MAX)
+            .is_none());
+
+        // rays leaving the solid find their way out
+        let (_, t) = solid
+            .ray_hit(&ray(Vec3::zero(), direction), 0.001, f32::MAX)
+            .unwrap();
+        assert!((t - 1.0).abs() < 1.0e-3);
+
+        // rays leaving a hit point don't hit the surface they start on, with the presets'
+        // epsilon which is larger than the distance grazing rays move in t_min
+        let solid = SdfSolid::new(Sdf::Sphere { radius: 1.0 }, 0.0005, 256);
+        let down = vec3(0.0, -1.0, 0.0);
+        let (hit, _) = solid
+            .ray_hit(&ray(vec3(0.0, 5.0, 0.0), down), 0.001, f32::MAX)
+            .unwrap();
+        assert!(solid
+            .ray_hit(&ray(hit.point, direction), 0.001, f32::MAX)
+            .is_none());
+        let (_, t) = solid
+            .ray_hit(&ray(hit.point, down), 0.001, f32::MAX)
+            .unwrap();
+        assert!((t - 2.0).abs() < 1.0e-3, "{}", t);
+
+        let bulb = SdfSolid::new(
+            Sdf::Mandelbulb {
+                power: 8.0,
+                iterations: 10,
+            },
+            1.0e-3,
+            256,
+        );
+        let (_, t) = bulb
+            .ray_hit(&ray(vec3(-5.0, 0.1, 0.2), direction), 0.001, f32::MAX)
+            .unwrap();
+        assert!(t > 3.5 && t < 5.0, "{}", t);
+    }
+}
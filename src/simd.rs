@@ -5,14 +5,44 @@ use std::arch::x86::*;
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
 
-#[derive(Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum TargetFeature {
+    /// 16 lanes with AVX-512F.
+    AVX512,
     AVX2,
     SSE4_1,
+    /// 8 lanes in plain Rust which the compiler vectorises for the target, such as NEON on ARM.
+    Portable,
     FallBack,
 }
 
 impl TargetFeature {
+    /// Every instruction set, widest first.
+    pub const ALL: [TargetFeature; 5] = [
+        TargetFeature::AVX512,
+        TargetFeature::AVX2,
+        TargetFeature::SSE4_1,
+        TargetFeature::Portable,
+        TargetFeature::FallBack,
+    ];
+
+    /// Names accepted by `from_name`.
+    pub const NAMES: [&'static str; 5] = ["avx512", "avx2", "sse4.1", "portable", "scalar"];
+
+    pub fn from_name(name: &str) -> Option<TargetFeature> {
+        match name {
+            "avx512" => Some(TargetFeature::AVX512),
+            "avx2" => Some(TargetFeature::AVX2),
+            "sse4.1" => Some(TargetFeature::SSE4_1),
+            "portable" => Some(TargetFeature::Portable),
+            "scalar" => Some(TargetFeature::FallBack),
+            _ => None,
+        }
+    }
+
+    /// The fastest instruction set the CPU supports. AVX-512 is only used when asked for, it
+    /// renders scenes with hundreds of spheres faster but the presets with a handful of spheres
+    /// slower than AVX2.
     pub fn detect() -> TargetFeature {
         #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
         {
@@ -23,18 +53,50 @@ impl TargetFeature {
                 return TargetFeature::SSE4_1;
             }
         }
-        TargetFeature::FallBack
+        TargetFeature::Portable
     }
-    pub fn print_version(&self) {
+
+    /// Whether the CPU running this can use the instruction set. AVX-512 also needs the
+    /// `avx512` cargo feature.
+    pub fn is_supported(self) -> bool {
+        match self {
+            #[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+            TargetFeature::AVX512 => is_x86_feature_detected!("avx512f"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            TargetFeature::AVX2 => is_x86_feature_detected!("avx2"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            TargetFeature::SSE4_1 => is_x86_feature_detected!("sse4.1"),
+            TargetFeature::Portable | TargetFeature::FallBack => true,
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    /// Why `is_supported` is false.
+    pub fn unsupported_reason(self) -> String {
+        if self == TargetFeature::AVX512 && !cfg!(feature = "avx512") {
+            "AVX512 needs building with the avx512 feature".to_string()
+        } else {
+            format!("this CPU doesn't support {}", self.name())
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
         match self {
-            TargetFeature::AVX2 => println!("Using AVX2"),
-            TargetFeature::SSE4_1 => println!("Using SSE4.1"),
-            TargetFeature::FallBack => println!("Using scalar"),
+            TargetFeature::AVX512 => "AVX512",
+            TargetFeature::AVX2 => "AVX2",
+            TargetFeature::SSE4_1 => "SSE4.1",
+            TargetFeature::Portable => "portable",
+            TargetFeature::FallBack => "scalar",
         }
     }
+    pub fn print_version(&self) {
+        println!("Using {}", self.name());
+    }
     pub fn get_bits(&self) -> usize {
         match self {
-            TargetFeature::AVX2 => 256,
+            TargetFeature::AVX512 => 512,
+            TargetFeature::AVX2 | TargetFeature::Portable => 256,
             TargetFeature::SSE4_1 => 128,
             TargetFeature::FallBack => 32,
         }
@@ -43,6 +105,7 @@ impl TargetFeature {
 
 macro_rules! _ps_const_ty {
     ($name:ident, $field:ident, $x:expr) => {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
         const $name: UnionCast = UnionCast {
             $field: [$x, $x, $x, $x],
         };
@@ -207,6 +270,7 @@ pub unsafe fn sinf_cosf_sse2(x: __m128) -> (__m128, __m128) {
     )
 }
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[cfg_attr(
     any(target_arch = "x86", target_arch = "x86_64"),
     target_feature(enable = "sse2")
@@ -216,6 +280,7 @@ pub unsafe fn blend_i32_sse2(lhs: __m128i, rhs: __m128i, cond: __m128) -> __m128
     _mm_or_si128(_mm_and_si128(d, rhs), _mm_andnot_si128(d, lhs))
 }
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[cfg_attr(
     any(target_arch = "x86", target_arch = "x86_64"),
     target_feature(enable = "sse2")
@@ -226,6 +291,7 @@ pub unsafe fn hmin_sse2(v: __m128) -> f32 {
     _mm_cvtss_f32(v)
 }
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[cfg_attr(
     any(target_arch = "x86", target_arch = "x86_64"),
     target_feature(enable = "avx2")
@@ -237,6 +303,7 @@ pub unsafe fn hmin_avx2(v: __m256) -> f32 {
     _mm256_cvtss_f32(v)
 }
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[cfg_attr(
     any(target_arch = "x86", target_arch = "x86_64"),
     target_feature(enable = "sse2")
@@ -246,6 +313,7 @@ pub unsafe fn blend_f32_sse2(lhs: __m128, rhs: __m128, cond: __m128) -> __m128 {
     _mm_or_ps(_mm_and_ps(d, rhs), _mm_andnot_ps(d, lhs))
 }
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[cfg_attr(
     any(target_arch = "x86", target_arch = "x86_64"),
     target_feature(enable = "sse2")
@@ -264,6 +332,7 @@ pub unsafe fn dot3_sse2(
     dot
 }
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 #[cfg_attr(
     any(target_arch = "x86", target_arch = "x86_64"),
     target_feature(enable = "avx2")
@@ -281,3 +350,19 @@ pub unsafe fn dot3_avx2(
     dot = _mm256_add_ps(dot, _mm256_mul_ps(z0, z1));
     dot
 }
+
+#[cfg(all(feature = "avx512", any(target_arch = "x86", target_arch = "x86_64")))]
+#[target_feature(enable = "avx512f")]
+pub unsafe fn dot3_avx512(
+    x0: __m512,
+    x1: __m512,
+    y0: __m512,
+    y1: __m512,
+    z0: __m512,
+    z1: __m512,
+) -> __m512 {
+    let mut dot = _mm512_mul_ps(x0, x1);
+    dot = _mm512_add_ps(dot, _mm512_mul_ps(y0, y1));
+    dot = _mm512_add_ps(dot, _mm512_mul_ps(z0, z1));
+    dot
+}
@@ -0,0 +1,243 @@
+use pathtrace_rs::{
+    scene::{Params, RayCounts},
+    simd::TargetFeature,
+};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+pub fn elapsed_secs(start: SystemTime) -> f64 {
+    let elapsed = start.elapsed().expect("SystemTime elapsed time failed");
+    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1_000_000_000.0
+}
+
+/// Peak resident set size of the process, only available on Linux.
+fn peak_memory_bytes() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+pub fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Wall clock seconds spent in each phase of an offline render.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PhaseTimes {
+    pub scene_build: f64,
+    pub render: f64,
+    pub denoise: f64,
+    pub write: f64,
+}
+
+impl PhaseTimes {
+    pub fn total(&self) -> f64 {
+        self.scene_build + self.render + self.denoise + self.write
+    }
+}
+
+/// Performance statistics for an offline render, written as JSON for tracking over time.
+pub struct RenderReport {
+    pub preset: String,
+    pub params: Params,
+    pub frames: u32,
+    pub times: PhaseTimes,
+    pub rays: RayCounts,
+    pub target_feature: TargetFeature,
+    /// The last image written.
+    pub image: Option<PathBuf>,
+}
+
+impl RenderReport {
+    pub fn new(preset: &str, params: &Params, target_feature: TargetFeature) -> RenderReport {
+        RenderReport {
+            preset: preset.to_string(),
+            params: *params,
+            frames: 0,
+            times: PhaseTimes::default(),
+            rays: RayCounts::default(),
+            target_feature,
+            image: None,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mrays_per_sec = if self.times.render > 0.0 {
+            self.rays.total() as f64 / 1_000_000.0 / self.times.render
+        } else {
+            0.0
+        };
+        let peak_memory = peak_memory_bytes().map_or("null".to_string(), |bytes| bytes.to_string());
+        let image = self.image.as_ref().map_or("null".to_string(), |path| {
+            json_string(&path.to_string_lossy())
+        });
+        format!(
+            r#"{{
+  "preset": {},
+  "image": {},
+  "width": {},
+  "height": {},
+  "samples_per_pixel": {},
+  "max_depth": {},
+  "frames": {},
+  "timings_secs": {{
+    "scene_build": {:.6},
+    "render": {:.6},
+    "denoise": {:.6},
+    "write": {:.6},
+    "total": {:.6}
+  }},
+  "rays": {{
+    "camera": {},
+    "bounce": {},
+    "shadow": {},
+    "total": {}
+  }},
+  "mrays_per_sec": {:.3},
+  "average_path_length": {:.4},
+  "simd": {},
+  "threads": {},
+  "peak_memory_bytes": {}
+}}
+"#,
+            json_string(&self.preset),
+            image,
+            self.params.width,
+            self.params.height,
+            self.params.samples,
+            self.params.max_depth,
+            self.frames,
+            self.times.scene_build,
+            self.times.render,
+            self.times.denoise,
+            self.times.write,
+            self.times.total(),
+            self.rays.camera,
+            self.rays.bounce,
+            self.rays.shadow,
+            self.rays.total(),
+            mrays_per_sec,
+            self.rays.average_path_length(),
+            json_string(self.target_feature.name()),
+            rayon::current_num_threads(),
+            peak_memory
+        )
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut file = fs::File::create(path)?;
+        file.write_all(self.to_json().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bench::Json;
+
+    fn report() -> RenderReport {
+        let mut report = RenderReport::new("aras", &Params::new(32, 18), TargetFeature::FallBack);
+        report.frames = 2;
+        report.times = PhaseTimes {
+            scene_build: 0.25,
+            render: 2.0,
+            denoise: 0.5,
+            write: 0.125,
+        };
+        report.rays = RayCounts {
+            camera: 1_000_000,
+            bounce: 2_000_000,
+            shadow: 1_000_000,
+        };
+        report
+    }
+
+    #[test]
+    fn phase_times_add_up() {
+        assert_eq!(report().times.total(), 2.875);
+        assert_eq!(PhaseTimes::default().total(), 0.0);
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(json_string(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+        assert_eq!(
+            json_string("tab\tnew\nline\u{1}"),
+            r#""tab\u0009new\u000aline\u0001""#
+        );
+        let tricky = "C:\\renders\\\"quoted\"\n\u{7f}é";
+        match Json::parse(&json_string(tricky)) {
+            Ok(Json::String(parsed)) => assert_eq!(parsed, tricky),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn report_is_valid_json() {
+        let mut report = report();
+        report.preset = "quote\"d".to_string();
+        report.image = Some(PathBuf::from("out/frame \"1\".png"));
+        let json = Json::parse(&report.to_json()).unwrap();
+        let number = |section: Option<&str>, key: &str| {
+            let value = match section {
+                Some(section) => json.get(section).and_then(|section| section.get(key)),
+                None => json.get(key),
+            };
+            value.and_then(Json::as_f64).unwrap()
+        };
+        assert_eq!(json.get("preset").and_then(Json::as_str), Some("quote\"d"));
+        assert_eq!(
+            json.get("image").and_then(Json::as_str),
+            Some("out/frame \"1\".png")
+        );
+        assert_eq!(number(None, "width"), 32.0);
+        assert_eq!(number(None, "height"), 18.0);
+        assert_eq!(number(None, "frames"), 2.0);
+        assert_eq!(number(Some("timings_secs"), "render"), 2.0);
+        assert_eq!(number(Some("timings_secs"), "total"), 2.875);
+        assert_eq!(number(Some("rays"), "total"), 4_000_000.0);
+        assert_eq!(number(None, "mrays_per_sec"), 2.0);
+        assert_eq!(number(None, "average_path_length"), 3.0);
+        assert_eq!(json.get("simd").and_then(Json::as_str), Some("scalar"));
+        assert!(number(None, "threads") >= 1.0);
+        // peak memory is only known on Linux
+        match json.get("peak_memory_bytes") {
+            Some(Json::Number(bytes)) => assert!(*bytes > 0.0),
+            Some(Json::Literal) => assert!(cfg!(not(target_os = "linux"))),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn missing_image_is_null() {
+        let json = Json::parse(&report().to_json()).unwrap();
+        match json.get("image") {
+            Some(Json::Literal) => {}
+            other => panic!("{:?}", other),
+        }
+        let empty = RenderReport::new("aras", &Params::new(1, 1), TargetFeature::FallBack);
+        let json = Json::parse(&empty.to_json()).unwrap();
+        assert_eq!(json.get("mrays_per_sec").and_then(Json::as_f64), Some(0.0));
+        assert_eq!(
+            json.get("average_path_length").and_then(Json::as_f64),
+            Some(0.0)
+        );
+    }
+}
@@ -0,0 +1,91 @@
+//! Runs the `--coordinator` and `--worker` command line modes as separate processes on
+//! localhost.
+
+use std::{
+    env, fs,
+    io::{BufRead, BufReader},
+    net::TcpListener,
+    path::PathBuf,
+    process::{Command, Stdio},
+    thread,
+};
+
+const BIN: &str = env!("CARGO_BIN_EXE_pathtrace-rs");
+
+/// An empty directory for one test's output.
+fn output_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("pathtrace-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A localhost address that was free a moment ago.
+fn free_address() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+/// Renders `passes` passes with a coordinator and `workers` worker processes, all given
+/// `args`, writing `output.png` to `dir`. Returns whether every process succeeded.
+fn render_distributed(dir: &PathBuf, passes: u32, workers: usize, args: &[&str]) -> bool {
+    let address = free_address();
+    let common = ["-W", "32", "-H", "24", "-S", "2", "-D", "4", "--seed", "3"];
+    let mut coordinator = Command::new(BIN)
+        .args(&common)
+        .args(args)
+        .args(&[
+            "-O",
+            "--coordinator",
+            &address,
+            "--passes",
+            &passes.to_string(),
+        ])
+        .arg("-o")
+        .arg(dir.join("output.png"))
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // start the workers once the coordinator is listening
+    let mut lines = BufReader::new(coordinator.stdout.take().unwrap()).lines();
+    loop {
+        match lines.next() {
+            Some(line) => {
+                if line.unwrap().starts_with("waiting for workers") {
+                    break;
+                }
+            }
+            None => return false,
+        }
+    }
+    let drain = thread::spawn(move || lines.for_each(drop));
+
+    let workers: Vec<_> = (0..workers)
+        .map(|_| {
+            Command::new(BIN)
+                .args(&common)
+                .args(args)
+                .args(&["--worker", &address])
+                .stdout(Stdio::null())
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    let workers_ok = workers
+        .into_iter()
+        .all(|mut worker| worker.wait().unwrap().success());
+    let coordinator_ok = coordinator.wait().unwrap().success();
+    drain.join().unwrap();
+    workers_ok && coordinator_ok
+}
+
+#[test]
+fn workers_render_aovs() {
+    let dir = output_dir("aovs");
+    assert!(render_distributed(&dir, 3, 2, &["--aovs", "exr"]));
+    let exr = fs::read(dir.join("output.exr")).unwrap();
+    let has_channel = |name: &[u8]| exr.windows(name.len()).any(|window| window == name);
+    assert!(has_channel(b"albedo.R\0"));
+    assert!(has_channel(b"objectId\0"));
+    fs::remove_dir_all(&dir).unwrap();
+}